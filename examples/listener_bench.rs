@@ -0,0 +1,157 @@
+
+/*!
+ * Compares the old sleep-and-poll listener strategy against the
+ * mio readiness loop used by run_listeners. Each strategy runs a UDP
+ * echo server on loopback; we measure round trip latency for a burst
+ * of pings, then leave the server idle, and report how much CPU time
+ * its thread used over both phases. Run it with
+ *
+ *   cargo run --release --example listener_bench [poll_delay_ns] [round_trips]
+ */
+
+use std::env;
+use std::io::{self, Write};
+use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+const PING_LEN: usize = 64;
+const IDLE_MS: u64 = 1000;
+const SOCKET_TOKEN: mio::Token = mio::Token(0);
+// meili.toml's default poll_delay_ns
+const DEFAULT_POLL_DELAY_NS: usize = 4000000;
+const DEFAULT_ROUND_TRIPS: usize = 1000;
+
+struct BenchResult {
+  mean_rtt: Duration,
+  max_rtt: Duration,
+  server_cpu: Option<Duration>,
+}
+
+fn main() -> io::Result<()> {
+  let args: Vec<String> = env::args().collect();
+  let poll_delay_ns = args.get(1).and_then(|s| s.parse::<usize>().ok()).unwrap_or(DEFAULT_POLL_DELAY_NS);
+  let round_trips = args.get(2).and_then(|s| s.parse::<usize>().ok()).unwrap_or(DEFAULT_ROUND_TRIPS);
+  run_listener_bench(&mut io::stdout(), poll_delay_ns, round_trips)
+}
+
+fn run_listener_bench(io: &mut dyn Write, poll_delay_ns: usize, round_trips: usize) -> io::Result<()> {
+  writeln!(io, "{} round trips then {}ms idle, poll_delay_ns={}", round_trips, IDLE_MS, poll_delay_ns)?;
+  let busy = bench(round_trips, move |socket, stop| busy_poll_echo(socket, stop, poll_delay_ns))?;
+  report(io, "busy poll", &busy)?;
+  let evented = bench(round_trips, evented_echo)?;
+  report(io, "event loop", &evented)?;
+  Ok(())
+}
+
+fn report(io: &mut dyn Write, name: &str, r: &BenchResult) -> io::Result<()> {
+  let cpu = match r.server_cpu {
+    Some(cpu) => format!("{:.1}ms", cpu.as_secs_f64() * 1000.0),
+    None => "n/a".to_string(),
+  };
+  writeln!(io, "{:>10}: mean rtt {:.1}us, max rtt {:.1}us, server cpu {}",
+    name, r.mean_rtt.as_secs_f64() * 1e6, r.max_rtt.as_secs_f64() * 1e6, cpu
+  )
+}
+
+fn bench<F>(round_trips: usize, server: F) -> io::Result<BenchResult>
+  where F: FnOnce(UdpSocket, Arc<AtomicBool>) -> io::Result<Option<Duration>> + Send + 'static
+{
+  let server_socket = UdpSocket::bind("127.0.0.1:0")?;
+  let server_addr = server_socket.local_addr()?;
+  let stop = Arc::new(AtomicBool::new(false));
+  let server_stop = stop.clone();
+  let server_thread = thread::spawn(move || server(server_socket, server_stop));
+
+  let client = UdpSocket::bind("127.0.0.1:0")?;
+  client.set_read_timeout(Some(Duration::from_secs(5)))?;
+  let ping = [7u8; PING_LEN];
+  let mut pong = [0u8; PING_LEN];
+  let mut total = Duration::from_secs(0);
+  let mut max_rtt = Duration::from_secs(0);
+  for _ in 0..round_trips {
+    let started = Instant::now();
+    client.send_to(&ping, server_addr)?;
+    client.recv_from(&mut pong)?;
+    let rtt = started.elapsed();
+    total += rtt;
+    if rtt > max_rtt {
+      max_rtt = rtt;
+    }
+  }
+
+  thread::sleep(Duration::from_millis(IDLE_MS));
+  stop.store(true, Ordering::SeqCst);
+  // Unblocks the evented server so it notices stop
+  client.send_to(&ping, server_addr)?;
+  let server_cpu = server_thread.join()
+    .map_err(|_| io::Error::other("bench server panicked"))??;
+
+  Ok(BenchResult {
+    mean_rtt: total / std::cmp::max(round_trips, 1) as u32,
+    max_rtt,
+    server_cpu,
+  })
+}
+
+/**
+ * The strategy run_listeners used before it was event driven.
+ */
+fn busy_poll_echo(socket: UdpSocket, stop: Arc<AtomicBool>, poll_delay_ns: usize) -> io::Result<Option<Duration>> {
+  socket.set_nonblocking(true)?;
+  let cpu_start = thread_cpu_time();
+  let mut buf = [0u8; PING_LEN];
+  while !stop.load(Ordering::SeqCst) {
+    match socket.recv_from(&mut buf) {
+      Ok((n, from)) => { socket.send_to(&buf[..n], from)?; }
+      Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+      Err(e) => return Err(e),
+    }
+    thread::sleep(Duration::from_nanos(poll_delay_ns as u64));
+  }
+  Ok(cpu_elapsed(cpu_start))
+}
+
+fn evented_echo(socket: UdpSocket, stop: Arc<AtomicBool>) -> io::Result<Option<Duration>> {
+  socket.set_nonblocking(true)?;
+  let mut socket = mio::net::UdpSocket::from_std(socket);
+  let mut poll = mio::Poll::new()?;
+  poll.registry().register(&mut socket, SOCKET_TOKEN, mio::Interest::READABLE)?;
+  let mut events = mio::Events::with_capacity(16);
+  let cpu_start = thread_cpu_time();
+  let mut buf = [0u8; PING_LEN];
+  while !stop.load(Ordering::SeqCst) {
+    poll.poll(&mut events, None)?;
+    loop {
+      match socket.recv_from(&mut buf) {
+        Ok((n, from)) => { socket.send_to(&buf[..n], from)?; }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(e) => return Err(e),
+      }
+    }
+  }
+  Ok(cpu_elapsed(cpu_start))
+}
+
+#[cfg(unix)]
+fn thread_cpu_time() -> Option<Duration> {
+  let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+  if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) } != 0 {
+    return None;
+  }
+  Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+#[cfg(not(unix))]
+fn thread_cpu_time() -> Option<Duration> {
+  None
+}
+
+fn cpu_elapsed(start: Option<Duration>) -> Option<Duration> {
+  match (start, thread_cpu_time()) {
+    (Some(start), Some(end)) => Some(end - start),
+    _ => None,
+  }
+}
@@ -0,0 +1,180 @@
+
+/*!
+ * The command line. Each way of running meili is a subcommand, gui being
+ * the default, and --app-dir and --set may be given to any of them.
+ * clap writes --help from the descriptions below and rejects anything
+ * it does not know, suggesting the closest subcommand or flag.
+ */
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use std::path::PathBuf;
+
+pub enum Action {
+  PrintAbout,
+  CheckConfig,
+  DumpDefaultConfig,
+  OpenGui,
+  RunCLI,
+  RunNetCLI,
+  RunDaemon,
+  ListPeers,
+  Send {
+    peer: String,
+    channel: String,
+    // None reads the message from stdin
+    message: Option<String>,
+  },
+  RunHttpMailbox(String),
+}
+
+pub struct Args {
+  pub action: Action,
+  // None when the OS's config directory is to be used
+  pub app_dir: Option<PathBuf>,
+  pub overrides: Vec<(String, String)>,
+}
+
+// Settings layers, shown after the generated --help
+const AFTER_HELP: &str = "Settings are layered, each layer replacing the top level keys of those before:
+  /etc/meili/meili.toml, when it exists
+  meili.toml in the app directory
+  MEILI_<KEY> environment variables, eg MEILI_POLL_DELAY_NS=2000000
+  --set key=value
+
+If no subcommand is given Meili attaches to the system tray and presents a menu for opening GUIs.";
+
+// Flags of earlier meili versions and the subcommands which replaced them
+const OLD_FLAGS: [(&str, &[&str]); 7] = [
+  ("--about", &["about"]),
+  ("--check-config", &["config", "check"]),
+  ("--dump-default-config", &["config", "dump-default"]),
+  ("--gui", &["gui"]),
+  ("--cli", &["cli"]),
+  ("--net-cli", &["net-cli"]),
+  ("--http-mailbox", &["http-mailbox"]),
+];
+
+/**
+ * --app-dir and --set, which every subcommand takes as well. They are not
+ * clap global args, which copy values between subcommand levels and so
+ * lose a --set given both before and after the subcommand.
+ */
+fn with_common_args(app: App<'static, 'static>) -> App<'static, 'static> {
+  app
+    .arg(Arg::with_name("app-dir")
+      .long("app-dir")
+      .value_name("DIR")
+      .help("Directory holding meili.toml, identity.key and peers.toml, instead of the OS's config directory"))
+    .arg(Arg::with_name("set")
+      .long("set")
+      .value_name("KEY=VALUE")
+      .multiple(true)
+      .number_of_values(1)
+      .validator(|s| if s.contains('=') { Ok(()) } else { Err(format!("--set takes key=value, not '{}'", s)) })
+      .help("Overrides a top level setting for this run, eg --set hostname=laptop. The value is read as TOML, so tables may be given inline: --set 'stun_servers=[{socket=\"203.0.113.10:3478\"}]'"))
+}
+
+fn subcommand(name: &'static str, about: &'static str) -> App<'static, 'static> {
+  with_common_args(SubCommand::with_name(name).about(about))
+}
+
+fn app() -> App<'static, 'static> {
+  with_common_args(App::new("meili"))
+    .version(crate::VERSION)
+    .about("Peer to peer messaging between nodes identified by Ed25519 keys")
+    .setting(AppSettings::VersionlessSubcommands)
+    .after_help(AFTER_HELP)
+    .subcommand(subcommand("gui", "Attaches to the system tray and presents a menu for opening GUIs (the default)"))
+    .subcommand(subcommand("cli", "Runs the interactive shell on this terminal"))
+    .subcommand(subcommand("net-cli", "Serves the interactive shell to local connections on tcp port 1339"))
+    .subcommand(subcommand("daemon", "Runs the listeners and scanner without a GUI or shell until killed"))
+    .subcommand(subcommand("about", "Prints the app directory, identity and every setting with the layer it came from"))
+    .subcommand(subcommand("peers", "Lists the peers remembered in peers.toml"))
+    .subcommand(subcommand("send", "Sends a data message to a known peer and exits once a session carried it. Meili must not already be running, as send binds the same listeners")
+      .arg(Arg::with_name("peer")
+        .required(true)
+        .help("Fingerprint or nickname of the peer"))
+      .arg(Arg::with_name("message")
+        .help("The message, read from stdin when left out"))
+      .arg(Arg::with_name("channel")
+        .long("channel")
+        .value_name("CHANNEL")
+        .default_value("")
+        .help("The channel message_handlers match on")))
+    .subcommand(subcommand("config", "Checks or prints meili.toml")
+      .setting(AppSettings::SubcommandRequiredElseHelp)
+      .subcommand(subcommand("check", "Lists every problem with the config, with its line and column, and exits non-zero when there are any. Files from older meili versions are upgraded when read, keeping a .bak copy"))
+      .subcommand(subcommand("dump-default", "Prints the default meili.toml, with comments for every setting")))
+    .subcommand(subcommand("http-mailbox", "Runs an HTTP mailbox relay which other nodes may list under [[http_mailboxes]] to exchange packets through")
+      .arg(Arg::with_name("address")
+        .required(true)
+        .help("Address to listen on, eg 0.0.0.0:8080")))
+}
+
+/**
+ * Rewrites flags of earlier versions, eg --about, to their subcommands so
+ * scripts keep working, saying what to use instead.
+ */
+fn replace_old_flags(args: Vec<String>) -> Vec<String> {
+  let mut replaced = Vec::with_capacity(args.len());
+  for arg in args {
+    match OLD_FLAGS.iter().find(|(flag, _)| *flag == arg) {
+      Some((flag, subcommand)) => {
+        eprintln!("{} is deprecated, use `meili {}`", flag, subcommand.join(" "));
+        replaced.extend(subcommand.iter().map(|s| s.to_string()));
+      }
+      None => replaced.push(arg),
+    }
+  }
+  replaced
+}
+
+/**
+ * Parses args, printing help, the version or what is wrong with args and
+ * exiting when there is nothing to run.
+ */
+pub fn parse_args(args: Vec<String>) -> Args {
+  let matches = app().get_matches_from(replace_old_flags(args));
+  // Each subcommand level has its own --app-dir and --set
+  let mut levels = vec![&matches];
+  while let (_, Some(sub)) = levels[levels.len() - 1].subcommand() {
+    levels.push(sub);
+  }
+
+  let action = match matches.subcommand() {
+    ("", _) | ("gui", _) => Action::OpenGui,
+    ("cli", _) => Action::RunCLI,
+    ("net-cli", _) => Action::RunNetCLI,
+    ("daemon", _) => Action::RunDaemon,
+    ("about", _) => Action::PrintAbout,
+    ("peers", _) => Action::ListPeers,
+    ("send", Some(m)) => Action::Send {
+      peer: value(m, "peer"),
+      channel: value(m, "channel"),
+      message: m.value_of("message").map(|s| s.to_string()),
+    },
+    ("config", Some(m)) => match m.subcommand_name() {
+      Some("dump-default") => Action::DumpDefaultConfig,
+      _ => Action::CheckConfig,
+    },
+    ("http-mailbox", Some(m)) => Action::RunHttpMailbox(value(m, "address")),
+    (name, _) => unreachable!("subcommand {} has no action", name),
+  };
+  let app_dir = levels.iter().rev().find_map(|m| m.value_of("app-dir")).map(PathBuf::from);
+  let overrides = levels.iter()
+    .flat_map(|m| m.values_of("set").into_iter().flatten())
+    .filter_map(|s| s.split_once('='))
+    .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+    .collect();
+
+  Args {
+    action,
+    app_dir,
+    overrides,
+  }
+}
+
+fn value(matches: &ArgMatches, name: &str) -> String {
+  matches.value_of(name).unwrap_or_default().to_string()
+}
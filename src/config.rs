@@ -12,14 +12,70 @@ use cidr_utils;
 
 use humantime;
 
-use std::path::{Path};
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
 use std::fs;
-use std::net::{SocketAddr};
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
 use std::fmt;
 
-#[derive(Debug)]
+use crate::config_check::{self, ConfigError, Locator, Sources};
+use crate::peers::TrustLevel;
+use crate::util;
+
+#[derive(Debug, PartialEq)]
 pub struct MeiliIpCidr(cidr_utils::cidr::IpCidr);
 
+impl MeiliIpCidr {
+  /**
+   * The number of addresses in the range. The only range which does not
+   * fit in a u128 is ::/0, which we clamp to u128::MAX.
+   */
+  pub fn size(&self) -> u128 {
+    let (size, overflowed) = self.0.size();
+    if overflowed {
+      return u128::MAX;
+    }
+    size
+  }
+
+  /**
+   * Returns the i-th address of the range, counting from the first address.
+   */
+  pub fn nth(&self, i: u128) -> IpAddr {
+    match self.0.first_as_ip_addr() {
+      IpAddr::V4(first) => IpAddr::V4(Ipv4Addr::from( u32::from(first).wrapping_add(i as u32) )),
+      IpAddr::V6(first) => IpAddr::V6(Ipv6Addr::from( u128::from(first).wrapping_add(i) )),
+    }
+  }
+
+  pub fn first(&self) -> IpAddr {
+    self.0.first_as_ip_addr()
+  }
+
+  pub fn last(&self) -> IpAddr {
+    self.0.last_as_ip_addr()
+  }
+
+  pub fn contains(&self, ip: IpAddr) -> bool {
+    self.0.contains(ip)
+  }
+
+  /**
+   * CIDR ranges either nest or are disjoint, so two overlap
+   * exactly when one contains the other's first address.
+   */
+  pub fn overlaps(&self, other: &MeiliIpCidr) -> bool {
+    self.contains(other.0.first_as_ip_addr()) || other.contains(self.0.first_as_ip_addr())
+  }
+}
+
+impl fmt::Display for MeiliIpCidr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
 impl Serialize for MeiliIpCidr {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -60,9 +116,15 @@ impl<'de> Deserialize<'de> for MeiliIpCidr {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct MeiliHumanDuration(humantime::Duration);
 
+impl MeiliHumanDuration {
+  pub fn as_duration(&self) -> std::time::Duration {
+    *self.0
+  }
+}
+
 impl Serialize for MeiliHumanDuration {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -108,8 +170,66 @@ impl<'de> Deserialize<'de> for MeiliHumanDuration {
 
 
 
+/**
+ * The meili.toml written on first run and printed by `meili config dump-default`.
+ */
+pub const DEFAULT_CONFIG: &str = include_str!("meili.toml");
+
+/**
+ * The config_version this meili writes, see MIGRATIONS for older ones.
+ */
+pub const CONFIG_VERSION: u32 = 2;
+
+/**
+ * Site-wide settings, which the user's meili.toml overrides key by key.
+ */
+pub const SYSTEM_CONFIG_FILE: &str = "/etc/meili/meili.toml";
+
+/**
+ * Where a top level setting came from. Layers are read in the order
+ * below and each replaces the keys of those before it, a table such as
+ * [[udp_sockets_to_listen_on]] being taken whole from one layer.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigLayer {
+  Default,
+  // SYSTEM_CONFIG_FILE, then the user's meili.toml
+  File(PathBuf),
+  // The MEILI_* variable, eg MEILI_HOSTNAME for hostname
+  Environment(String),
+  // The key of a --set key=value
+  CommandLine(String),
+}
+
+impl fmt::Display for ConfigLayer {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ConfigLayer::Default => write!(f, "default"),
+      ConfigLayer::File(file) => write!(f, "{}", file.display()),
+      ConfigLayer::Environment(var) => write!(f, "{}", var),
+      ConfigLayer::CommandLine(key) => write!(f, "--set {}", key),
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
+  // The directory meili.toml was read from; state files live next to it.
+  #[serde(skip)]
+  pub app_dir: PathBuf,
+
+  // --set key=value overrides from the command line, applied again on reloads
+  #[serde(skip)]
+  pub overrides: Vec<(String, String)>,
+
+  // The layer each top level setting was taken from, missing ones are defaults
+  #[serde(skip)]
+  pub sources: BTreeMap<String, ConfigLayer>,
+
+  // Files older than config_version are version 1, see migrate_config
+  #[serde(default = "default_config_version")]
+  pub config_version: u32,
+
   pub hostname: String,
   
   pub poll_delay_ns: usize,
@@ -118,45 +238,312 @@ pub struct Config {
   pub upnp_gw_timeout_ms: usize,
   pub upnp_pref_public_port: usize,
   pub upnp_local_port: usize,
+  #[serde(default = "default_upnp_lease_duration_s")]
+  pub upnp_lease_duration_s: u32,
+  // Where NAT-PMP and PCP requests go, the default route's gateway when unset (linux only)
+  #[serde(default)]
+  pub port_mapping_gateway: Option<Ipv4Addr>,
 
-  #[serde(default = "default_ip_range_scan_seed")]
-  pub ip_range_scan_seed: usize,
+  // TOML integers are signed, so the seed is an i64 whose bits the walk uses as a u64
+  #[serde(default)]
+  pub ip_range_scan_seed: Option<i64>,
   pub ip_ranges_to_scan: Vec<IPRange>,
   
-  pub udp_sockets_to_listen_on: Vec<ConfSocket>
+  pub udp_sockets_to_listen_on: Vec<ConfSocket>,
+
+  #[serde(default = "default_lan_announce_interval_ms")]
+  pub lan_announce_interval_ms: usize,
+  #[serde(default = "default_lan_announce_ttl")]
+  pub lan_announce_ttl: u32,
+
+  #[serde(default = "default_session_rekey_interval")]
+  pub session_rekey_interval: MeiliHumanDuration,
+  #[serde(default = "default_session_idle_timeout")]
+  pub session_idle_timeout: MeiliHumanDuration,
+
+  // Introduce nodes behind NATs to each other and relay for them, see net::rendezvous
+  #[serde(default)]
+  pub rendezvous_serve: bool,
+  #[serde(default = "default_rendezvous_register_interval_ms")]
+  pub rendezvous_register_interval_ms: usize,
+  #[serde(default = "default_hole_punch_timeout_ms")]
+  pub hole_punch_timeout_ms: usize,
+
+  #[serde(default = "default_stun_interval")]
+  pub stun_interval: MeiliHumanDuration,
+  #[serde(default = "default_stun_timeout_ms")]
+  pub stun_timeout_ms: usize,
+
+  #[serde(default)]
+  pub tcp_sockets_to_listen_on: Vec<ConfSocket>,
+
+  #[serde(default)]
+  pub http_mailboxes: Vec<ConfSocket>,
+
+  #[serde(default)]
+  pub rendezvous_servers: Vec<ConfSocket>,
+
+  #[serde(default)]
+  pub stun_servers: Vec<ConfSocket>,
+
+  #[serde(default)]
+  pub message_handlers: Vec<MessageHandler>,
+
+  // When set the identity key is used from a PKCS#11 token instead of identity.key
+  #[serde(default)]
+  pub pkcs11_identity: Option<Pkcs11Identity>,
 }
 
-fn default_ip_range_scan_seed() -> usize {
-  12345 // TODO replace w/ hash of hostname
+/**
+ * A stable 64-bit FNV-1a hash of the hostname. std's DefaultHasher
+ * may change between rust releases, which would break resumable scans.
+ */
+pub fn default_ip_range_scan_seed(hostname: &str) -> u64 {
+  let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+  for b in hostname.as_bytes() {
+    hash ^= *b as u64;
+    hash = hash.wrapping_mul(0x0100_0000_01b3);
+  }
+  hash
+}
+fn default_config_version() -> u32 {
+  1
+}
+fn default_upnp_lease_duration_s() -> u32 {
+  3600
+}
+fn default_lan_announce_interval_ms() -> usize {
+  30000
+}
+fn default_lan_announce_ttl() -> u32 {
+  1
+}
+fn default_rendezvous_register_interval_ms() -> usize {
+  20000
+}
+fn default_hole_punch_timeout_ms() -> usize {
+  5000
+}
+fn default_stun_interval() -> MeiliHumanDuration {
+  MeiliHumanDuration( "30m".parse::<humantime::Duration>().unwrap() )
+}
+fn default_stun_timeout_ms() -> usize {
+  1500
 }
 fn default_max_ips_per_second() -> usize {
   100
 }
+fn default_scan_port() -> u16 {
+  1337
+}
+fn default_handler_message_type() -> String {
+  "data".to_string()
+}
+fn default_handler_timeout_ms() -> usize {
+  5000
+}
+fn default_handler_min_trust() -> TrustLevel {
+  TrustLevel::Verified
+}
 fn default_rescan_age() -> MeiliHumanDuration {
   MeiliHumanDuration( "24h".parse::<humantime::Duration>().unwrap().into() )
 }
+fn default_session_rekey_interval() -> MeiliHumanDuration {
+  MeiliHumanDuration( "10m".parse::<humantime::Duration>().unwrap() )
+}
+fn default_session_idle_timeout() -> MeiliHumanDuration {
+  MeiliHumanDuration( "5m".parse::<humantime::Duration>().unwrap() )
+}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct IPRange {
   pub name: Option<String>,
   pub cidr: MeiliIpCidr,
 
   #[serde(default = "default_max_ips_per_second")]
   pub max_ips_per_second: usize,
+
+  #[serde(default = "default_scan_port")]
+  pub port: u16,
   
   #[serde(default = "default_rescan_age")]
   pub rescan_age: MeiliHumanDuration,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct ConfSocket {
   pub name: Option<String>,
   pub socket: SocketAddr,
+  // Ask the router to forward a public port to this listener
+  #[serde(default)]
+  pub forward: bool,
+}
+
+impl Config {
+  /**
+   * The meili.toml this config was read from, which reloads read again.
+   */
+  pub fn file(&self) -> PathBuf {
+    self.app_dir.join("meili.toml")
+  }
+
+  /**
+   * Every top level setting in effect as TOML, each under a comment naming
+   * the layer it came from. Settings come before tables so it reads back.
+   */
+  pub fn describe_sources(&self) -> String {
+    let values = match toml::Value::try_from(self) {
+      Ok(toml::Value::Table(values)) => values,
+      _ => return String::new(),
+    };
+    let is_table = |v: &toml::Value| match v {
+      toml::Value::Table(_) => true,
+      toml::Value::Array(entries) => entries.iter().any(|e| e.is_table()),
+      _ => false,
+    };
+    let mut keys: Vec<&str> = config_check::fields_of::<Config>().iter()
+      .copied()
+      .filter(|k| values.contains_key(*k))
+      .collect();
+    keys.sort_by_key(|k| is_table(&values[*k]));
+    let mut description = String::new();
+    for key in keys {
+      let mut setting = toml::value::Table::new();
+      setting.insert(key.to_string(), values[key].clone());
+      let layer = self.sources.get(key).unwrap_or(&ConfigLayer::Default);
+      if is_table(&values[key]) {
+        description += "\n";
+      }
+      description += &format!("# {} from {}\n", key, layer);
+      description += &toml::to_string(&setting).unwrap_or_default();
+    }
+    description
+  }
+
+  pub fn scan_seed(&self) -> u64 {
+    self.ip_range_scan_seed.map(|seed| seed as u64).unwrap_or_else(|| default_ip_range_scan_seed(&self.hostname))
+  }
+
+  /**
+   * The unicast ports we listen on, which LAN announcements advertise.
+   */
+  pub fn unicast_udp_ports(&self) -> Vec<u16> {
+    unicast_ports(&self.udp_sockets_to_listen_on)
+  }
+  pub fn unicast_tcp_ports(&self) -> Vec<u16> {
+    unicast_ports(&self.tcp_sockets_to_listen_on)
+  }
+
+  /**
+   * The unicast UDP listeners, which STUN probes and rendezvous traffic are sent from.
+   */
+  pub fn unicast_udp_sockets(&self) -> Vec<SocketAddr> {
+    self.udp_sockets_to_listen_on.iter()
+      .filter(|s| !s.socket.ip().is_multicast())
+      .map(|s| s.socket)
+      .collect()
+  }
+
+  pub fn is_rendezvous_server(&self, addr: &SocketAddr) -> bool {
+    self.rendezvous_servers.iter().any(|s| s.socket == *addr)
+  }
+}
+
+fn unicast_ports(sockets: &[ConfSocket]) -> Vec<u16> {
+  let mut ports: Vec<u16> = sockets.iter()
+    .filter(|s| !s.socket.ip().is_multicast())
+    .map(|s| s.socket.port())
+    .collect();
+  ports.sort();
+  ports.dedup();
+  ports
+}
+
+/**
+ * An external program which received messages are piped to.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MessageHandler {
+  pub name: Option<String>,
+
+  #[serde(default = "default_handler_message_type")]
+  pub message_type: String,
+
+  // Only data messages have a channel
+  pub channel: Option<String>,
+
+  pub command: Vec<String>,
+
+  #[serde(default)]
+  pub reply: bool,
+
+  #[serde(default = "default_handler_timeout_ms")]
+  pub timeout_ms: usize,
+
+  // Messages from peers trusted less than this are not handled
+  #[serde(default = "default_handler_min_trust")]
+  pub min_trust: TrustLevel,
+}
+
+impl MessageHandler {
+  pub fn matches(&self, message_type: &str, channel: Option<&str>) -> bool {
+    if self.message_type != message_type {
+      return false;
+    }
+    match &self.channel {
+      Some(want) => channel == Some(want.as_str()),
+      None => true,
+    }
+  }
+
+  pub fn display_name(&self) -> String {
+    self.name.clone().unwrap_or_else(|| self.command.join(" "))
+  }
+}
+
+/**
+ * Where to find an Ed25519 identity key held by a PKCS#11 token.
+ * The key is found by key_label and/or key_id (hex CKA_ID); when
+ * token_label is omitted the first token holding such a key is used.
+ */
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct Pkcs11Identity {
+  pub module: PathBuf,
+  pub token_label: Option<String>,
+  pub key_label: Option<String>,
+  pub key_id: Option<String>,
+
+  // Falls back to the MEILI_PKCS11_PIN environment variable
+  #[serde(default, skip_serializing)]
+  pub pin: Option<String>,
+}
+
+impl Pkcs11Identity {
+  pub fn user_pin(&self) -> Option<String> {
+    self.pin.clone().or_else(|| std::env::var("MEILI_PKCS11_PIN").ok())
+  }
+}
+
+impl fmt::Debug for Pkcs11Identity {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    // config is printed by the status command, never print the pin
+    f.debug_struct("Pkcs11Identity")
+      .field("module", &self.module)
+      .field("token_label", &self.token_label)
+      .field("key_label", &self.key_label)
+      .field("key_id", &self.key_id)
+      .field("pin", &self.pin.as_ref().map(|_| "<hidden>"))
+      .finish()
+  }
 }
 
 impl Default for Config {
   fn default() -> Config {
     Config {
+      app_dir: PathBuf::new(),
+      overrides: Vec::new(),
+      sources: BTreeMap::new(),
+      config_version: CONFIG_VERSION,
       hostname: String::new(),
       poll_delay_ns: 4000000,
 
@@ -164,37 +551,770 @@ impl Default for Config {
       upnp_gw_timeout_ms: 5000,
       upnp_pref_public_port: 1337,
       upnp_local_port: 1337,
+      upnp_lease_duration_s: default_upnp_lease_duration_s(),
+      port_mapping_gateway: None,
 
-      ip_range_scan_seed: default_ip_range_scan_seed(),
+      ip_range_scan_seed: None,
       ip_ranges_to_scan: Vec::new(),
 
       udp_sockets_to_listen_on: Vec::new(),
+      lan_announce_interval_ms: default_lan_announce_interval_ms(),
+      lan_announce_ttl: default_lan_announce_ttl(),
+      session_rekey_interval: default_session_rekey_interval(),
+      session_idle_timeout: default_session_idle_timeout(),
+      rendezvous_serve: false,
+      rendezvous_register_interval_ms: default_rendezvous_register_interval_ms(),
+      hole_punch_timeout_ms: default_hole_punch_timeout_ms(),
+      stun_interval: default_stun_interval(),
+      stun_timeout_ms: default_stun_timeout_ms(),
+      tcp_sockets_to_listen_on: Vec::new(),
+      http_mailboxes: Vec::new(),
+      rendezvous_servers: Vec::new(),
+      stun_servers: Vec::new(),
+      message_handlers: Vec::new(),
+      pkcs11_identity: None,
     }
   }
 }
 
-pub fn read_config(conf_file: &Path) -> Config {
-  let mut c = read_config_from_file(conf_file);
+/**
+ * Reads the layers of settings, SYSTEM_CONFIG_FILE when it exists, then
+ * conf_file, MEILI_* environment variables and last the --set overrides,
+ * and checks the result, returning every problem found when it is not usable.
+ * An old conf_file is upgraded on disk only when upgrade_on_disk is set,
+ * the site-wide file only ever in memory.
+ */
+pub fn read_config(conf_file: &Path, overrides: &[(String, String)], upgrade_on_disk: bool) -> Result<Config, Vec<ConfigError>> {
+  let mut files = Vec::new();
+  let system_file = PathBuf::from(SYSTEM_CONFIG_FILE);
+  if system_file.exists() && system_file != conf_file {
+    files.push(system_file);
+  }
+  files.push(conf_file.to_path_buf());
+
+  let mut settings = toml::value::Table::new();
+  let mut sources = BTreeMap::new();
+  let mut locators = HashMap::new();
+  let mut errors = Vec::new();
+  for file in files {
+    let conf_contents = match fs::read_to_string(&file) {
+      Ok(conf_contents) => conf_contents,
+      Err(e) => {
+        errors.push(ConfigError::new(file.display(), None, format!("Error opening config: {}", e)));
+        continue;
+      }
+    };
+    let on_disk = upgrade_on_disk && file == conf_file;
+    let layer = migrate_config(&file, conf_contents, on_disk).and_then(|c| parse_config(&file, &c).map(|layer| (c, layer)));
+    match layer {
+      Ok((conf_contents, layer)) => {
+        for (key, value) in layer {
+          sources.insert(key.clone(), ConfigLayer::File(file.clone()));
+          settings.insert(key, value);
+        }
+        locators.insert(file, Locator::new(&conf_contents));
+      }
+      Err(file_errors) => errors.extend(file_errors),
+    }
+  }
+  if !errors.is_empty() {
+    return Err(errors);
+  }
+
+  let fields = config_check::fields_of::<Config>();
+  let mut layered: Vec<(ConfigLayer, &str, String)> = fields.iter()
+    .filter_map(|key| {
+      let var = format!("MEILI_{}", key.to_uppercase());
+      env::var(&var).ok().map(|value| (ConfigLayer::Environment(var), *key, value))
+    })
+    .collect();
+  for (key, value) in overrides {
+    match fields.iter().find(|f| *f == key) {
+      Some(key) => layered.push((ConfigLayer::CommandLine(key.to_string()), key, value.clone())),
+      None => {
+        let message = match util::closest_match(key, fields) {
+          Some(known) => format!("unknown key '{}', did you mean '{}'?", key, known),
+          None => format!("unknown key '{}'", key),
+        };
+        errors.push(ConfigError::new(ConfigLayer::CommandLine(key.clone()), None, message));
+      }
+    }
+  }
+  for (layer, key, value) in layered {
+    let mut with_override = settings.clone();
+    with_override.insert(key.to_string(), override_value(&value, settings.get(key)));
+    // Settings may still be missing, but this override must not be what is wrong
+    let mut with_defaults = with_override.clone();
+    with_defaults.extend(missing_defaults(&with_override));
+    match toml::Value::Table(with_defaults).try_into::<Config>() {
+      Err(e) => errors.push(ConfigError::new(layer, None, e.to_string())),
+      Ok(_) => {
+        settings = with_override;
+        sources.insert(key.to_string(), layer);
+      }
+    }
+  }
+  if !errors.is_empty() {
+    return Err(errors);
+  }
+
+  let mut c: Config = match toml::Value::Table(settings).try_into() {
+    Ok(c) => c,
+    Err(e) => return Err(vec![ConfigError::new(conf_file.display(), None, e.to_string())]),
+  };
+  let errors = config_check::check_config(&c, &Sources {
+    layers: &sources,
+    locators: &locators,
+    user_file: conf_file,
+  });
+  if !errors.is_empty() {
+    return Err(errors);
+  }
   if c.hostname.len() < 4 {
     c.hostname = hostname::get().unwrap_or( std::ffi::OsString::from("localhost") ).to_string_lossy().to_string();
+    sources.remove("hostname");
+  }
+  if let Some(app_dir) = conf_file.parent() {
+    c.app_dir = app_dir.to_path_buf();
+  }
+  c.overrides = overrides.to_vec();
+  c.sources = sources;
+  return Ok(c);
+}
+
+/**
+ * Parses one layer's conf_contents, which need not have every setting,
+ * conf_file only names it in errors.
+ */
+pub fn parse_config(conf_file: &Path, conf_contents: &str) -> Result<toml::value::Table, Vec<ConfigError>> {
+  let locator = Locator::new(conf_contents);
+  // A misspelt key often explains a missing field error, so warn first
+  for warning in config_check::unknown_keys(conf_contents, conf_file, &locator) {
+    println!("warning: {}", warning);
+  }
+  let layer = match conf_contents.parse::<toml::Value>() {
+    Ok(toml::Value::Table(layer)) => layer,
+    Ok(_) => return Ok(toml::value::Table::new()),
+    Err(e) => return Err(vec![ConfigError::from_toml(conf_file, &e)]),
+  };
+  // Deserializing the text rather than the table keeps the position of bad
+  // values. The settings other layers may give are written above it as their
+  // defaults, so only what this layer holds can be wrong.
+  let defaults = toml::to_string(&missing_defaults(&layer)).unwrap_or_default();
+  let lines_before = defaults.lines().count();
+  match toml::from_str::<Config>(&(defaults + conf_contents)) {
+    Ok(_) => Ok(layer),
+    Err(e) => {
+      let mut error = ConfigError::from_toml(conf_file, &e);
+      error.line = error.line.and_then(|l| l.checked_sub(lines_before)).filter(|l| *l > 0);
+      if error.line.is_none() {
+        error.col = None;
+      }
+      Err(vec![error])
+    }
+  }
+}
+
+/**
+ * The top level settings of Config::default() which layer does not have.
+ */
+fn missing_defaults(layer: &toml::value::Table) -> toml::value::Table {
+  let defaults = match toml::Value::try_from(Config::default()) {
+    Ok(toml::Value::Table(defaults)) => defaults,
+    _ => toml::value::Table::new(),
+  };
+  defaults.into_iter().filter(|(key, _)| !layer.contains_key(key)).collect()
+}
+
+/**
+ * A value given in the environment or to --set is read as TOML, so
+ * numbers, booleans and [[table]] entries as inline tables work, but
+ * is taken as a string when it is not TOML or replaces a string.
+ */
+fn override_value(value: &str, replaces: Option<&toml::Value>) -> toml::Value {
+  let parsed = format!("value = {}", value).parse::<toml::Value>().ok()
+    .and_then(|v| v.get("value").cloned());
+  match parsed {
+    Some(parsed) if parsed.is_str() || !replaces.map(|r| r.is_str()).unwrap_or(false) => parsed,
+    _ => toml::Value::String(value.to_string()),
   }
-  return c;
 }
 
-pub fn read_config_from_file(conf_file: &Path) -> Config {
-  match fs::read_to_string(conf_file) {
-    Ok(conf_contents) => {
-      match toml::from_str(&conf_contents) {
-        Ok(config_data) => config_data,
-        Err(e) => {
-          println!("Error reading config: {}", e);
-          Config::default()
+/**
+ * Written as the user's meili.toml on first run when SYSTEM_CONFIG_FILE
+ * exists, so the site-wide settings apply until the user overrides them.
+ */
+pub fn user_config_stub() -> String {
+  format!(r#"# Settings here override those of {system_file}, key by key;
+# a [[table]] listed here replaces all of that table's entries.
+# `meili config dump-default` prints every setting with its description.
+config_version = {version}
+"#,
+  system_file=SYSTEM_CONFIG_FILE,
+  version=CONFIG_VERSION,
+)
+}
+
+/**
+ * MIGRATIONS[n - 1] upgrades the text of a version n file to version n + 1,
+ * adding a note for each change in what the file does.
+ * They edit the text rather than serializing a Config so the user's
+ * comments, formatting and commented out settings survive.
+ */
+const MIGRATIONS: [fn(&str, &mut Vec<String>) -> String; 1] = [
+  migrate_1_to_2,
+];
+
+/**
+ * config_version appeared, along with many settings since the first release.
+ */
+fn migrate_1_to_2(contents: &str, notes: &mut Vec<String>) -> String {
+  add_missing_defaults(&hoist_stranded_settings(contents, notes))
+}
+
+/**
+ * The config_version of a file's text, None when it cannot be told.
+ */
+fn version_of(conf_contents: &str) -> Option<i64> {
+  let value = conf_contents.parse::<toml::Value>().ok()?;
+  match value.get("config_version") {
+    // Before layers every meili.toml was whole, one without listeners only overrides some keys
+    None if value.get("udp_sockets_to_listen_on").is_none() => Some(CONFIG_VERSION as i64),
+    None => Some(1),
+    Some(v) => v.as_integer(),
+  }
+}
+
+/**
+ * The older config_version of conf_file when it is read as upgraded but
+ * left as it is on disk, which happens until meili next starts.
+ */
+pub fn pending_upgrade(conf_file: &Path) -> Option<i64> {
+  let version = version_of(&fs::read_to_string(conf_file).ok()?)?;
+  if version >= 1 && version < CONFIG_VERSION as i64 {
+    Some(version)
+  } else {
+    None
+  }
+}
+
+/**
+ * Upgrades conf_contents to CONFIG_VERSION. With on_disk the result is
+ * written back to conf_file and the old file kept next to it as
+ * meili.toml.v<version>.bak, unless that backup already exists.
+ */
+fn migrate_config(conf_file: &Path, conf_contents: String, on_disk: bool) -> Result<String, Vec<ConfigError>> {
+  let version = match version_of(&conf_contents) {
+    Some(version) => version,
+    // parse_config reports what is wrong with it
+    None => return Ok(conf_contents),
+  };
+  if version == CONFIG_VERSION as i64 {
+    return Ok(conf_contents);
+  }
+  if version < 1 || version > CONFIG_VERSION as i64 {
+    let at = Locator::new(&conf_contents).find("", 0, "config_version");
+    let message = format!("config_version {} is not one this meili knows, which are 1 to {}", version, CONFIG_VERSION);
+    return Err(vec![ConfigError::new(conf_file.display(), at, message)]);
+  }
+
+  let mut migrated = conf_contents.clone();
+  let mut notes = Vec::new();
+  for from in version as u32..CONFIG_VERSION {
+    migrated = MIGRATIONS[from as usize - 1](&migrated, &mut notes);
+    migrated = set_config_version(&migrated, from + 1);
+  }
+  if let Err(e) = migrated.parse::<toml::Value>() {
+    println!("Could not upgrade {} from config_version {}, reading it as it is e={}", conf_file.display(), version, e);
+    return Ok(conf_contents);
+  }
+  for note in &notes {
+    println!("Upgrading {}: {}", conf_file.display(), note);
+  }
+  if !on_disk {
+    println!("Read {} as upgraded from config_version {} to {}, the file is left as it is until meili next starts and lines below count in the upgraded text", conf_file.display(), version, CONFIG_VERSION);
+    return Ok(migrated);
+  }
+
+  let file_name = conf_file.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+  let backup = conf_file.with_file_name(format!("{}.v{}.bak", file_name, version));
+  if backup.exists() {
+    println!("{} already exists, not upgrading {} on disk until it is moved away", backup.display(), conf_file.display());
+    return Ok(migrated);
+  }
+  if let Err(e) = fs::write(&backup, &conf_contents) {
+    println!("Cannot back up {} to {}, not upgrading it on disk e={}", conf_file.display(), backup.display(), e);
+    return Ok(migrated);
+  }
+  match fs::write(conf_file, &migrated) {
+    Ok(()) => println!("Upgraded {} from config_version {} to {}, the old file is kept as {}", conf_file.display(), version, CONFIG_VERSION, backup.display()),
+    Err(e) => println!("Cannot write upgraded {} e={}", conf_file.display(), e),
+  }
+  Ok(migrated)
+}
+
+fn set_config_version(contents: &str, version: u32) -> String {
+  let mut lines: Vec<String> = contents.lines().map(|l| l.to_string()).collect();
+  let line = format!("config_version = {}", version);
+  match lines.iter().position(|l| setting_key(l) == Some("config_version") && !l.trim_start().starts_with('#')) {
+    Some(i) => lines[i] = line,
+    None => lines.insert(0, line),
+  }
+  lines.join("\n") + "\n"
+}
+
+/**
+ * The key a line sets, whether or not it is commented out. Comments which
+ * are prose always have a space after the #, commented settings do not.
+ */
+fn setting_key(line: &str) -> Option<&str> {
+  let line = line.trim_start();
+  let line = line.strip_prefix('#').unwrap_or(line);
+  let key_len = line.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(line.len());
+  if key_len == 0 || !line[key_len..].trim_start().starts_with('=') {
+    return None;
+  }
+  Some(&line[..key_len])
+}
+
+fn is_table_header(line: &str) -> bool {
+  line.trim_start().starts_with('[')
+}
+
+fn is_comment(line: &str) -> bool {
+  line.trim_start().starts_with('#')
+}
+
+/**
+ * Moves top level settings written below a table header, where TOML reads
+ * them as part of that table, above the first table along with the comment
+ * lines right above them. The first release's default meili.toml had
+ * ip_range_scan_seed there. Meili ignored them before, so each is noted.
+ */
+fn hoist_stranded_settings(contents: &str, notes: &mut Vec<String>) -> String {
+  let top_level_fields = config_check::fields_of::<Config>();
+  let mut lines: Vec<String> = contents.lines().map(|l| l.to_string()).collect();
+  let first_table = match lines.iter().position(|l| is_table_header(l)) {
+    Some(i) => i,
+    None => return contents.to_string(),
+  };
+  let mut hoisted: Vec<String> = Vec::new();
+  let mut table_fields: Option<&[&str]> = None;
+  let mut table_header = String::new();
+  let mut i = first_table;
+  while i < lines.len() {
+    if is_table_header(&lines[i]) {
+      table_header = lines[i].trim().to_string();
+      let name = table_header.trim_matches(|c| c == '[' || c == ']').trim();
+      table_fields = config_check::table_fields(name);
+      i += 1;
+      continue;
+    }
+    let is_stranded = match setting_key(&lines[i]) {
+      Some(key) if !is_comment(&lines[i]) => {
+        top_level_fields.contains(&key) && !table_fields.map(|f| f.contains(&key)).unwrap_or(false)
+      }
+      _ => false,
+    };
+    if !is_stranded {
+      i += 1;
+      continue;
+    }
+    notes.push(format!(
+      "moved {} out of {}, where it was ignored, it now takes effect",
+      setting_key(&lines[i]).unwrap_or_default(), table_header,
+    ));
+    let mut start = i;
+    while start > first_table + 1 && is_comment(&lines[start - 1]) && setting_key(&lines[start - 1]).is_none() {
+      start -= 1;
+    }
+    hoisted.extend(lines.drain(start..=i));
+    i = start;
+  }
+  if hoisted.is_empty() {
+    return contents.to_string();
+  }
+  // Above the first table and the comments describing it
+  let mut at = first_table;
+  while at > 0 && is_comment(&lines[at - 1]) {
+    at -= 1;
+  }
+  hoisted.push(String::new());
+  lines.splice(at..at, hoisted);
+  lines.join("\n") + "\n"
+}
+
+/**
+ * The lines of text split where blank lines are.
+ */
+fn paragraphs(text: &str) -> Vec<Vec<&str>> {
+  let mut paragraphs = vec![Vec::new()];
+  for line in text.lines() {
+    if line.trim().is_empty() {
+      paragraphs.push(Vec::new());
+    } else if let Some(last) = paragraphs.last_mut() {
+      last.push(line);
+    }
+  }
+  paragraphs.retain(|p| !p.is_empty());
+  paragraphs
+}
+
+/**
+ * Brings in what the default meili.toml documents and contents lacks:
+ * top level settings, with their comments, placed next to their neighbours
+ * in the default, and the commented out examples of tables contents does not
+ * mention at all. Settings commented out in contents count as present.
+ */
+fn add_missing_defaults(contents: &str) -> String {
+  let mut lines: Vec<String> = contents.lines().map(|l| l.to_string()).collect();
+  // Where a top level setting of contents is, ignoring keys inside tables
+  let find_key = |lines: &Vec<String>, key: &str| {
+    lines.iter()
+      .take_while(|l| !is_table_header(l))
+      .position(|l| setting_key(l) == Some(key))
+  };
+
+  let default_top_level: String = DEFAULT_CONFIG.lines()
+    .take_while(|l| !is_table_header(l))
+    .map(|l| format!("{}\n", l))
+    .collect();
+  // The last line of the setting the next missing paragraph follows, none meaning the top
+  let mut insert_after: Option<usize> = None;
+  for paragraph in paragraphs(&default_top_level) {
+    let keys: Vec<&str> = paragraph.iter().filter_map(|l| setting_key(l)).collect();
+    let present: Vec<usize> = keys.iter().filter_map(|k| find_key(&lines, k)).collect();
+    if keys.is_empty() {
+      continue;
+    }
+    match present.iter().max() {
+      None => {
+        let mut block: Vec<String> = paragraph.iter().map(|l| l.to_string()).collect();
+        let at = match insert_after {
+          Some(i) => {
+            block.insert(0, String::new());
+            i + 1
+          }
+          None => {
+            block.push(String::new());
+            0
+          }
+        };
+        let block_len = block.len();
+        lines.splice(at..at, block);
+        insert_after = Some(if at == 0 { block_len - 2 } else { at + block_len - 1 });
+      }
+      Some(last) => {
+        // Each missing setting comes with the comment lines right above it
+        let mut last = *last;
+        let mut comments: Vec<&str> = Vec::new();
+        for line in &paragraph {
+          match setting_key(line) {
+            Some(key) if find_key(&lines, key).is_none() => {
+              for l in comments.drain(..).chain(std::iter::once(*line)) {
+                last += 1;
+                lines.insert(last, l.to_string());
+              }
+            }
+            Some(_) => comments.clear(),
+            None => comments.push(line),
+          }
         }
+        insert_after = Some(last);
       }
     }
-    Err(e) => {
-      println!("Error opening config: {}", e);
-      Config::default()
+  }
+
+  let default_tables: String = DEFAULT_CONFIG.lines()
+    .skip_while(|l| !is_table_header(l))
+    .map(|l| format!("{}\n", l))
+    .collect();
+  for paragraph in paragraphs(&default_tables) {
+    // eg #[[rendezvous_servers]], which contents neither has nor has commented out
+    let table = paragraph.iter()
+      .map(|l| l.trim_start())
+      .find(|l| l.starts_with("#[") && l.ends_with(']'))
+      .map(|l| l.trim_start_matches('#').trim_matches(|c| c == '[' || c == ']').to_string());
+    if let Some(table) = table {
+      let is_mentioned = lines.iter().any(|l| {
+        l.trim_start().trim_start_matches('#').trim_start().trim_start_matches('[').starts_with(&format!("{}]", table))
+      });
+      if !is_mentioned {
+        lines.push(String::new());
+        lines.extend(paragraph.iter().map(|l| l.to_string()));
+      }
+    }
+  }
+  lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // The default meili.toml of the first release, before config_version
+  const FIRST_RELEASE_CONFIG: &str = r#"
+# This may be used to overwrite your hostname for friendly identification
+# reasons. The hostname is never used as a crypto identity and
+# should not be used except to identify between 2 strangers.
+# When the value has a length < 4 we replace it with the system hostname.
+hostname = ""
+
+# How long should the network thread sleep between checking
+# sockets for packets? High values will burn fewer CPU cycles
+# but will handle fewer total packets per second, while low
+# values will use 100% of a CPU and handle as many packets as possible
+# per second.
+# Value is in Nanoseconds (1000000000 = 1s, 1000000 = 1ms),
+# default is 4ms (4000000ns)
+poll_delay_ns = 4000000
+
+# This causes meili to autodiscover a router
+# and ask for any public UDP port to be forwarded.
+# We first ask for pref_upnp_public_port specifically, and
+# if that fails we ask for a random public port.
+# upnp_local_port must point to a local port opened by
+# a udp_sockets_to_listen_on entry.
+attempt_upnp_port_forward = true
+upnp_gw_timeout_ms = 5000
+upnp_pref_public_port = 1337
+upnp_local_port = 1337
+
+[[udp_sockets_to_listen_on]]
+name = "Default meili local address"
+socket = "0.0.0.0:1337"
+
+[[udp_sockets_to_listen_on]]
+name = "Default meili LAN multicast address"
+socket = "239.10.10.10:1338"
+
+
+# When scanning ip address ranges this number is used
+# to seed the random walk which is performed over the
+# range. By re-using the seed we can perform a random
+# scan which is resumable. If this value is not set in meili.toml
+# a 64-bit hash of the hostname is used.
+ip_range_scan_seed = 12345
+
+# Users may specify as many [[ip_ranges_to_scan]]
+# items as they wany (including none), the name
+# field is optional.
+# the cidr field is required and specifies the network to scan.
+# The max_ips_per_second field limits how many IPs are
+# scanned per second, if omitted it defaults to 100.
+# The rescan_age field specifies when to re-scan an IP address,
+# defaulting to 24 hours.
+[[ip_ranges_to_scan]]
+name = "Optional Name - ipv4 local multicast block"
+cidr = "239.0.0.0/8"
+max_ips_per_second = 150
+rescan_age = "36h"
+
+[[ip_ranges_to_scan]]
+name = "some ipv6 link local range"
+cidr = "fe80:1::1/64"
+
+"#;
+
+  fn top_level_keys(contents: &str) -> Vec<&str> {
+    contents.lines()
+      .take_while(|l| !is_table_header(l))
+      .filter(|l| !is_comment(l))
+      .filter_map(|l| setting_key(l))
+      .collect()
+  }
+
+  fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("meili-config-{}-{}", std::process::id(), name));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("meili.toml");
+    fs::write(&file, contents).unwrap();
+    file
+  }
+
+  #[test]
+  fn setting_key_of_lines() {
+    assert_eq!(setting_key("hostname = \"\""), Some("hostname"));
+    assert_eq!(setting_key("  poll_delay_ns=4000000"), Some("poll_delay_ns"));
+    assert_eq!(setting_key("#stun_interval = \"30m\""), Some("stun_interval"));
+    assert_eq!(setting_key("name = \"a = b\""), Some("name"));
+    // Prose, not a commented out setting
+    assert_eq!(setting_key("# hostname = is what peers see"), None);
+    assert_eq!(setting_key("[[udp_sockets_to_listen_on]]"), None);
+    assert_eq!(setting_key(""), None);
+  }
+
+  #[test]
+  fn upgrades_the_first_release_config() {
+    let mut notes = Vec::new();
+    let migrated = set_config_version(&migrate_1_to_2(FIRST_RELEASE_CONFIG, &mut notes), 2);
+
+    let value = migrated.parse::<toml::Value>().unwrap();
+    assert_eq!(value.get("config_version").and_then(|v| v.as_integer()), Some(2));
+    assert_eq!(value.get("ip_range_scan_seed").and_then(|v| v.as_integer()), Some(12345));
+    assert_eq!(notes.len(), 1);
+    assert!(notes[0].contains("ip_range_scan_seed") && notes[0].contains("[[udp_sockets_to_listen_on]]"));
+    // The comment describing the seed moved with it
+    let comment = migrated.find("# When scanning ip address ranges").unwrap();
+    assert!(comment < migrated.find("ip_range_scan_seed = 12345").unwrap());
+    assert!(comment < migrated.find("[[udp_sockets_to_listen_on]]").unwrap());
+
+    // The user's values are kept, every setting the default has is there
+    let c: Config = toml::from_str(&migrated).unwrap();
+    assert_eq!(c.ip_range_scan_seed, Some(12345));
+    assert_eq!(c.udp_sockets_to_listen_on.len(), 2);
+    assert_eq!(c.ip_ranges_to_scan.len(), 2);
+    assert!(c.upnp_gw_timeout_ms == 5000 && c.upnp_pref_public_port == 1337);
+    let keys: Vec<&str> = migrated.lines().take_while(|l| !is_table_header(l)).filter_map(|l| setting_key(l)).collect();
+    for key in top_level_keys(DEFAULT_CONFIG) {
+      assert!(keys.contains(&key), "{} is missing", key);
+    }
+    for table in &["tcp_sockets_to_listen_on", "http_mailboxes", "rendezvous_servers", "stun_servers", "message_handlers"] {
+      assert!(migrated.contains(&format!("#[[{}]]", table)), "{} is missing", table);
     }
+
+    // Upgrading again changes nothing
+    let mut notes = Vec::new();
+    assert_eq!(migrate_1_to_2(&migrated, &mut notes), migrated);
+    assert!(notes.is_empty());
+  }
+
+  #[test]
+  fn commented_out_settings_count_as_present() {
+    let contents = "#hostname = \"mine\"\npoll_delay_ns = 1000\n\n[[udp_sockets_to_listen_on]]\nsocket = \"0.0.0.0:1337\"\n";
+    let migrated = add_missing_defaults(contents);
+    assert_eq!(migrated.lines().filter(|l| setting_key(l) == Some("hostname")).count(), 1);
+    assert_eq!(migrated.lines().filter(|l| setting_key(l) == Some("poll_delay_ns")).count(), 1);
+    assert!(migrated.contains("#hostname = \"mine\""));
+    let value = migrated.parse::<toml::Value>().unwrap();
+    assert_eq!(value.get("poll_delay_ns").and_then(|v| v.as_integer()), Some(1000));
+    assert!(value.get("hostname").is_none());
+  }
+
+  #[test]
+  fn missing_tables_are_added_commented_out() {
+    let contents = "hostname = \"mine\"\n\n[[stun_servers]]\nsocket = \"127.0.0.1:3478\"\n";
+    let migrated = add_missing_defaults(contents);
+    assert!(migrated.contains("#[[rendezvous_servers]]"));
+    assert!(migrated.contains("#[[message_handlers]]"));
+    // A table the file has is not added again
+    assert!(!migrated.contains("#[[stun_servers]]"));
+    let value = migrated.parse::<toml::Value>().unwrap();
+    assert_eq!(value.get("hostname").and_then(|v| v.as_str()), Some("mine"));
+    assert_eq!(value.get("stun_servers").and_then(|v| v.as_array()).map(|a| a.len()), Some(1));
+    assert!(value.get("rendezvous_servers").is_none() && value.get("message_handlers").is_none());
+  }
+
+  #[test]
+  fn stranded_settings_are_hoisted() {
+    let contents = "hostname = \"mine\"\n\n[[udp_sockets_to_listen_on]]\nname = \"lan\"\nsocket = \"0.0.0.0:1337\"\n\n# How often\nstun_interval = \"5m\"\n#lan_announce_ttl = 2\n\n[[ip_ranges_to_scan]]\ncidr = \"10.0.0.0/8\"\nlan_announce_ttl = 3\n";
+    let mut notes = Vec::new();
+    let migrated = hoist_stranded_settings(contents, &mut notes);
+    let value = migrated.parse::<toml::Value>().unwrap();
+    assert_eq!(value.get("stun_interval").and_then(|v| v.as_str()), Some("5m"));
+    assert_eq!(value.get("lan_announce_ttl").and_then(|v| v.as_integer()), Some(3));
+    assert_eq!(notes.len(), 2);
+    assert!(notes[1].contains("lan_announce_ttl") && notes[1].contains("[[ip_ranges_to_scan]]"));
+    // Table fields stay in their table, commented out settings are left alone
+    let sockets = value.get("udp_sockets_to_listen_on").and_then(|v| v.as_array()).unwrap();
+    assert_eq!(sockets[0].get("name").and_then(|v| v.as_str()), Some("lan"));
+    assert!(migrated.find("#lan_announce_ttl = 2").unwrap() > migrated.find("[[udp_sockets_to_listen_on]]").unwrap());
+    assert!(migrated.find("# How often").unwrap() < migrated.find("[[udp_sockets_to_listen_on]]").unwrap());
+
+    let mut notes = Vec::new();
+    let no_tables = "hostname = \"mine\"\n";
+    assert_eq!(hoist_stranded_settings(no_tables, &mut notes), no_tables);
+    assert!(notes.is_empty());
+  }
+
+  #[test]
+  fn upgrades_on_disk_only_when_asked() {
+    let file = temp_file("on-disk", FIRST_RELEASE_CONFIG);
+    let backup = file.with_file_name("meili.toml.v1.bak");
+
+    let migrated = migrate_config(&file, FIRST_RELEASE_CONFIG.to_string(), false).unwrap();
+    assert_eq!(fs::read_to_string(&file).unwrap(), FIRST_RELEASE_CONFIG);
+    assert!(!backup.exists());
+
+    assert_eq!(migrate_config(&file, FIRST_RELEASE_CONFIG.to_string(), true).unwrap(), migrated);
+    assert_eq!(fs::read_to_string(&file).unwrap(), migrated);
+    assert_eq!(fs::read_to_string(&backup).unwrap(), FIRST_RELEASE_CONFIG);
+
+    // An earlier backup is never overwritten
+    fs::write(&file, FIRST_RELEASE_CONFIG).unwrap();
+    fs::write(&backup, "kept").unwrap();
+    assert_eq!(migrate_config(&file, FIRST_RELEASE_CONFIG.to_string(), true).unwrap(), migrated);
+    assert_eq!(fs::read_to_string(&file).unwrap(), FIRST_RELEASE_CONFIG);
+    assert_eq!(fs::read_to_string(&backup).unwrap(), "kept");
+
+    let _ = fs::remove_dir_all(file.parent().unwrap());
+  }
+
+  #[test]
+  fn check_leaves_an_old_file_alone() {
+    let file = temp_file("check", FIRST_RELEASE_CONFIG);
+    let c = read_config(&file, &[], false).unwrap();
+    assert_eq!(c.ip_range_scan_seed, Some(12345));
+    assert_eq!(fs::read_to_string(&file).unwrap(), FIRST_RELEASE_CONFIG);
+    assert!(!file.with_file_name("meili.toml.v1.bak").exists());
+    assert_eq!(pending_upgrade(&file), Some(1));
+    let _ = fs::remove_dir_all(file.parent().unwrap());
+  }
+
+  #[test]
+  fn an_upgraded_file_has_no_pending_upgrade() {
+    let file = temp_file("upgraded", FIRST_RELEASE_CONFIG);
+    read_config(&file, &[], true).unwrap();
+    assert_eq!(pending_upgrade(&file), None);
+    let _ = fs::remove_dir_all(file.parent().unwrap());
+  }
+
+  #[test]
+  fn a_layer_need_not_have_every_setting() {
+    let file = PathBuf::from("meili.toml");
+    let layer = parse_config(&file, "config_version = 2\npoll_delay_ns = 1000\n").unwrap();
+    assert_eq!(layer.len(), 2);
+  }
+
+  #[test]
+  fn errors_are_placed_in_the_layer() {
+    let file = PathBuf::from("meili.toml");
+    let errors = parse_config(&file, "config_version = 2\n\npoll_delay_ns = \"soon\"\n").unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].line, Some(3));
+    assert!(errors[0].message.contains("invalid type"), "{}", errors[0].message);
+
+    // A field missing from a [[table]] entry is reported where the entry is
+    let contents = "hostname = \"mine\"\n\n[[udp_sockets_to_listen_on]]\nname = \"lan\"\n";
+    let errors = parse_config(&file, contents).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].message.contains("missing field `socket`"), "{}", errors[0].message);
+    assert!(errors[0].line.map(|l| (3..=4).contains(&l)).unwrap_or(false), "{:?}", errors[0].line);
+  }
+
+  #[test]
+  fn overrides_are_checked_on_their_own() {
+    let file = temp_file("overrides", DEFAULT_CONFIG);
+    let overrides = vec![("poll_delay_ns".to_string(), "\"soon\"".to_string())];
+    let errors = read_config(&file, &overrides, false).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].origin, ConfigLayer::CommandLine("poll_delay_ns".to_string()).to_string());
+
+    let overrides = vec![("poll_delay_ns".to_string(), "1000".to_string())];
+    let c = read_config(&file, &overrides, false).unwrap();
+    assert_eq!(c.poll_delay_ns, 1000);
+    let _ = fs::remove_dir_all(file.parent().unwrap());
+  }
+
+  #[test]
+  fn scan_seed_takes_any_toml_integer() {
+    let file = temp_file("seed", &format!("ip_range_scan_seed = -1\n{}", DEFAULT_CONFIG));
+    let c = read_config(&file, &[], false).unwrap();
+    assert_eq!(c.scan_seed(), u64::MAX);
+    // What meili writes can be read back
+    let written = toml::to_string(&toml::Value::try_from(&c).unwrap()).unwrap();
+    let read: Config = toml::from_str(&written).unwrap();
+    assert_eq!(read.ip_range_scan_seed, Some(-1));
+    let _ = fs::remove_dir_all(file.parent().unwrap());
   }
 }
@@ -0,0 +1,457 @@
+
+/*!
+ * Checks a parsed meili.toml for mistakes serde cannot see, such as
+ * listeners which conflict or settings which depend on one another.
+ * Every problem is reported, each with the line and column of the
+ * offending key when it appears in the file, so a typo never costs
+ * more than one round of fixing. Keys serde would ignore are warned about.
+ * A problem with a layered config is reported against the layer which
+ * made the setting, be it a file, a MEILI_* variable or a --set.
+ */
+
+use serde::de::{self, Deserializer, DeserializeOwned, Visitor};
+use serde::forward_to_deserialize_any;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, ConfigLayer, ConfSocket, IPRange, MessageHandler, Pkcs11Identity};
+use crate::net::packet::MessageType;
+use crate::peers::TrustLevel;
+use crate::util;
+
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+  // The file, or the MEILI_* variable or --set, the problem is in
+  pub origin: String,
+  // Both count from 1, and are unknown for keys left out of the file
+  pub line: Option<usize>,
+  pub col: Option<usize>,
+  pub message: String,
+}
+
+impl ConfigError {
+  pub fn new(origin: impl fmt::Display, line_col: Option<(usize, usize)>, message: String) -> ConfigError {
+    ConfigError {
+      origin: origin.to_string(),
+      line: line_col.map(|(l, _)| l),
+      col: line_col.map(|(_, c)| c),
+      message,
+    }
+  }
+
+  /**
+   * toml reports positions counting from 0 and repeats them at the end of its message.
+   */
+  pub fn from_toml(file: &Path, e: &toml::de::Error) -> ConfigError {
+    let mut message = e.to_string();
+    if let Some(i) = message.rfind(" at line ") {
+      message.truncate(i);
+    }
+    ConfigError::new(file.display(), e.line_col().map(|(l, c)| (l + 1, c + 1)), message)
+  }
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match (self.line, self.col) {
+      (Some(line), Some(col)) => write!(f, "{}:{}:{}: {}", self.origin, line, col, self.message),
+      _ => write!(f, "{}: {}", self.origin, self.message),
+    }
+  }
+}
+
+/**
+ * Finds where keys are in the text of a config file. This does not parse
+ * TOML, it only follows table headers and `key =` at the start of lines,
+ * which is how meili.toml is written.
+ */
+pub struct Locator {
+  // (table, index of [[table]] entry, key) -> (line, col), "" being the top level
+  keys: HashMap<(String, usize, String), (usize, usize)>,
+  headers: HashMap<(String, usize), (usize, usize)>,
+}
+
+impl Locator {
+  pub fn new(contents: &str) -> Locator {
+    let mut keys = HashMap::new();
+    let mut headers = HashMap::new();
+    let mut entries: HashMap<String, usize> = HashMap::new();
+    let mut table = (String::new(), 0);
+    for (i, line) in contents.lines().enumerate() {
+      let trimmed = line.trim_start();
+      let at = (i + 1, line.len() - trimmed.len() + 1);
+      if let Some(rest) = trimmed.strip_prefix("[[") {
+        let name = rest.split("]]").next().unwrap_or("").trim().to_string();
+        let count = entries.entry(name.clone()).or_insert(0);
+        table = (name, *count);
+        *count += 1;
+        headers.insert(table.clone(), at);
+      }
+      else if let Some(rest) = trimmed.strip_prefix('[') {
+        let name = rest.split(']').next().unwrap_or("").trim().to_string();
+        table = (name, 0);
+        headers.insert(table.clone(), at);
+      }
+      else if !trimmed.starts_with('#') {
+        if let Some(eq) = trimmed.find('=') {
+          let key = trimmed[..eq].trim().trim_matches('"').to_string();
+          keys.entry((table.0.clone(), table.1, key)).or_insert(at);
+        }
+      }
+    }
+    Locator {
+      keys,
+      headers,
+    }
+  }
+
+  /**
+   * Where key of the index-th table entry is, or else where that entry starts.
+   */
+  pub fn find(&self, table: &str, index: usize, key: &str) -> Option<(usize, usize)> {
+    self.keys.get(&(table.to_string(), index, key.to_string())).copied()
+      .or_else(|| self.headers.get(&(table.to_string(), index)).copied())
+  }
+}
+
+/**
+ * Which layer made each top level setting of a config, and where the keys
+ * of the file layers are, so a problem is reported where it can be fixed.
+ */
+pub struct Sources<'a> {
+  pub layers: &'a BTreeMap<String, ConfigLayer>,
+  pub locators: &'a HashMap<PathBuf, Locator>,
+  // Settings no layer made are blamed on the user's meili.toml
+  pub user_file: &'a Path,
+}
+
+impl<'a> Sources<'a> {
+  /**
+   * The origin and position of key in the index-th entry of table, the
+   * layer being the one which set the whole table.
+   */
+  fn locate(&self, table: &str, index: usize, key: &str) -> (String, Option<(usize, usize)>) {
+    let top_level_key = if table.is_empty() { key } else { table };
+    match self.layers.get(top_level_key) {
+      Some(ConfigLayer::File(file)) => {
+        let at = self.locators.get(file).and_then(|l| l.find(table, index, key));
+        (file.display().to_string(), at)
+      }
+      Some(layer) => (layer.to_string(), None),
+      None => (self.user_file.display().to_string(), None),
+    }
+  }
+}
+
+struct Checker<'a> {
+  sources: &'a Sources<'a>,
+  errors: Vec<ConfigError>,
+}
+
+impl<'a> Checker<'a> {
+  fn error(&mut self, table: &str, index: usize, key: &str, message: String) {
+    let (origin, at) = self.sources.locate(table, index, key);
+    self.errors.push(ConfigError::new(origin, at, message));
+  }
+
+  fn top_level(&mut self, key: &str, message: String) {
+    self.error("", 0, key, message);
+  }
+}
+
+/**
+ * Returns every problem with config, grouped by the layer they are in
+ * and in the order they appear in it.
+ */
+pub fn check_config(config: &Config, sources: &Sources) -> Vec<ConfigError> {
+  let mut c = Checker {
+    sources,
+    errors: Vec::new(),
+  };
+  check_timing(config, &mut c);
+  check_upnp(config, &mut c);
+  check_listeners(config, &mut c);
+  check_servers(config, &mut c);
+  check_ip_ranges(config, &mut c);
+  check_message_handlers(config, &mut c);
+  check_pkcs11_identity(config, &mut c);
+  c.errors.sort_by(|a, b| (&a.origin, a.line, a.col).cmp(&(&b.origin, b.line, b.col)));
+  c.errors
+}
+
+fn check_timing(config: &Config, c: &mut Checker) {
+  if config.poll_delay_ns == 0 {
+    c.top_level("poll_delay_ns", "poll_delay_ns must be more than 0".to_string());
+  }
+  if config.lan_announce_ttl > 255 {
+    c.top_level("lan_announce_ttl", format!("lan_announce_ttl is {}, it can be at most 255", config.lan_announce_ttl));
+  }
+  if config.hole_punch_timeout_ms == 0 {
+    c.top_level("hole_punch_timeout_ms", "hole_punch_timeout_ms must be more than 0".to_string());
+  }
+  if config.stun_timeout_ms == 0 {
+    c.top_level("stun_timeout_ms", "stun_timeout_ms must be more than 0".to_string());
+  }
+  if config.rendezvous_register_interval_ms == 0 && !config.rendezvous_servers.is_empty() {
+    c.top_level("rendezvous_register_interval_ms", "rendezvous_register_interval_ms must be more than 0 when there are rendezvous_servers".to_string());
+  }
+  let durations = [
+    ("session_rekey_interval", &config.session_rekey_interval),
+    ("session_idle_timeout", &config.session_idle_timeout),
+    ("stun_interval", &config.stun_interval),
+  ];
+  for (key, duration) in durations.iter() {
+    if duration.as_duration().as_nanos() == 0 {
+      c.top_level(key, format!("{} must be longer than 0s", key));
+    }
+  }
+}
+
+fn check_upnp(config: &Config, c: &mut Checker) {
+  let ports = [
+    ("upnp_pref_public_port", config.upnp_pref_public_port),
+    ("upnp_local_port", config.upnp_local_port),
+  ];
+  for (key, port) in ports.iter() {
+    if *port == 0 || *port > 65535 {
+      c.top_level(key, format!("{} is {}, ports go from 1 to 65535", key, port));
+    }
+  }
+  let local_port = config.upnp_local_port;
+  if config.attempt_upnp_port_forward && local_port <= 65535 && local_port != 0
+    && !config.unicast_udp_ports().contains(&(local_port as u16)) {
+    c.top_level("upnp_local_port", format!("upnp_local_port {} is not the port of a unicast udp_sockets_to_listen_on entry", local_port));
+  }
+}
+
+fn check_listeners(config: &Config, c: &mut Checker) {
+  let tables = [
+    ("udp_sockets_to_listen_on", &config.udp_sockets_to_listen_on),
+    ("tcp_sockets_to_listen_on", &config.tcp_sockets_to_listen_on),
+  ];
+  for (table, listeners) in tables.iter() {
+    for (i, listener) in listeners.iter().enumerate() {
+      let socket = listener.socket;
+      if listeners[..i].iter().any(|l| l.socket == socket) {
+        c.error(table, i, "socket", format!("{} is listed twice in {}", socket, table));
+      }
+      if socket.ip().is_multicast() {
+        if *table == "tcp_sockets_to_listen_on" {
+          c.error(table, i, "socket", format!("{} is a multicast address, only udp_sockets_to_listen_on may be multicast", socket));
+        }
+        else if socket.port() == 0 {
+          c.error(table, i, "socket", format!("multicast address {} needs a port", socket));
+        }
+      }
+      if listener.forward && !is_forwardable(&socket) {
+        c.error(table, i, "forward", format!("{} cannot be forwarded, only unicast IPv4 listeners can be", socket));
+      }
+    }
+  }
+}
+
+fn is_forwardable(socket: &SocketAddr) -> bool {
+  socket.is_ipv4() && !socket.ip().is_multicast()
+}
+
+fn check_servers(config: &Config, c: &mut Checker) {
+  let tables = [
+    ("http_mailboxes", &config.http_mailboxes),
+    ("rendezvous_servers", &config.rendezvous_servers),
+    ("stun_servers", &config.stun_servers),
+  ];
+  for (table, servers) in tables.iter() {
+    for (i, server) in servers.iter().enumerate() {
+      check_server_socket(table, i, server, c);
+    }
+  }
+
+  // Rendezvous and STUN traffic goes out from the first unicast UDP listener
+  if !config.unicast_udp_sockets().is_empty() {
+    return;
+  }
+  let missing_listener = "but there is no unicast udp_sockets_to_listen_on entry to talk to them from";
+  if config.rendezvous_serve {
+    c.top_level("rendezvous_serve", "rendezvous_serve is true but there is no unicast udp_sockets_to_listen_on entry to serve from".to_string());
+  }
+  if !config.rendezvous_servers.is_empty() {
+    c.error("rendezvous_servers", 0, "socket", format!("rendezvous_servers are listed {}", missing_listener));
+  }
+  if !config.stun_servers.is_empty() {
+    c.error("stun_servers", 0, "socket", format!("stun_servers are listed {}", missing_listener));
+  }
+}
+
+fn check_server_socket(table: &str, i: usize, server: &ConfSocket, c: &mut Checker) {
+  let socket = server.socket;
+  if socket.ip().is_multicast() || socket.ip().is_unspecified() {
+    c.error(table, i, "socket", format!("{} is not an address a server can be reached at", socket));
+  }
+  else if socket.port() == 0 {
+    c.error(table, i, "socket", format!("{} needs a port", socket));
+  }
+  if server.forward {
+    c.error(table, i, "forward", format!("forward only applies to listeners, not {}", table));
+  }
+}
+
+fn check_ip_ranges(config: &Config, c: &mut Checker) {
+  let ranges = &config.ip_ranges_to_scan;
+  for (i, range) in ranges.iter().enumerate() {
+    if let Some(other) = ranges[..i].iter().find(|r| r.cidr.overlaps(&range.cidr)) {
+      c.error("ip_ranges_to_scan", i, "cidr", format!("{} overlaps {}, which is already scanned", range.cidr, other.cidr));
+    }
+    if range.port == 0 {
+      c.error("ip_ranges_to_scan", i, "port", "port must be more than 0".to_string());
+    }
+  }
+}
+
+fn check_message_handlers(config: &Config, c: &mut Checker) {
+  let names: Vec<&str> = (1..=255).filter_map(MessageType::from_u8).map(|t| t.name()).collect();
+  for (i, handler) in config.message_handlers.iter().enumerate() {
+    if handler.command.first().map(|p| p.is_empty()).unwrap_or(true) {
+      c.error("message_handlers", i, "command", "command must start with the program to run".to_string());
+    }
+    if !names.contains(&handler.message_type.as_str()) {
+      c.error("message_handlers", i, "message_type", format!("unknown message_type '{}', expected one of {}", handler.message_type, names.join(", ")));
+    }
+    else if handler.channel.is_some() && handler.message_type != MessageType::Data.name() {
+      c.error("message_handlers", i, "channel", format!("only data messages have a channel, not {} messages", handler.message_type));
+    }
+    if handler.min_trust == TrustLevel::Blocked {
+      c.error("message_handlers", i, "min_trust", "messages from blocked peers are dropped, min_trust must be unknown, seen or verified".to_string());
+    }
+  }
+}
+
+fn check_pkcs11_identity(config: &Config, c: &mut Checker) {
+  let conf = match &config.pkcs11_identity {
+    Some(conf) => conf,
+    None => return,
+  };
+  if conf.key_label.is_none() && conf.key_id.is_none() {
+    c.error("pkcs11_identity", 0, "key_label", "pkcs11_identity needs a key_label or key_id to pick the identity key".to_string());
+  }
+  if let Some(hex) = &conf.key_id {
+    if util::from_hex(hex).is_none() {
+      c.error("pkcs11_identity", 0, "key_id", format!("key_id '{}' is not hex", hex));
+    }
+  }
+}
+
+/**
+ * Keys serde would silently ignore, most likely typos or settings of
+ * another meili version. They are only warned about, as the config
+ * still works without them.
+ */
+pub fn unknown_keys(contents: &str, file: &Path, locator: &Locator) -> Vec<ConfigError> {
+  let value: toml::Value = match contents.parse() {
+    Ok(value) => value,
+    Err(_) => return Vec::new(),
+  };
+  let top_level = match value.as_table() {
+    Some(top_level) => top_level,
+    None => return Vec::new(),
+  };
+  let mut warnings = Vec::new();
+  let top_level_fields = fields_of::<Config>();
+  let mut check = |table: &toml::value::Table, name: &str, index: usize, fields: &[&str]| {
+    for key in table.keys().filter(|k| !fields.contains(&k.as_str())) {
+      let message = match util::closest_match(key, fields) {
+        _ if !name.is_empty() && top_level_fields.contains(&key.as_str()) => {
+          format!("'{}' is a top level setting but is read as part of a {} table here, move it above the first table", key, name)
+        }
+        Some(known) => format!("unknown key '{}' is ignored, did you mean '{}'?", key, known),
+        None => format!("unknown key '{}' is ignored", key),
+      };
+      // Tables are found by their header rather than a key
+      let at = locator.find(name, index, key).or_else(|| locator.find(key, 0, ""));
+      warnings.push(ConfigError::new(file.display(), at, message));
+    }
+  };
+  check(top_level, "", 0, top_level_fields);
+  for (name, entry) in top_level {
+    let fields = match table_fields(name) {
+      Some(fields) => fields,
+      None => continue,
+    };
+    match entry {
+      toml::Value::Array(entries) => {
+        for (i, table) in entries.iter().enumerate().filter_map(|(i, e)| e.as_table().map(|t| (i, t))) {
+          check(table, name, i, fields);
+        }
+      }
+      toml::Value::Table(table) => check(table, name, 0, fields),
+      _ => {}
+    }
+  }
+  warnings.sort_by_key(|w| (w.line, w.col));
+  warnings
+}
+
+pub fn table_fields(name: &str) -> Option<&'static [&'static str]> {
+  match name {
+    "udp_sockets_to_listen_on" | "tcp_sockets_to_listen_on" | "http_mailboxes" | "rendezvous_servers" | "stun_servers" => {
+      Some(fields_of::<ConfSocket>())
+    }
+    "ip_ranges_to_scan" => Some(fields_of::<IPRange>()),
+    "message_handlers" => Some(fields_of::<MessageHandler>()),
+    "pkcs11_identity" => Some(fields_of::<Pkcs11Identity>()),
+    _ => None,
+  }
+}
+
+/**
+ * Asks a struct's derived Deserialize for its field names, so the
+ * known keys never drift from the structs they are read into.
+ */
+pub fn fields_of<T: DeserializeOwned>() -> &'static [&'static str] {
+  match T::deserialize(FieldNames) {
+    Err(FieldNamesFound(fields)) => fields,
+    Ok(_) => &[],
+  }
+}
+
+/**
+ * A Deserializer which stops at the first struct, handing back its field names as the error.
+ */
+struct FieldNames;
+
+#[derive(Debug)]
+struct FieldNamesFound(&'static [&'static str]);
+
+impl fmt::Display for FieldNamesFound {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "fields {:?}", self.0)
+  }
+}
+
+impl std::error::Error for FieldNamesFound {}
+
+impl de::Error for FieldNamesFound {
+  fn custom<T: fmt::Display>(_msg: T) -> Self {
+    FieldNamesFound(&[])
+  }
+}
+
+impl<'de> Deserializer<'de> for FieldNames {
+  type Error = FieldNamesFound;
+
+  fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, FieldNamesFound> {
+    Err(FieldNamesFound(&[]))
+  }
+
+  fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], _visitor: V) -> Result<V::Value, FieldNamesFound> {
+    Err(FieldNamesFound(fields))
+  }
+
+  forward_to_deserialize_any! {
+    bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+    bytes byte_buf option unit unit_struct newtype_struct seq tuple
+    tuple_struct map enum identifier ignored_any
+  }
+}
@@ -0,0 +1,131 @@
+
+/*!
+ * meili.toml is read again whenever it changes on disk or the reload
+ * shell command asks, and a config which parses and passes config_check
+ * replaces the running one in Global. Each thread applies it the next
+ * time it wakes: the listener loop binds and closes listeners, the
+ * scanner picks up new ranges and rates and the UPnP manager redoes
+ * port mappings whose settings changed. Sessions and peers are kept.
+ * An edit with problems is reported and the running config stays.
+ * A file from an older meili is read as upgraded but not rewritten
+ * while it may be open in an editor, meili upgrades it when it next starts.
+ */
+
+use std::thread;
+use std::sync::Arc;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::config::{self, Config};
+use crate::config_check::ConfigError;
+use crate::global::Global;
+
+// How often meili.toml's modification time is checked
+const WATCH_INTERVAL_MS: u64 = 1000;
+
+pub fn spawn_config_watcher(global: Arc<Global>) {
+  thread::spawn(move || {
+    run_config_watcher(&global);
+  });
+}
+
+fn run_config_watcher(global: &Global) {
+  let file = global.get_config().file();
+  let system_file = PathBuf::from(config::SYSTEM_CONFIG_FILE);
+  let mut last_modified = (modified(&file), modified(&system_file));
+  loop {
+    thread::sleep(Duration::from_millis(WATCH_INTERVAL_MS));
+    let modified = (modified(&file), modified(&system_file));
+    if modified == last_modified {
+      continue;
+    }
+    last_modified = modified;
+    // Some editors save by removing the file and writing a new one,
+    // the site-wide file may come and go
+    if modified.0.is_none() {
+      continue;
+    }
+    match reload_config(global) {
+      Ok(changed) if changed.is_empty() => {}
+      result => {
+        for line in describe_reload(&file, &result) {
+          println!("{}", line);
+        }
+      }
+    }
+  }
+}
+
+fn modified(file: &Path) -> Option<SystemTime> {
+  fs::metadata(file).and_then(|m| m.modified()).ok()
+}
+
+/**
+ * Reads every layer of the config again, with the same --set overrides,
+ * and publishes it when it checks out, returning the top level keys which
+ * changed. On errors the running config is kept.
+ */
+pub fn reload_config(global: &Global) -> Result<Vec<&'static str>, Vec<ConfigError>> {
+  let old = global.get_config();
+  let new = config::read_config(&old.file(), &old.overrides, false)?;
+  let changed = changed_keys(&old, &new);
+  if !changed.is_empty() {
+    global.set_config(Arc::new(new));
+  }
+  Ok(changed)
+}
+
+fn changed_keys(old: &Config, new: &Config) -> Vec<&'static str> {
+  let mut changed = Vec::new();
+  macro_rules! compare {
+    ($($key:ident),*) => {
+      // Naming every field makes a setting added to Config but not here a compile error
+      let Config { app_dir: _, overrides: _, sources: _, config_version: _, $($key: _),* } = new;
+      $(
+        if old.$key != new.$key {
+          changed.push(stringify!($key));
+        }
+      )*
+    };
+  }
+  compare!(
+    hostname, poll_delay_ns,
+    attempt_upnp_port_forward, upnp_gw_timeout_ms, upnp_pref_public_port, upnp_local_port,
+    upnp_lease_duration_s, port_mapping_gateway,
+    ip_range_scan_seed, ip_ranges_to_scan,
+    udp_sockets_to_listen_on, lan_announce_interval_ms, lan_announce_ttl,
+    session_rekey_interval, session_idle_timeout,
+    rendezvous_serve, rendezvous_register_interval_ms, hole_punch_timeout_ms,
+    stun_interval, stun_timeout_ms,
+    tcp_sockets_to_listen_on, http_mailboxes, rendezvous_servers, stun_servers,
+    message_handlers, pkcs11_identity
+  );
+  changed
+}
+
+/**
+ * What to tell the user about a reload of file.
+ */
+pub fn describe_reload(file: &Path, result: &Result<Vec<&'static str>, Vec<ConfigError>>) -> Vec<String> {
+  let mut lines = Vec::new();
+  match result {
+    Ok(changed) if changed.is_empty() => {
+      lines.push(format!("{} has no changes to apply", file.display()));
+    }
+    Ok(changed) => {
+      lines.push(format!("Reloaded {}, changed {}", file.display(), changed.join(", ")));
+      if changed.contains(&"pkcs11_identity") {
+        lines.push("The identity key is loaded once, pkcs11_identity changes take effect when meili restarts".to_string());
+      }
+    }
+    Err(errors) => {
+      lines.extend(errors.iter().map(|e| e.to_string()));
+      lines.push(format!("{} has {} problem(s), keeping the running config", file.display(), errors.len()));
+    }
+  }
+  if let Some(version) = config::pending_upgrade(file) {
+    lines.push(format!("{} is config_version {}, meili upgrades it to {} when it next starts", file.display(), version, config::CONFIG_VERSION));
+  }
+  lines
+}
@@ -5,22 +5,141 @@
  * of meili.
  */
 
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
+use std::net::SocketAddr;
+
+use crate::config::Config;
+use crate::identity::{self, Identity, PUBLIC_KEY_LEN};
+use crate::peers::{PeerRegistry, KnownPeer, TrustLevel};
+use crate::net::scan_history::RangeCoverage;
+use crate::net::transport::PeerAddr;
+use crate::net::packet::Packet;
+use crate::net::session::{SessionTable, SessionInfo};
+use crate::net::upnp::UpnpMapping;
+use crate::net::rendezvous::{RendezvousTable, FailedPunch};
+use crate::net::stun::{StunProbe, StunResult};
+
+// Peers discovered this run which are kept, the least recently seen making way
+const MAX_DISCOVERED_PEERS: usize = 1024;
 
 #[derive(Debug)]
 pub struct Global {
+  pub identity: Identity,
+  // The meili.toml in effect, replaced whenever it is reloaded
+  pub config: Mutex<Arc<Config>>,
   pub scan_ips_in_background: Mutex<bool>,
+  pub rejected_packets: Mutex<HashMap<&'static str, usize>>,
+  pub discovered_peers: Mutex<HashMap<[u8; PUBLIC_KEY_LEN], DiscoveredPeer>>,
+  pub peer_registry: Mutex<PeerRegistry>,
+  pub sessions: Mutex<SessionTable>,
+  pub scan_coverage: Mutex<Vec<RangeCoverage>>,
+  // Packets produced off the listener thread, eg by external message handlers
+  pub outbox: Mutex<VecDeque<(PeerAddr, Packet)>>,
+  // Data packets written out to each address, see Transport::send_counted
+  pub flushed_data: Mutex<HashMap<PeerAddr, usize>>,
+  pub handler_failures: Mutex<HashMap<String, usize>>,
+  // External message handlers running now, see net::external
+  pub running_handlers: Mutex<usize>,
+  // Set by net::run_listeners so other threads can interrupt its poll
+  pub listener_waker: Mutex<Option<Arc<mio::Waker>>>,
+  // The port mappings net::upnp keeps alive, one per forwarded listener
+  pub upnp_mappings: Mutex<Vec<UpnpMapping>>,
+  // Set once meili starts exiting, after which no new mappings are kept
+  pub upnp_torn_down: Mutex<bool>,
+  // Whether the net::upnp manager is running, and whether it was asked to set up mappings now
+  pub upnp_manager_running: Mutex<bool>,
+  pub upnp_setup_requested: Mutex<bool>,
+  // Where peers outside the LAN can reach us, if we know
+  pub public_address: Mutex<Option<PublicAddress>>,
+  // Rendezvous clients we serve and the hole punches we are attempting
+  pub rendezvous: Mutex<RendezvousTable>,
+  // Running STUN probes and the transport each sends from
+  pub stun_probes: Mutex<Vec<(usize, StunProbe)>>,
+  // The latest STUN probe from each unicast UDP listener
+  pub stun_results: Mutex<Vec<StunResult>>,
+}
+
+/**
+ * A meili node we have heard from since startup,
+ * eg by receiving a reply to a scanner hello packet
+ * or hearing its LAN announcement.
+ */
+#[derive(Debug, Clone)]
+pub struct DiscoveredPeer {
+  pub public_key: [u8; PUBLIC_KEY_LEN],
+  pub addr: PeerAddr,
+  // Only known once the peer has announced itself
+  pub hostname: Option<String>,
+  // Where the peer says it can be reached from outside its LAN
+  pub public_addr: Option<SocketAddr>,
+  pub source: &'static str,
+  pub last_seen: SystemTime,
 }
 
-impl Default for Global {
-  fn default() -> Self {
+impl DiscoveredPeer {
+  pub fn fingerprint(&self) -> String {
+    identity::fingerprint_of(&self.public_key)
+  }
+}
+
+/**
+ * Our own address as seen from outside the LAN and what told us about it,
+ * eg "upnp" for the external address and port of a UPnP port mapping.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicAddress {
+  pub addr: SocketAddr,
+  pub source: &'static str,
+  pub since: SystemTime,
+}
+
+impl Global {
+  pub fn new(identity: Identity, peer_registry: PeerRegistry, config: Arc<Config>) -> Self {
+    let sessions = SessionTable::new(identity.public_key());
     Global {
+      identity,
+      config: Mutex::new(config),
       scan_ips_in_background: Mutex::new(false),
+      rejected_packets: Mutex::new(HashMap::new()),
+      discovered_peers: Mutex::new(HashMap::new()),
+      peer_registry: Mutex::new(peer_registry),
+      sessions: Mutex::new(sessions),
+      scan_coverage: Mutex::new(Vec::new()),
+      outbox: Mutex::new(VecDeque::new()),
+      flushed_data: Mutex::new(HashMap::new()),
+      handler_failures: Mutex::new(HashMap::new()),
+      running_handlers: Mutex::new(0),
+      listener_waker: Mutex::new(None),
+      upnp_mappings: Mutex::new(Vec::new()),
+      upnp_torn_down: Mutex::new(false),
+      upnp_manager_running: Mutex::new(false),
+      upnp_setup_requested: Mutex::new(false),
+      public_address: Mutex::new(None),
+      rendezvous: Mutex::new(RendezvousTable::default()),
+      stun_probes: Mutex::new(Vec::new()),
+      stun_results: Mutex::new(Vec::new()),
     }
   }
-}
 
-impl Global {
+  pub fn get_config(&self) -> Arc<Config> {
+    if let Ok(config) = self.config.lock() {
+      return config.clone();
+    }
+    Arc::new(Config::default())
+  }
+  /**
+   * Publishes a reloaded config. Threads compare what they hold against
+   * get_config when they wake, so the listeners are woken to notice now.
+   */
+  pub fn set_config(&self, val: Arc<Config>) {
+    if let Ok(mut config) = self.config.lock() {
+      *config = val;
+    }
+    self.wake_listeners();
+  }
+
   pub fn set_scan_ips_in_background(&self, val: bool) {
     if let Ok(mut scan_ips_in_background) = self.scan_ips_in_background.lock() {
       *scan_ips_in_background = val;
@@ -32,4 +151,526 @@ impl Global {
     }
     return false;
   }
+
+  pub fn count_rejected_packet(&self, reason: &'static str) {
+    if let Ok(mut rejected_packets) = self.rejected_packets.lock() {
+      *rejected_packets.entry(reason).or_insert(0) += 1;
+    }
+  }
+  pub fn get_rejected_packets(&self) -> HashMap<&'static str, usize> {
+    if let Ok(rejected_packets) = self.rejected_packets.lock() {
+      return rejected_packets.clone();
+    }
+    HashMap::new()
+  }
+
+  pub fn record_discovered_peer(&self, public_key: [u8; PUBLIC_KEY_LEN], addr: PeerAddr, source: &'static str, hostname: Option<String>) {
+    if public_key == self.identity.public_key() {
+      return; // We discovered ourselves
+    }
+    if let Ok(mut peer_registry) = self.peer_registry.lock() {
+      peer_registry.record_seen(public_key, &addr);
+    }
+    if self.is_peer_blocked(&public_key) {
+      return;
+    }
+    let is_new_and_full = match self.discovered_peers.lock() {
+      Ok(discovered_peers) => !discovered_peers.contains_key(&public_key) && discovered_peers.len() >= MAX_DISCOVERED_PEERS,
+      Err(_) => false,
+    };
+    if is_new_and_full {
+      self.forget_oldest_discovered_peer();
+    }
+    if let Ok(mut discovered_peers) = self.discovered_peers.lock() {
+      let (known_hostname, known_public_addr) = match discovered_peers.get(&public_key) {
+        Some(peer) => (peer.hostname.clone(), peer.public_addr),
+        None => {
+          println!("Discovered peer {} at {} via {}", identity::fingerprint_of(&public_key), addr, source);
+          (None, None)
+        }
+      };
+      discovered_peers.insert(public_key, DiscoveredPeer {
+        public_key,
+        addr,
+        hostname: hostname.or(known_hostname),
+        public_addr: known_public_addr,
+        source,
+        last_seen: SystemTime::now(),
+      });
+    }
+  }
+  /**
+   * Makes way for a newly discovered peer, keeping Verified ones.
+   * The registry is read first as set_peer_trust locks it before discovered_peers.
+   */
+  fn forget_oldest_discovered_peer(&self) {
+    let verified: Vec<[u8; PUBLIC_KEY_LEN]> = self.get_known_peers().iter()
+      .filter(|p| p.trust == TrustLevel::Verified)
+      .map(|p| p.public_key)
+      .collect();
+    if let Ok(mut discovered_peers) = self.discovered_peers.lock() {
+      let oldest = discovered_peers.values()
+        .filter(|p| !verified.contains(&p.public_key))
+        .min_by_key(|p| p.last_seen)
+        .map(|p| p.public_key);
+      if let Some(oldest) = oldest {
+        discovered_peers.remove(&oldest);
+      }
+    }
+  }
+  /**
+   * Records the public address a peer advertised, or that it advertised none.
+   */
+  pub fn set_peer_public_addr(&self, public_key: &[u8; PUBLIC_KEY_LEN], public_addr: Option<SocketAddr>) {
+    if let Ok(mut discovered_peers) = self.discovered_peers.lock() {
+      if let Some(peer) = discovered_peers.get_mut(public_key) {
+        peer.public_addr = public_addr;
+      }
+    }
+    if let Some(public_addr) = public_addr {
+      if let Ok(mut peer_registry) = self.peer_registry.lock() {
+        peer_registry.set_public_addr(public_key, public_addr);
+      }
+    }
+  }
+  pub fn get_discovered_peers(&self) -> Vec<DiscoveredPeer> {
+    if let Ok(discovered_peers) = self.discovered_peers.lock() {
+      return discovered_peers.values().cloned().collect();
+    }
+    Vec::new()
+  }
+
+  pub fn save_peers_if_due(&self) {
+    if let Ok(mut peer_registry) = self.peer_registry.lock() {
+      peer_registry.save_if_due();
+    }
+  }
+  /**
+   * Saves what was heard since the last save_peers_if_due, before exiting.
+   */
+  pub fn save_peers(&self) {
+    if let Ok(mut peer_registry) = self.peer_registry.lock() {
+      peer_registry.save();
+    }
+  }
+
+  pub fn get_known_peers(&self) -> Vec<KnownPeer> {
+    if let Ok(peer_registry) = self.peer_registry.lock() {
+      return peer_registry.list();
+    }
+    Vec::new()
+  }
+  pub fn get_known_peer(&self, public_key: &[u8; PUBLIC_KEY_LEN]) -> Option<KnownPeer> {
+    if let Ok(peer_registry) = self.peer_registry.lock() {
+      return peer_registry.get(public_key).cloned();
+    }
+    None
+  }
+  pub fn find_known_peer(&self, query: &str) -> Result<[u8; PUBLIC_KEY_LEN], String> {
+    match self.peer_registry.lock() {
+      Ok(peer_registry) => peer_registry.find(query),
+      Err(e) => Err(format!("{}", e)),
+    }
+  }
+  pub fn is_peer_blocked(&self, public_key: &[u8; PUBLIC_KEY_LEN]) -> bool {
+    if let Ok(peer_registry) = self.peer_registry.lock() {
+      return peer_registry.is_blocked(public_key);
+    }
+    false
+  }
+  pub fn set_peer_nickname(&self, public_key: &[u8; PUBLIC_KEY_LEN], nickname: Option<String>) {
+    if let Ok(mut peer_registry) = self.peer_registry.lock() {
+      peer_registry.set_nickname(public_key, nickname);
+    }
+  }
+  pub fn set_peer_trust(&self, public_key: &[u8; PUBLIC_KEY_LEN], trust: TrustLevel) {
+    if let Ok(mut peer_registry) = self.peer_registry.lock() {
+      peer_registry.set_trust(public_key, trust);
+    }
+    if trust == TrustLevel::Blocked {
+      if let Ok(mut discovered_peers) = self.discovered_peers.lock() {
+        discovered_peers.remove(public_key);
+      }
+    }
+  }
+  /**
+   * The trust of a peer, Unknown for peers not in the registry.
+   */
+  pub fn get_peer_trust(&self, public_key: &[u8; PUBLIC_KEY_LEN]) -> TrustLevel {
+    if let Ok(peer_registry) = self.peer_registry.lock() {
+      if let Some(peer) = peer_registry.get(public_key) {
+        return peer.trust;
+      }
+    }
+    TrustLevel::Unknown
+  }
+  pub fn confirm_peer_verification(&self, public_key: &[u8; PUBLIC_KEY_LEN], by_us: bool) -> Option<TrustLevel> {
+    if let Ok(mut peer_registry) = self.peer_registry.lock() {
+      return peer_registry.confirm_verification(public_key, by_us);
+    }
+    None
+  }
+  pub fn set_peer_notes(&self, public_key: &[u8; PUBLIC_KEY_LEN], notes: String) {
+    if let Ok(mut peer_registry) = self.peer_registry.lock() {
+      peer_registry.set_notes(public_key, notes);
+    }
+  }
+
+  /**
+   * See SessionTable::seal_outgoing
+   */
+  pub fn seal_outgoing(&self, peer: &PeerAddr, packet: Packet) -> Option<Packet> {
+    if let Ok(mut sessions) = self.sessions.lock() {
+      return sessions.seal_outgoing(peer, packet);
+    }
+    None
+  }
+  pub fn seal_in_session(&self, session_id: u64, packet: &Packet) -> Option<Packet> {
+    if let Ok(mut sessions) = self.sessions.lock() {
+      return sessions.seal(session_id, packet);
+    }
+    None
+  }
+  pub fn open_sealed(&self, sealed: &Packet, from: &PeerAddr) -> Result<(Packet, u64), &'static str> {
+    match self.sessions.lock() {
+      Ok(mut sessions) => sessions.open(sealed, from),
+      Err(_) => Err("sessions_unavailable"),
+    }
+  }
+  pub fn accept_session(&self, init: &Packet, from: &PeerAddr) -> Result<Packet, &'static str> {
+    match self.sessions.lock() {
+      Ok(mut sessions) => sessions.accept(init, from),
+      Err(_) => Err("sessions_unavailable"),
+    }
+  }
+  pub fn complete_session(&self, accept: &Packet, from: &PeerAddr) -> Result<Vec<Packet>, &'static str> {
+    match self.sessions.lock() {
+      Ok(mut sessions) => sessions.complete(accept, from),
+      Err(_) => Err("sessions_unavailable"),
+    }
+  }
+  pub fn maintain_sessions(&self, rekey_interval: Duration, idle_timeout: Duration) -> Vec<(PeerAddr, Packet)> {
+    if let Ok(mut sessions) = self.sessions.lock() {
+      return sessions.maintain(rekey_interval, idle_timeout);
+    }
+    Vec::new()
+  }
+  pub fn get_sessions(&self) -> Vec<SessionInfo> {
+    if let Ok(sessions) = self.sessions.lock() {
+      return sessions.list();
+    }
+    Vec::new()
+  }
+
+  pub fn set_scan_coverage(&self, val: Vec<RangeCoverage>) {
+    if let Ok(mut scan_coverage) = self.scan_coverage.lock() {
+      *scan_coverage = val;
+    }
+  }
+  pub fn get_scan_coverage(&self) -> Vec<RangeCoverage> {
+    if let Ok(scan_coverage) = self.scan_coverage.lock() {
+      return scan_coverage.clone();
+    }
+    Vec::new()
+  }
+
+  pub fn queue_outgoing(&self, peer: PeerAddr, packet: Packet) {
+    if let Ok(mut outbox) = self.outbox.lock() {
+      outbox.push_back((peer, packet));
+    }
+    self.wake_listeners();
+  }
+  pub fn take_outgoing(&self) -> Vec<(PeerAddr, Packet)> {
+    if let Ok(mut outbox) = self.outbox.lock() {
+      return outbox.drain(..).collect();
+    }
+    Vec::new()
+  }
+  pub fn count_flushed_data(&self, peer: &PeerAddr) {
+    if let Ok(mut flushed_data) = self.flushed_data.lock() {
+      *flushed_data.entry(peer.clone()).or_insert(0) += 1;
+    }
+  }
+  pub fn get_flushed_data(&self, peer: &PeerAddr) -> usize {
+    if let Ok(flushed_data) = self.flushed_data.lock() {
+      return flushed_data.get(peer).copied().unwrap_or(0);
+    }
+    0
+  }
+
+  pub fn count_handler_failure(&self, handler_name: &str) {
+    if let Ok(mut handler_failures) = self.handler_failures.lock() {
+      *handler_failures.entry(handler_name.to_string()).or_insert(0) += 1;
+    }
+  }
+  pub fn get_handler_failures(&self) -> HashMap<String, usize> {
+    if let Ok(handler_failures) = self.handler_failures.lock() {
+      return handler_failures.clone();
+    }
+    HashMap::new()
+  }
+
+  /**
+   * Counts a handler as running, unless max already are.
+   */
+  pub fn try_start_handler(&self, max: usize) -> bool {
+    if let Ok(mut running_handlers) = self.running_handlers.lock() {
+      if *running_handlers < max {
+        *running_handlers += 1;
+        return true;
+      }
+    }
+    false
+  }
+  pub fn finish_handler(&self) {
+    if let Ok(mut running_handlers) = self.running_handlers.lock() {
+      *running_handlers = running_handlers.saturating_sub(1);
+    }
+  }
+
+  pub fn set_listener_waker(&self, val: Arc<mio::Waker>) {
+    if let Ok(mut listener_waker) = self.listener_waker.lock() {
+      *listener_waker = Some(val);
+    }
+  }
+  pub fn wake_listeners(&self) {
+    if let Ok(listener_waker) = self.listener_waker.lock() {
+      if let Some(waker) = listener_waker.as_ref() {
+        if let Err(e) = waker.wake() {
+          println!("Could not wake listeners e={:?}", e);
+        }
+      }
+    }
+  }
+
+  pub fn get_upnp_mappings(&self) -> Vec<UpnpMapping> {
+    if let Ok(upnp_mappings) = self.upnp_mappings.lock() {
+      return upnp_mappings.clone();
+    }
+    Vec::new()
+  }
+  /**
+   * Returns false (and keeps nothing) once tear_down_upnp has been called.
+   */
+  pub fn set_upnp_mappings(&self, val: Vec<UpnpMapping>) -> bool {
+    if let Ok(upnp_torn_down) = self.upnp_torn_down.lock() {
+      if *upnp_torn_down {
+        return false;
+      }
+      if let Ok(mut upnp_mappings) = self.upnp_mappings.lock() {
+        *upnp_mappings = val;
+      }
+    }
+    true
+  }
+  pub fn is_upnp_torn_down(&self) -> bool {
+    if let Ok(upnp_torn_down) = self.upnp_torn_down.lock() {
+      return *upnp_torn_down;
+    }
+    false
+  }
+  pub fn set_upnp_manager_running(&self, val: bool) {
+    if let Ok(mut upnp_manager_running) = self.upnp_manager_running.lock() {
+      *upnp_manager_running = val;
+    }
+  }
+  /**
+   * Asks the manager to set up missing mappings, or renew the rest, without
+   * waiting for its next retry. Returns false when no manager is running.
+   */
+  pub fn request_upnp_setup(&self) -> bool {
+    if let Ok(upnp_manager_running) = self.upnp_manager_running.lock() {
+      if !*upnp_manager_running {
+        return false;
+      }
+    }
+    if let Ok(mut upnp_setup_requested) = self.upnp_setup_requested.lock() {
+      *upnp_setup_requested = true;
+    }
+    true
+  }
+  pub fn take_upnp_setup_request(&self) -> bool {
+    if let Ok(mut upnp_setup_requested) = self.upnp_setup_requested.lock() {
+      return std::mem::replace(&mut *upnp_setup_requested, false);
+    }
+    false
+  }
+  /**
+   * Stops any further mappings being kept and hands back the current ones for removal.
+   */
+  pub fn tear_down_upnp(&self) -> Vec<UpnpMapping> {
+    if let Ok(mut upnp_torn_down) = self.upnp_torn_down.lock() {
+      *upnp_torn_down = true;
+      if let Ok(mut upnp_mappings) = self.upnp_mappings.lock() {
+        return std::mem::take(&mut *upnp_mappings);
+      }
+    }
+    Vec::new()
+  }
+
+  pub fn get_public_address(&self) -> Option<PublicAddress> {
+    if let Ok(public_address) = self.public_address.lock() {
+      return public_address.clone();
+    }
+    None
+  }
+  /**
+   * Sets our public address, keeping `since` if only the source was repeated.
+   */
+  pub fn set_public_address(&self, addr: Option<SocketAddr>, source: &'static str) {
+    if let Ok(mut public_address) = self.public_address.lock() {
+      let current = public_address.as_ref().map(|p| (p.addr, p.source));
+      if current == addr.map(|a| (a, source)) {
+        return;
+      }
+      match addr {
+        Some(addr) => println!("Public address is now {} (via {})", addr, source),
+        None => println!("No longer know our public address"),
+      }
+      *public_address = addr.map(|addr| PublicAddress {
+        addr,
+        source,
+        since: SystemTime::now(),
+      });
+    }
+  }
+
+  /**
+   * Forgets our public address if one of sources gave it, leaving one
+   * learnt elsewhere (eg over STUN) in place.
+   */
+  pub fn clear_public_address(&self, sources: &[&str]) {
+    if let Some(current) = self.get_public_address() {
+      if sources.contains(&current.source) {
+        self.set_public_address(None, current.source);
+      }
+    }
+  }
+
+  /**
+   * Like set_public_address, for sources which only guess at our address
+   * and so must not replace one another source (eg a port mapping) gave.
+   */
+  pub fn offer_public_address(&self, addr: Option<SocketAddr>, source: &'static str) {
+    if let Some(current) = self.get_public_address() {
+      if current.source != source {
+        return;
+      }
+    }
+    self.set_public_address(addr, source);
+  }
+
+  pub fn set_stun_probes(&self, val: Vec<(usize, StunProbe)>) {
+    if let Ok(mut stun_probes) = self.stun_probes.lock() {
+      *stun_probes = val;
+    }
+  }
+  /**
+   * Passes a STUN message to the probe it answers, returning false when none was waiting for it.
+   */
+  pub fn handle_stun_message(&self, buf: &[u8]) -> bool {
+    if let Ok(mut stun_probes) = self.stun_probes.lock() {
+      return stun_probes.iter_mut().any(|(_, probe)| probe.handle(buf));
+    }
+    false
+  }
+  /**
+   * The requests due now, with the transport to send each from.
+   */
+  pub fn poll_stun_probes(&self, now: Instant) -> Vec<(usize, SocketAddr, Vec<u8>)> {
+    let mut requests = Vec::new();
+    if let Ok(mut stun_probes) = self.stun_probes.lock() {
+      for (i, probe) in stun_probes.iter_mut() {
+        if let Some((server, request)) = probe.poll(now) {
+          requests.push((*i, server, request));
+        }
+      }
+    }
+    requests
+  }
+  /**
+   * Removes the probes which have finished, returning their results.
+   */
+  pub fn take_finished_stun_probes(&self) -> Vec<(usize, StunResult)> {
+    let mut results = Vec::new();
+    if let Ok(mut stun_probes) = self.stun_probes.lock() {
+      stun_probes.retain(|(i, probe)| match probe.result() {
+        Some(result) => {
+          results.push((*i, result));
+          false
+        }
+        None => true,
+      });
+    }
+    results
+  }
+  pub fn get_next_stun_wakeup(&self) -> Option<Instant> {
+    if let Ok(stun_probes) = self.stun_probes.lock() {
+      return stun_probes.iter().filter_map(|(_, probe)| probe.next_wakeup()).min();
+    }
+    None
+  }
+  pub fn set_stun_result(&self, val: StunResult) {
+    if let Ok(mut stun_results) = self.stun_results.lock() {
+      stun_results.retain(|r| r.listener != val.listener);
+      stun_results.push(val);
+      stun_results.sort_by_key(|r| r.listener);
+    }
+  }
+  pub fn get_stun_results(&self) -> Vec<StunResult> {
+    if let Ok(stun_results) = self.stun_results.lock() {
+      return stun_results.clone();
+    }
+    Vec::new()
+  }
+
+  pub fn register_rendezvous_client(&self, public_key: [u8; PUBLIC_KEY_LEN], addr: SocketAddr) {
+    if let Ok(mut rendezvous) = self.rendezvous.lock() {
+      rendezvous.register(public_key, addr);
+    }
+  }
+  pub fn get_rendezvous_client_addr(&self, public_key: &[u8; PUBLIC_KEY_LEN]) -> Option<SocketAddr> {
+    if let Ok(rendezvous) = self.rendezvous.lock() {
+      return rendezvous.registered_addr(public_key);
+    }
+    None
+  }
+  pub fn set_rendezvous_seen_as(&self, server: SocketAddr, addr: SocketAddr) -> bool {
+    if let Ok(mut rendezvous) = self.rendezvous.lock() {
+      return rendezvous.set_seen_as(server, addr);
+    }
+    false
+  }
+  pub fn get_rendezvous_seen_as(&self) -> Vec<(SocketAddr, SocketAddr)> {
+    if let Ok(rendezvous) = self.rendezvous.lock() {
+      return rendezvous.seen_as();
+    }
+    Vec::new()
+  }
+  pub fn start_punch(&self, peer: [u8; PUBLIC_KEY_LEN], addr: SocketAddr, server: SocketAddr) {
+    if let Ok(mut rendezvous) = self.rendezvous.lock() {
+      rendezvous.start_punch(peer, addr, server);
+    }
+  }
+  pub fn complete_punch(&self, peer: &[u8; PUBLIC_KEY_LEN]) -> bool {
+    if let Ok(mut rendezvous) = self.rendezvous.lock() {
+      return rendezvous.complete_punch(peer);
+    }
+    false
+  }
+  /**
+   * See RendezvousTable::maintain
+   */
+  pub fn maintain_punches(&self, timeout: Duration) -> (Vec<(SocketAddr, SocketAddr)>, Vec<FailedPunch>) {
+    if let Ok(mut rendezvous) = self.rendezvous.lock() {
+      return rendezvous.maintain(timeout);
+    }
+    (Vec::new(), Vec::new())
+  }
+  pub fn get_next_punch(&self) -> Option<Instant> {
+    if let Ok(rendezvous) = self.rendezvous.lock() {
+      return rendezvous.next_punch();
+    }
+    None
+  }
 }
@@ -9,18 +9,24 @@ use shrust::{Shell, ShellIO, ExecError};
 use crossbeam;
 
 use std::io::prelude::*;
+use std::path::Path;
 
 use crate::punwrap_r;
-use crate::config::Config;
+use crate::config_reload;
 use crate::global::Global;
-use crate::net;
+use crate::peers::TrustLevel;
+use crate::pkcs11;
+use crate::identity;
+use crate::util;
+use crate::net::packet::{Packet, MessageType};
+use crate::net::transport::PeerAddr;
 
-pub fn open_cli(args: &Vec<String>, config: &Config, global: &Global) {
-  let mut shell = create_shell(args, config, global);
+pub fn open_cli(args: &Vec<String>, global: &Global) {
+  let mut shell = create_shell(args, global);
   shell.run_loop(&mut ShellIO::default());
 }
 
-pub fn start_tcp_cli(args: &Vec<String>, config: &Config, global: &Global) {
+pub fn start_tcp_cli(args: &Vec<String>, global: &Global) {
   use std::net::{TcpListener};
 
   let serv = TcpListener::bind("[::]:1339").expect("Cannot open socket");
@@ -32,7 +38,7 @@ pub fn start_tcp_cli(args: &Vec<String>, config: &Config, global: &Global) {
         Ok((mut sock, addr)) => {
           let is_localhost = addr.ip().is_loopback();
           if is_localhost {
-            let mut shell = create_shell(args, config, global);
+            let mut shell = create_shell(args, global);
             let mut io = ShellIO::new_io(sock);
             s.spawn(move |_| {
               shell.run_loop(&mut io);
@@ -55,31 +61,255 @@ pub fn start_tcp_cli(args: &Vec<String>, config: &Config, global: &Global) {
 /**
  * This creates a shell which may be presented over any IO device.
  */
-pub fn create_shell<'a>(args: &'a Vec<String>, config: &'a Config, global: &'a Global) -> shrust::Shell<(&'a Vec<String>, &'a Config, &'a Global)> {
-  let mut shell = Shell::new((args, config, global));
+pub fn create_shell<'a>(args: &'a Vec<String>, global: &'a Global) -> shrust::Shell<(&'a Vec<String>, &'a Global)> {
+  let mut shell = Shell::new((args, global));
 
   shell.new_command("status", "Get the status of network comms and local settings", 0, |io, shell_data, cmd_args| {
-      let (args, config, global) = shell_data;
+      let (args, global) = shell_data;
+      let config = global.get_config();
+      writeln!(io, "identity={}", global.identity.fingerprint())?;
+      writeln!(io, "public_address={}", super::format_public_address(global))?;
+      for result in global.get_stun_results() {
+        writeln!(io, "stun {}", super::format_stun_result(&result))?;
+      }
+      for (server, addr) in global.get_rendezvous_seen_as() {
+        writeln!(io, "rendezvous {} sees us as {}", server, addr)?;
+      }
       writeln!(io, "cmd_args={:?}", &cmd_args)?;
       writeln!(io, "args={:?}", &args)?;
       writeln!(io, "config={:#?}", &config)?;
       writeln!(io, "global={:#?}", &global)?;
+      for (reason, count) in global.get_rejected_packets() {
+        writeln!(io, "rejected packets ({})={}", reason, count)?;
+      }
+      for (handler_name, count) in global.get_handler_failures() {
+        writeln!(io, "message handler failures ({})={}", handler_name, count)?;
+      }
+      let known_peers = global.get_known_peers();
+      writeln!(io, "{} known peers", known_peers.len())?;
+      for peer in known_peers {
+        writeln!(io, "{}", super::format_known_peer(&peer))?;
+      }
       Ok(())
   });
 
-  shell.new_command("setup-upnp", "Detect the UPNP gateway and ask it to forward ports", 0, |io, shell_data, cmd_args| {
-      let (args, config, global) = shell_data;
-      net::attempt_upnp_setup(args, config, global);
+  shell.new_command("setup-upnp", "Detect the router (UPnP IGD, NAT-PMP or PCP) and ask it to forward ports", 0, |io, shell_data, cmd_args| {
+      let (_args, global) = shell_data;
+      if !global.get_config().attempt_upnp_port_forward {
+        writeln!(io, "attempt_upnp_port_forward is off in meili.toml")?;
+      }
+      else if global.request_upnp_setup() {
+        writeln!(io, "Asked the router for port mappings, `status` shows the public address they give")?;
+      }
+      else {
+        writeln!(io, "Port mappings are only kept while the listeners run, which they do not in this mode")?;
+      }
       Ok(())
   });
 
   shell.new_command("scan-ips", "[start|stop] Start/stop the background scanning IP addresses.", 0, |io, shell_data, cmd_args| {
-      let (args, config, global) = shell_data;
+      let (args, global) = shell_data;
       let arg0: &str = cmd_args.get(0).unwrap_or(&"");
       global.set_scan_ips_in_background(!arg0.contains("stop"));
       Ok(())
   });
 
+  shell.new_command_noargs("scan-coverage", "Show how much of each ip_ranges_to_scan entry has been probed", |io, shell_data| {
+      let (_args, global) = shell_data;
+      for c in global.get_scan_coverage() {
+        writeln!(io, "{} ({}): {} of {} probed, {} within rescan_age, {} answered",
+          c.name, c.cidr, c.probed, c.size, c.fresh, c.answered
+        )?;
+      }
+      Ok(())
+  });
+
+  shell.new_command_noargs("peers", "List meili nodes discovered since startup", |io, shell_data| {
+      let (_args, global) = shell_data;
+      let peers = global.get_discovered_peers();
+      writeln!(io, "{} peers", peers.len())?;
+      for peer in peers {
+        let flag = match global.get_peer_trust(&peer.public_key) {
+          TrustLevel::Verified => "",
+          _ => " (unverified)",
+        };
+        writeln!(io, "{}{}", super::format_peer(&peer), flag)?;
+      }
+      Ok(())
+  });
+
+  shell.new_command("peer", "<fingerprint|nickname> [nickname <name>|trust <unknown|seen|verified|blocked>|notes <text>] Show or edit a known peer", 1, |io, shell_data, cmd_args| {
+      let (_args, global) = shell_data;
+      let public_key = match global.find_known_peer(cmd_args[0]) {
+        Ok(public_key) => public_key,
+        Err(e) => {
+          writeln!(io, "{}", e)?;
+          return Ok(());
+        }
+      };
+      let value = cmd_args[2.min(cmd_args.len())..].join(" ");
+      match cmd_args.get(1).copied() {
+        None => {}
+        Some("nickname") => {
+          global.set_peer_nickname(&public_key, if value.is_empty() { None } else { Some(value) });
+        }
+        Some("trust") => {
+          match TrustLevel::from_name(&value) {
+            Some(trust) => global.set_peer_trust(&public_key, trust),
+            None => {
+              writeln!(io, "Unknown trust level '{}'", value)?;
+              return Ok(());
+            }
+          }
+        }
+        Some("notes") => {
+          global.set_peer_notes(&public_key, value);
+        }
+        Some(other) => {
+          writeln!(io, "Unknown peer field '{}'", other)?;
+          return Ok(());
+        }
+      }
+
+      if let Some(peer) = global.get_known_peer(&public_key) {
+        writeln!(io, "{}", super::format_known_peer(&peer))?;
+        if !peer.notes.is_empty() {
+          writeln!(io, "notes: {}", peer.notes)?;
+        }
+        for a in &peer.addresses {
+          writeln!(io, "  {}://{} first seen {} last seen {}",
+            a.transport, a.socket, super::format_unix_s(a.first_seen), super::format_unix_s(a.last_seen)
+          )?;
+        }
+      }
+      Ok(())
+  });
+
+  shell.new_command("verify", "<fingerprint|nickname> [confirm|reject] Show the code to compare with a peer, confirm once both sides show the same code, reject to block a peer showing another code", 1, |io, shell_data, cmd_args| {
+      let (_args, global) = shell_data;
+      let public_key = match global.find_known_peer(cmd_args[0]) {
+        Ok(public_key) => public_key,
+        Err(e) => {
+          writeln!(io, "{}", e)?;
+          return Ok(());
+        }
+      };
+      match cmd_args.get(1).copied() {
+        None => {}
+        Some("confirm") => {
+          if let Some(note) = super::confirm_verification(global, &public_key) {
+            writeln!(io, "{}", note)?;
+          }
+        }
+        Some("reject") => {
+          super::reject_verification(global, &public_key);
+        }
+        Some(other) => {
+          writeln!(io, "Unknown verify action '{}'", other)?;
+          return Ok(());
+        }
+      }
+      if let Some(peer) = global.get_known_peer(&public_key) {
+        writeln!(io, "{}", super::format_verification(global, &peer))?;
+      }
+      Ok(())
+  });
+
+  shell.new_command("punch", "<fingerprint|nickname|public key hex> Ask our rendezvous servers to introduce us to a peer so we can punch a direct path to it", 1, |io, shell_data, cmd_args| {
+      let (_args, global) = shell_data;
+      let config = global.get_config();
+      let public_key = match global.find_known_peer(cmd_args[0]) {
+        Ok(public_key) => public_key,
+        Err(e) => match util::from_hex(cmd_args[0]) {
+          Some(bytes) if bytes.len() == identity::PUBLIC_KEY_LEN => {
+            let mut public_key = [0u8; identity::PUBLIC_KEY_LEN];
+            public_key.copy_from_slice(&bytes);
+            public_key
+          }
+          _ => {
+            writeln!(io, "{}", e)?;
+            return Ok(());
+          }
+        }
+      };
+      if config.rendezvous_servers.is_empty() {
+        writeln!(io, "No [[rendezvous_servers]] are configured")?;
+        return Ok(());
+      }
+      for server in &config.rendezvous_servers {
+        global.queue_outgoing(PeerAddr::Udp(server.socket), Packet::new(MessageType::IntroduceRequest, public_key.to_vec()));
+        writeln!(io, "Asked {} to introduce us to {}", server.socket, identity::fingerprint_of(&public_key))?;
+      }
+      Ok(())
+  });
+
+  shell.new_command_noargs("sessions", "List encrypted sessions with peers", |io, shell_data| {
+      let (_args, global) = shell_data;
+      let sessions = global.get_sessions();
+      writeln!(io, "{} sessions", sessions.len())?;
+      for s in sessions {
+        writeln!(io, "{:016x} {} at {} started by {}, {}s old, idle {}s{}",
+          s.id, s.peer_fingerprint, s.addr, if s.initiator { "us" } else { "peer" },
+          s.age.as_secs(), s.idle.as_secs(), if s.current { "" } else { " (replaced)" }
+        )?;
+      }
+      Ok(())
+  });
+
+  shell.new_command("pkcs11-slots", "[module] List the slots of a PKCS#11 module (default [pkcs11_identity] module) and the keys on their tokens", 0, |io, shell_data, cmd_args| {
+      let (_args, global) = shell_data;
+      let config = global.get_config();
+      let pin = config.pkcs11_identity.as_ref().and_then(|p| p.user_pin());
+      // Re-use the identity's module so listing does not finalize it under the identity key
+      let identity_module = config.pkcs11_identity.as_ref().map(|p| p.module.as_path());
+      let context = match (cmd_args.first(), global.identity.token_key()) {
+        (None, Some(token_key)) => Ok(token_key.context().clone()),
+        (Some(module), Some(token_key)) if Some(Path::new(module)) == identity_module => Ok(token_key.context().clone()),
+        (Some(module), _) => pkcs11::open_module(Path::new(module)),
+        (None, None) => match &config.pkcs11_identity {
+          Some(p) => pkcs11::open_module(&p.module),
+          None => Err("No [pkcs11_identity] configured, pass the path of a PKCS#11 module".to_string()),
+        },
+      };
+      let context = match context {
+        Ok(context) => context,
+        Err(e) => {
+          writeln!(io, "{}", e)?;
+          return Ok(());
+        }
+      };
+      let slots = match pkcs11::list_slots(&context, pin) {
+        Ok(slots) => slots,
+        Err(e) => {
+          writeln!(io, "{}", e)?;
+          return Ok(());
+        }
+      };
+      writeln!(io, "{} slots", slots.len())?;
+      for slot in slots {
+        match &slot.token_label {
+          Some(label) => writeln!(io, "slot {} ({}) token '{}'", slot.slot_id, slot.description, label)?,
+          None => writeln!(io, "slot {} ({}) no token", slot.slot_id, slot.description)?,
+        }
+        for key in slot.keys {
+          writeln!(io, "  handle {} {} {} label '{}' id {}", key.handle, key.class, key.key_type, key.label, key.id)?;
+        }
+        if let Some(e) = slot.error {
+          writeln!(io, "  {}", e)?;
+        }
+      }
+      Ok(())
+  });
+
+  shell.new_command_noargs("reload", "Read meili.toml again and apply its changes, keeping the running config if it has problems", |io, shell_data| {
+      let (_args, global) = shell_data;
+      let result = config_reload::reload_config(global);
+      for line in config_reload::describe_reload(&global.get_config().file(), &result) {
+        writeln!(io, "{}", line)?;
+      }
+      Ok(())
+  });
+
   shell.new_command_noargs("quit", "Exit the meili process", |_, _shell_data| {
     Err(ExecError::Quit)
   });
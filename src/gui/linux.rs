@@ -1,13 +1,14 @@
 
 use glib;
 use gtk::{
-    self, MenuShellExt, GtkMenuItemExt, WidgetExt
+    self, MenuShellExt, GtkMenuItemExt, WidgetExt, DialogExt, GtkWindowExt
 };
 use libappindicator::{AppIndicator, AppIndicatorStatus};
 use std::{
     self,
     cell::RefCell,
     collections::HashMap,
+    sync::Arc,
     sync::mpsc::{channel, Sender, Receiver},
     thread,
     error,
@@ -19,10 +20,10 @@ use tempfile;
 //use crossbeam;
 
 use crate::punwrap_r;
-use crate::config::Config;
 use crate::global::Global;
+use crate::net::upnp;
 
-pub fn open_gui(args: &Vec<String>, config: &Config, global: &Global) {
+pub fn open_gui(args: &Vec<String>, global: &Arc<Global>) {
   let icon_tmp = tempfile::Builder::new()
                     .suffix(".png")
                     .rand_bytes(5)
@@ -36,7 +37,7 @@ pub fn open_gui(args: &Vec<String>, config: &Config, global: &Global) {
 
     punwrap_r!(app.set_icon_from_file( &icon_tmp.path().to_string_lossy() ));
 
-    let hostname_s = format!("h: {}", config.hostname);
+    let hostname_s = format!("h: {}", global.get_config().hostname);
     app.add_menu_item(&hostname_s, |_| {
         
         // TODO real menu items
@@ -45,8 +46,27 @@ pub fn open_gui(args: &Vec<String>, config: &Config, global: &Global) {
         Ok::<_, Error>(())
     }).unwrap();
 
-    app.add_menu_item("dis_i=first", |_| -> Result<(), Error> {
-        println!("Printing dis_i=first");
+    let peers_global = global.clone();
+    app.add_menu_item("peers: 0", move |_| -> Result<(), Error> {
+        print_peers(&peers_global);
+        Ok(())
+    }).unwrap();
+
+    let known_peers_global = global.clone();
+    app.add_menu_item("known peers: 0", move |_| -> Result<(), Error> {
+        print_known_peers(&known_peers_global);
+        Ok(())
+    }).unwrap();
+
+    let verify_global = global.clone();
+    app.add_menu_item("verify peers", move |_| -> Result<(), Error> {
+        super::verify_peers(&verify_global, ask_yes_no, show_message);
+        Ok(())
+    }).unwrap();
+
+    let public_global = global.clone();
+    app.add_menu_item("public address: unknown", move |_| -> Result<(), Error> {
+        println!("public_address={}", super::format_public_address(&public_global));
         Ok(())
     }).unwrap();
 
@@ -54,25 +74,40 @@ pub fn open_gui(args: &Vec<String>, config: &Config, global: &Global) {
     // This is seriously unsafe but graphics is always like that.
     let app_ptr = &mut app as *mut _;
     let app_ptr_i: usize = unsafe { std::mem::transmute(app_ptr) };
+    let peers_global = global.clone();
     thread::spawn(move || {
         let app_ptr: *mut _ = unsafe { std::mem::transmute(app_ptr_i) };
         let app: &mut Application = unsafe { &mut *app_ptr };
-        let mut i = 0;
         loop {
             std::thread::sleep( std::time::Duration::from_millis(800) );
-            let dis_i = i;
-            app.set_menu_item(1, &format!("dis_i={}", dis_i), move |_| {
-                
-                // TODO real menu items
-                println!("Printing a dis_i={}", dis_i);
-          
+            let num_peers = peers_global.get_discovered_peers().len();
+            let click_global = peers_global.clone();
+            app.set_menu_item(1, &format!("peers: {}", num_peers), move |_| {
+                print_peers(&click_global);
+                Ok::<_, Error>(())
+            }).unwrap();
+            let num_known_peers = peers_global.get_known_peers().len();
+            let click_global = peers_global.clone();
+            app.set_menu_item(2, &format!("known peers: {}", num_known_peers), move |_| {
+                print_known_peers(&click_global);
+                Ok::<_, Error>(())
+            }).unwrap();
+            let public_address = match peers_global.get_public_address() {
+                Some(p) => p.addr.to_string(),
+                None => "unknown".to_string(),
+            };
+            let click_global = peers_global.clone();
+            app.set_menu_item(4, &format!("public address: {}", public_address), move |_| {
+                println!("public_address={}", super::format_public_address(&click_global));
                 Ok::<_, Error>(())
             }).unwrap();
-            i += 1;
         }
     });
 
-    app.add_menu_item("quit", |_| -> Result<(), Error> {
+    let quit_global = global.clone();
+    app.add_menu_item("quit", move |_| -> Result<(), Error> {
+        upnp::remove_upnp_mappings(&quit_global);
+        quit_global.save_peers();
         std::process::exit(0)
     }).unwrap();
 
@@ -83,6 +118,61 @@ pub fn open_gui(args: &Vec<String>, config: &Config, global: &Global) {
   }
 }
 
+fn print_peers(global: &Global) {
+  for peer in global.get_discovered_peers() {
+    println!("{}", super::format_peer(&peer));
+  }
+}
+
+fn print_known_peers(global: &Global) {
+  for peer in global.get_known_peers() {
+    println!("{}", super::format_known_peer(&peer));
+  }
+}
+
+/**
+ * Asks a question in a dialog on the gtk thread, None when it is dismissed.
+ */
+fn ask_yes_no(question: &str) -> Option<bool> {
+  let (tx, rx) = channel();
+  let question = question.to_string();
+  run_on_gtk_thread(move |_| {
+    let dialog = gtk::MessageDialog::new(
+      None::<&gtk::Window>, gtk::DialogFlags::MODAL, gtk::MessageType::Question, gtk::ButtonsType::None, &question
+    );
+    dialog.set_title("meili");
+    dialog.add_button("Later", gtk::ResponseType::Cancel);
+    dialog.add_button("Reject", gtk::ResponseType::No);
+    dialog.add_button("Confirm", gtk::ResponseType::Yes);
+    let response = dialog.run();
+    dialog.destroy();
+    punwrap_r!(tx.send(response));
+  });
+  match rx.recv() {
+    Ok(gtk::ResponseType::Yes) => Some(true),
+    Ok(gtk::ResponseType::No) => Some(false),
+    _ => None,
+  }
+}
+
+/**
+ * Shows a message in a dialog on the gtk thread and waits until it is closed.
+ */
+fn show_message(message: &str) {
+  let (tx, rx) = channel();
+  let message = message.to_string();
+  run_on_gtk_thread(move |_| {
+    let dialog = gtk::MessageDialog::new(
+      None::<&gtk::Window>, gtk::DialogFlags::MODAL, gtk::MessageType::Info, gtk::ButtonsType::Ok, &message
+    );
+    dialog.set_title("meili");
+    dialog.run();
+    dialog.destroy();
+    punwrap_r!(tx.send(()));
+  });
+  rx.recv().ok();
+}
+
 /*
  * Everything below is mostly a copy/paste from systray-rs,
  * but as new needs are added (update menu text, add icon from &[u8], etc.)
@@ -1,6 +1,7 @@
 
 use std;
 
+use std::sync::Arc;
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::{
     collections::HashMap,
@@ -30,13 +31,13 @@ use objc_foundation;
 use cocoa::foundation::{NSAutoreleasePool, NSString};
 use objc_foundation::{INSObject, NSObject};
 
-use crate::config::Config;
 use crate::global::Global;
+use crate::net::upnp;
 
-pub fn open_gui(args: &Vec<String>, config: &Config, global: &Global) {
+pub fn open_gui(args: &Vec<String>, global: &Arc<Global>) {
   if let Ok(mut app) = Application::new() {
 
-    let hostname_s = format!("h: {}", config.hostname);
+    let hostname_s = format!("h: {}", global.get_config().hostname);
     app.add_menu_item(&hostname_s, |_| {
         
         // TODO real menu items
@@ -45,7 +46,38 @@ pub fn open_gui(args: &Vec<String>, config: &Config, global: &Global) {
         Ok::<_, Error>(())
     }).unwrap();
    
-    app.add_menu_item("quit", |_| -> Result<(), Error> {
+    let peers_global = global.clone();
+    app.add_menu_item("peers", move |_| -> Result<(), Error> {
+        for peer in peers_global.get_discovered_peers() {
+          println!("{}", super::format_peer(&peer));
+        }
+        Ok(())
+    }).unwrap();
+
+    let known_peers_global = global.clone();
+    app.add_menu_item("known peers", move |_| -> Result<(), Error> {
+        for peer in known_peers_global.get_known_peers() {
+          println!("{}", super::format_known_peer(&peer));
+        }
+        Ok(())
+    }).unwrap();
+
+    let verify_global = global.clone();
+    app.add_menu_item("verify peers", move |_| -> Result<(), Error> {
+        super::verify_peers(&verify_global, ask_yes_no, show_message);
+        Ok(())
+    }).unwrap();
+
+    let public_global = global.clone();
+    app.add_menu_item("public address", move |_| -> Result<(), Error> {
+        println!("public_address={}", super::format_public_address(&public_global));
+        Ok(())
+    }).unwrap();
+
+    let quit_global = global.clone();
+    app.add_menu_item("quit", move |_| -> Result<(), Error> {
+        upnp::remove_upnp_mappings(&quit_global);
+        quit_global.save_peers();
         std::process::exit(0)
     }).unwrap();
 
@@ -57,6 +89,73 @@ pub fn open_gui(args: &Vec<String>, config: &Config, global: &Global) {
 }
 
 
+// CFUserNotification alerts may be shown from any thread, unlike NSAlert
+// which wants the main thread that NSApp is not running on here.
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    fn CFUserNotificationDisplayAlert(
+        timeout: f64,
+        flags: libc::c_ulong,
+        icon_url: *const c_void,
+        sound_url: *const c_void,
+        localization_url: *const c_void,
+        alert_header: *const c_void,
+        alert_message: *const c_void,
+        default_button_title: *const c_void,
+        alternate_button_title: *const c_void,
+        other_button_title: *const c_void,
+        response_flags: *mut libc::c_ulong,
+    ) -> i32;
+}
+
+const CF_USER_NOTIFICATION_DEFAULT_RESPONSE: libc::c_ulong = 0;
+const CF_USER_NOTIFICATION_ALTERNATE_RESPONSE: libc::c_ulong = 1;
+
+// An NSString is also a CFStringRef. Callers drain an autorelease pool around its use.
+fn cf_string(s: &str) -> *const c_void {
+    unsafe { NSString::alloc(nil).init_str(s).autorelease() as *const c_void }
+}
+
+/**
+ * Asks a question in an alert, None when it is dismissed.
+ */
+fn ask_yes_no(question: &str) -> Option<bool> {
+    let mut response: libc::c_ulong = 0;
+    let err = unsafe {
+        let pool = NSAutoreleasePool::new(nil);
+        let err = CFUserNotificationDisplayAlert(
+            0.0, 0, std::ptr::null(), std::ptr::null(), std::ptr::null(),
+            cf_string("meili"), cf_string(question),
+            cf_string("Confirm"), cf_string("Reject"), cf_string("Later"),
+            &mut response,
+        );
+        pool.drain();
+        err
+    };
+    if err != 0 {
+        return None;
+    }
+    match response & 0x3 {
+        CF_USER_NOTIFICATION_DEFAULT_RESPONSE => Some(true),
+        CF_USER_NOTIFICATION_ALTERNATE_RESPONSE => Some(false),
+        _ => None,
+    }
+}
+
+fn show_message(message: &str) {
+    let mut response: libc::c_ulong = 0;
+    unsafe {
+        let pool = NSAutoreleasePool::new(nil);
+        CFUserNotificationDisplayAlert(
+            0.0, 0, std::ptr::null(), std::ptr::null(), std::ptr::null(),
+            cf_string("meili"), cf_string(message),
+            std::ptr::null(), std::ptr::null(), std::ptr::null(),
+            &mut response,
+        );
+        pool.drain();
+    }
+}
+
 // This allows us to send *mut Object pointers to threads
 struct ObjCObjectWrapper(*mut objc::runtime::Object);
 impl Default for ObjCObjectWrapper {
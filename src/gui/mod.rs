@@ -9,9 +9,13 @@
  */
 
 use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
 
-use crate::config::Config;
-use crate::global::Global;
+use crate::global::{Global, DiscoveredPeer};
+use crate::identity::{self, PUBLIC_KEY_LEN};
+use crate::peers::{KnownPeer, TrustLevel};
+use crate::net::packet::{Packet, MessageType};
+use crate::net::stun::StunResult;
 
 #[allow(dead_code, unused_variables)]
 const ICON_PNG: &'static [u8] = include_bytes!("../../res/icon.png");
@@ -35,25 +39,221 @@ mod linux;
 
 mod cli;
 
-pub fn open_gui(args: Arc<Vec<String>>, config: Arc<Config>, global: Arc<Global>) {
+pub fn open_gui(args: Arc<Vec<String>>, global: Arc<Global>) {
   // TODO spawn a thread to perform bg tasks using global
 
   #[cfg(target_os = "linux")]
-  linux::open_gui(&args, &config, &global);
+  linux::open_gui(&args, &global);
   
   #[cfg(target_os = "windows")]
-  win::open_gui(&args, &config, &global);
+  win::open_gui(&args, &global);
 
   #[cfg(target_os = "macos")]
-  macos::open_gui(&args, &config, &global);
+  macos::open_gui(&args, &global);
 }
 
+/**
+ * One line of text describing a peer, shared by the shell and tray menus.
+ */
+pub fn format_peer(peer: &DiscoveredPeer) -> String {
+  let hostname = match &peer.hostname {
+    Some(hostname) => format!(" ({})", hostname),
+    None => String::new(),
+  };
+  let public_addr = match &peer.public_addr {
+    Some(public_addr) => format!(" public {}", public_addr),
+    None => String::new(),
+  };
+  format!("{}{} {}{} via {} last seen {}",
+    peer.fingerprint(), hostname, peer.addr, public_addr, peer.source, humantime::format_rfc3339_seconds(peer.last_seen)
+  )
+}
+
+/**
+ * One line of text describing a peer registry entry.
+ */
+pub fn format_known_peer(peer: &KnownPeer) -> String {
+  let nickname = match &peer.nickname {
+    Some(nickname) => format!(" \"{}\"", nickname),
+    None => String::new(),
+  };
+  let last_seen = match peer.last_address() {
+    Some(a) => format!(" last seen {} at {}://{}", format_unix_s(a.last_seen), a.transport, a.socket),
+    None => " never seen".to_string(),
+  };
+  let public_addr = match &peer.public_addr {
+    Some(public_addr) => format!(" public {}", public_addr),
+    None => String::new(),
+  };
+  format!("{}{} trust={}{}{}", peer.fingerprint(), nickname, peer.trust, last_seen, public_addr)
+}
+
+/**
+ * The short authentication string for a peer and what to do with it.
+ */
+pub fn format_verification(global: &Global, peer: &KnownPeer) -> String {
+  let code = identity::short_auth_string(&global.identity.public_key(), &peer.public_key);
+  let name = peer.nickname.clone().unwrap_or_else(|| peer.fingerprint());
+  let state = match (peer.trust, peer.verify_confirmed_by_us, peer.verify_confirmed_by_peer) {
+    (TrustLevel::Verified, _, _) => "verified".to_string(),
+    (TrustLevel::Blocked, _, _) => "blocked".to_string(),
+    (_, true, false) => "waiting for the peer to confirm".to_string(),
+    (_, false, true) => format!("the peer confirmed, run `verify {} confirm` if the codes match", name),
+    _ => format!("if {} shows the same code run `verify {} confirm`", name, name),
+  };
+  format!("{} code {}: {}", name, code, state)
+}
 
-pub fn open_cli(args: Arc<Vec<String>>, config: Arc<Config>, global: Arc<Global>) {
-  cli::open_cli(&args, &config, &global);
+/**
+ * Records that the peer shows the same code as we do and tells it so,
+ * which is `verify <peer> confirm` in the shell and the tray's confirm.
+ * Returns a note for the user when the peer could not be told yet.
+ */
+pub fn confirm_verification(global: &Global, public_key: &[u8; PUBLIC_KEY_LEN]) -> Option<String> {
+  let addr = global.get_known_peer(public_key)
+    .and_then(|p| p.last_address().and_then(|a| a.to_peer_addr(public_key)));
+  let note = match addr {
+    Some(addr) => {
+      let digest = identity::verification_digest(&global.identity.public_key(), public_key);
+      global.queue_outgoing(addr, Packet::new(MessageType::VerifyConfirm, digest.to_vec()));
+      None
+    }
+    None => Some("No address is known for the peer, confirm again once it has been seen so it learns of the confirmation".to_string()),
+  };
+  global.confirm_peer_verification(public_key, true);
+  note
 }
 
-pub fn start_tcp_cli(args: Arc<Vec<String>>, config: Arc<Config>, global: Arc<Global>) {
-  cli::start_tcp_cli(&args, &config, &global);
+/**
+ * Records that the peer shows a different code, which is `verify <peer> reject`
+ * in the shell and the tray's reject. Someone is between us and the peer or
+ * is not the peer at all, so it is blocked.
+ */
+pub fn reject_verification(global: &Global, public_key: &[u8; PUBLIC_KEY_LEN]) {
+  global.set_peer_trust(public_key, TrustLevel::Blocked);
 }
 
+/**
+ * Walks the user through each peer left to verify for the trays,
+ * skipping those we confirmed already.
+ * ask shows a question and returns Some(true) when the codes match,
+ * Some(false) when they differ and None to stop; tell shows a message.
+ */
+pub fn verify_peers<A, T>(global: &Global, ask: A, tell: T)
+where
+  A: Fn(&str) -> Option<bool>,
+  T: Fn(&str),
+{
+  let peers: Vec<KnownPeer> = global.get_known_peers().into_iter()
+    .filter(|p| p.trust != TrustLevel::Verified && p.trust != TrustLevel::Blocked && !p.verify_confirmed_by_us)
+    .collect();
+  if peers.is_empty() {
+    tell("No peer is left to verify, the rest are verified, blocked or waiting for the peer to confirm");
+    return;
+  }
+  for peer in peers {
+    let code = identity::short_auth_string(&global.identity.public_key(), &peer.public_key);
+    let name = peer.nickname.clone().unwrap_or_else(|| peer.fingerprint());
+    let confirmed = if peer.verify_confirmed_by_peer { format!("\n\n{} has already confirmed.", name) } else { String::new() };
+    let question = format!("Does {} show the code {}?\n\nConfirm if it does. Reject if it shows another code, which blocks the peer.{}",
+      name, code, confirmed
+    );
+    match ask(&question) {
+      Some(true) => {
+        if let Some(note) = confirm_verification(global, &peer.public_key) {
+          tell(&note);
+        }
+      }
+      Some(false) => reject_verification(global, &peer.public_key),
+      None => return,
+    }
+    if let Some(peer) = global.get_known_peer(&peer.public_key) {
+      tell(&format_verification(global, &peer));
+    }
+  }
+}
+
+/**
+ * Our public address for the status command and tray, eg "198.51.100.7:1337 via upnp".
+ */
+pub fn format_public_address(global: &Global) -> String {
+  match global.get_public_address() {
+    Some(p) => format!("{} via {} since {}", p.addr, p.source, humantime::format_rfc3339_seconds(p.since)),
+    None => "unknown".to_string(),
+  }
+}
+
+/**
+ * What a STUN probe learned about one listener, eg
+ * "0.0.0.0:1337 seen as 198.51.100.7:40312 by 203.0.113.5:3478, NAT type port restricted".
+ */
+pub fn format_stun_result(result: &StunResult) -> String {
+  let seen_as = match (result.mapped, result.server) {
+    (Some(mapped), Some(server)) => format!("seen as {} by {}", mapped, server),
+    _ => "not answered by any STUN server".to_string(),
+  };
+  format!("{} {}, NAT type {}, checked {}",
+    result.listener, seen_as, result.nat_type, humantime::format_rfc3339_seconds(result.checked)
+  )
+}
+
+pub fn format_unix_s(secs: u64) -> String {
+  humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs)).to_string()
+}
+
+
+pub fn open_cli(args: Arc<Vec<String>>, global: Arc<Global>) {
+  cli::open_cli(&args, &global);
+}
+
+pub fn start_tcp_cli(args: Arc<Vec<String>>, global: Arc<Global>) {
+  cli::start_tcp_cli(&args, &global);
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::cell::RefCell;
+  use std::fs;
+  use std::net::SocketAddr;
+
+  use crate::config::Config;
+  use crate::identity::Identity;
+  use crate::peers::PeerRegistry;
+  use crate::net::transport::PeerAddr;
+
+  #[test]
+  fn the_tray_confirms_and_rejects_like_the_shell() {
+    let app_dir = std::env::temp_dir().join(format!("meili-gui-{}-{}", std::process::id(), "verify"));
+    fs::create_dir_all(&app_dir).unwrap();
+    let global = Global::new(Identity::generate(), PeerRegistry::load(&app_dir), Arc::new(Config::default()));
+    let addr: SocketAddr = "192.0.2.1:1337".parse().unwrap();
+    let alice = Identity::generate().public_key();
+    let mallory = Identity::generate().public_key();
+    global.record_discovered_peer(alice, PeerAddr::Udp(addr), "test", None);
+    global.record_discovered_peer(mallory, PeerAddr::Udp(addr), "test", None);
+    global.set_peer_nickname(&alice, Some("alice".to_string()));
+    global.set_peer_nickname(&mallory, Some("mallory".to_string()));
+
+    let told = RefCell::new(Vec::new());
+    verify_peers(&global, |question| Some(question.contains("alice")), |message| told.borrow_mut().push(message.to_string()));
+
+    let alice_peer = global.get_known_peer(&alice).unwrap();
+    assert!(alice_peer.verify_confirmed_by_us);
+    assert_eq!(alice_peer.trust, TrustLevel::Seen);
+    assert_eq!(global.get_peer_trust(&mallory), TrustLevel::Blocked);
+    let outgoing = global.take_outgoing();
+    assert_eq!(outgoing.len(), 1);
+    assert_eq!(outgoing[0].0, PeerAddr::Udp(addr));
+    assert_eq!(outgoing[0].1.msg_type, MessageType::VerifyConfirm);
+    assert_eq!(told.borrow().len(), 2);
+
+    // Nothing is left to ask about
+    verify_peers(&global, |_| panic!("asked about a peer already answered"), |message| told.borrow_mut().push(message.to_string()));
+    assert_eq!(told.borrow().last().unwrap(), "No peer is left to verify, the rest are verified, blocked or waiting for the peer to confirm");
+
+    fs::remove_dir_all(&app_dir).unwrap();
+  }
+}
@@ -5,6 +5,7 @@ use std;
 use std::cell::RefCell;
 use std::ffi::OsStr;
 use std::os::windows::ffi::OsStrExt;
+use std::sync::Arc;
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::{
     collections::HashMap,
@@ -38,15 +39,15 @@ use winapi::{
 use tempfile;
 
 
-use crate::config::Config;
 use crate::global::Global;
+use crate::net::upnp;
 
-pub fn open_gui(args: &Vec<String>, config: &Config, global: &Global) {
+pub fn open_gui(args: &Vec<String>, global: &Arc<Global>) {
   // When no arguments are presented
   // we instruct the OS to close our console. If the user runs the meili
-  // from a console it reads/writes to that console, and if they run it with "--gui"
+  // from a console it reads/writes to that console, and if they run it with "gui"
   // the console will remain open which is nice for debugging.
-  if ! args.contains(&"--gui".to_string()) {
+  if ! args.iter().skip(1).any(|a| a == "gui" || a == "--gui") {
     // This delay exists to show the console opening, then closing.
     // Environment variable must be assigned at build time to take effect.
     if let Some(val) = option_env!("MEILI_BUILD_ADD_DELAYS") {
@@ -81,7 +82,7 @@ pub fn open_gui(args: &Vec<String>, config: &Config, global: &Global) {
         println!("e = {:?}", e);
     }
 
-    let hostname_s = format!("h: {}", config.hostname);
+    let hostname_s = format!("h: {}", global.get_config().hostname);
     app.add_menu_item(&hostname_s, |_| {
         
         // TODO real menu items
@@ -90,7 +91,39 @@ pub fn open_gui(args: &Vec<String>, config: &Config, global: &Global) {
         Ok::<_, Error>(())
     }).unwrap();
    
-    app.add_menu_item("quit", |_| -> Result<(), Error> {
+    let peers_global = global.clone();
+    app.add_menu_item("peers", move |_| -> Result<(), Error> {
+        for peer in peers_global.get_discovered_peers() {
+          println!("{}", super::format_peer(&peer));
+        }
+        Ok(())
+    }).unwrap();
+
+    let known_peers_global = global.clone();
+    app.add_menu_item("known peers", move |_| -> Result<(), Error> {
+        for peer in known_peers_global.get_known_peers() {
+          println!("{}", super::format_known_peer(&peer));
+        }
+        Ok(())
+    }).unwrap();
+
+    let verify_global = global.clone();
+    app.add_menu_item("verify peers", move |_| -> Result<(), Error> {
+        // A message box rather than println, the console is gone unless run with gui
+        super::verify_peers(&verify_global, ask_yes_no, show_message);
+        Ok(())
+    }).unwrap();
+
+    let public_global = global.clone();
+    app.add_menu_item("public address", move |_| -> Result<(), Error> {
+        println!("public_address={}", super::format_public_address(&public_global));
+        Ok(())
+    }).unwrap();
+
+    let quit_global = global.clone();
+    app.add_menu_item("quit", move |_| -> Result<(), Error> {
+        upnp::remove_upnp_mappings(&quit_global);
+        quit_global.save_peers();
         std::process::exit(0)
     }).unwrap();
 
@@ -109,6 +142,37 @@ pub fn open_gui(args: &Vec<String>, config: &Config, global: &Global) {
 // doing SetLongPtr tho.
 thread_local!(static WININFO_STASH: RefCell<Option<WindowsLoopData>> = RefCell::new(None));
 
+/**
+ * Asks a question in a message box, None when it is dismissed.
+ */
+fn ask_yes_no(question: &str) -> Option<bool> {
+    let question = format!("{}\n\nYes confirms, No rejects, Cancel asks again later.", question);
+    let response = unsafe {
+        winuser::MessageBoxW(
+            std::ptr::null_mut(),
+            to_wstring(&question).as_ptr(),
+            to_wstring("meili").as_ptr(),
+            winuser::MB_YESNOCANCEL | winuser::MB_ICONQUESTION,
+        )
+    };
+    match response {
+        winuser::IDYES => Some(true),
+        winuser::IDNO => Some(false),
+        _ => None,
+    }
+}
+
+fn show_message(message: &str) {
+    unsafe {
+        winuser::MessageBoxW(
+            std::ptr::null_mut(),
+            to_wstring(message).as_ptr(),
+            to_wstring("meili").as_ptr(),
+            winuser::MB_OK | winuser::MB_ICONINFORMATION,
+        );
+    }
+}
+
 fn to_wstring(str: &str) -> Vec<u16> {
     OsStr::new(str)
         .encode_wide()
@@ -0,0 +1,240 @@
+
+/*!
+ * The identity of a meili node is an Ed25519 keypair
+ * stored next to meili.toml in the app_dir, or held by a PKCS#11
+ * token when [pkcs11_identity] is configured. The public key is the
+ * only thing peers should use to tell nodes apart; the hostname
+ * is merely a friendly label.
+ */
+
+use ed25519_dalek::{SigningKey, VerifyingKey, Signature, Signer, Verifier};
+use sha2::{Sha256, Digest};
+use rand::RngCore;
+
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::io::{self, Write};
+use std::fmt;
+
+use crate::config::Pkcs11Identity;
+use crate::pkcs11::TokenKey;
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+const SECRET_KEY_LEN: usize = 32;
+
+pub struct Identity {
+  public_key: [u8; PUBLIC_KEY_LEN],
+  key: IdentityKey,
+}
+
+enum IdentityKey {
+  File(SigningKey),
+  Token(TokenKey),
+}
+
+#[derive(Debug)]
+pub enum IdentityError {
+  Io(PathBuf, io::Error),
+  Corrupt(PathBuf, String),
+  WrongPermissions(PathBuf, u32),
+  Token(String),
+}
+
+impl fmt::Display for IdentityError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      IdentityError::Io(path, e) => write!(f, "Could not access identity key {}: {}", path.to_string_lossy(), e),
+      IdentityError::Corrupt(path, why) => write!(f, "Identity key {} is corrupt ({}). Move it aside to generate a new identity.", path.to_string_lossy(), why),
+      IdentityError::WrongPermissions(path, mode) => write!(f, "Identity key {} has permissions {:o}, it must only be readable by its owner (600).", path.to_string_lossy(), mode),
+      IdentityError::Token(why) => write!(f, "Could not use the PKCS#11 identity key: {}", why),
+    }
+  }
+}
+
+impl fmt::Debug for Identity {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    // Never print the secret half
+    write!(f, "Identity({})", self.fingerprint())
+  }
+}
+
+impl Identity {
+  /**
+   * Loads the identity key from app_dir, generating one if no key file exists.
+   * An existing key which cannot be read is an error; we never replace a key silently.
+   */
+  pub fn load_or_generate(app_dir: &Path) -> Result<Identity, IdentityError> {
+    let key_file = identity_key_file(app_dir);
+    if !key_file.as_path().exists() {
+      let identity = Identity::generate();
+      identity.write_to(&key_file)?;
+      println!("Generated new identity {}", identity.fingerprint());
+      return Ok(identity);
+    }
+    Identity::read_from(&key_file)
+  }
+
+  /**
+   * Uses the key described by [pkcs11_identity]. Unlike load_or_generate
+   * this never creates a key; keys are put on tokens with the token's own tools.
+   */
+  pub fn load_from_token(conf: &Pkcs11Identity) -> Result<Identity, IdentityError> {
+    let (token_key, public_key) = TokenKey::open(conf).map_err(IdentityError::Token)?;
+    Ok(Identity {
+      public_key,
+      key: IdentityKey::Token(token_key),
+    })
+  }
+
+  pub fn generate() -> Identity {
+    let mut secret = [0u8; SECRET_KEY_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut secret);
+    Identity::from_signing_key(SigningKey::from_bytes(&secret))
+  }
+
+  fn from_signing_key(signing_key: SigningKey) -> Identity {
+    Identity {
+      public_key: signing_key.verifying_key().to_bytes(),
+      key: IdentityKey::File(signing_key),
+    }
+  }
+
+  fn read_from(key_file: &Path) -> Result<Identity, IdentityError> {
+    check_permissions(key_file)?;
+    let bytes = fs::read(key_file).map_err(|e| IdentityError::Io(key_file.to_path_buf(), e))?;
+    if bytes.len() != SECRET_KEY_LEN {
+      return Err(IdentityError::Corrupt(key_file.to_path_buf(), format!("expected {} bytes, found {}", SECRET_KEY_LEN, bytes.len())));
+    }
+    let mut secret = [0u8; SECRET_KEY_LEN];
+    secret.copy_from_slice(&bytes);
+    Ok(Identity::from_signing_key(SigningKey::from_bytes(&secret)))
+  }
+
+  fn write_to(&self, key_file: &Path) -> Result<(), IdentityError> {
+    let signing_key = match &self.key {
+      IdentityKey::File(signing_key) => signing_key,
+      IdentityKey::Token(_) => return Ok(()),
+    };
+    // The key is created owner-only rather than restricted after writing,
+    // and create_new refuses to replace a key file which appeared meanwhile
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::OpenOptionsExt;
+      options.mode(0o600);
+    }
+    let mut file = options.open(key_file).map_err(|e| IdentityError::Io(key_file.to_path_buf(), e))?;
+    file.write_all(&signing_key.to_bytes()).map_err(|e| IdentityError::Io(key_file.to_path_buf(), e))?;
+    Ok(())
+  }
+
+  pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+    self.public_key
+  }
+
+  pub fn fingerprint(&self) -> String {
+    fingerprint_of(&self.public_key())
+  }
+
+  /**
+   * A token which stops answering (eg the card was pulled) produces
+   * an all-zero signature, which every peer rejects.
+   */
+  pub fn sign(&self, msg: &[u8]) -> [u8; SIGNATURE_LEN] {
+    match &self.key {
+      IdentityKey::File(signing_key) => signing_key.sign(msg).to_bytes(),
+      IdentityKey::Token(token_key) => {
+        match token_key.sign(msg) {
+          Ok(signature) => signature,
+          Err(e) => {
+            println!("PKCS#11 identity key could not sign: {}", e);
+            [0u8; SIGNATURE_LEN]
+          }
+        }
+      }
+    }
+  }
+
+  /**
+   * The token holding the key, for identities loaded with load_from_token.
+   */
+  pub fn token_key(&self) -> Option<&TokenKey> {
+    match &self.key {
+      IdentityKey::File(_) => None,
+      IdentityKey::Token(token_key) => Some(token_key),
+    }
+  }
+}
+
+pub fn identity_key_file(app_dir: &Path) -> PathBuf {
+  let mut f = app_dir.to_path_buf();
+  f.push("identity.key");
+  f
+}
+
+/**
+ * A fingerprint is the first 16 bytes of the SHA-256 of a public key
+ * written as colon-separated hex pairs, eg "ab12:cd34:...".
+ */
+pub fn fingerprint_of(public_key: &[u8; PUBLIC_KEY_LEN]) -> String {
+  let digest = Sha256::digest(public_key);
+  digest[..16].chunks(2)
+    .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+    .collect::<Vec<String>>()
+    .join(":")
+}
+
+/**
+ * A digest of two public keys which does not depend on their order,
+ * so both ends of a conversation compute the same value.
+ */
+pub fn verification_digest(a: &[u8; PUBLIC_KEY_LEN], b: &[u8; PUBLIC_KEY_LEN]) -> [u8; 32] {
+  let (lo, hi) = if a <= b { (a, b) } else { (b, a) };
+  let mut hasher = Sha256::new();
+  hasher.update(b"meili sas v1");
+  hasher.update(lo);
+  hasher.update(hi);
+  hasher.finalize().into()
+}
+
+/**
+ * The short authentication string users read to each other over a channel
+ * they already trust (in person, a phone call) to confirm nobody sits between
+ * their nodes, eg "042 913 007 558". 12 digits make grinding a key whose
+ * code collides with the real one impractical.
+ */
+pub fn short_auth_string(a: &[u8; PUBLIC_KEY_LEN], b: &[u8; PUBLIC_KEY_LEN]) -> String {
+  let digest = verification_digest(a, b);
+  let mut first = [0u8; 8];
+  first.copy_from_slice(&digest[..8]);
+  let code = format!("{:012}", u64::from_be_bytes(first) % 1_000_000_000_000);
+  code.as_bytes().chunks(3)
+    .map(|group| String::from_utf8_lossy(group).to_string())
+    .collect::<Vec<String>>()
+    .join(" ")
+}
+
+pub fn verify(public_key: &[u8; PUBLIC_KEY_LEN], msg: &[u8], signature: &[u8; SIGNATURE_LEN]) -> bool {
+  match VerifyingKey::from_bytes(public_key) {
+    Ok(key) => key.verify(msg, &Signature::from_bytes(signature)).is_ok(),
+    Err(_) => false,
+  }
+}
+
+#[cfg(unix)]
+fn check_permissions(key_file: &Path) -> Result<(), IdentityError> {
+  use std::os::unix::fs::PermissionsExt;
+  let metadata = fs::metadata(key_file).map_err(|e| IdentityError::Io(key_file.to_path_buf(), e))?;
+  let mode = metadata.permissions().mode() & 0o777;
+  if mode & 0o077 != 0 {
+    return Err(IdentityError::WrongPermissions(key_file.to_path_buf(), mode));
+  }
+  Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_key_file: &Path) -> Result<(), IdentityError> {
+  Ok(())
+}
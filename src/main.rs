@@ -4,59 +4,44 @@ use app_dirs;
 use std::path::{PathBuf};
 use std::env;
 use std::fs;
+use std::io::Read;
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use args::Action;
+use net::packet::{self, Packet, MessageType};
+
+mod args;
 mod gui;
 mod config;
+mod config_check;
+mod config_reload;
 mod global;
+mod identity;
 mod net;
+mod peers;
+mod pkcs11;
 mod util;
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+// How long send waits for a session with the peer to carry the message
+const SEND_TIMEOUT_MS: u64 = 10000;
 const APP_INFO: app_dirs::AppInfo = app_dirs::AppInfo{
   name: "meili",
   author: "meili"
 };
 
-enum Action {
-  PrintAbout,
-  PrintUsage,
-  OpenGui,
-  RunCLI,
-  RunNetCLI,
-}
-
 fn main() {
-    let mut action = Action::OpenGui;
-    let mut app_dir = get_app_dir();
-
-    // arguments modify the variables above, which are then passed into the rest of the program.
     let args: Vec<String> = env::args().collect();
-    for i in 0..args.len() {
-      match args[i].as_str() {
-        "--about" => {
-          action = Action::PrintAbout;
-        }
-        "--help" => {
-          action = Action::PrintUsage;
-        }
-        "--app-dir" => {
-          app_dir = PathBuf::from(args.get(i+1).expect("User did not supply argument to --app-dir"));
-        }
-        "--gui" => {
-          action = Action::OpenGui;
-        }
-        "--cli" => {
-          action = Action::RunCLI;
-        }
-        "--net-cli" => {
-          action = Action::RunNetCLI;
-        }
-        _unk => {
-          // likely an arg to another arg. meili just ignores garbage arguments.
-        }
-      }
-      
+    let parsed = args::parse_args(args.clone());
+    let action = parsed.action;
+    let app_dir = parsed.app_dir.unwrap_or_else(get_app_dir);
+    let overrides = parsed.overrides;
+
+    if let Action::DumpDefaultConfig = action {
+      print!("{}", config::DEFAULT_CONFIG);
+      return;
     }
 
     // Read in config file, creating the default one if nothing exists.
@@ -69,34 +54,147 @@ fn main() {
       c
     };
     if !config_file.as_path().exists() {
-      fs::write(config_file.as_path(), include_str!("meili.toml")).expect("Could not write default meili.toml");
+      // With a site-wide config the user's file starts out overriding nothing
+      let contents = if PathBuf::from(config::SYSTEM_CONFIG_FILE).exists() {
+        config::user_config_stub()
+      } else {
+        config::DEFAULT_CONFIG.to_string()
+      };
+      fs::write(config_file.as_path(), contents).expect("Could not write default meili.toml");
+    }
+    // Running with part of a config would be worse than not running at all,
+    // and checking it should not upgrade an old file on disk
+    let config = match config::read_config( &config_file.as_path(), &overrides, !matches!(action, Action::CheckConfig) ) {
+      Ok(config) => config,
+      Err(errors) => {
+        for e in &errors {
+          println!("{}", e);
+        }
+        println!("The config has {} problem(s), meili will not start until they are fixed", errors.len());
+        std::process::exit(1);
+      }
+    };
+    if let Action::CheckConfig = action {
+      println!("{} is valid", config_file.display());
+      return;
     }
-    let config = config::read_config( &config_file.as_path() );
-    let global = global::Global::default();
+    let identity = match &config.pkcs11_identity {
+      Some(pkcs11_identity) => identity::Identity::load_from_token(pkcs11_identity),
+      None => identity::Identity::load_or_generate( app_dir.as_path() ),
+    };
+    let identity = match identity {
+      Ok(identity) => identity,
+      Err(e) => {
+        println!("{}", e);
+        std::process::exit(1);
+      }
+    };
+    let peer_registry = peers::PeerRegistry::load( app_dir.as_path() );
 
     let args = Arc::new(args);
     let config = Arc::new(config);
-    let global = Arc::new(global);
+    let global = Arc::new(global::Global::new(identity, peer_registry, config.clone()));
+    spawn_exit_signal_handler(global.clone());
 
     // Now we execute things. This mostly consists of forwarding the input data to functions.
     match action {
-      Action::PrintAbout => { print_about(&app_dir, &config); }
-      Action::PrintUsage => { print_usage(); }
+      Action::PrintAbout => { print_about(&app_dir, &config, &global); }
+      Action::ListPeers => { print_peers(&global); }
+      // Both have returned above
+      Action::CheckConfig | Action::DumpDefaultConfig => { }
       Action::OpenGui => {
-        net::spawn_listeners(args.clone(), config.clone(), global.clone());
-        net::spawn_ip_scanning(args.clone(), config.clone(), global.clone());
-        gui::open_gui(args.clone(), config.clone(), global.clone());
+        config_reload::spawn_config_watcher(global.clone());
+        net::spawn_listeners(args.clone(), global.clone());
+        net::spawn_ip_scanning(args.clone(), global.clone());
+        gui::open_gui(args.clone(), global.clone());
       }
       Action::RunCLI => {
-        net::spawn_ip_scanning(args.clone(), config.clone(), global.clone());
-        gui::open_cli(args.clone(), config.clone(), global.clone());
+        net::spawn_ip_scanning(args.clone(), global.clone());
+        gui::open_cli(args.clone(), global.clone());
       }
       Action::RunNetCLI => {
-        net::spawn_ip_scanning(args.clone(), config.clone(), global.clone());
-        gui::start_tcp_cli(args.clone(), config.clone(), global.clone());
+        net::spawn_ip_scanning(args.clone(), global.clone());
+        gui::start_tcp_cli(args.clone(), global.clone());
+      }
+      Action::RunDaemon => {
+        config_reload::spawn_config_watcher(global.clone());
+        net::spawn_listeners(args.clone(), global.clone());
+        net::spawn_ip_scanning(args.clone(), global.clone());
+        loop {
+          thread::park();
+        }
+      }
+      Action::Send { peer, channel, message } => {
+        net::spawn_listeners(args.clone(), global.clone());
+        let sent = send_message(&global, &peer, &channel, message);
+        net::upnp::remove_upnp_mappings(&global);
+        global.save_peers();
+        if let Err(e) = sent {
+          println!("{}", e);
+          std::process::exit(1);
+        }
+        return;
+      }
+      Action::RunHttpMailbox(addr) => {
+        net::http_mailbox::run_http_mailbox(&addr);
       }
     }
 
+    // Leaving the shell with quit/exit ends up here, the tray's quit item
+    // removes the mapping and saves peers itself before exiting.
+    net::upnp::remove_upnp_mappings(&global);
+    global.save_peers();
+
+}
+
+// Written to by on_exit_signal, read by the thread spawn_exit_signal_handler starts
+#[cfg(unix)]
+static EXIT_SIGNAL_PIPE: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(-1);
+
+#[cfg(unix)]
+extern "C" fn on_exit_signal(signal: libc::c_int) {
+  // Only async-signal-safe calls may be made here, so the work happens on another thread
+  let byte = signal as u8;
+  unsafe {
+    libc::write(EXIT_SIGNAL_PIPE.load(std::sync::atomic::Ordering::SeqCst), &byte as *const u8 as *const libc::c_void, 1);
+  }
+}
+
+/**
+ * SIGINT and SIGTERM (eg Ctrl-C, or stopping a daemon) would otherwise end
+ * meili without passing any of its ways out, leaving our port mappings on
+ * the router until their lease runs out.
+ */
+#[cfg(unix)]
+fn spawn_exit_signal_handler(global: Arc<global::Global>) {
+  let mut fds = [0 as libc::c_int; 2];
+  if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+    println!("Cannot handle exit signals, port mappings will outlive meili e={}", std::io::Error::last_os_error());
+    return;
+  }
+  EXIT_SIGNAL_PIPE.store(fds[1], std::sync::atomic::Ordering::SeqCst);
+  let handler: extern "C" fn(libc::c_int) = on_exit_signal;
+  unsafe {
+    libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+  }
+  thread::spawn(move || {
+    let mut signal = 0u8;
+    while unsafe { libc::read(fds[0], &mut signal as *mut u8 as *mut libc::c_void, 1) } != 1 {
+      if std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted {
+        return;
+      }
+    }
+    println!("Exiting on signal {}", signal);
+    net::upnp::remove_upnp_mappings(&global);
+    global.save_peers();
+    std::process::exit(128 + signal as i32);
+  });
+}
+
+// Elsewhere mappings are left to expire with their lease
+#[cfg(not(unix))]
+fn spawn_exit_signal_handler(_global: Arc<global::Global>) {
 }
 
 fn get_app_dir() -> PathBuf {
@@ -104,17 +202,69 @@ fn get_app_dir() -> PathBuf {
     .unwrap_or(PathBuf::new())
 }
 
-fn print_about(app_dir: &PathBuf, config: &config::Config) {
+fn print_about(app_dir: &PathBuf, config: &config::Config, global: &global::Global) {
   println!(r#"Meili {VERSION}
 app_dir={app_dir}
-config={config:#?}
-"#,
+identity={fingerprint}{token}"#,
   VERSION=VERSION,
   app_dir=app_dir.to_string_lossy(),
-  config=config,
+  fingerprint=global.identity.fingerprint(),
+  token=global.identity.token_key().map(|k| format!(" (PKCS#11 {})", k.description)).unwrap_or_default(),
 );
+  // The listeners are not running, so each probe gets its own socket
+  if !config.stun_servers.is_empty() {
+    let servers: Vec<_> = config.stun_servers.iter().map(|s| s.socket).collect();
+    let timeout = std::time::Duration::from_millis(config.stun_timeout_ms as u64);
+    for listener in config.unicast_udp_sockets() {
+      match net::stun::probe_blocking(listener, servers.clone(), timeout) {
+        Ok(result) => println!("stun={}", gui::format_stun_result(&result)),
+        Err(e) => println!("stun={} e={}", listener, e),
+      }
+    }
+  }
+  println!("config:\n{}", config.describe_sources());
 }
 
-fn print_usage() {
-  println!(include_str!("usage.txt"));
+fn print_peers(global: &global::Global) {
+  let peers = global.get_known_peers();
+  println!("{} known peers", peers.len());
+  for peer in peers {
+    println!("{}", gui::format_known_peer(&peer));
+  }
+}
+
+/**
+ * Queues a data message for peer and waits for the listeners to set up
+ * a session with it, which sends the message as soon as it is up.
+ */
+fn send_message(global: &global::Global, peer: &str, channel: &str, message: Option<String>) -> Result<(), String> {
+  let public_key = global.find_known_peer(peer)?;
+  let addr = global.get_known_peer(&public_key)
+    .and_then(|p| p.last_address().and_then(|a| a.to_peer_addr(&public_key)))
+    .ok_or_else(|| format!("No address is known for {}", peer))?;
+  let body = match message {
+    Some(message) => message.into_bytes(),
+    None => {
+      let mut body = Vec::new();
+      std::io::stdin().read_to_end(&mut body).map_err(|e| format!("Cannot read the message from stdin e={}", e))?;
+      body
+    }
+  };
+  let flushed = global.get_flushed_data(&addr);
+  global.queue_outgoing(addr.clone(), Packet::new(MessageType::Data, packet::encode_data_payload(channel, &body)));
+  global.wake_listeners();
+
+  // Returning ends the listeners, so wait until a transport has written the packet out
+  let deadline = Instant::now() + Duration::from_millis(SEND_TIMEOUT_MS);
+  while Instant::now() < deadline {
+    if global.get_flushed_data(&addr) > flushed {
+      println!("Sent {} bytes to {} at {}", body.len(), peer, addr);
+      return Ok(());
+    }
+    thread::sleep(Duration::from_millis(50));
+  }
+  if global.get_sessions().iter().any(|s| s.current && s.addr == addr) {
+    return Err(format!("Could not write the message to {} at {} within {}ms, it was not sent", peer, addr, SEND_TIMEOUT_MS));
+  }
+  Err(format!("{} at {} did not answer within {}ms, the message was not sent", peer, addr, SEND_TIMEOUT_MS))
 }
@@ -0,0 +1,388 @@
+
+/*!
+ * The dispatch table routes decoded packets to a handler
+ * for their MessageType. Handlers may return a packet which
+ * is sent back to the peer over the socket the request arrived on.
+ */
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::net::SocketAddr;
+
+use crate::config::Config;
+use crate::global::Global;
+use crate::net::packet::{self, Packet, MessageType, Announcement};
+use crate::net::transport::PeerAddr;
+use crate::net::external;
+use crate::net::rendezvous;
+use crate::identity;
+use crate::peers::TrustLevel;
+
+pub type Handler = fn(&Packet, &PeerAddr, &Config, &Global) -> Option<Packet>;
+
+pub struct Dispatcher {
+  handlers: HashMap<MessageType, Handler>,
+}
+
+impl Default for Dispatcher {
+  fn default() -> Self {
+    let mut d = Dispatcher {
+      handlers: HashMap::new(),
+    };
+    d.register(MessageType::Hello, handle_hello);
+    d.register(MessageType::HelloAck, handle_hello_ack);
+    d.register(MessageType::Data, handle_data);
+    d.register(MessageType::Announce, handle_announce);
+    d.register(MessageType::SessionInit, handle_session_init);
+    d.register(MessageType::SessionAccept, handle_session_accept);
+    d.register(MessageType::VerifyConfirm, handle_verify_confirm);
+    d.register(MessageType::RendezvousRegister, handle_rendezvous_register);
+    d.register(MessageType::RendezvousRegistered, handle_rendezvous_registered);
+    d.register(MessageType::IntroduceRequest, handle_introduce_request);
+    d.register(MessageType::Introduce, handle_introduce);
+    d.register(MessageType::Punch, handle_punch);
+    d.register(MessageType::PunchAck, handle_punch_ack);
+    d.register(MessageType::Relay, handle_relay);
+    d.register(MessageType::Relayed, handle_relayed);
+    d
+  }
+}
+
+impl Dispatcher {
+  pub fn register(&mut self, msg_type: MessageType, handler: Handler) {
+    self.handlers.insert(msg_type, handler);
+  }
+
+  /**
+   * Decodes buf and passes it to the registered handler, returning
+   * the encoded reply (if any). Malformed packets are logged and counted
+   * in global under their DecodeError::reason(), as are packets from blocked peers.
+   * Sealed packets are decrypted first and the reply sealed the same way.
+   * Matching [[message_handlers]] programs are started in the background.
+   */
+  pub fn dispatch(&self, buf: &[u8], from: &PeerAddr, config: &Arc<Config>, global: &Arc<Global>) -> Option<Vec<u8>> {
+    let packet = match Packet::decode(buf) {
+      Ok(p) => p,
+      Err(e) => {
+        println!("Rejected {} bytes from {}: {}", buf.len(), from, e);
+        global.count_rejected_packet(e.reason());
+        return None;
+      }
+    };
+    if global.is_peer_blocked(&packet.sender_id) {
+      global.count_rejected_packet("blocked_peer");
+      return None;
+    }
+    // Sealed packets are unwrapped and handled as the packet they carry
+    let (packet, session_id) = if packet.msg_type == MessageType::Sealed {
+      match global.open_sealed(&packet, from) {
+        Ok((inner, session_id)) => (inner, Some(session_id)),
+        Err(reason) => {
+          println!("Rejected sealed packet from {}: {}", from, reason);
+          global.count_rejected_packet(reason);
+          return None;
+        }
+      }
+    } else if packet.msg_type.requires_session() {
+      println!("Rejected {} packet from {} sent outside a session", packet.msg_type.name(), from);
+      global.count_rejected_packet("unsealed");
+      return None;
+    } else {
+      (packet, None)
+    };
+    external::run_matching_handlers(&packet, from, config, global);
+    match self.handlers.get(&packet.msg_type) {
+      Some(handler) => {
+        let reply = handler(&packet, from, config, global)?;
+        // Replies to sealed packets are sealed in the same session
+        let reply = match session_id {
+          Some(session_id) => global.seal_in_session(session_id, &reply)?,
+          None => reply,
+        };
+        Some(reply.encode(&global.identity))
+      }
+      None => {
+        println!("No handler for {:?} from {}", packet.msg_type, from);
+        global.count_rejected_packet("no_handler");
+        None
+      }
+    }
+  }
+}
+
+/**
+ * HelloAck payloads carry our public address (see packet::encode_socket_addr)
+ * when we know it, and are empty otherwise.
+ */
+fn handle_hello(_packet: &Packet, from: &PeerAddr, _config: &Config, global: &Global) -> Option<Packet> {
+  println!("Hello from {}", from);
+  let mut payload = Vec::new();
+  if let Some(public_address) = global.get_public_address() {
+    packet::encode_socket_addr(&public_address.addr, &mut payload);
+  }
+  Some(Packet::new(MessageType::HelloAck, payload))
+}
+
+/**
+ * Only the scanner says Hello, from its own sockets (see net::scan), so a
+ * HelloAck reaching a listener answers nothing we sent and is not recorded.
+ */
+fn handle_hello_ack(_packet: &Packet, _from: &PeerAddr, _config: &Config, global: &Global) -> Option<Packet> {
+  global.count_rejected_packet("unsolicited_hello_ack");
+  None
+}
+
+fn handle_data(packet: &Packet, from: &PeerAddr, _config: &Config, global: &Global) -> Option<Packet> {
+  let flag = match global.get_peer_trust(&packet.sender_id) {
+    TrustLevel::Verified => "",
+    _ => " (UNVERIFIED)",
+  };
+  println!("Got {} data bytes from {}{}", packet.payload.len(), from, flag);
+  None
+}
+
+/**
+ * LAN beacons arrive from the sender's multicast socket, so we record
+ * the first unicast port it advertises rather than the source port.
+ */
+fn handle_announce(packet: &Packet, from: &PeerAddr, _config: &Config, global: &Global) -> Option<Packet> {
+  let announcement = match Announcement::decode(&packet.payload) {
+    Some(a) => a,
+    None => {
+      println!("Malformed announcement from {}", from);
+      global.count_rejected_packet("bad_announcement");
+      return None;
+    }
+  };
+  let addr = match from {
+    PeerAddr::Udp(src) => {
+      if let Some(port) = announcement.udp_ports.first() {
+        PeerAddr::Udp(SocketAddr::new(src.ip(), *port))
+      } else if let Some(port) = announcement.tcp_ports.first() {
+        PeerAddr::Tcp(SocketAddr::new(src.ip(), *port))
+      } else {
+        from.clone()
+      }
+    }
+    _ => from.clone(),
+  };
+  global.record_discovered_peer(packet.sender_id, addr, "lan", Some(announcement.hostname));
+  global.set_peer_public_addr(&packet.sender_id, announcement.public_addr);
+  None
+}
+
+fn handle_session_init(packet: &Packet, from: &PeerAddr, _config: &Config, global: &Global) -> Option<Packet> {
+  match global.accept_session(packet, from) {
+    Ok(accept) => Some(accept),
+    Err(reason) => {
+      println!("Rejected session init from {}: {}", from, reason);
+      global.count_rejected_packet(reason);
+      None
+    }
+  }
+}
+
+/**
+ * Packets which were waiting for the session are queued again,
+ * and sealed when the listener next sends.
+ */
+fn handle_session_accept(packet: &Packet, from: &PeerAddr, _config: &Config, global: &Global) -> Option<Packet> {
+  match global.complete_session(packet, from) {
+    Ok(queued) => {
+      for waiting in queued {
+        global.queue_outgoing(from.clone(), waiting);
+      }
+    }
+    Err(reason) => {
+      println!("Rejected session accept from {}: {}", from, reason);
+      global.count_rejected_packet(reason);
+    }
+  }
+  None
+}
+
+/**
+ * The peer ran `verify <us> confirm`. VerifyConfirm is only accepted
+ * sealed, so it arrived in a session keyed by the sender's identity key
+ * and that is what shows the peer sent it. Its payload, the verification
+ * digest of both public keys, is no secret; it only shows the peer
+ * compared the code for our key and not some other.
+ */
+fn handle_verify_confirm(packet: &Packet, from: &PeerAddr, _config: &Config, global: &Global) -> Option<Packet> {
+  let expected = identity::verification_digest(&global.identity.public_key(), &packet.sender_id);
+  if packet.payload[..] != expected[..] {
+    println!("Verification confirmation from {} does not match our keys", from);
+    global.count_rejected_packet("bad_verify_confirm");
+    return None;
+  }
+  let fingerprint = identity::fingerprint_of(&packet.sender_id);
+  match global.confirm_peer_verification(&packet.sender_id, false) {
+    Some(TrustLevel::Verified) => println!("Peer {} is now verified", fingerprint),
+    Some(_) => println!("Peer {} confirmed its verification code, run `verify {}` to compare it", fingerprint, fingerprint),
+    None => {}
+  }
+  None
+}
+
+/**
+ * The UDP address of a client talking to us as a rendezvous server,
+ * or None (counting why) when we do not serve as one.
+ */
+fn rendezvous_client(from: &PeerAddr, config: &Config, global: &Global) -> Option<SocketAddr> {
+  if !config.rendezvous_serve {
+    global.count_rejected_packet("not_rendezvous");
+    return None;
+  }
+  match from {
+    PeerAddr::Udp(addr) => Some(*addr),
+    _ => {
+      global.count_rejected_packet("rendezvous_not_udp");
+      None
+    }
+  }
+}
+
+/**
+ * The rendezvous server from is, or None (counting it) when it is not one of ours.
+ */
+fn rendezvous_server(from: &PeerAddr, config: &Config, global: &Global) -> Option<SocketAddr> {
+  match from {
+    PeerAddr::Udp(addr) if config.is_rendezvous_server(addr) => Some(*addr),
+    _ => {
+      println!("Ignoring rendezvous packet from {}, which is not in [[rendezvous_servers]]", from);
+      global.count_rejected_packet("unknown_rendezvous");
+      None
+    }
+  }
+}
+
+/**
+ * Clients register from behind their NAT, so the address we see is
+ * the one their peers must punch towards. We tell them what it is.
+ */
+fn handle_rendezvous_register(packet: &Packet, from: &PeerAddr, config: &Config, global: &Global) -> Option<Packet> {
+  let addr = rendezvous_client(from, config, global)?;
+  global.register_rendezvous_client(packet.sender_id, addr);
+  let mut payload = Vec::new();
+  packet::encode_socket_addr(&addr, &mut payload);
+  Some(Packet::new(MessageType::RendezvousRegistered, payload))
+}
+
+fn handle_rendezvous_registered(packet: &Packet, from: &PeerAddr, config: &Config, global: &Global) -> Option<Packet> {
+  let server = rendezvous_server(from, config, global)?;
+  match packet::decode_socket_addr(&packet.payload) {
+    Some((addr, _)) => {
+      if global.set_rendezvous_seen_as(server, addr) {
+        println!("Rendezvous {} sees us as {}", server, addr);
+      }
+    }
+    None => global.count_rejected_packet("bad_rendezvous_registered"),
+  }
+  None
+}
+
+/**
+ * Introduces the sender and the peer it asked for to each other, both
+ * at once so they punch at the same time. The sender only learns the
+ * peer is not registered when it is not.
+ */
+fn handle_introduce_request(packet: &Packet, from: &PeerAddr, config: &Config, global: &Global) -> Option<Packet> {
+  let addr = rendezvous_client(from, config, global)?;
+  if packet.payload.len() != identity::PUBLIC_KEY_LEN {
+    global.count_rejected_packet("bad_introduce_request");
+    return None;
+  }
+  let mut peer = [0u8; identity::PUBLIC_KEY_LEN];
+  peer.copy_from_slice(&packet.payload);
+  // Asking is as good as registering, the request came through the same mapping
+  global.register_rendezvous_client(packet.sender_id, addr);
+  let peer_addr = global.get_rendezvous_client_addr(&peer);
+  if let Some(peer_addr) = peer_addr {
+    println!("Introducing {} at {} and {} at {}",
+      identity::fingerprint_of(&packet.sender_id), addr, identity::fingerprint_of(&peer), peer_addr
+    );
+    let introduce = rendezvous::encode_introduce(&packet.sender_id, Some(addr));
+    global.queue_outgoing(PeerAddr::Udp(peer_addr), Packet::new(MessageType::Introduce, introduce));
+  }
+  Some(Packet::new(MessageType::Introduce, rendezvous::encode_introduce(&peer, peer_addr)))
+}
+
+fn handle_introduce(packet: &Packet, from: &PeerAddr, config: &Config, global: &Global) -> Option<Packet> {
+  let server = rendezvous_server(from, config, global)?;
+  let (peer, addr) = match rendezvous::decode_introduce(&packet.payload) {
+    Some(introduce) => introduce,
+    None => {
+      global.count_rejected_packet("bad_introduce");
+      return None;
+    }
+  };
+  if peer == global.identity.public_key() {
+    return None;
+  }
+  match addr {
+    Some(addr) => {
+      println!("Punching towards {} at {}, introduced by {}", identity::fingerprint_of(&peer), addr, server);
+      global.start_punch(peer, addr, server);
+    }
+    None => println!("Peer {} is not registered with rendezvous {}", identity::fingerprint_of(&peer), server),
+  }
+  None
+}
+
+/**
+ * A punch getting through in either direction proves the path works.
+ * We always answer, the peer may have reached us before its introduction reached us.
+ */
+fn handle_punch(packet: &Packet, from: &PeerAddr, config: &Config, global: &Global) -> Option<Packet> {
+  handle_punch_ack(packet, from, config, global);
+  Some(Packet::new(MessageType::PunchAck, Vec::new()))
+}
+
+fn handle_punch_ack(packet: &Packet, from: &PeerAddr, _config: &Config, global: &Global) -> Option<Packet> {
+  if global.complete_punch(&packet.sender_id) {
+    println!("Punched a direct path to {} at {}", identity::fingerprint_of(&packet.sender_id), from);
+    global.record_discovered_peer(packet.sender_id, from.clone(), "punch", None);
+  }
+  None
+}
+
+/**
+ * Forwards a registered client's packet to another registered client.
+ * The packet must be the client's own, so we cannot be used to pass
+ * off packets as coming from someone else's address.
+ */
+fn handle_relay(packet: &Packet, from: &PeerAddr, config: &Config, global: &Global) -> Option<Packet> {
+  let addr = rendezvous_client(from, config, global)?;
+  let (recipient, inner) = match rendezvous::decode_relay(&packet.payload) {
+    Some(relay) => relay,
+    None => {
+      global.count_rejected_packet("bad_relay");
+      return None;
+    }
+  };
+  if global.get_rendezvous_client_addr(&packet.sender_id) != Some(addr) {
+    global.count_rejected_packet("relay_unregistered");
+    return None;
+  }
+  if packet::peek_sender_id(inner) != Some(packet.sender_id) {
+    global.count_rejected_packet("relay_not_own_packet");
+    return None;
+  }
+  match global.get_rendezvous_client_addr(&recipient) {
+    Some(recipient_addr) => {
+      global.queue_outgoing(PeerAddr::Udp(recipient_addr), Packet::new(MessageType::Relayed, inner.to_vec()));
+    }
+    None => global.count_rejected_packet("relay_unknown_recipient"),
+  }
+  None
+}
+
+/**
+ * The udp transport unwraps Relayed packets from our rendezvous servers,
+ * so those which get here came from elsewhere or carry no packet.
+ */
+fn handle_relayed(_packet: &Packet, from: &PeerAddr, config: &Config, global: &Global) -> Option<Packet> {
+  rendezvous_server(from, config, global)?;
+  global.count_rejected_packet("bad_relayed");
+  None
+}
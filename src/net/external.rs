@@ -0,0 +1,172 @@
+
+/*!
+ * Runs the external programs configured as [[message_handlers]].
+ * Each program runs on its own thread so a slow or crashing handler
+ * can never stall or take down the listener thread; failures are
+ * logged and counted in Global instead. At most MAX_RUNNING_HANDLERS
+ * run at once, messages arriving while they do are not handled.
+ */
+
+use std::io;
+use std::thread;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio, ExitStatus};
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, MessageHandler};
+use crate::global::Global;
+use crate::identity::{self, SIGNATURE_LEN};
+use crate::peers::TrustLevel;
+use crate::util::to_hex;
+use crate::net::packet::{self, Packet, MessageType};
+use crate::net::session;
+use crate::net::transport::PeerAddr;
+use crate::net::NET_BUFF_SIZE;
+
+const WAIT_POLL_MS: u64 = 10;
+const MAX_RUNNING_HANDLERS: usize = 16;
+// The longest reply which still fits in one sealed packet
+const MAX_REPLY_LEN: usize = NET_BUFF_SIZE - packet::HEADER_LEN - SIGNATURE_LEN - session::SEAL_OVERHEAD - 1 - packet::MAX_CHANNEL_LEN;
+
+/**
+ * Holds one of the MAX_RUNNING_HANDLERS places until dropped.
+ */
+struct RunningHandler {
+  global: Arc<Global>,
+}
+
+impl Drop for RunningHandler {
+  fn drop(&mut self) {
+    self.global.finish_handler();
+  }
+}
+
+pub fn run_matching_handlers(packet: &Packet, from: &PeerAddr, config: &Arc<Config>, global: &Arc<Global>) {
+  let (channel, body) = match packet.msg_type {
+    MessageType::Data => match packet::decode_data_payload(&packet.payload) {
+      Some((channel, body)) => (Some(channel), body.to_vec()),
+      None => (None, packet.payload.clone()),
+    },
+    _ => (None, packet.payload.clone()),
+  };
+
+  let trust = global.get_peer_trust(&packet.sender_id);
+  for (i, handler) in config.message_handlers.iter().enumerate() {
+    if !handler.matches(packet.msg_type.name(), channel.as_deref()) || !trust.at_least(handler.min_trust) {
+      continue;
+    }
+    if !global.try_start_handler(MAX_RUNNING_HANDLERS) {
+      println!("Message handler '{}' not run, {} handlers are already running", handler.display_name(), MAX_RUNNING_HANDLERS);
+      global.count_handler_failure(&handler.display_name());
+      continue;
+    }
+    let running = RunningHandler {
+      global: global.clone(),
+    };
+    let config = config.clone();
+    let global = global.clone();
+    let msg_type = packet.msg_type;
+    let sender_id = packet.sender_id;
+    let from = from.clone();
+    let channel = channel.clone();
+    let body = body.clone();
+    thread::spawn(move || {
+      let _running = running;
+      let handler = &config.message_handlers[i];
+      match run_handler(handler, msg_type, &sender_id, trust, &from, channel.as_ref(), &body) {
+        Ok(output) => {
+          if handler.reply && !output.is_empty() {
+            let reply_channel = channel.unwrap_or("".to_string());
+            let reply = Packet::new(MessageType::Data, packet::encode_data_payload(&reply_channel, &output));
+            global.queue_outgoing(from, reply);
+          }
+        }
+        Err(reason) => {
+          println!("Message handler '{}' failed: {}", handler.display_name(), reason);
+          global.count_handler_failure(&handler.display_name());
+        }
+      }
+    });
+  }
+}
+
+/**
+ * Runs handler to completion, returning its stdout or why it failed.
+ */
+fn run_handler(handler: &MessageHandler, msg_type: MessageType, sender_id: &[u8; identity::PUBLIC_KEY_LEN], trust: TrustLevel, from: &PeerAddr, channel: Option<&String>, body: &[u8]) -> Result<Vec<u8>, String> {
+  if handler.command.is_empty() {
+    return Err("command is empty".to_string());
+  }
+  let mut child = Command::new(&handler.command[0])
+    .args(&handler.command[1..])
+    .env("MEILI_MESSAGE_TYPE", msg_type.name())
+    .env("MEILI_CHANNEL", channel.map(|c| c.as_str()).unwrap_or(""))
+    .env("MEILI_SENDER_ID", to_hex(sender_id))
+    .env("MEILI_SENDER_FINGERPRINT", identity::fingerprint_of(sender_id))
+    .env("MEILI_SENDER_TRUST", trust.name())
+    .env("MEILI_SENDER_ADDR", from.to_string())
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::inherit())
+    .spawn()
+    .map_err(|e| format!("could not start {:?}: {}", handler.command[0], e))?;
+
+  // Read stdout concurrently so a chatty program cannot deadlock against our stdin write
+  let (output_tx, output_rx) = mpsc::channel();
+  if let Some(stdout) = child.stdout.take() {
+    thread::spawn(move || {
+      let _ = output_tx.send(read_reply(stdout));
+    });
+  }
+
+  if let Some(mut stdin) = child.stdin.take() {
+    // A handler may legitimately exit without reading its input
+    let _ = stdin.write_all(body);
+  }
+
+  let deadline = Instant::now() + Duration::from_millis(handler.timeout_ms as u64);
+  let status = wait_until(&mut child, deadline)?;
+  // A process the handler started may keep stdout open after the handler exited
+  let output = match output_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+    Ok(output) => output?,
+    Err(mpsc::RecvTimeoutError::Timeout) => return Err("stdout was not closed before timeout_ms".to_string()),
+    Err(mpsc::RecvTimeoutError::Disconnected) => Vec::new(),
+  };
+  if !status.success() {
+    return Err(format!("exited with {}", status));
+  }
+  Ok(output)
+}
+
+/**
+ * Reads a reply of at most MAX_REPLY_LEN bytes. Anything longer is read
+ * and thrown away, so the program is not left blocked on a full pipe.
+ */
+fn read_reply<R: Read>(mut stdout: R) -> Result<Vec<u8>, String> {
+  let mut output = Vec::new();
+  (&mut stdout).take(MAX_REPLY_LEN as u64 + 1).read_to_end(&mut output)
+    .map_err(|e| format!("could not read stdout: {}", e))?;
+  if output.len() > MAX_REPLY_LEN {
+    let _ = io::copy(&mut stdout, &mut io::sink());
+    return Err(format!("printed more than the {} bytes a reply may hold", MAX_REPLY_LEN));
+  }
+  Ok(output)
+}
+
+fn wait_until(child: &mut std::process::Child, deadline: Instant) -> Result<ExitStatus, String> {
+  loop {
+    match child.try_wait() {
+      Ok(Some(status)) => return Ok(status),
+      Ok(None) => {}
+      Err(e) => return Err(format!("could not wait for program: {}", e)),
+    }
+    if Instant::now() >= deadline {
+      let _ = child.kill();
+      let _ = child.wait();
+      return Err("ran longer than timeout_ms and was killed".to_string());
+    }
+    thread::sleep(Duration::from_millis(WAIT_POLL_MS));
+  }
+}
@@ -0,0 +1,90 @@
+
+/*!
+ * Just enough HTTP/1.1 for the mailbox server and transport to talk to each
+ * other (and through ordinary reverse proxies): one request per connection,
+ * bodies framed by Content-Length.
+ */
+
+use std::io::{self, Read, Write, BufRead, BufReader};
+use std::collections::HashMap;
+
+// Longest start line or header we read, and the most headers
+const MAX_LINE_LEN: u64 = 8192;
+const MAX_HEADERS: usize = 64;
+
+pub struct HttpMessage {
+  pub start_line: String,
+  // Header names are lowercased
+  pub headers: HashMap<String, String>,
+  pub body: Vec<u8>,
+}
+
+impl HttpMessage {
+  /**
+   * For a response, the numeric status code from the start line.
+   */
+  pub fn status(&self) -> Option<u16> {
+    self.start_line.split_whitespace().nth(1).and_then(|s| s.parse::<u16>().ok())
+  }
+}
+
+/**
+ * Reads a line of at most MAX_LINE_LEN bytes, so a peer cannot make us buffer without end.
+ */
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+  let mut line = String::new();
+  reader.take(MAX_LINE_LEN).read_line(&mut line)?;
+  if line.len() as u64 == MAX_LINE_LEN && !line.ends_with('\n') {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("line is longer than {} bytes", MAX_LINE_LEN)));
+  }
+  Ok(line)
+}
+
+pub fn read_message<R: Read>(stream: R, max_body: usize) -> io::Result<HttpMessage> {
+  let mut reader = BufReader::new(stream);
+  let start_line = read_line(&mut reader)?;
+
+  let mut headers = HashMap::new();
+  loop {
+    let line = read_line(&mut reader)?;
+    if line.is_empty() {
+      break;
+    }
+    let line = line.trim_end();
+    if line.is_empty() {
+      break;
+    }
+    if headers.len() >= MAX_HEADERS {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, format!("more than {} headers", MAX_HEADERS)));
+    }
+    if let Some(colon) = line.find(':') {
+      headers.insert(line[..colon].trim().to_lowercase(), line[colon+1..].trim().to_string());
+    }
+  }
+
+  let content_length = headers.get("content-length")
+    .and_then(|s| s.parse::<usize>().ok())
+    .unwrap_or(0);
+  if content_length > max_body {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} byte body is too large", content_length)));
+  }
+  let mut body = vec![0; content_length];
+  reader.read_exact(&mut body)?;
+
+  Ok(HttpMessage {
+    start_line: start_line.trim_end().to_string(),
+    headers,
+    body,
+  })
+}
+
+pub fn write_message<W: Write>(mut stream: W, start_line: &str, headers: &[(&str, String)], body: &[u8]) -> io::Result<()> {
+  let mut head = format!("{}\r\n", start_line);
+  for (name, val) in headers {
+    head.push_str(&format!("{}: {}\r\n", name, val));
+  }
+  head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", body.len()));
+  stream.write_all(head.as_bytes())?;
+  stream.write_all(body)?;
+  stream.flush()
+}
@@ -0,0 +1,415 @@
+
+/*!
+ * The HTTP mailbox is a tiny relay for nodes which cannot reach each other
+ * directly, run with `meili http-mailbox <addr>`. Nodes PUT packets to
+ * /mailbox/<recipient public key hex> and the recipient reads its own
+ * mailbox with a GET, which must carry an X-Meili-Auth header of
+ * "<unix seconds>:<signature hex>" signing "GET <path> <unix seconds>"
+ * with the mailbox owner's key.
+ *
+ * A GET leaves the packets in place and answers with an X-Meili-Cursor
+ * header. The owner then removes what it read with a DELETE whose
+ * X-Meili-Auth is "<cursor>:<signature hex>" signing "DELETE <path> <cursor>".
+ * Cursors only ever grow and name the relay's run, so replaying either
+ * request cannot lose packets which arrived after it.
+ *
+ * Packets are already signed by their sender, so the relay never needs
+ * to be trusted with anything else. Memory is bounded by MAX_TOTAL_BYTES,
+ * with each mailbox holding at most MAX_BYTES_PER_MAILBOX, and packets
+ * nobody collected are dropped after MAX_PACKET_AGE_S.
+ */
+
+use rand::RngCore;
+
+use std::io::{self, Read};
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::punwrap_r;
+use crate::util::{to_hex, from_hex};
+use crate::identity::{self, Identity, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+use crate::net::packet::Packet;
+use crate::net::http;
+use crate::net::NET_BUFF_SIZE;
+
+pub const MAILBOX_PATH: &str = "/mailbox/";
+pub const AUTH_HEADER: &str = "X-Meili-Auth";
+pub const CURSOR_HEADER: &str = "X-Meili-Cursor";
+
+const MAX_MAILBOXES: usize = 4096;
+const MAX_QUEUED_PER_MAILBOX: usize = 1024;
+const MAX_BYTES_PER_MAILBOX: usize = 4 * 1024 * 1024;
+const MAX_TOTAL_BYTES: usize = 256 * 1024 * 1024;
+const MAX_PACKET_AGE_S: u64 = 24 * 60 * 60;
+// How often mailboxes are swept for packets past MAX_PACKET_AGE_S
+const EXPIRE_INTERVAL_S: u64 = 60;
+const MAX_AUTH_SKEW_S: u64 = 300;
+// Connections are served one at a time by each worker
+const WORKER_THREADS: usize = 16;
+// How long a whole request may take to arrive, however slowly it trickles in
+const CONN_TIMEOUT_MS: u64 = 5000;
+// What one GET returns, the rest waits for the next
+pub const MAX_GET_BODY: usize = 64 * NET_BUFF_SIZE;
+
+struct Mailboxes {
+  // Random per run, so cursors from an earlier run match nothing
+  run_id: String,
+  // Sequence number of the next packet put in any mailbox
+  next_seq: u64,
+  queues: HashMap<String, Mailbox>,
+  // What every mailbox holds together
+  total_bytes: usize,
+  next_expiry: Instant,
+}
+
+#[derive(Default)]
+struct Mailbox {
+  // Sequence number, when it was put and the packet, oldest first
+  packets: VecDeque<(u64, Instant, Vec<u8>)>,
+  bytes: usize,
+}
+
+impl Mailboxes {
+  fn new(run_id: String) -> Mailboxes {
+    Mailboxes {
+      run_id,
+      next_seq: 0,
+      queues: HashMap::new(),
+      total_bytes: 0,
+      next_expiry: Instant::now(),
+    }
+  }
+
+  /**
+   * Drops packets from the front of key_hex's mailbox while keep says no,
+   * and the mailbox itself once it is empty.
+   */
+  fn drop_front(&mut self, key_hex: &str, keep: impl Fn(u64, Instant) -> bool) {
+    let mut dropped = 0;
+    let now_empty = match self.queues.get_mut(key_hex) {
+      Some(mailbox) => {
+        while let Some((seq, put, _)) = mailbox.packets.front() {
+          if keep(*seq, *put) {
+            break;
+          }
+          if let Some((_, _, packet)) = mailbox.packets.pop_front() {
+            dropped += packet.len();
+          }
+        }
+        mailbox.bytes -= dropped;
+        mailbox.packets.is_empty()
+      }
+      None => false,
+    };
+    self.total_bytes -= dropped;
+    if now_empty {
+      self.queues.remove(key_hex);
+    }
+  }
+
+  fn expire(&mut self, now: Instant) {
+    if now < self.next_expiry {
+      return;
+    }
+    self.next_expiry = now + Duration::from_secs(EXPIRE_INTERVAL_S);
+    let max_age = Duration::from_secs(MAX_PACKET_AGE_S);
+    let keys: Vec<String> = self.queues.keys().cloned().collect();
+    for key_hex in keys {
+      self.drop_front(&key_hex, |_, put| now.saturating_duration_since(put) <= max_age);
+    }
+  }
+}
+
+pub fn run_http_mailbox(addr: &str) {
+  let serv = TcpListener::bind(addr).expect("Cannot open http mailbox socket");
+  println!("HTTP mailbox listening on http://{}{}", addr, MAILBOX_PATH);
+
+  let mut run_id = [0; 8];
+  rand::rngs::OsRng.fill_bytes(&mut run_id);
+  let mailboxes = Arc::new(Mutex::new(Mailboxes::new(to_hex(&run_id))));
+  let mut workers = Vec::new();
+  for _ in 0..WORKER_THREADS {
+    let serv = serv.try_clone().expect("Cannot share http mailbox socket");
+    let mailboxes = mailboxes.clone();
+    workers.push(thread::spawn(move || {
+      for stream in serv.incoming() {
+        let stream = punwrap_r!(stream, continue);
+        handle_conn(stream, &mailboxes);
+      }
+    }));
+  }
+  for worker in workers {
+    if worker.join().is_err() {
+      println!("An http mailbox worker panicked");
+    }
+  }
+}
+
+/**
+ * Reads from a stream until deadline, so a client sending a byte at a
+ * time cannot hold a worker for longer than CONN_TIMEOUT_MS.
+ */
+struct DeadlineReader<'a> {
+  stream: &'a TcpStream,
+  deadline: Instant,
+}
+
+impl<'a> Read for DeadlineReader<'a> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let left = self.deadline.saturating_duration_since(Instant::now());
+    if left == Duration::from_secs(0) {
+      return Err(io::Error::new(io::ErrorKind::TimedOut, "request took too long"));
+    }
+    self.stream.set_read_timeout(Some(left))?;
+    self.stream.read(buf)
+  }
+}
+
+fn handle_conn(stream: TcpStream, mailboxes: &Mutex<Mailboxes>) {
+  punwrap_r!(stream.set_write_timeout(Some(Duration::from_millis(CONN_TIMEOUT_MS))), nothing);
+
+  let reader = DeadlineReader {
+    stream: &stream,
+    deadline: Instant::now() + Duration::from_millis(CONN_TIMEOUT_MS),
+  };
+  let request = punwrap_r!(http::read_message(reader, NET_BUFF_SIZE), return);
+  let (status, headers, body) = respond(&request, mailboxes);
+  punwrap_r!(http::write_message(&stream, &format!("HTTP/1.1 {}", status), &headers, &body), nothing);
+}
+
+fn respond(request: &http::HttpMessage, mailboxes: &Mutex<Mailboxes>) -> (&'static str, Vec<(&'static str, String)>, Vec<u8>) {
+  let mut parts = request.start_line.split_whitespace();
+  let method = parts.next().unwrap_or("");
+  let path = parts.next().unwrap_or("");
+  let key_hex = match mailbox_key(path) {
+    Some(key_hex) => key_hex,
+    None => return ("404 Not Found", vec![], vec![]),
+  };
+  let auth = request.headers.get(&AUTH_HEADER.to_lowercase());
+  let mut mailboxes = match mailboxes.lock() {
+    Ok(mailboxes) => mailboxes,
+    Err(_) => return ("500 Internal Server Error", vec![], vec![]),
+  };
+  mailboxes.expire(Instant::now());
+
+  match method {
+    "PUT" => {
+      if let Err(e) = Packet::decode(&request.body) {
+        println!("Refusing packet for {}: {}", key_hex, e);
+        return ("400 Bad Request", vec![], vec![]);
+      }
+      if !mailboxes.queues.contains_key(&key_hex) && mailboxes.queues.len() >= MAX_MAILBOXES {
+        return ("503 Service Unavailable", vec![], vec![]);
+      }
+      if mailboxes.total_bytes + request.body.len() > MAX_TOTAL_BYTES {
+        return ("507 Insufficient Storage", vec![], vec![]);
+      }
+      let seq = mailboxes.next_seq;
+      let mailbox = mailboxes.queues.entry(key_hex).or_default();
+      if mailbox.packets.len() >= MAX_QUEUED_PER_MAILBOX || mailbox.bytes + request.body.len() > MAX_BYTES_PER_MAILBOX {
+        return ("429 Too Many Requests", vec![], vec![]);
+      }
+      mailbox.packets.push_back((seq, Instant::now(), request.body.clone()));
+      mailbox.bytes += request.body.len();
+      mailboxes.total_bytes += request.body.len();
+      mailboxes.next_seq += 1;
+      ("204 No Content", vec![], vec![])
+    }
+    "GET" => {
+      if !is_authorized(path, &key_hex, auth) {
+        return ("403 Forbidden", vec![], vec![]);
+      }
+      let mut body = Vec::new();
+      // Everything before the next sequence number, including what was already removed
+      let mut read_through = mailboxes.next_seq;
+      if let Some(mailbox) = mailboxes.queues.get(&key_hex) {
+        for (seq, _, packet) in &mailbox.packets {
+          if body.len() + packet.len() > MAX_GET_BODY {
+            read_through = *seq;
+            break;
+          }
+          body.extend_from_slice(packet);
+        }
+      }
+      let cursor = format!("{}.{}", mailboxes.run_id, read_through);
+      ("200 OK", vec![(CURSOR_HEADER, cursor)], body)
+    }
+    "DELETE" => {
+      let read_through = match is_ack_authorized(path, &key_hex, &mailboxes.run_id, auth) {
+        Some(read_through) => read_through,
+        None => return ("403 Forbidden", vec![], vec![]),
+      };
+      mailboxes.drop_front(&key_hex, |seq, _| seq >= read_through);
+      ("204 No Content", vec![], vec![])
+    }
+    _ => ("405 Method Not Allowed", vec![], vec![]),
+  }
+}
+
+/**
+ * The lowercase hex public key named by a mailbox path, if it is one.
+ */
+fn mailbox_key(path: &str) -> Option<String> {
+  if !path.starts_with(MAILBOX_PATH) {
+    return None;
+  }
+  let key_hex = path[MAILBOX_PATH.len()..].to_lowercase();
+  match from_hex(&key_hex) {
+    Some(ref key) if key.len() == PUBLIC_KEY_LEN => Some(key_hex),
+    _ => None,
+  }
+}
+
+/**
+ * Splits an X-Meili-Auth header into what was signed and the signature,
+ * checking the signature is the mailbox owner's over "<method> <path> <signed>".
+ */
+fn verify_auth<'a>(method: &str, path: &str, key_hex: &str, auth: Option<&'a String>) -> Option<&'a str> {
+  let (signed, sig_hex) = auth?.rsplit_once(':')?;
+  let sig = match from_hex(sig_hex) {
+    Some(ref sig) if sig.len() == SIGNATURE_LEN => {
+      let mut s = [0; SIGNATURE_LEN];
+      s.copy_from_slice(sig);
+      s
+    }
+    _ => return None,
+  };
+  let mut key = [0; PUBLIC_KEY_LEN];
+  key.copy_from_slice(&from_hex(key_hex).unwrap_or(vec![0; PUBLIC_KEY_LEN]));
+  if !identity::verify(&key, auth_message(method, path, signed).as_bytes(), &sig) {
+    return None;
+  }
+  Some(signed)
+}
+
+fn is_authorized(path: &str, key_hex: &str, auth: Option<&String>) -> bool {
+  let timestamp = match verify_auth("GET", path, key_hex, auth).and_then(|s| s.parse::<u64>().ok()) {
+    Some(timestamp) => timestamp,
+    None => return false,
+  };
+  let skew = if timestamp > now_s() { timestamp - now_s() } else { now_s() - timestamp };
+  skew <= MAX_AUTH_SKEW_S
+}
+
+/**
+ * The sequence number a DELETE removes packets up to, when it is signed
+ * by the mailbox owner and its cursor is from this run.
+ */
+fn is_ack_authorized(path: &str, key_hex: &str, run_id: &str, auth: Option<&String>) -> Option<u64> {
+  let cursor = verify_auth("DELETE", path, key_hex, auth)?;
+  let (cursor_run_id, read_through) = cursor.split_once('.')?;
+  if cursor_run_id != run_id {
+    return None;
+  }
+  read_through.parse::<u64>().ok()
+}
+
+fn auth_message(method: &str, path: &str, signed: &str) -> String {
+  format!("{} {} {}", method, path, signed)
+}
+
+/**
+ * The X-Meili-Auth header value identity uses to read its mailbox at path.
+ */
+pub fn auth_header(identity: &Identity, path: &str) -> String {
+  let timestamp = now_s().to_string();
+  format!("{}:{}", timestamp, to_hex(&identity.sign(auth_message("GET", path, &timestamp).as_bytes())))
+}
+
+/**
+ * The X-Meili-Auth header value identity uses to remove what a GET of
+ * its mailbox at path returned, given that GET's X-Meili-Cursor.
+ */
+pub fn ack_header(identity: &Identity, path: &str, cursor: &str) -> String {
+  format!("{}:{}", cursor, to_hex(&identity.sign(auth_message("DELETE", path, cursor).as_bytes())))
+}
+
+pub fn mailbox_path(public_key: &[u8; PUBLIC_KEY_LEN]) -> String {
+  format!("{}{}", MAILBOX_PATH, to_hex(public_key))
+}
+
+fn now_s() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::net::packet::MessageType;
+
+  fn request(method: &str, path: &str, headers: Vec<(&str, String)>, body: Vec<u8>) -> http::HttpMessage {
+    http::HttpMessage {
+      start_line: format!("{} {} HTTP/1.1", method, path),
+      headers: headers.into_iter().map(|(name, value)| (name.to_lowercase(), value)).collect(),
+      body,
+    }
+  }
+
+  fn put(mailboxes: &Mutex<Mailboxes>, path: &str, packet: &[u8]) -> &'static str {
+    respond(&request("PUT", path, vec![], packet.to_vec()), mailboxes).0
+  }
+
+  fn packet(payload_len: usize) -> Vec<u8> {
+    Packet::new(MessageType::Data, vec![0; payload_len]).encode(&Identity::generate())
+  }
+
+  #[test]
+  fn a_mailbox_holds_at_most_its_byte_cap() {
+    let mailboxes = Mutex::new(Mailboxes::new("run".to_string()));
+    let path = mailbox_path(&Identity::generate().public_key());
+    let packet = packet(NET_BUFF_SIZE / 2);
+    let fits = MAX_BYTES_PER_MAILBOX / packet.len();
+    for _ in 0..fits {
+      assert_eq!(put(&mailboxes, &path, &packet), "204 No Content");
+    }
+    assert_eq!(put(&mailboxes, &path, &packet), "429 Too Many Requests");
+    // Another mailbox still has room
+    assert_eq!(put(&mailboxes, &mailbox_path(&Identity::generate().public_key()), &packet), "204 No Content");
+    assert_eq!(mailboxes.lock().unwrap().total_bytes, (fits + 1) * packet.len());
+  }
+
+  #[test]
+  fn the_relay_holds_at_most_its_total_budget() {
+    let mailboxes = Mutex::new(Mailboxes::new("run".to_string()));
+    let packet = packet(100);
+    mailboxes.lock().unwrap().total_bytes = MAX_TOTAL_BYTES - packet.len() + 1;
+    let path = mailbox_path(&Identity::generate().public_key());
+    assert_eq!(put(&mailboxes, &path, &packet), "507 Insufficient Storage");
+    assert!(mailboxes.lock().unwrap().queues.is_empty());
+  }
+
+  #[test]
+  fn collected_and_expired_packets_free_their_bytes() {
+    let mailboxes = Mutex::new(Mailboxes::new("run".to_string()));
+    let owner = Identity::generate();
+    let path = mailbox_path(&owner.public_key());
+    let packet = packet(100);
+    assert_eq!(put(&mailboxes, &path, &packet), "204 No Content");
+    assert_eq!(put(&mailboxes, &path, &packet), "204 No Content");
+
+    let (status, headers, body) = respond(&request("GET", &path, vec![(AUTH_HEADER, auth_header(&owner, &path))], vec![]), &mailboxes);
+    assert_eq!(status, "200 OK");
+    assert_eq!(body.len(), 2 * packet.len());
+    let ack = ack_header(&owner, &path, &headers[0].1);
+    assert_eq!(respond(&request("DELETE", &path, vec![(AUTH_HEADER, ack)], vec![]), &mailboxes).0, "204 No Content");
+    {
+      let mailboxes = mailboxes.lock().unwrap();
+      assert_eq!(mailboxes.total_bytes, 0);
+      assert!(mailboxes.queues.is_empty());
+    }
+
+    // Nobody collects these
+    assert_eq!(put(&mailboxes, &path, &packet), "204 No Content");
+    let mut mailboxes = mailboxes.lock().unwrap();
+    mailboxes.expire(Instant::now() + Duration::from_secs(EXPIRE_INTERVAL_S));
+    assert_eq!(mailboxes.total_bytes, packet.len());
+    mailboxes.next_expiry = Instant::now();
+    mailboxes.expire(Instant::now() + Duration::from_secs(MAX_PACKET_AGE_S + 1));
+    assert_eq!(mailboxes.total_bytes, 0);
+    assert!(mailboxes.queues.is_empty());
+  }
+}
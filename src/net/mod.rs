@@ -1,200 +1,440 @@
 
-use igd;
-
-#[cfg(not(windows))]
-use get_if_addrs;
+use mio;
 
 use std::thread;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::io;
-use std::time::Duration;
-use std::net::{
-  UdpSocket,
-  SocketAddrV4,
-  IpAddr, Ipv4Addr,
-};
+use std::time::{Duration, Instant};
+use std::net::SocketAddr;
 
 use crate::punwrap_r;
-use crate::config::Config;
+use crate::config::{Config, ConfSocket};
 use crate::global::Global;
+use crate::identity;
+
+pub mod packet;
+pub mod dispatch;
+pub mod scan;
+pub mod scan_state;
+pub mod scan_history;
+pub mod walk;
+pub mod transport;
+pub mod http;
+pub mod http_mailbox;
+pub mod external;
+pub mod session;
+pub mod upnp;
+pub mod natpmp;
+pub mod pcp;
+pub mod rendezvous;
+pub mod stun;
+
+use dispatch::Dispatcher;
+use packet::{Packet, MessageType, Announcement};
+use transport::{Transport, PeerAddr, UdpTransport, TcpTransport, HttpMailboxTransport};
+use stun::StunProbe;
+
+pub use scan::spawn_ip_scanning;
 
 //const NET_BUFF_SIZE: usize = 65535;
-const NET_BUFF_SIZE: usize = 32535;
+pub const NET_BUFF_SIZE: usize = 32535;
+
+const WAKE_TOKEN: mio::Token = mio::Token(usize::MAX);
+const EVENTS_CAPACITY: usize = 256;
+// How often idle sessions are expired and due sessions rekeyed
+const SESSION_MAINTENANCE_INTERVAL_MS: u64 = 1000;
 
 
-pub fn spawn_ip_scanning(args: Arc<Vec<String>>, config: Arc<Config>, global: Arc<Global>) {
+pub fn spawn_listeners(args: Arc<Vec<String>>, global: Arc<Global>) {
   thread::spawn(move || {
-    run_ip_scanning(args, config, global);
+    run_listeners(args, global);
   });
 }
 
-pub fn run_ip_scanning(args: Arc<Vec<String>>, config: Arc<Config>, global: Arc<Global>) {
-  loop {
-    if global.get_scan_ips_in_background() {
-      println!("Running IP scanning");
-      
-    }
-    thread::sleep(Duration::from_millis(250));
-  }
+/**
+ * The transports the listener loop is bound to. A transport's index is
+ * its mio token and what peer_routes and STUN probes refer to it by.
+ */
+#[derive(Default)]
+struct Listeners {
+  transports: Vec<Box<dyn Transport>>,
+  // The kind of listener and socket each transport was bound for, in the same order
+  bound_to: Vec<(&'static str, SocketAddr)>,
+  // Which transports are unicast UDP listeners, and their addresses
+  unicast_udp: Vec<(usize, SocketAddr)>,
+  // Transports without sockets to register are checked whenever we wake up.
+  // If registration fails we fall back to checking them every poll_delay_ns.
+  unregistered: Vec<usize>,
+  fallback_timeout: Option<Duration>,
 }
 
-pub fn spawn_listeners(args: Arc<Vec<String>>, config: Arc<Config>, global: Arc<Global>) {
-  thread::spawn(move || {
-    run_listeners(args, config, global);
-  });
-}
+impl Listeners {
+  /**
+   * Binds what config lists that we are not bound to yet and closes what
+   * it no longer lists. Transports may be renumbered, so all are registered
+   * again and routes to closed ones are forgotten. Returns true when
+   * anything was bound or closed.
+   */
+  fn apply(&mut self, config: &Config, global: &Arc<Global>, registry: &mio::Registry, peer_routes: &mut HashMap<PeerAddr, usize>) -> bool {
+    let wanted: Vec<(&'static str, &ConfSocket)> = config.udp_sockets_to_listen_on.iter().map(|s| ("udp", s))
+      .chain(config.tcp_sockets_to_listen_on.iter().map(|s| ("tcp", s)))
+      .chain(config.http_mailboxes.iter().map(|s| ("http", s)))
+      .collect();
+    let old_transports = std::mem::take(&mut self.transports);
+    let old_bound_to = std::mem::take(&mut self.bound_to);
+    let mut changed = false;
+    // Old index -> new index of the transports we keep
+    let mut kept: HashMap<usize, usize> = HashMap::new();
+    for (i, (mut t, bound_to)) in old_transports.into_iter().zip(old_bound_to).enumerate() {
+      punwrap_r!(t.deregister(registry), nothing);
+      if wanted.iter().any(|(kind, s)| (*kind, s.socket) == bound_to) {
+        kept.insert(i, self.transports.len());
+        self.transports.push(t);
+        self.bound_to.push(bound_to);
+      } else {
+        println!("Stopped listening to {}", t.name());
+        changed = true;
+      }
+    }
+    for (kind, conf_socket) in wanted {
+      if self.bound_to.contains(&(kind, conf_socket.socket)) {
+        continue;
+      }
+      let bound = match kind {
+        "udp" => bind_transport::<UdpTransport>(conf_socket, global, &mut self.transports),
+        "tcp" => bind_transport::<TcpTransport>(conf_socket, global, &mut self.transports),
+        _ => bind_transport::<HttpMailboxTransport>(conf_socket, global, &mut self.transports),
+      };
+      if bound {
+        self.bound_to.push((kind, conf_socket.socket));
+        changed = true;
+      }
+    }
 
-pub fn run_listeners(args: Arc<Vec<String>>, config: Arc<Config>, global: Arc<Global>) {
-  // First bind to everything config tells us to bind to...
-
-  let mut udp_sockets: Vec<UdpSocket> = Vec::new();
-
-  for conf_socket in &config.udp_sockets_to_listen_on {
-    let name = conf_socket.name.clone().unwrap_or("".to_string());
-    println!("Listening to '{}' ({:?})", name, conf_socket.socket);
-    match UdpSocket::bind(&conf_socket.socket) {
-      Ok(s) => {
-        punwrap_r!(s.set_nonblocking(true), nothing);
-
-        if conf_socket.socket.ip().is_multicast() {
-          match conf_socket.socket.ip() {
-            IpAddr::V4(ip_a) => {
-              punwrap_r!(s.join_multicast_v4(&ip_a, &Ipv4Addr::new(0,0,0,0)), nothing);
-            }
-            IpAddr::V6(ip_a) => {
-              punwrap_r!(s.join_multicast_v6(&ip_a, 0), nothing);
-            }
-          }
+    self.unregistered.clear();
+    self.fallback_timeout = None;
+    for (i, t) in self.transports.iter_mut().enumerate() {
+      match t.register(registry, mio::Token(i)) {
+        Ok(true) => {}
+        Ok(false) => self.unregistered.push(i),
+        Err(e) => {
+          println!("Cannot register {}, falling back to polling every poll_delay_ns e={:?}", t.name(), e);
+          self.unregistered.push(i);
+          self.fallback_timeout = Some(Duration::from_nanos(config.poll_delay_ns as u64));
         }
+      }
+    }
+    self.unicast_udp = self.bound_to.iter().enumerate()
+      .filter(|(_, (kind, socket))| *kind == "udp" && !socket.ip().is_multicast())
+      .map(|(i, (_, socket))| (i, *socket))
+      .collect();
 
-        udp_sockets.push(s);
+    peer_routes.retain(|_, i| match kept.get(i) {
+      Some(new_i) => {
+        *i = *new_i;
+        true
       }
-      Err(e) => {
-        println!("e={:?}", e);
+      None => false,
+    });
+    // Rendezvous servers are talked to from the first unicast UDP listener
+    if let Some((i, _)) = self.unicast_udp.first().copied() {
+      for server in &config.rendezvous_servers {
+        peer_routes.insert(PeerAddr::Udp(server.socket), i);
       }
+    } else if !config.rendezvous_servers.is_empty() {
+      println!("No unicast UDP listener to reach [[rendezvous_servers]] from");
     }
+    changed
   }
+}
 
-  // We spawn this to a thread b/c attempt_upnp_setup blocks
-  let upnp_args = args.clone();
-  let upnp_config = config.clone();
-  let upnp_global = global.clone();
-  thread::spawn(move || {
-    if let Err(e) = attempt_upnp_setup(&upnp_args, &upnp_config, &upnp_global) {
-      println!("upnp e={:?}", e);
+/**
+ * Binds everything config tells us to bind to and handles packets until
+ * meili exits. A reloaded config (see config_reload) is applied when the
+ * loop wakes, which Global::set_config makes it do straight away.
+ */
+pub fn run_listeners(_args: Arc<Vec<String>>, global: Arc<Global>) {
+  let mut config = global.get_config();
+
+  let mut poll = match mio::Poll::new() {
+    Ok(poll) => poll,
+    Err(e) => {
+      println!("Cannot create listener poll, no packets will be received e={:?}", e);
+      return;
     }
-  });
+  };
+  match mio::Waker::new(poll.registry(), WAKE_TOKEN) {
+    Ok(waker) => global.set_listener_waker(Arc::new(waker)),
+    Err(e) => println!("Cannot create listener waker e={:?}", e),
+  }
 
+  let dispatcher = Dispatcher::default();
   let mut net_buf = [0; NET_BUFF_SIZE];
+  let mut events = mio::Events::with_capacity(EVENTS_CAPACITY);
+  // Which transport we last heard each peer on, used to route queued packets
+  let mut peer_routes: HashMap<PeerAddr, usize> = HashMap::new();
+  let mut listeners = Listeners::default();
+  listeners.apply(&config, &global, poll.registry(), &mut peer_routes);
+
+  upnp::spawn_upnp_manager(global.clone());
+
+  let mut next_announce = Instant::now();
+  let session_maintenance_interval = Duration::from_millis(SESSION_MAINTENANCE_INTERVAL_MS);
+  let mut next_session_maintenance = Instant::now() + session_maintenance_interval;
+  let mut next_rendezvous_register = Instant::now();
+  let mut next_stun = Instant::now();
   loop {
-    // Poll sockets for incoming packets...
-    for s in &udp_sockets {
-      match s.recv_from(&mut net_buf) {
-        Ok((num_bytes, client_sockaddr)) => {
-          // Handle the packet
-          println!("Got {} bytes from {:?}", num_bytes, client_sockaddr);
+    let latest = global.get_config();
+    if !Arc::ptr_eq(&latest, &config) {
+      let listeners_changed = listeners.apply(&latest, &global, poll.registry(), &mut peer_routes);
+      // Running probes may refer to transports by their old index
+      if listeners_changed || stun_sockets(&latest) != stun_sockets(&config) {
+        global.set_stun_probes(Vec::new());
+        next_stun = Instant::now();
+      }
+      // Peers and rendezvous servers hear of our new ports and servers of us straight away
+      next_announce = Instant::now();
+      next_rendezvous_register = Instant::now();
+      config = latest;
+    }
+    let stun_servers = stun_sockets(&config);
 
-        }
-        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-          continue;
-        }
-        Err(e) => {
-          println!("socket e={:?}", e);
-        },
+    if config.lan_announce_interval_ms > 0 && Instant::now() >= next_announce {
+      let announcement = Announcement {
+        hostname: config.hostname.clone(),
+        udp_ports: config.unicast_udp_ports(),
+        tcp_ports: config.unicast_tcp_ports(),
+        public_addr: global.get_public_address().map(|p| p.addr),
+      };
+      send_announcements(&mut listeners.transports, &announcement, &config, &global);
+      next_announce = Instant::now() + Duration::from_millis(config.lan_announce_interval_ms as u64);
+    }
+    if Instant::now() >= next_session_maintenance {
+      let rekeys = global.maintain_sessions(config.session_rekey_interval.as_duration(), config.session_idle_timeout.as_duration());
+      for (peer, init) in rekeys {
+        send_queued(&mut listeners.transports, &peer_routes, &peer, &init.encode(&global.identity));
       }
+      next_session_maintenance = Instant::now() + session_maintenance_interval;
     }
+    if !config.rendezvous_servers.is_empty() && Instant::now() >= next_rendezvous_register {
+      let register = Packet::new(MessageType::RendezvousRegister, Vec::new()).encode(&global.identity);
+      for server in &config.rendezvous_servers {
+        send_queued(&mut listeners.transports, &peer_routes, &PeerAddr::Udp(server.socket), &register);
+      }
+      next_rendezvous_register = Instant::now() + Duration::from_millis(config.rendezvous_register_interval_ms as u64);
+    }
+    maintain_punches(&mut listeners.transports, &mut peer_routes, &config, &global);
+    if !stun_servers.is_empty() && Instant::now() >= next_stun {
+      let timeout = Duration::from_millis(config.stun_timeout_ms as u64);
+      global.set_stun_probes(listeners.unicast_udp.iter()
+        .map(|(i, addr)| (*i, StunProbe::new(*addr, stun_servers.clone(), timeout)))
+        .collect());
+      next_stun = Instant::now() + config.stun_interval.as_duration();
+    }
+    maintain_stun_probes(&mut listeners.transports, &listeners.unicast_udp, &global);
+    global.save_peers_if_due();
 
-    std::thread::sleep( Duration::from_nanos(*&config.poll_delay_ns as u64) );
-  }
-}
+    let mut next_wakeup = next_session_maintenance;
+    if config.lan_announce_interval_ms > 0 {
+      next_wakeup = std::cmp::min(next_wakeup, next_announce);
+    }
+    if !config.rendezvous_servers.is_empty() {
+      next_wakeup = std::cmp::min(next_wakeup, next_rendezvous_register);
+    }
+    if let Some(next_punch) = global.get_next_punch() {
+      next_wakeup = std::cmp::min(next_wakeup, next_punch);
+    }
+    if !stun_servers.is_empty() {
+      next_wakeup = std::cmp::min(next_wakeup, next_stun);
+    }
+    if let Some(stun_wakeup) = global.get_next_stun_wakeup() {
+      next_wakeup = std::cmp::min(next_wakeup, stun_wakeup);
+    }
+    let until_wakeup = next_wakeup.saturating_duration_since(Instant::now());
+    let timeout = Some(listeners.fallback_timeout.map_or(until_wakeup, |t| std::cmp::min(t, until_wakeup)));
 
-pub fn attempt_upnp_setup(_args: &Vec<String>, config: &Config, _global: &Global) -> Result<(), igd::Error> {
-  if !config.attempt_upnp_port_forward {
-    return Ok(());
-  }
+    // Block until a socket is readable, another thread wakes us or the timeout passes
+    if let Err(e) = poll.poll(&mut events, timeout) {
+      if e.kind() != io::ErrorKind::Interrupted {
+        println!("listener poll e={:?}", e);
+      }
+      continue;
+    }
 
-  let igd_opts = igd::SearchOptions {
-    timeout: Some(Duration::from_millis(config.upnp_gw_timeout_ms as u64)),
-    ..Default::default()
-  };
-  let gw = igd::search_gateway(igd_opts)?;
-  //println!("gw={:?}", &gw);
-
-  let mut lan_ip_a: Ipv4Addr = Ipv4Addr::BROADCAST;
-  // We pick the first 
-  #[cfg(not(windows))]
-  {
-    match get_if_addrs::get_if_addrs() {
-      Ok(if_addrs) => {
-        for addr in if_addrs {
-          if addr.is_loopback() {
-            continue;
-          }
-          // igd only takes SocketAddrV4 so we cannot use ipv6 for this :(
-          if let IpAddr::V4(addr) = addr.ip() {
-            lan_ip_a = addr;
-            break;
-          }
-        }
+    let mut ready: Vec<usize> = events.iter()
+      .map(|event| event.token())
+      .filter(|token| *token != WAKE_TOKEN)
+      .map(|token| token.0)
+      .collect();
+    ready.extend(listeners.unregistered.iter());
+    ready.sort();
+    ready.dedup();
+
+    for i in ready {
+      if let Some(t) = listeners.transports.get_mut(i) {
+        receive_all(i, t, &mut net_buf, &mut peer_routes, &dispatcher, &config, &global);
       }
-      Err(e) => {
-        println!("e={:?}", e);
-        // Not exactly the most appropriate error but we'll use it
-        return Err(igd::Error::GetExternalIpError( igd::GetExternalIpError::ActionNotAuthorized ));
+    }
+
+    for (peer, packet) in global.take_outgoing() {
+      let is_data = packet.msg_type == MessageType::Data;
+      let packet = if packet.msg_type.requires_session() {
+        match global.seal_outgoing(&peer, packet) {
+          Some(packet) => packet,
+          None => continue, // Waiting for a session
+        }
+      } else {
+        packet
+      };
+      let buf = packet.encode(&global.identity);
+      // Without a session the Data waits, and what goes out is the SessionInit
+      if is_data && packet.msg_type == MessageType::Sealed {
+        send_data(&mut listeners.transports, &peer_routes, &peer, &buf, &global);
+      } else {
+        send_queued(&mut listeners.transports, &peer_routes, &peer, &buf);
       }
     }
   }
-  #[cfg(windows)]
-  {
-    // TODO windows IP addr lookup
-  }
-  println!("lan_ip_a={:?}", &lan_ip_a);
+}
 
-  let local_addr = SocketAddrV4::new(lan_ip_a.clone(), config.upnp_local_port as u16);
-  let mut external_port = config.upnp_pref_public_port as u16;
+fn stun_sockets(config: &Config) -> Vec<SocketAddr> {
+  config.stun_servers.iter().map(|s| s.socket).collect()
+}
 
-  // First search existing ports + exit if one has our lan_ip_a and config.upnp_local_port and config.upnp_pref_public_port
-  let mut i = 0;
+/**
+ * Readiness is edge triggered, so a ready transport must be read until it would block.
+ */
+fn receive_all(i: usize, t: &mut Box<dyn Transport>, net_buf: &mut [u8], peer_routes: &mut HashMap<PeerAddr, usize>, dispatcher: &Dispatcher, config: &Arc<Config>, global: &Arc<Global>) {
   loop {
-    match gw.get_generic_port_mapping_entry(i) {
-      Ok(entry) => {
-        println!(
-          "i={} external_port={} internal_client={} port_mapping_description={} lease_duration={}",
-          i, entry.external_port, entry.internal_client, entry.port_mapping_description, entry.lease_duration
-        );
-        let is_match = entry.port_mapping_description.contains("meili") &&
-                       entry.internal_client == format!("{}", lan_ip_a) &&
-                       entry.internal_port == config.upnp_local_port as u16;
-        if is_match {
-          // return b/c we already have an entry; TODO Write external_port to global?
-          println!("Already have requested UPNP port mapping on public port :{}", entry.external_port);
-          return Ok(());
-        }
+    match t.recv_from(net_buf) {
+      // Answers to our STUN probes share the socket with meili packets
+      Ok((num_bytes, _)) if stun::is_stun_message(&net_buf[..num_bytes]) => {
+        global.handle_stun_message(&net_buf[..num_bytes]);
       }
-      Err(igd::GetGenericPortMappingEntryError::RequestError(re))  => {
-        println!("re={:?}", re);
-        continue;
+      Ok((num_bytes, peer)) => {
+        peer_routes.insert(peer.clone(), i);
+        // Handle the packet
+        if let Some(reply) = dispatcher.dispatch(&net_buf[..num_bytes], &peer, config, global) {
+          punwrap_r!(t.send_to(&reply, &peer), nothing);
+        }
       }
-      Err(_e)  => {
+      Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
         break;
       }
+      Err(e) => {
+        println!("{} e={:?}", t.name(), e);
+        break;
+      },
     }
-    if i > 256 {
-      break; // Sanity check
+  }
+}
+
+/**
+ * Sends over the transport peer was last heard on,
+ * falling back to the first transport able to reach it.
+ */
+fn send_queued(transports: &mut Vec<Box<dyn Transport>>, peer_routes: &HashMap<PeerAddr, usize>, peer: &PeerAddr, buf: &[u8]) {
+  send_routed(transports, peer_routes, peer, |t| t.send_to(buf, peer));
+}
+
+/**
+ * Sends a sealed Data packet like send_queued, counting it once it is written out.
+ */
+fn send_data(transports: &mut Vec<Box<dyn Transport>>, peer_routes: &HashMap<PeerAddr, usize>, peer: &PeerAddr, buf: &[u8], global: &Global) {
+  send_routed(transports, peer_routes, peer, |t| t.send_counted(buf, peer, global));
+}
+
+fn send_routed(transports: &mut Vec<Box<dyn Transport>>, peer_routes: &HashMap<PeerAddr, usize>, peer: &PeerAddr, send: impl Fn(&mut Box<dyn Transport>) -> io::Result<()>) {
+  // Relayed packets leave through the listener the rendezvous server knows us by
+  let route = match peer {
+    PeerAddr::Relay(server, _) => peer_routes.get(peer).or_else(|| peer_routes.get(&PeerAddr::Udp(*server))),
+    _ => peer_routes.get(peer),
+  };
+  if let Some(i) = route {
+    if let Some(t) = transports.get_mut(*i) {
+      punwrap_r!(send(t), nothing);
+      return;
+    }
+  }
+  for t in transports.iter_mut() {
+    if send(t).is_ok() {
+      return;
     }
-    i += 1;
   }
+  println!("No transport can reach {}", peer);
+}
+
+/**
+ * Sends the punches which are due, from the listener the introducing
+ * rendezvous knows us by as that is the NAT mapping the peer was told of.
+ * Peers we could not punch through to are reached through the rendezvous.
+ */
+fn maintain_punches(transports: &mut Vec<Box<dyn Transport>>, peer_routes: &mut HashMap<PeerAddr, usize>, config: &Config, global: &Global) {
+  let (due, failed) = global.maintain_punches(Duration::from_millis(config.hole_punch_timeout_ms as u64));
+  if !due.is_empty() {
+    let punch = Packet::new(MessageType::Punch, Vec::new()).encode(&global.identity);
+    for (addr, server) in due {
+      let peer = PeerAddr::Udp(addr);
+      if let Some(i) = peer_routes.get(&PeerAddr::Udp(server)).copied() {
+        peer_routes.insert(peer.clone(), i);
+      }
+      send_queued(transports, peer_routes, &peer, &punch);
+    }
+  }
+  for f in failed {
+    println!("Could not punch through to {} at {}, relaying through {}", identity::fingerprint_of(&f.peer), f.addr, f.server);
+    global.record_discovered_peer(f.peer, PeerAddr::Relay(f.server, f.peer), "relay", None);
+  }
+}
 
-  let lease_duration_s = 300;
-  if let Err(e) = gw.add_port(igd::PortMappingProtocol::UDP, external_port, local_addr, lease_duration_s, "meili port mapping") {
-    println!("upnp e={:?}", e);
-    // Attempt w/ random public port
-    external_port = gw.add_any_port(igd::PortMappingProtocol::UDP, local_addr, lease_duration_s, "meili port mapping")?;
+/**
+ * Sends the STUN requests which are due and publishes finished probes.
+ * Our public address is only taken from the first listener, the one
+ * LAN announcements advertise, and only when peers can reach it unasked.
+ */
+fn maintain_stun_probes(transports: &mut Vec<Box<dyn Transport>>, unicast_udp: &[(usize, SocketAddr)], global: &Global) {
+  for (i, server, request) in global.poll_stun_probes(Instant::now()) {
+    if let Some(t) = transports.get_mut(i) {
+      punwrap_r!(t.send_to(&request, &PeerAddr::Udp(server)), nothing);
+    }
+  }
+  for (i, result) in global.take_finished_stun_probes() {
+    match result.mapped {
+      Some(mapped) => println!("STUN: {} is seen as {}, NAT type {}", result.listener, mapped, result.nat_type),
+      None => println!("STUN: {} got no answer, NAT type {}", result.listener, result.nat_type),
+    }
+    if unicast_udp.first().map(|(first, _)| *first) == Some(i) {
+      let reachable = result.mapped.filter(|_| result.nat_type.is_reachable());
+      global.offer_public_address(reachable, "stun");
+    }
+    global.set_stun_result(result);
   }
-  
-  // Write external_port to global? Not sure what the most useful action is here.
-  println!("Added requested UPNP port mapping on public port :{}", external_port);
+}
 
-  Ok(())
+/**
+ * Sends our LAN beacon to every multicast group we listen on.
+ */
+fn send_announcements(transports: &mut Vec<Box<dyn Transport>>, announcement: &Announcement, config: &Config, global: &Global) {
+  let buf = Packet::new(MessageType::Announce, announcement.encode()).encode(&global.identity);
+  for t in transports.iter_mut() {
+    if let Err(e) = t.announce(&buf, config.lan_announce_ttl) {
+      println!("Cannot announce on {} e={:?}", t.name(), e);
+    }
+  }
 }
 
+fn bind_transport<T: Transport + 'static>(conf_socket: &ConfSocket, global: &Arc<Global>, transports: &mut Vec<Box<dyn Transport>>) -> bool {
+  let name = conf_socket.name.clone().unwrap_or("".to_string());
+  match T::bind(conf_socket, global) {
+    Ok(t) => {
+      println!("Listening to '{}' ({})", name, t.name());
+      transports.push(Box::new(t));
+      true
+    }
+    Err(e) => {
+      println!("Cannot listen to '{}' ({:?}) e={:?}", name, conf_socket.socket, e);
+      false
+    }
+  }
+}
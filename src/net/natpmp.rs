@@ -0,0 +1,265 @@
+
+/*!
+ * A NAT-PMP (RFC 6886) client for routers which map ports with it
+ * rather than UPnP IGD. Each request is one UDP datagram to port 5351
+ * of the default gateway, resent with a doubling timeout until answered.
+ * PCP, its successor, is spoken on the same port, see net::pcp.
+ */
+
+use igd::PortMappingProtocol;
+
+use std::io;
+use std::net::{UdpSocket, SocketAddr, SocketAddrV4, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+pub const PORT: u16 = 5351;
+
+const VERSION: u8 = 0;
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+const OP_MAP_TCP: u8 = 2;
+// Responses echo the request's opcode with the top bit set
+const OP_RESPONSE: u8 = 128;
+// RFC 6886 3.1: the first resend is after 250ms, doubling after that
+const FIRST_RESEND_MS: u64 = 250;
+
+/**
+ * A mapping as the gateway granted it, which may differ from what we asked for.
+ */
+#[derive(Debug, Clone)]
+pub struct Mapped {
+  pub external_port: u16,
+  pub lifetime_s: u32,
+  // PCP answers with it, NAT-PMP needs a separate request
+  pub external_ip: Option<Ipv4Addr>,
+}
+
+/**
+ * Opens a UDP socket from local_ip to the gateway. When local_ip is
+ * unspecified the socket gets the address of the interface the
+ * gateway is reached through.
+ */
+pub fn connect(local_ip: Ipv4Addr, gateway: SocketAddrV4) -> Result<UdpSocket, String> {
+  let socket = UdpSocket::bind(SocketAddrV4::new(local_ip, 0)).map_err(|e| format!("Cannot bind {}: {}", local_ip, e))?;
+  socket.connect(gateway).map_err(|e| format!("Cannot reach {}: {}", gateway, e))?;
+  Ok(socket)
+}
+
+pub fn local_ip(socket: &UdpSocket) -> Result<Ipv4Addr, String> {
+  match socket.local_addr() {
+    Ok(SocketAddr::V4(addr)) => Ok(*addr.ip()),
+    Ok(addr) => Err(format!("{} is not an IPv4 address", addr)),
+    Err(e) => Err(e.to_string()),
+  }
+}
+
+/**
+ * Sends request until a datagram for which is_reply is true arrives
+ * or timeout passes. Used by both NAT-PMP and PCP.
+ */
+pub fn exchange(socket: &UdpSocket, request: &[u8], timeout: Duration, is_reply: impl Fn(&[u8]) -> bool) -> Result<Vec<u8>, String> {
+  let deadline = Instant::now() + timeout;
+  let mut resend_wait = Duration::from_millis(FIRST_RESEND_MS);
+  // PCP responses are at most 1100 bytes
+  let mut buf = [0u8; 1100];
+  loop {
+    socket.send(request).map_err(|e| e.to_string())?;
+    let resend_at = std::cmp::min(Instant::now() + resend_wait, deadline);
+    loop {
+      let now = Instant::now();
+      if now >= resend_at {
+        break;
+      }
+      socket.set_read_timeout(Some(resend_at - now)).map_err(|e| e.to_string())?;
+      match socket.recv(&mut buf) {
+        Ok(n) if is_reply(&buf[..n]) => return Ok(buf[..n].to_vec()),
+        Ok(_) => continue,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+        // eg connection refused when nothing listens on the gateway's port
+        Err(e) => return Err(e.to_string()),
+      }
+    }
+    if Instant::now() >= deadline {
+      return Err(format!("No answer within {}ms", timeout.as_millis()));
+    }
+    resend_wait *= 2;
+  }
+}
+
+fn map_opcode(protocol: PortMappingProtocol) -> u8 {
+  match protocol {
+    PortMappingProtocol::UDP => OP_MAP_UDP,
+    PortMappingProtocol::TCP => OP_MAP_TCP,
+  }
+}
+
+fn result_message(code: u16) -> String {
+  match code {
+    1 => "unsupported version".to_string(),
+    2 => "not authorized or refused".to_string(),
+    3 => "network failure".to_string(),
+    4 => "out of resources".to_string(),
+    5 => "unsupported opcode".to_string(),
+    n => format!("result code {}", n),
+  }
+}
+
+/**
+ * Sends a NAT-PMP request and checks the version and result code of the response.
+ */
+fn request(socket: &UdpSocket, req: &[u8], response_len: usize, timeout: Duration) -> Result<Vec<u8>, String> {
+  let op = req[1] | OP_RESPONSE;
+  // A PCP-only gateway answers in its own version, which we also take as a reply
+  let response = exchange(socket, req, timeout, |b| b.len() >= 4 && (b[0] != VERSION || b[1] == op))?;
+  if response[0] != VERSION {
+    return Err(format!("Gateway speaks version {} rather than NAT-PMP", response[0]));
+  }
+  let result = u16::from_be_bytes([response[2], response[3]]);
+  if result != 0 {
+    return Err(format!("Gateway answered {}", result_message(result)));
+  }
+  if response.len() < response_len {
+    return Err(format!("Gateway answered with a {} byte response", response.len()));
+  }
+  Ok(response)
+}
+
+pub fn get_external_ip(gateway: SocketAddrV4, timeout: Duration) -> Result<Ipv4Addr, String> {
+  let socket = connect(Ipv4Addr::UNSPECIFIED, gateway)?;
+  let response = request(&socket, &[VERSION, OP_EXTERNAL_ADDRESS], 12, timeout)?;
+  Ok(Ipv4Addr::new(response[8], response[9], response[10], response[11]))
+}
+
+/**
+ * Asks the gateway to forward external_port to local_addr. The gateway
+ * maps to the address the request comes from, so it is sent from there.
+ * A lifetime_s of 0 deletes the mapping instead.
+ */
+pub fn add_port(gateway: SocketAddrV4, protocol: PortMappingProtocol, local_addr: SocketAddrV4, external_port: u16, lifetime_s: u32, timeout: Duration) -> Result<Mapped, String> {
+  let socket = connect(*local_addr.ip(), gateway)?;
+  let mut req = vec![VERSION, map_opcode(protocol), 0, 0];
+  req.extend_from_slice(&local_addr.port().to_be_bytes());
+  req.extend_from_slice(&external_port.to_be_bytes());
+  req.extend_from_slice(&lifetime_s.to_be_bytes());
+  let response = request(&socket, &req, 16, timeout)?;
+  Ok(Mapped {
+    external_port: u16::from_be_bytes([response[10], response[11]]),
+    lifetime_s: u32::from_be_bytes([response[12], response[13], response[14], response[15]]),
+    external_ip: None,
+  })
+}
+
+pub fn remove_port(gateway: SocketAddrV4, protocol: PortMappingProtocol, local_addr: SocketAddrV4, timeout: Duration) -> Result<(), String> {
+  // RFC 6886 3.4: deletions ask for external port 0 and lifetime 0
+  add_port(gateway, protocol, local_addr, 0, 0, timeout).map(|_| ())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+
+  use std::thread;
+
+  const TIMEOUT: Duration = Duration::from_secs(2);
+
+  /**
+   * A gateway on loopback which answers each datagram with what answer
+   * returns for it, or nothing when that is empty. PCP's tests use it too.
+   */
+  pub(crate) fn fake_gateway(answer: impl Fn(&[u8]) -> Vec<u8> + Send + 'static) -> SocketAddrV4 {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = match socket.local_addr().unwrap() {
+      SocketAddr::V4(addr) => addr,
+      addr => panic!("{} is not IPv4", addr),
+    };
+    thread::spawn(move || {
+      let mut buf = [0u8; 1100];
+      while let Ok((n, from)) = socket.recv_from(&mut buf) {
+        let reply = answer(&buf[..n]);
+        if !reply.is_empty() {
+          let _ = socket.send_to(&reply, from);
+        }
+      }
+    });
+    addr
+  }
+
+  #[test]
+  fn external_address() {
+    let gateway = fake_gateway(|req| {
+      assert_eq!(req, &[VERSION, OP_EXTERNAL_ADDRESS]);
+      vec![VERSION, OP_RESPONSE, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7]
+    });
+    assert_eq!(get_external_ip(gateway, TIMEOUT), Ok(Ipv4Addr::new(203, 0, 113, 7)));
+  }
+
+  #[test]
+  fn map_udp() {
+    let gateway = fake_gateway(|req| {
+      assert_eq!(req, &[VERSION, OP_MAP_UDP, 0, 0, 0x05, 0x39, 0x05, 0x39, 0, 0, 0x0e, 0x10]);
+      // Granted another external port, for half the lifetime
+      let mut res = vec![VERSION, OP_MAP_UDP | OP_RESPONSE, 0, 0, 0, 0, 0, 1];
+      res.extend_from_slice(&[0x05, 0x39, 0x9c, 0x40, 0, 0, 0x07, 0x08]);
+      res
+    });
+    let mapped = add_port(gateway, PortMappingProtocol::UDP, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1337), 1337, 3600, TIMEOUT).unwrap();
+    assert_eq!(mapped.external_port, 40000);
+    assert_eq!(mapped.lifetime_s, 1800);
+    assert_eq!(mapped.external_ip, None);
+  }
+
+  #[test]
+  fn remove_asks_for_port_and_lifetime_zero() {
+    let gateway = fake_gateway(|req| {
+      assert_eq!(&req[..2], &[VERSION, OP_MAP_TCP]);
+      assert_eq!(&req[6..], &[0, 0, 0, 0, 0, 0]);
+      let mut res = vec![VERSION, OP_MAP_TCP | OP_RESPONSE, 0, 0, 0, 0, 0, 1];
+      res.extend_from_slice(&req[4..6]);
+      res.extend_from_slice(&[0; 6]);
+      res
+    });
+    assert_eq!(remove_port(gateway, PortMappingProtocol::TCP, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1337), TIMEOUT), Ok(()));
+  }
+
+  #[test]
+  fn result_code() {
+    let gateway = fake_gateway(|req| vec![VERSION, req[1] | OP_RESPONSE, 0, 2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let err = add_port(gateway, PortMappingProtocol::UDP, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1337), 1337, 3600, TIMEOUT).unwrap_err();
+    assert_eq!(err, "Gateway answered not authorized or refused");
+    let err = get_external_ip(gateway, TIMEOUT).unwrap_err();
+    assert_eq!(err, "Gateway answered not authorized or refused");
+  }
+
+  #[test]
+  fn pcp_only_gateway() {
+    // RFC 6887 9: a PCP server answers older versions with UNSUPP_VERSION in its own
+    let gateway = fake_gateway(|req| vec![2, req[1] | OP_RESPONSE, 0, 1, 0, 0, 0, 0]);
+    assert_eq!(get_external_ip(gateway, TIMEOUT).unwrap_err(), "Gateway speaks version 2 rather than NAT-PMP");
+  }
+
+  #[test]
+  fn short_response() {
+    let gateway = fake_gateway(|req| vec![VERSION, req[1] | OP_RESPONSE, 0, 0, 0, 0, 0, 1]);
+    assert_eq!(get_external_ip(gateway, TIMEOUT).unwrap_err(), "Gateway answered with a 8 byte response");
+  }
+
+  #[test]
+  fn resends_until_answered() {
+    let seen = std::sync::atomic::AtomicUsize::new(0);
+    let gateway = fake_gateway(move |_| {
+      // The first request is lost
+      if seen.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+        return vec![];
+      }
+      vec![VERSION, OP_RESPONSE, 0, 0, 0, 0, 0, 1, 198, 51, 100, 1]
+    });
+    assert_eq!(get_external_ip(gateway, TIMEOUT), Ok(Ipv4Addr::new(198, 51, 100, 1)));
+  }
+
+  #[test]
+  fn no_answer() {
+    let gateway = fake_gateway(|_| vec![]);
+    let err = get_external_ip(gateway, Duration::from_millis(300)).unwrap_err();
+    assert_eq!(err, "No answer within 300ms");
+  }
+}
@@ -0,0 +1,441 @@
+
+/*!
+ * The packet module defines meili's binary wire format.
+ * Every packet is laid out as:
+ *
+ *   magic       4 bytes  b"MEIL"
+ *   version     1 byte   PROTOCOL_VERSION
+ *   msg_type    1 byte   see MessageType
+ *   sender_id  32 bytes  Ed25519 public key of the sending node
+ *   payload_len 4 bytes  big-endian u32
+ *   payload     payload_len bytes
+ *   signature  64 bytes  Ed25519 signature over every preceding byte
+ *
+ * Anything which does not match this exactly is rejected by decode()
+ * with a DecodeError describing why.
+ */
+
+use std::fmt;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::identity::{self, Identity, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+
+pub const MAGIC: [u8; 4] = *b"MEIL";
+pub const PROTOCOL_VERSION: u8 = 2;
+pub const SENDER_ID_LEN: usize = PUBLIC_KEY_LEN;
+pub const HEADER_LEN: usize = 4 + 1 + 1 + SENDER_ID_LEN + 4;
+pub const MAX_CHANNEL_LEN: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageType {
+  Hello,
+  HelloAck,
+  Data,
+  Announce,
+  SessionInit,
+  SessionAccept,
+  Sealed,
+  VerifyConfirm,
+  RendezvousRegister,
+  RendezvousRegistered,
+  IntroduceRequest,
+  Introduce,
+  Punch,
+  PunchAck,
+  Relay,
+  Relayed,
+}
+
+impl MessageType {
+  pub fn to_u8(self) -> u8 {
+    match self {
+      MessageType::Hello => 1,
+      MessageType::HelloAck => 2,
+      MessageType::Data => 3,
+      MessageType::Announce => 4,
+      MessageType::SessionInit => 5,
+      MessageType::SessionAccept => 6,
+      MessageType::Sealed => 7,
+      MessageType::VerifyConfirm => 8,
+      MessageType::RendezvousRegister => 9,
+      MessageType::RendezvousRegistered => 10,
+      MessageType::IntroduceRequest => 11,
+      MessageType::Introduce => 12,
+      MessageType::Punch => 13,
+      MessageType::PunchAck => 14,
+      MessageType::Relay => 15,
+      MessageType::Relayed => 16,
+    }
+  }
+  pub fn from_u8(val: u8) -> Option<MessageType> {
+    match val {
+      1 => Some(MessageType::Hello),
+      2 => Some(MessageType::HelloAck),
+      3 => Some(MessageType::Data),
+      4 => Some(MessageType::Announce),
+      5 => Some(MessageType::SessionInit),
+      6 => Some(MessageType::SessionAccept),
+      7 => Some(MessageType::Sealed),
+      8 => Some(MessageType::VerifyConfirm),
+      9 => Some(MessageType::RendezvousRegister),
+      10 => Some(MessageType::RendezvousRegistered),
+      11 => Some(MessageType::IntroduceRequest),
+      12 => Some(MessageType::Introduce),
+      13 => Some(MessageType::Punch),
+      14 => Some(MessageType::PunchAck),
+      15 => Some(MessageType::Relay),
+      16 => Some(MessageType::Relayed),
+      _ => None,
+    }
+  }
+  /**
+   * The name used for this type in meili.toml, eg [[message_handlers]].
+   */
+  pub fn name(&self) -> &'static str {
+    match self {
+      MessageType::Hello => "hello",
+      MessageType::HelloAck => "hello_ack",
+      MessageType::Data => "data",
+      MessageType::Announce => "announce",
+      MessageType::SessionInit => "session_init",
+      MessageType::SessionAccept => "session_accept",
+      MessageType::Sealed => "sealed",
+      MessageType::VerifyConfirm => "verify_confirm",
+      MessageType::RendezvousRegister => "rendezvous_register",
+      MessageType::RendezvousRegistered => "rendezvous_registered",
+      MessageType::IntroduceRequest => "introduce_request",
+      MessageType::Introduce => "introduce",
+      MessageType::Punch => "punch",
+      MessageType::PunchAck => "punch_ack",
+      MessageType::Relay => "relay",
+      MessageType::Relayed => "relayed",
+    }
+  }
+  /**
+   * Types which are only sent encrypted, wrapped in a Sealed packet
+   * (see net::session). They are rejected when received in the clear.
+   */
+  pub fn requires_session(&self) -> bool {
+    matches!(self, MessageType::Data | MessageType::VerifyConfirm)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+  pub msg_type: MessageType,
+  pub sender_id: [u8; SENDER_ID_LEN],
+  pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+  TooShort(usize),
+  BadMagic,
+  UnsupportedVersion(u8),
+  UnknownMessageType(u8),
+  LengthMismatch { declared: usize, actual: usize },
+  BadSignature,
+}
+
+impl DecodeError {
+  /**
+   * A short, stable name for the error used to count rejected packets.
+   */
+  pub fn reason(&self) -> &'static str {
+    match self {
+      DecodeError::TooShort(_) => "too_short",
+      DecodeError::BadMagic => "bad_magic",
+      DecodeError::UnsupportedVersion(_) => "unsupported_version",
+      DecodeError::UnknownMessageType(_) => "unknown_message_type",
+      DecodeError::LengthMismatch { .. } => "length_mismatch",
+      DecodeError::BadSignature => "bad_signature",
+    }
+  }
+}
+
+impl fmt::Display for DecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DecodeError::TooShort(len) => write!(f, "packet of {} bytes is shorter than the {} byte header and signature", len, HEADER_LEN + SIGNATURE_LEN),
+      DecodeError::BadMagic => write!(f, "packet does not begin with meili magic bytes"),
+      DecodeError::UnsupportedVersion(v) => write!(f, "protocol version {} is not supported (we speak {})", v, PROTOCOL_VERSION),
+      DecodeError::UnknownMessageType(t) => write!(f, "unknown message type {}", t),
+      DecodeError::LengthMismatch { declared, actual } => write!(f, "header declares {} payload bytes but {} were received", declared, actual),
+      DecodeError::BadSignature => write!(f, "signature does not match sender_id"),
+    }
+  }
+}
+
+impl Packet {
+  pub fn new(msg_type: MessageType, payload: Vec<u8>) -> Packet {
+    Packet {
+      msg_type,
+      sender_id: [0; SENDER_ID_LEN],
+      payload,
+    }
+  }
+
+  /**
+   * Encodes the packet as sent by identity, overwriting sender_id
+   * with identity's public key and appending a signature.
+   */
+  pub fn encode(&self, identity: &Identity) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len() + SIGNATURE_LEN);
+    buf.extend_from_slice(&MAGIC);
+    buf.push(PROTOCOL_VERSION);
+    buf.push(self.msg_type.to_u8());
+    buf.extend_from_slice(&identity.public_key());
+    buf.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&self.payload);
+    let signature = identity.sign(&buf);
+    buf.extend_from_slice(&signature);
+    buf
+  }
+
+  pub fn decode(buf: &[u8]) -> Result<Packet, DecodeError> {
+    if buf.len() < HEADER_LEN + SIGNATURE_LEN {
+      return Err(DecodeError::TooShort(buf.len()));
+    }
+    if buf[0..4] != MAGIC {
+      return Err(DecodeError::BadMagic);
+    }
+    if buf[4] != PROTOCOL_VERSION {
+      return Err(DecodeError::UnsupportedVersion(buf[4]));
+    }
+    let msg_type = match MessageType::from_u8(buf[5]) {
+      Some(t) => t,
+      None => return Err(DecodeError::UnknownMessageType(buf[5])),
+    };
+    let mut sender_id = [0; SENDER_ID_LEN];
+    sender_id.copy_from_slice(&buf[6..6+SENDER_ID_LEN]);
+
+    let mut len_bytes = [0; 4];
+    len_bytes.copy_from_slice(&buf[HEADER_LEN-4..HEADER_LEN]);
+    let declared = u32::from_be_bytes(len_bytes) as usize;
+    let actual = buf.len() - HEADER_LEN - SIGNATURE_LEN;
+    if declared != actual {
+      return Err(DecodeError::LengthMismatch { declared, actual });
+    }
+
+    let signed_len = HEADER_LEN + declared;
+    let mut signature = [0; SIGNATURE_LEN];
+    signature.copy_from_slice(&buf[signed_len..]);
+    if !identity::verify(&sender_id, &buf[..signed_len], &signature) {
+      return Err(DecodeError::BadSignature);
+    }
+
+    Ok(Packet {
+      msg_type,
+      sender_id,
+      payload: buf[HEADER_LEN..signed_len].to_vec(),
+    })
+  }
+}
+
+/**
+ * Stream transports have no datagram boundaries, so they read the
+ * header to learn how long the packet at the front of buf will be.
+ * Returns None until the whole header has arrived.
+ */
+pub fn frame_len(buf: &[u8]) -> Result<Option<usize>, DecodeError> {
+  if buf.len() < HEADER_LEN {
+    return Ok(None);
+  }
+  if buf[0..4] != MAGIC {
+    return Err(DecodeError::BadMagic);
+  }
+  let mut len_bytes = [0; 4];
+  len_bytes.copy_from_slice(&buf[HEADER_LEN-4..HEADER_LEN]);
+  Ok(Some(HEADER_LEN + u32::from_be_bytes(len_bytes) as usize + SIGNATURE_LEN))
+}
+
+/**
+ * Reads the sender_id of an encoded packet without verifying it,
+ * for transports which need to know who to reply to.
+ */
+pub fn peek_sender_id(buf: &[u8]) -> Option<[u8; SENDER_ID_LEN]> {
+  if buf.len() < HEADER_LEN {
+    return None;
+  }
+  let mut sender_id = [0; SENDER_ID_LEN];
+  sender_id.copy_from_slice(&buf[6..6+SENDER_ID_LEN]);
+  Some(sender_id)
+}
+
+/**
+ * Where the payload of an encoded packet of msg_type lies in buf, without
+ * verifying it, for transports which unwrap packets carried inside others.
+ */
+pub fn peek_payload(buf: &[u8], msg_type: MessageType) -> Option<std::ops::Range<usize>> {
+  if buf.len() < HEADER_LEN + SIGNATURE_LEN || buf[4] != PROTOCOL_VERSION || buf[5] != msg_type.to_u8() {
+    return None;
+  }
+  match frame_len(buf) {
+    Ok(Some(len)) if len == buf.len() => Some(HEADER_LEN..len - SIGNATURE_LEN),
+    _ => None,
+  }
+}
+
+/**
+ * Data payloads begin with the name of the channel they were sent on:
+ *
+ *   channel_len 1 byte
+ *   channel     channel_len bytes of utf8
+ *   body        the rest of the payload
+ */
+pub fn encode_data_payload(channel: &str, body: &[u8]) -> Vec<u8> {
+  // Longer channels are cut at the last whole character which fits
+  let mut channel_len = std::cmp::min(channel.len(), MAX_CHANNEL_LEN);
+  while !channel.is_char_boundary(channel_len) {
+    channel_len -= 1;
+  }
+  let channel = &channel.as_bytes()[..channel_len];
+  let mut payload = Vec::with_capacity(1 + channel.len() + body.len());
+  payload.push(channel.len() as u8);
+  payload.extend_from_slice(channel);
+  payload.extend_from_slice(body);
+  payload
+}
+
+pub fn decode_data_payload(payload: &[u8]) -> Option<(String, &[u8])> {
+  let channel_len = *payload.first()? as usize;
+  if payload.len() < 1 + channel_len {
+    return None;
+  }
+  let channel = String::from_utf8(payload[1..1+channel_len].to_vec()).ok()?;
+  Some((channel, &payload[1+channel_len..]))
+}
+
+/**
+ * Announce payloads are the LAN beacons sent to multicast groups,
+ * telling listeners how to reach the sender directly:
+ *
+ *   hostname_len 1 byte
+ *   hostname     hostname_len bytes of utf8
+ *   port_count   1 byte
+ *   ports        port_count entries of
+ *                  protocol 1 byte, 1 = udp or 2 = tcp
+ *                  port     2 bytes big-endian
+ *   public_addr  optional, see encode_socket_addr
+ *
+ * public_addr is where the sender can be reached from outside the LAN,
+ * eg the external address of its UPnP port mapping.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+  pub hostname: String,
+  pub udp_ports: Vec<u16>,
+  pub tcp_ports: Vec<u16>,
+  pub public_addr: Option<SocketAddr>,
+}
+
+const ANNOUNCE_UDP: u8 = 1;
+const ANNOUNCE_TCP: u8 = 2;
+
+impl Announcement {
+  pub fn encode(&self) -> Vec<u8> {
+    let mut hostname_len = std::cmp::min(self.hostname.len(), 255);
+    while !self.hostname.is_char_boundary(hostname_len) {
+      hostname_len -= 1;
+    }
+    let ports: Vec<(u8, u16)> = self.udp_ports.iter().map(|p| (ANNOUNCE_UDP, *p))
+      .chain(self.tcp_ports.iter().map(|p| (ANNOUNCE_TCP, *p)))
+      .take(255)
+      .collect();
+
+    let mut payload = Vec::with_capacity(2 + hostname_len + 3 * ports.len());
+    payload.push(hostname_len as u8);
+    payload.extend_from_slice(&self.hostname.as_bytes()[..hostname_len]);
+    payload.push(ports.len() as u8);
+    for (protocol, port) in ports {
+      payload.push(protocol);
+      payload.extend_from_slice(&port.to_be_bytes());
+    }
+    if let Some(public_addr) = &self.public_addr {
+      encode_socket_addr(public_addr, &mut payload);
+    }
+    payload
+  }
+
+  pub fn decode(payload: &[u8]) -> Option<Announcement> {
+    let hostname_len = *payload.first()? as usize;
+    let hostname = String::from_utf8(payload.get(1..1+hostname_len)?.to_vec()).ok()?;
+    let mut rest = &payload[1+hostname_len..];
+    let port_count = *rest.first()? as usize;
+    rest = &rest[1..];
+    let entries = rest.get(..3 * port_count)?;
+    rest = &rest[3 * port_count..];
+    let public_addr = match rest.len() {
+      0 => None,
+      _ => {
+        let (addr, used) = decode_socket_addr(rest)?;
+        if used != rest.len() {
+          return None;
+        }
+        Some(addr)
+      }
+    };
+    let mut announcement = Announcement {
+      hostname,
+      udp_ports: Vec::new(),
+      tcp_ports: Vec::new(),
+      public_addr,
+    };
+    for entry in entries.chunks(3) {
+      let port = u16::from_be_bytes([entry[1], entry[2]]);
+      match entry[0] {
+        ANNOUNCE_UDP => announcement.udp_ports.push(port),
+        ANNOUNCE_TCP => announcement.tcp_ports.push(port),
+        _ => {} // Protocols added later
+      }
+    }
+    Some(announcement)
+  }
+}
+
+const ADDR_V4: u8 = 4;
+const ADDR_V6: u8 = 6;
+
+/**
+ * Socket addresses inside payloads are written as:
+ *
+ *   family 1 byte, 4 or 6
+ *   ip     4 or 16 bytes
+ *   port   2 bytes big-endian
+ */
+pub fn encode_socket_addr(addr: &SocketAddr, buf: &mut Vec<u8>) {
+  match addr.ip() {
+    IpAddr::V4(ip) => {
+      buf.push(ADDR_V4);
+      buf.extend_from_slice(&ip.octets());
+    }
+    IpAddr::V6(ip) => {
+      buf.push(ADDR_V6);
+      buf.extend_from_slice(&ip.octets());
+    }
+  }
+  buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/**
+ * Returns the address at the front of buf and how many bytes it took up.
+ */
+pub fn decode_socket_addr(buf: &[u8]) -> Option<(SocketAddr, usize)> {
+  let ip_len = match *buf.first()? {
+    ADDR_V4 => 4,
+    ADDR_V6 => 16,
+    _ => return None,
+  };
+  let ip_bytes = buf.get(1..1+ip_len)?;
+  let port_bytes = buf.get(1+ip_len..3+ip_len)?;
+  let ip = if ip_len == 4 {
+    let mut octets = [0u8; 4];
+    octets.copy_from_slice(ip_bytes);
+    IpAddr::V4(Ipv4Addr::from(octets))
+  } else {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(ip_bytes);
+    IpAddr::V6(Ipv6Addr::from(octets))
+  };
+  Some((SocketAddr::new(ip, u16::from_be_bytes([port_bytes[0], port_bytes[1]])), 3 + ip_len))
+}
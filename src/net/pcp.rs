@@ -0,0 +1,209 @@
+
+/*!
+ * A PCP (RFC 6887) client, for routers which dropped NAT-PMP for its
+ * successor. Only the MAP opcode is used, over IPv4. PCP ties a mapping
+ * to a random nonce, so renewing or deleting it must send the same nonce.
+ */
+
+use igd::PortMappingProtocol;
+use rand::RngCore;
+
+use std::net::{SocketAddrV4, Ipv4Addr};
+use std::time::Duration;
+
+use super::natpmp::{self, Mapped};
+
+pub const NONCE_LEN: usize = 12;
+
+const VERSION: u8 = 2;
+const OP_MAP: u8 = 1;
+// Responses echo the request's opcode with the top bit set
+const OP_RESPONSE: u8 = 128;
+const HEADER_LEN: usize = 24;
+const MAP_LEN: usize = 36;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+pub fn new_nonce() -> [u8; NONCE_LEN] {
+  let mut nonce = [0u8; NONCE_LEN];
+  rand::rngs::OsRng.fill_bytes(&mut nonce);
+  nonce
+}
+
+fn result_message(code: u8) -> String {
+  match code {
+    1 => "unsupported version".to_string(),
+    2 => "not authorized".to_string(),
+    3 => "malformed request".to_string(),
+    4 => "unsupported opcode".to_string(),
+    7 => "network failure".to_string(),
+    8 => "no resources".to_string(),
+    9 => "unsupported protocol".to_string(),
+    10 => "user exceeded quota".to_string(),
+    11 => "cannot provide external port".to_string(),
+    12 => "address mismatch, we are likely behind another NAT".to_string(),
+    n => format!("result code {}", n),
+  }
+}
+
+/**
+ * IPv4 addresses travel as IPv4-mapped IPv6 addresses (::ffff:a.b.c.d).
+ */
+fn push_mapped_ip(ip: Ipv4Addr, buf: &mut Vec<u8>) {
+  buf.extend_from_slice(&ip.to_ipv6_mapped().octets());
+}
+
+/**
+ * Asks the gateway to forward external_port to local_addr.
+ * A lifetime_s of 0 deletes the mapping made with nonce instead.
+ */
+pub fn add_port(gateway: SocketAddrV4, nonce: &[u8; NONCE_LEN], protocol: PortMappingProtocol, local_addr: SocketAddrV4, external_port: u16, lifetime_s: u32, timeout: Duration) -> Result<Mapped, String> {
+  // The client address in the request must match the one it is sent from
+  let socket = natpmp::connect(*local_addr.ip(), gateway)?;
+  let local_ip = natpmp::local_ip(&socket)?;
+
+  let mut req = Vec::with_capacity(HEADER_LEN + MAP_LEN);
+  req.extend_from_slice(&[VERSION, OP_MAP, 0, 0]);
+  req.extend_from_slice(&lifetime_s.to_be_bytes());
+  push_mapped_ip(local_ip, &mut req);
+  req.extend_from_slice(nonce);
+  req.push(match protocol {
+    PortMappingProtocol::UDP => PROTOCOL_UDP,
+    PortMappingProtocol::TCP => PROTOCOL_TCP,
+  });
+  req.extend_from_slice(&[0, 0, 0]);
+  req.extend_from_slice(&local_addr.port().to_be_bytes());
+  req.extend_from_slice(&external_port.to_be_bytes());
+  // Any external address will do
+  push_mapped_ip(Ipv4Addr::UNSPECIFIED, &mut req);
+
+  let is_reply = |b: &[u8]| {
+    // A NAT-PMP-only gateway answers in its own version, which we also take as a reply.
+    // Errors may leave out the opcode data, successes must carry our nonce.
+    b.len() >= 4 && (b[0] != VERSION || (b[1] == (OP_MAP | OP_RESPONSE) &&
+      (b[3] != 0 || b.get(HEADER_LEN..HEADER_LEN + NONCE_LEN) == Some(&nonce[..]))))
+  };
+  let response = natpmp::exchange(&socket, &req, timeout, is_reply)?;
+  if response[0] != VERSION {
+    return Err(format!("Gateway speaks version {} rather than PCP", response[0]));
+  }
+  if response[3] != 0 {
+    return Err(format!("Gateway answered {}", result_message(response[3])));
+  }
+  if response.len() < HEADER_LEN + MAP_LEN {
+    return Err(format!("Gateway answered with a {} byte response", response.len()));
+  }
+  let map = &response[HEADER_LEN..];
+  let mut external_ip = [0u8; 16];
+  external_ip.copy_from_slice(&map[20..36]);
+  Ok(Mapped {
+    external_port: u16::from_be_bytes([map[18], map[19]]),
+    lifetime_s: u32::from_be_bytes([response[4], response[5], response[6], response[7]]),
+    external_ip: std::net::Ipv6Addr::from(external_ip).to_ipv4_mapped(),
+  })
+}
+
+pub fn remove_port(gateway: SocketAddrV4, nonce: &[u8; NONCE_LEN], protocol: PortMappingProtocol, local_addr: SocketAddrV4, timeout: Duration) -> Result<(), String> {
+  add_port(gateway, nonce, protocol, local_addr, 0, 0, timeout).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use super::super::natpmp::tests::fake_gateway;
+
+  use std::net::{SocketAddr, UdpSocket};
+  use std::thread;
+
+  const TIMEOUT: Duration = Duration::from_secs(2);
+
+  fn local_addr() -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1337)
+  }
+
+  /**
+   * A MAP response to req granting external_ip:external_port, with req's nonce.
+   */
+  fn map_response(req: &[u8], result: u8, lifetime_s: u32, external_ip: Ipv4Addr, external_port: u16) -> Vec<u8> {
+    let mut res = vec![VERSION, OP_MAP | OP_RESPONSE, 0, result];
+    res.extend_from_slice(&lifetime_s.to_be_bytes());
+    // Epoch and reserved
+    res.extend_from_slice(&[0; 16]);
+    res.extend_from_slice(&req[HEADER_LEN..HEADER_LEN + NONCE_LEN]);
+    res.extend_from_slice(&req[HEADER_LEN + 12..HEADER_LEN + 18]);
+    res.extend_from_slice(&external_port.to_be_bytes());
+    push_mapped_ip(external_ip, &mut res);
+    res
+  }
+
+  #[test]
+  fn map_udp() {
+    let nonce = new_nonce();
+    let gateway = fake_gateway(move |req| {
+      assert_eq!(req.len(), HEADER_LEN + MAP_LEN);
+      assert_eq!(&req[..8], &[VERSION, OP_MAP, 0, 0, 0, 0, 0x0e, 0x10]);
+      assert_eq!(&req[8..24], &Ipv4Addr::LOCALHOST.to_ipv6_mapped().octets());
+      assert_eq!(&req[24..36], &nonce);
+      assert_eq!(&req[36..40], &[PROTOCOL_UDP, 0, 0, 0]);
+      assert_eq!(&req[40..44], &[0x05, 0x39, 0x05, 0x39]);
+      map_response(req, 0, 1800, Ipv4Addr::new(203, 0, 113, 7), 40000)
+    });
+    let mapped = add_port(gateway, &nonce, PortMappingProtocol::UDP, local_addr(), 1337, 3600, TIMEOUT).unwrap();
+    assert_eq!(mapped.external_port, 40000);
+    assert_eq!(mapped.lifetime_s, 1800);
+    assert_eq!(mapped.external_ip, Some(Ipv4Addr::new(203, 0, 113, 7)));
+  }
+
+  #[test]
+  fn remove_sends_the_same_nonce() {
+    let nonce = new_nonce();
+    let gateway = fake_gateway(move |req| {
+      assert_eq!(&req[4..8], &[0, 0, 0, 0]);
+      assert_eq!(&req[24..36], &nonce);
+      assert_eq!(req[36], PROTOCOL_TCP);
+      map_response(req, 0, 0, Ipv4Addr::UNSPECIFIED, 0)
+    });
+    assert_eq!(remove_port(gateway, &nonce, PortMappingProtocol::TCP, local_addr(), TIMEOUT), Ok(()));
+  }
+
+  #[test]
+  fn ignores_other_nonces() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let gateway = match socket.local_addr().unwrap() {
+      SocketAddr::V4(addr) => addr,
+      addr => panic!("{} is not IPv4", addr),
+    };
+    thread::spawn(move || {
+      let mut buf = [0u8; 1100];
+      let (n, from) = socket.recv_from(&mut buf).unwrap();
+      // A stale answer to an earlier request, then ours
+      let mut stale = map_response(&buf[..n], 0, 60, Ipv4Addr::new(192, 0, 2, 1), 1);
+      stale[HEADER_LEN] ^= 0xff;
+      socket.send_to(&stale, from).unwrap();
+      socket.send_to(&map_response(&buf[..n], 0, 1800, Ipv4Addr::new(203, 0, 113, 7), 1337), from).unwrap();
+    });
+    let mapped = add_port(gateway, &new_nonce(), PortMappingProtocol::UDP, local_addr(), 1337, 3600, TIMEOUT).unwrap();
+    assert_eq!(mapped.external_ip, Some(Ipv4Addr::new(203, 0, 113, 7)));
+  }
+
+  #[test]
+  fn result_code() {
+    let gateway = fake_gateway(|req| {
+      // Errors may leave out the opcode data
+      let mut res = map_response(req, 8, 0, Ipv4Addr::UNSPECIFIED, 0);
+      res.truncate(HEADER_LEN);
+      res
+    });
+    let err = add_port(gateway, &new_nonce(), PortMappingProtocol::UDP, local_addr(), 1337, 3600, TIMEOUT).unwrap_err();
+    assert_eq!(err, "Gateway answered no resources");
+  }
+
+  #[test]
+  fn natpmp_only_gateway() {
+    // A NAT-PMP gateway answers versions it does not know with its own, result 1
+    let gateway = fake_gateway(|req| vec![0, req[1] | OP_RESPONSE, 0, 1, 0, 0, 0, 0]);
+    let err = add_port(gateway, &new_nonce(), PortMappingProtocol::UDP, local_addr(), 1337, 3600, TIMEOUT).unwrap_err();
+    assert_eq!(err, "Gateway speaks version 0 rather than PCP");
+  }
+}
@@ -0,0 +1,397 @@
+
+/*!
+ * Rendezvous lets two nodes behind NATs without port mapping reach each
+ * other. A publicly reachable node with rendezvous_serve = true remembers
+ * the address each client's registrations arrive from, which is the
+ * client's mapping on its NAT, and introduces two clients by telling each
+ * the other's address. Both then send Punch packets to each other; once
+ * each NAT has seen a packet go out to the other peer it lets the other's
+ * packets in, and the first Punch or PunchAck to arrive proves the direct
+ * path. When none arrives within hole_punch_timeout_ms the peers talk
+ * through the server instead, which forwards Relay packets to the
+ * recipient's registered address.
+ *
+ * Everything is sent over UDP from the listener the server is reached on,
+ * so it all leaves through the same NAT mapping:
+ *
+ *   RendezvousRegister    client -> server, empty
+ *   RendezvousRegistered  server -> client, the address the register came
+ *                         from (see packet::encode_socket_addr)
+ *   IntroduceRequest      client -> server, public key of the peer to reach
+ *   Introduce             server -> both clients
+ *     public_key  32 bytes  the other client
+ *     addr        its registered address, left out when it is not registered
+ *   Punch, PunchAck       client -> client, empty
+ *   Relay                 client -> server
+ *     recipient   32 bytes  public key of a registered client
+ *     packet      an encoded packet sent by the client
+ *   Relayed               server -> recipient, the encoded packet
+ *
+ * Relayed packets are still signed by their original sender, so the
+ * server cannot forge them, and sealed packets stay sealed.
+ */
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::identity::PUBLIC_KEY_LEN;
+use crate::net::packet;
+
+// Servers forget clients which have not registered for this long
+const REGISTRATION_TTL_S: u64 = 120;
+// How often expired registrations are dropped, registered_addr already ignores them
+const EXPIRE_INTERVAL_S: u64 = 30;
+// Punches are resent this often until the peer answers
+const PUNCH_INTERVAL_MS: u64 = 250;
+
+#[derive(Debug)]
+struct Registration {
+  addr: SocketAddr,
+  last_seen: Instant,
+}
+
+#[derive(Debug)]
+struct PunchAttempt {
+  addr: SocketAddr,
+  // The rendezvous which introduced us, and relays if punching fails
+  server: SocketAddr,
+  started: Instant,
+  next_punch: Instant,
+}
+
+/**
+ * A punch which ran out of time, to be relayed through server instead.
+ */
+#[derive(Debug, Clone)]
+pub struct FailedPunch {
+  pub peer: [u8; PUBLIC_KEY_LEN],
+  pub addr: SocketAddr,
+  pub server: SocketAddr,
+}
+
+#[derive(Debug, Default)]
+pub struct RendezvousTable {
+  // Clients registered with us, when we serve as a rendezvous
+  registrations: HashMap<[u8; PUBLIC_KEY_LEN], Registration>,
+  // Peers we were introduced to and are punching towards
+  attempts: HashMap<[u8; PUBLIC_KEY_LEN], PunchAttempt>,
+  // The address each rendezvous server sees our packets come from
+  seen_as: HashMap<SocketAddr, SocketAddr>,
+  // When maintain next drops expired registrations
+  next_expiry: Option<Instant>,
+}
+
+impl RendezvousTable {
+  pub fn register(&mut self, public_key: [u8; PUBLIC_KEY_LEN], addr: SocketAddr) {
+    self.registrations.insert(public_key, Registration {
+      addr,
+      last_seen: Instant::now(),
+    });
+  }
+
+  pub fn registered_addr(&self, public_key: &[u8; PUBLIC_KEY_LEN]) -> Option<SocketAddr> {
+    let r = self.registrations.get(public_key)?;
+    if r.last_seen.elapsed() >= Duration::from_secs(REGISTRATION_TTL_S) {
+      return None;
+    }
+    Some(r.addr)
+  }
+
+  /**
+   * Returns true when server saw us somewhere new.
+   */
+  pub fn set_seen_as(&mut self, server: SocketAddr, addr: SocketAddr) -> bool {
+    self.seen_as.insert(server, addr) != Some(addr)
+  }
+
+  pub fn seen_as(&self) -> Vec<(SocketAddr, SocketAddr)> {
+    let mut seen_as: Vec<(SocketAddr, SocketAddr)> = self.seen_as.iter().map(|(s, a)| (*s, *a)).collect();
+    seen_as.sort();
+    seen_as
+  }
+
+  /**
+   * Starts punching towards peer, restarting an attempt already under way.
+   */
+  pub fn start_punch(&mut self, peer: [u8; PUBLIC_KEY_LEN], addr: SocketAddr, server: SocketAddr) {
+    let now = Instant::now();
+    self.attempts.insert(peer, PunchAttempt {
+      addr,
+      server,
+      started: now,
+      next_punch: now,
+    });
+  }
+
+  /**
+   * Returns true when we were punching towards peer.
+   */
+  pub fn complete_punch(&mut self, peer: &[u8; PUBLIC_KEY_LEN]) -> bool {
+    self.attempts.remove(peer).is_some()
+  }
+
+  fn expire_registrations(&mut self, now: Instant) {
+    if let Some(next_expiry) = self.next_expiry {
+      if now < next_expiry {
+        return;
+      }
+    }
+    let ttl = Duration::from_secs(REGISTRATION_TTL_S);
+    self.registrations.retain(|_, r| now.duration_since(r.last_seen) < ttl);
+    self.next_expiry = Some(now + Duration::from_secs(EXPIRE_INTERVAL_S));
+  }
+
+  /**
+   * Returns the addresses due another Punch, with the rendezvous whose
+   * route they should take, and the attempts which gave up after timeout.
+   * Registrations which expired are dropped every EXPIRE_INTERVAL_S.
+   */
+  pub fn maintain(&mut self, timeout: Duration) -> (Vec<(SocketAddr, SocketAddr)>, Vec<FailedPunch>) {
+    let now = Instant::now();
+    self.expire_registrations(now);
+    let mut failed = Vec::new();
+    self.attempts.retain(|peer, a| {
+      if now.duration_since(a.started) < timeout {
+        return true;
+      }
+      failed.push(FailedPunch {
+        peer: *peer,
+        addr: a.addr,
+        server: a.server,
+      });
+      false
+    });
+    let mut due = Vec::new();
+    for a in self.attempts.values_mut() {
+      if now >= a.next_punch {
+        due.push((a.addr, a.server));
+        a.next_punch = now + Duration::from_millis(PUNCH_INTERVAL_MS);
+      }
+    }
+    (due, failed)
+  }
+
+  /**
+   * When maintain next has a Punch to send, if punching at all.
+   */
+  pub fn next_punch(&self) -> Option<Instant> {
+    self.attempts.values().map(|a| a.next_punch).min()
+  }
+}
+
+fn key_from(buf: &[u8]) -> Option<[u8; PUBLIC_KEY_LEN]> {
+  let mut key = [0u8; PUBLIC_KEY_LEN];
+  key.copy_from_slice(buf.get(..PUBLIC_KEY_LEN)?);
+  Some(key)
+}
+
+pub fn encode_introduce(peer: &[u8; PUBLIC_KEY_LEN], addr: Option<SocketAddr>) -> Vec<u8> {
+  let mut payload = peer.to_vec();
+  if let Some(addr) = addr {
+    packet::encode_socket_addr(&addr, &mut payload);
+  }
+  payload
+}
+
+pub fn decode_introduce(payload: &[u8]) -> Option<([u8; PUBLIC_KEY_LEN], Option<SocketAddr>)> {
+  let peer = key_from(payload)?;
+  let rest = &payload[PUBLIC_KEY_LEN..];
+  if rest.is_empty() {
+    return Some((peer, None));
+  }
+  match packet::decode_socket_addr(rest)? {
+    (addr, used) if used == rest.len() => Some((peer, Some(addr))),
+    _ => None,
+  }
+}
+
+pub fn encode_relay(recipient: &[u8; PUBLIC_KEY_LEN], inner: &[u8]) -> Vec<u8> {
+  let mut payload = Vec::with_capacity(PUBLIC_KEY_LEN + inner.len());
+  payload.extend_from_slice(recipient);
+  payload.extend_from_slice(inner);
+  payload
+}
+
+pub fn decode_relay(payload: &[u8]) -> Option<([u8; PUBLIC_KEY_LEN], &[u8])> {
+  let recipient = key_from(payload)?;
+  Some((recipient, &payload[PUBLIC_KEY_LEN..]))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::net::UdpSocket;
+  use std::path::PathBuf;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+
+  use crate::config::{Config, ConfSocket};
+  use crate::global::Global;
+  use crate::identity::Identity;
+  use crate::net::packet::{Packet, MessageType};
+  use crate::net::transport::PeerAddr;
+  use crate::peers::PeerRegistry;
+
+  const WAIT_MS: u64 = 5000;
+
+  #[test]
+  fn registrations_expire_on_a_timer() {
+    let mut table = RendezvousTable::default();
+    let addr: SocketAddr = "192.0.2.1:1337".parse().unwrap();
+    let now = Instant::now();
+    let ttl = Duration::from_secs(REGISTRATION_TTL_S);
+    let registered_at = |table: &mut RendezvousTable, key: u8, last_seen: Instant| {
+      table.registrations.insert([key; PUBLIC_KEY_LEN], Registration {
+        addr,
+        last_seen,
+      });
+    };
+    registered_at(&mut table, 1, now);
+    table.expire_registrations(now);
+    assert_eq!(table.registered_addr(&[1; PUBLIC_KEY_LEN]), Some(addr));
+
+    table.expire_registrations(now + ttl);
+    assert!(table.registrations.is_empty());
+    // An old registration is kept until the next expiry
+    registered_at(&mut table, 2, now);
+    table.expire_registrations(now + ttl + Duration::from_secs(1));
+    assert_eq!(table.registrations.len(), 1);
+    table.expire_registrations(now + ttl + Duration::from_secs(EXPIRE_INTERVAL_S));
+    assert!(table.registrations.is_empty());
+  }
+
+  fn free_udp_addr() -> SocketAddr {
+    UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+  }
+
+  fn conf_socket(socket: SocketAddr) -> ConfSocket {
+    ConfSocket {
+      name: None,
+      socket,
+      forward: false,
+    }
+  }
+
+  fn app_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("meili-rendezvous-{}-{}", std::process::id(), name))
+  }
+
+  /**
+   * Runs a node's listeners on udp, with its own app dir for peers.toml.
+   */
+  fn node(name: &str, udp: SocketAddr, servers: &[SocketAddr]) -> Arc<Global> {
+    let app_dir = app_dir(name);
+    std::fs::create_dir_all(&app_dir).unwrap();
+    let config = Arc::new(Config {
+      app_dir: app_dir.clone(),
+      hostname: name.to_string(),
+      attempt_upnp_port_forward: false,
+      lan_announce_interval_ms: 0,
+      rendezvous_serve: servers.is_empty(),
+      hole_punch_timeout_ms: 500,
+      udp_sockets_to_listen_on: vec![conf_socket(udp)],
+      rendezvous_servers: servers.iter().map(|s| conf_socket(*s)).collect(),
+      ..Config::default()
+    });
+    let global = Arc::new(Global::new(Identity::generate(), PeerRegistry::load(&app_dir), config.clone()));
+    crate::net::spawn_listeners(Arc::new(Vec::new()), global.clone());
+    global
+  }
+
+  /**
+   * A NAT in front of client which only lets it reach server: what the
+   * client sends to inside leaves outside towards server, and outside
+   * only lets server's packets back in, like a port restricted cone NAT.
+   * Packets anyone else sends to outside are dropped and counted.
+   */
+  struct Nat {
+    inside: SocketAddr,
+    outside: SocketAddr,
+    dropped: Arc<AtomicUsize>,
+  }
+
+  fn nat(client: SocketAddr, server: SocketAddr) -> Nat {
+    let inside = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let outside = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let nat = Nat {
+      inside: inside.local_addr().unwrap(),
+      outside: outside.local_addr().unwrap(),
+      dropped: Arc::new(AtomicUsize::new(0)),
+    };
+    let (inside_in, outside_out) = (inside.try_clone().unwrap(), outside.try_clone().unwrap());
+    thread::spawn(move || {
+      let mut buf = [0u8; 2048];
+      while let Ok((n, from)) = inside_in.recv_from(&mut buf) {
+        if from == client {
+          let _ = outside_out.send_to(&buf[..n], server);
+        }
+      }
+    });
+    let dropped = nat.dropped.clone();
+    thread::spawn(move || {
+      let mut buf = [0u8; 2048];
+      while let Ok((n, from)) = outside.recv_from(&mut buf) {
+        if from == server {
+          let _ = inside.send_to(&buf[..n], client);
+        } else {
+          dropped.fetch_add(1, Ordering::SeqCst);
+        }
+      }
+    });
+    nat
+  }
+
+  fn wait_for(what: &str, done: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_millis(WAIT_MS);
+    while !done() {
+      assert!(Instant::now() < deadline, "gave up waiting for {}", what);
+      thread::sleep(Duration::from_millis(20));
+    }
+  }
+
+  /**
+   * Two clients behind NATs which drop each other's punches meet at a
+   * rendezvous, fall back to relaying through it and set up a session.
+   * Relayed packets from anyone but a rendezvous server are rejected.
+   */
+  #[test]
+  fn relays_between_clients_behind_nats() {
+    let server_addr = free_udp_addr();
+    let (a_addr, b_addr) = (free_udp_addr(), free_udp_addr());
+    let (nat_a, nat_b) = (nat(a_addr, server_addr), nat(b_addr, server_addr));
+    let server = node("server", server_addr, &[]);
+    let a = node("a", a_addr, &[nat_a.inside]);
+    let b = node("b", b_addr, &[nat_b.inside]);
+    let (a_key, b_key) = (a.identity.public_key(), b.identity.public_key());
+
+    // The server sees each client at its NAT's outside address
+    wait_for("registrations", || {
+      server.get_rendezvous_client_addr(&a_key) == Some(nat_a.outside) &&
+        server.get_rendezvous_client_addr(&b_key) == Some(nat_b.outside)
+    });
+
+    a.queue_outgoing(PeerAddr::Udp(nat_a.inside), Packet::new(MessageType::IntroduceRequest, b_key.to_vec()));
+    a.wake_listeners();
+    let relayed = PeerAddr::Relay(nat_a.inside, b_key);
+    wait_for("punching to give up", || a.get_discovered_peers().iter().any(|p| p.public_key == b_key && p.addr == relayed));
+    assert!(nat_b.dropped.load(Ordering::SeqCst) > 0, "a's punches never reached b's NAT");
+
+    a.queue_outgoing(relayed.clone(), Packet::new(MessageType::Data, crate::net::packet::encode_data_payload("", b"hello")));
+    a.wake_listeners();
+    wait_for("a relayed session", || a.get_sessions().iter().any(|s| s.current && s.addr == relayed));
+    wait_for("the data to be written out", || a.get_flushed_data(&relayed) == 1);
+
+    // A Hello from a, relayed by someone who is not b's rendezvous server
+    let hello = Packet::new(MessageType::Hello, Vec::new()).encode(&a.identity);
+    let forged = Packet::new(MessageType::Relayed, hello).encode(&Identity::generate());
+    UdpSocket::bind("127.0.0.1:0").unwrap().send_to(&forged, b_addr).unwrap();
+    wait_for("the forged relay to be rejected", || b.get_rejected_packets().contains_key("unknown_rendezvous"));
+
+    for name in &["server", "a", "b"] {
+      let _ = std::fs::remove_dir_all(app_dir(name));
+    }
+  }
+}
@@ -0,0 +1,283 @@
+
+/*!
+ * The scanner walks every configured IPRange sending a hello packet
+ * to each address and records any meili node which answers.
+ * Addresses are visited in a seeded pseudo-random order (see walk.rs)
+ * and progress is saved in ScanState so scans survive restarts.
+ * Every range is rate limited independently by its max_ips_per_second.
+ *
+ * Addresses probed less than rescan_age ago (according to ScanHistory)
+ * are skipped without using any of the range's rate limit. When a walk
+ * finishes the range rests for rescan_age before starting another pass.
+ * Ranges added to a reloaded config join the scan and removed ones leave it.
+ */
+
+use std::thread;
+use std::sync::Arc;
+use std::io;
+use std::time::{Duration, Instant};
+use std::net::{UdpSocket, SocketAddr, IpAddr};
+
+use crate::punwrap_r;
+use crate::config::{Config, IPRange};
+use crate::global::Global;
+use crate::net::packet::{self, Packet, MessageType};
+use crate::net::NET_BUFF_SIZE;
+use crate::net::walk::CidrWalk;
+use crate::net::scan_state::ScanState;
+use crate::net::scan_history::{self, ScanHistory};
+use crate::net::transport::PeerAddr;
+use crate::identity;
+
+// Longest we sleep while idle so that scan-ips start/stop is noticed quickly
+const MAX_IDLE_MS: u64 = 250;
+// Bounds the work done skipping fresh addresses before checking for replies again
+const MAX_SKIPS_PER_WAKE: usize = 10000;
+const COVERAGE_INTERVAL_S: u64 = 10;
+
+struct RangeCursor {
+  key: String,
+  walk: CidrWalk,
+  next_index: u128,
+  interval: Duration,
+  next_probe: Instant,
+  rescan_age: Duration,
+  pass_finished: Option<Instant>,
+}
+
+impl RangeCursor {
+  fn new(range: &IPRange, seed: u64, scan_state: &ScanState) -> RangeCursor {
+    let max_ips_per_second = std::cmp::max(range.max_ips_per_second, 1);
+    let key = ScanState::key(range, seed);
+    let next_index = scan_state.get_cursor(&key);
+    RangeCursor {
+      key,
+      walk: CidrWalk::new(range.cidr.size(), seed),
+      next_index,
+      interval: Duration::from_nanos(1_000_000_000 / max_ips_per_second as u64),
+      next_probe: Instant::now(),
+      rescan_age: range.rescan_age.as_duration(),
+      pass_finished: None,
+    }
+  }
+
+  fn is_done(&self) -> bool {
+    self.next_index >= self.walk.size()
+  }
+
+  /**
+   * A finished range rests for rescan_age, after which its walk starts over.
+   * Returns true while resting.
+   */
+  fn rest_or_restart(&mut self) -> bool {
+    if !self.is_done() {
+      return false;
+    }
+    if let Some(pass_finished) = self.pass_finished {
+      if pass_finished.elapsed() < self.rescan_age {
+        return true;
+      }
+    }
+    self.next_index = 0;
+    self.pass_finished = None;
+    false
+  }
+}
+
+/**
+ * Ephemeral sockets the scanner sends hellos from; replies arrive on the same socket.
+ */
+struct ScanSockets {
+  v4: Option<UdpSocket>,
+  v6: Option<UdpSocket>,
+}
+
+impl ScanSockets {
+  fn bind() -> ScanSockets {
+    ScanSockets {
+      v4: bind_nonblocking("0.0.0.0:0"),
+      v6: bind_nonblocking("[::]:0"),
+    }
+  }
+
+  fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+    let s = match addr.ip() {
+      IpAddr::V4(_) => &self.v4,
+      IpAddr::V6(_) => &self.v6,
+    };
+    match s {
+      Some(s) => s.send_to(buf, addr),
+      None => Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no scan socket for address family")),
+    }
+  }
+
+  fn sockets(&self) -> Vec<&UdpSocket> {
+    self.v4.iter().chain(self.v6.iter()).collect()
+  }
+}
+
+fn bind_nonblocking(addr: &str) -> Option<UdpSocket> {
+  match UdpSocket::bind(addr) {
+    Ok(s) => {
+      punwrap_r!(s.set_nonblocking(true), nothing);
+      Some(s)
+    }
+    Err(e) => {
+      println!("Could not bind scan socket {}: {}", addr, e);
+      None
+    }
+  }
+}
+
+pub fn spawn_ip_scanning(args: Arc<Vec<String>>, global: Arc<Global>) {
+  thread::spawn(move || {
+    run_ip_scanning(args, global);
+  });
+}
+
+pub fn run_ip_scanning(_args: Arc<Vec<String>>, global: Arc<Global>) {
+  let mut config = global.get_config();
+  let sockets = ScanSockets::bind();
+  let hello = Packet::new(MessageType::Hello, vec![]).encode(&global.identity);
+  let mut scan_state = ScanState::load(&config.app_dir);
+  let mut history = ScanHistory::load(&config.app_dir, scan_history::max_rescan_age(&config));
+  let mut cursors = update_cursors(Vec::new(), &config, &scan_state);
+  let mut net_buf = [0; NET_BUFF_SIZE];
+  let mut was_scanning = false;
+  let mut last_coverage: Option<Instant> = None;
+
+  loop {
+    let latest = global.get_config();
+    if !Arc::ptr_eq(&latest, &config) {
+      cursors = update_cursors(cursors, &latest, &scan_state);
+      history.set_max_age(scan_history::max_rescan_age(&latest));
+      last_coverage = None;
+      config = latest;
+    }
+
+    let scanning = global.get_scan_ips_in_background();
+    if scanning != was_scanning {
+      println!("Background IP scanning {}", if scanning { "started" } else { "stopped" });
+      // Pausing must not let a range burst through its backlog when resumed
+      for cursor in cursors.iter_mut() {
+        cursor.next_probe = Instant::now();
+      }
+      if !scanning {
+        scan_state.save();
+      }
+      was_scanning = scanning;
+    }
+
+    receive_replies(&sockets, &mut net_buf, &global, &mut history);
+
+    if last_coverage.map(|t| t.elapsed() >= Duration::from_secs(COVERAGE_INTERVAL_S)).unwrap_or(true) {
+      global.set_scan_coverage(config.ip_ranges_to_scan.iter().map(|range| history.coverage(range)).collect());
+      last_coverage = Some(Instant::now());
+    }
+
+    if !scanning {
+      thread::sleep(Duration::from_millis(MAX_IDLE_MS));
+      continue;
+    }
+
+    let now = Instant::now();
+    let mut next_wake = now + Duration::from_millis(MAX_IDLE_MS);
+    for (range, cursor) in config.ip_ranges_to_scan.iter().zip(cursors.iter_mut()) {
+      if cursor.rest_or_restart() {
+        continue;
+      }
+      if cursor.next_probe <= now {
+        let mut skipped = 0;
+        while !cursor.is_done() && skipped < MAX_SKIPS_PER_WAKE {
+          let ip = range.cidr.nth(cursor.walk.nth(cursor.next_index));
+          cursor.next_index += 1;
+          if history.is_fresh(&ip, cursor.rescan_age) {
+            skipped += 1;
+            continue;
+          }
+          punwrap_r!(sockets.send_to(&hello, &SocketAddr::new(ip, range.port)), nothing);
+          history.record_probe(ip);
+          cursor.next_probe += cursor.interval;
+          if cursor.next_probe < now {
+            // We fell behind (eg a slow send); do not try to catch up in a burst
+            cursor.next_probe = now + cursor.interval;
+          }
+          break;
+        }
+        scan_state.set_cursor(&cursor.key, cursor.next_index);
+        if cursor.is_done() {
+          println!("Finished scanning {} ({})", range.name.clone().unwrap_or("".to_string()), range.cidr);
+          cursor.pass_finished = Some(Instant::now());
+          scan_state.save();
+          continue;
+        }
+      }
+      if cursor.next_probe < next_wake {
+        next_wake = cursor.next_probe;
+      }
+    }
+
+    scan_state.save_if_due();
+
+    let now = Instant::now();
+    if next_wake > now {
+      thread::sleep(next_wake - now);
+    }
+  }
+}
+
+/**
+ * Makes a cursor for every range in config. Ranges which were already
+ * being scanned keep their place and only take on the new rate and rescan_age.
+ */
+fn update_cursors(old: Vec<RangeCursor>, config: &Config, scan_state: &ScanState) -> Vec<RangeCursor> {
+  let seed = config.scan_seed();
+  let mut old = old;
+  config.ip_ranges_to_scan.iter()
+    .map(|range| {
+      let mut cursor = RangeCursor::new(range, seed, scan_state);
+      if let Some(i) = old.iter().position(|c| c.key == cursor.key) {
+        let prev = old.swap_remove(i);
+        cursor.next_probe = prev.next_probe;
+        cursor.pass_finished = prev.pass_finished;
+      }
+      cursor
+    })
+    .collect()
+}
+
+fn receive_replies(sockets: &ScanSockets, net_buf: &mut [u8], global: &Global, history: &mut ScanHistory) {
+  for s in sockets.sockets() {
+    loop {
+      match s.recv_from(net_buf) {
+        Ok((num_bytes, from)) => {
+          match Packet::decode(&net_buf[..num_bytes]) {
+            Ok(packet) => {
+              if packet.msg_type != MessageType::HelloAck {
+                continue;
+              }
+              if history.record_reply(from.ip(), identity::fingerprint_of(&packet.sender_id)) {
+                global.record_discovered_peer(packet.sender_id, PeerAddr::Udp(from), "scan", None);
+                let public_addr = packet::decode_socket_addr(&packet.payload).map(|(addr, _)| addr);
+                global.set_peer_public_addr(&packet.sender_id, public_addr);
+              } else {
+                global.count_rejected_packet("unsolicited_scan_reply");
+              }
+            }
+            Err(e) => {
+              println!("Rejected {} bytes from {:?}: {}", num_bytes, from, e);
+              global.count_rejected_packet(e.reason());
+            }
+          }
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+          break;
+        }
+        Err(e) => {
+          println!("scan socket e={:?}", e);
+          break;
+        }
+      }
+    }
+  }
+}
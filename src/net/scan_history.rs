@@ -0,0 +1,266 @@
+
+/*!
+ * ScanHistory records when every address was last probed and what
+ * answered, so the scanner can skip addresses younger than their
+ * range's rescan_age. It is kept as an append-only log,
+ * scan_history.log in the app_dir, with one line per event:
+ *
+ *   <unix seconds> <ip address> <fingerprint or "-" for no reply>
+ *
+ * The log is compacted to one line per address when it is loaded and
+ * whenever it has grown to twice the addresses it holds. Compacting drops
+ * addresses probed longer ago than the largest rescan_age, as they are
+ * due for probing again anyway.
+ */
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::punwrap_r;
+use crate::config::{Config, IPRange};
+
+// The log is not compacted before it has this many lines
+const MIN_COMPACT_LINES: usize = 4096;
+// How long after a probe its reply is still accepted
+const REPLY_TIMEOUT_S: u64 = 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeResult {
+  NoReply,
+  Meili(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ScanRecord {
+  pub last_probed: u64,
+  pub result: ProbeResult,
+}
+
+/**
+ * How much of a range has been scanned, as shown by the scan-coverage command.
+ */
+#[derive(Debug, Clone)]
+pub struct RangeCoverage {
+  pub name: String,
+  pub cidr: String,
+  pub size: u128,
+  pub probed: usize,
+  pub fresh: usize,
+  pub answered: usize,
+}
+
+pub struct ScanHistory {
+  path: PathBuf,
+  // Ordered by address so a range's records are found without walking the rest
+  records: BTreeMap<IpAddr, ScanRecord>,
+  log: Option<File>,
+  // Lines appended to the log since it was last compacted
+  appended: usize,
+  max_age: Duration,
+}
+
+/**
+ * The largest rescan_age of config's ranges; older records are never fresh.
+ */
+pub fn max_rescan_age(config: &Config) -> Duration {
+  config.ip_ranges_to_scan.iter()
+    .map(|range| range.rescan_age.as_duration())
+    .max()
+    .unwrap_or(Duration::from_secs(0))
+}
+
+impl ScanHistory {
+  pub fn load(app_dir: &Path, max_age: Duration) -> ScanHistory {
+    let mut path = app_dir.to_path_buf();
+    path.push("scan_history.log");
+
+    let mut records = BTreeMap::new();
+    if let Ok(contents) = fs::read_to_string(&path) {
+      for line in contents.lines() {
+        if let Some((ip, record)) = parse_line(line) {
+          records.insert(ip, record);
+        }
+      }
+    }
+
+    let mut history = ScanHistory {
+      path,
+      records,
+      log: None,
+      appended: 0,
+      max_age,
+    };
+    history.compact();
+    history
+  }
+
+  /**
+   * Takes on the largest rescan_age of a new config, which applies from the next compaction.
+   */
+  pub fn set_max_age(&mut self, max_age: Duration) {
+    self.max_age = max_age;
+  }
+
+  fn compact(&mut self) {
+    let oldest = now_s().saturating_sub(self.max_age.as_secs());
+    self.records.retain(|_, record| record.last_probed >= oldest);
+    self.appended = 0;
+    let mut contents = String::new();
+    for (ip, record) in &self.records {
+      contents.push_str(&format_line(ip, record));
+    }
+    punwrap_r!(fs::write(&self.path, contents), nothing);
+    self.log = match OpenOptions::new().append(true).create(true).open(&self.path) {
+      Ok(f) => Some(f),
+      Err(e) => {
+        println!("Cannot append to {}, scan history will not be saved: {}", self.path.to_string_lossy(), e);
+        None
+      }
+    };
+  }
+
+  fn append(&mut self, ip: IpAddr, record: ScanRecord) {
+    if let Some(log) = &mut self.log {
+      punwrap_r!(log.write_all(format_line(&ip, &record).as_bytes()), nothing);
+    }
+    self.records.insert(ip, record);
+    self.appended += 1;
+    if self.appended >= self.records.len().max(MIN_COMPACT_LINES) {
+      self.compact();
+    }
+  }
+
+  /**
+   * True when ip was probed less than rescan_age ago.
+   */
+  pub fn is_fresh(&self, ip: &IpAddr, rescan_age: Duration) -> bool {
+    match self.records.get(ip) {
+      Some(record) => is_younger(record, now_s(), rescan_age),
+      None => false,
+    }
+  }
+
+  pub fn record_probe(&mut self, ip: IpAddr) {
+    self.append(ip, ScanRecord {
+      last_probed: now_s(),
+      result: ProbeResult::NoReply,
+    });
+  }
+
+  /**
+   * Replies arrive after the probe was recorded, so we keep its probe time.
+   * Only a reply to a probe sent within REPLY_TIMEOUT_S which has not been
+   * answered yet is recorded, so packets nobody asked for cannot fill the log.
+   * Returns whether it was recorded.
+   */
+  pub fn record_reply(&mut self, ip: IpAddr, fingerprint: String) -> bool {
+    let last_probed = match self.records.get(&ip) {
+      Some(record) if record.result == ProbeResult::NoReply && now_s().saturating_sub(record.last_probed) <= REPLY_TIMEOUT_S => record.last_probed,
+      _ => return false,
+    };
+    self.append(ip, ScanRecord {
+      last_probed,
+      result: ProbeResult::Meili(fingerprint),
+    });
+    true
+  }
+
+  pub fn coverage(&self, range: &IPRange) -> RangeCoverage {
+    let rescan_age = range.rescan_age.as_duration();
+    let now = now_s();
+    let mut coverage = RangeCoverage {
+      name: range.name.clone().unwrap_or("".to_string()),
+      cidr: range.cidr.to_string(),
+      size: range.cidr.size(),
+      probed: 0,
+      fresh: 0,
+      answered: 0,
+    };
+    for (_, record) in self.records.range(range.cidr.first()..=range.cidr.last()) {
+      coverage.probed += 1;
+      if is_younger(record, now, rescan_age) {
+        coverage.fresh += 1;
+      }
+      if record.result != ProbeResult::NoReply {
+        coverage.answered += 1;
+      }
+    }
+    coverage
+  }
+}
+
+fn is_younger(record: &ScanRecord, now: u64, age: Duration) -> bool {
+  now.saturating_sub(record.last_probed) < age.as_secs()
+}
+
+fn now_s() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn format_line(ip: &IpAddr, record: &ScanRecord) -> String {
+  let result = match &record.result {
+    ProbeResult::NoReply => "-",
+    ProbeResult::Meili(fingerprint) => fingerprint.as_str(),
+  };
+  format!("{} {} {}\n", record.last_probed, ip, result)
+}
+
+fn parse_line(line: &str) -> Option<(IpAddr, ScanRecord)> {
+  let mut parts = line.split_whitespace();
+  let last_probed = parts.next()?.parse::<u64>().ok()?;
+  let ip = parts.next()?.parse::<IpAddr>().ok()?;
+  let result = match parts.next()? {
+    "-" => ProbeResult::NoReply,
+    fingerprint => ProbeResult::Meili(fingerprint.to_string()),
+  };
+  Some((ip, ScanRecord {
+    last_probed,
+    result,
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn history(name: &str) -> (PathBuf, ScanHistory) {
+    let app_dir = std::env::temp_dir().join(format!("meili-scan-history-{}-{}", std::process::id(), name));
+    fs::create_dir_all(&app_dir).unwrap();
+    let history = ScanHistory::load(&app_dir, Duration::from_secs(3600));
+    (app_dir, history)
+  }
+
+  #[test]
+  fn only_replies_to_probes_are_recorded() {
+    let (app_dir, mut history) = history("replies");
+    let probed: IpAddr = "10.0.0.1".parse().unwrap();
+    let unprobed: IpAddr = "10.0.0.2".parse().unwrap();
+
+    assert!(!history.record_reply(unprobed, "a".to_string()));
+    history.record_probe(probed);
+    assert!(history.record_reply(probed, "a".to_string()));
+    // The probe has its answer, another reply is not one
+    assert!(!history.record_reply(probed, "b".to_string()));
+
+    let contents = fs::read_to_string(app_dir.join("scan_history.log")).unwrap();
+    assert_eq!(contents.lines().count(), 2);
+    assert!(!contents.contains(&unprobed.to_string()));
+    let _ = fs::remove_dir_all(&app_dir);
+  }
+
+  #[test]
+  fn late_replies_are_not_recorded() {
+    let (app_dir, mut history) = history("late");
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    history.records.insert(ip, ScanRecord {
+      last_probed: now_s() - REPLY_TIMEOUT_S - 1,
+      result: ProbeResult::NoReply,
+    });
+    assert!(!history.record_reply(ip, "a".to_string()));
+    let _ = fs::remove_dir_all(&app_dir);
+  }
+}
@@ -0,0 +1,93 @@
+
+/*!
+ * ScanState remembers how far the scanner got through each range
+ * so that a restarted meili resumes its random walk instead of
+ * starting over. It is stored as scan_state.toml in the app_dir.
+ */
+
+use serde::{Serialize, Deserialize};
+use toml;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::time::{Duration, Instant};
+
+use crate::punwrap_r;
+use crate::config::IPRange;
+
+const SAVE_INTERVAL_S: u64 = 5;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct ScanStateFile {
+  // toml integers are i64, so u128 cursors are stored as strings
+  #[serde(default)]
+  cursors: HashMap<String, String>,
+}
+
+pub struct ScanState {
+  path: PathBuf,
+  data: ScanStateFile,
+  dirty: bool,
+  last_save: Instant,
+}
+
+impl ScanState {
+  pub fn load(app_dir: &Path) -> ScanState {
+    let mut path = app_dir.to_path_buf();
+    path.push("scan_state.toml");
+    let data = match fs::read_to_string(&path) {
+      Ok(contents) => {
+        match toml::from_str(&contents) {
+          Ok(data) => data,
+          Err(e) => {
+            println!("Error reading {}, scans will restart: {}", path.to_string_lossy(), e);
+            ScanStateFile::default()
+          }
+        }
+      }
+      Err(_) => ScanStateFile::default(),
+    };
+    ScanState {
+      path,
+      data,
+      dirty: false,
+      last_save: Instant::now(),
+    }
+  }
+
+  /**
+   * Cursors are keyed by both the range and the seed; changing
+   * either describes a different walk which must start from 0.
+   */
+  pub fn key(range: &IPRange, seed: u64) -> String {
+    format!("{} seed={}", range.cidr, seed)
+  }
+
+  pub fn get_cursor(&self, key: &str) -> u128 {
+    self.data.cursors.get(key)
+      .and_then(|s| s.parse::<u128>().ok())
+      .unwrap_or(0)
+  }
+
+  pub fn set_cursor(&mut self, key: &str, cursor: u128) {
+    self.data.cursors.insert(key.to_string(), cursor.to_string());
+    self.dirty = true;
+  }
+
+  pub fn save_if_due(&mut self) {
+    if self.dirty && self.last_save.elapsed() >= Duration::from_secs(SAVE_INTERVAL_S) {
+      self.save();
+    }
+  }
+
+  pub fn save(&mut self) {
+    self.last_save = Instant::now();
+    if !self.dirty {
+      return;
+    }
+    let contents = punwrap_r!(toml::to_string(&self.data), return);
+    punwrap_r!(fs::write(&self.path, contents), return);
+    self.dirty = false;
+  }
+}
@@ -0,0 +1,528 @@
+
+/*!
+ * Encrypted sessions between pairs of nodes.
+ *
+ * Every packet is already signed by its sender's identity key, so a
+ * session is set up with a single round trip of signed ephemeral
+ * X25519 keys:
+ *
+ *   SessionInit    initiator -> responder
+ *     ephemeral   32 bytes
+ *     timestamp    8 bytes  big-endian unix seconds
+ *   SessionAccept  responder -> initiator
+ *     ephemeral   32 bytes
+ *     echo        32 bytes  the initiator's ephemeral
+ *
+ * Both sides run HKDF-SHA256 over the X25519 shared secret, salted with a
+ * hash of both ephemerals and both identity keys, to get a key for each
+ * direction and a session id. Packets are then carried as Sealed packets:
+ *
+ *   session_id  8 bytes
+ *   counter     8 bytes  big-endian, counts up from 1 for each packet sent
+ *   ciphertext  ChaCha20-Poly1305 of msg_type (1 byte) + payload, plus a 16 byte tag
+ *
+ * The session id and counter are authenticated as associated data and the
+ * counter is the nonce. Receivers reject counters they have already seen, or
+ * which are too old to tell, so sealed packets cannot be replayed; inits are
+ * timestamped and remembered for as long as they would be accepted so a
+ * captured SessionInit cannot be replayed either.
+ */
+
+use x25519_dalek::{StaticSecret, PublicKey as X25519PublicKey};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, KeyInit};
+use chacha20poly1305::aead::{Aead, Payload};
+use hkdf::Hkdf;
+use sha2::{Sha256, Digest};
+use rand::rngs::OsRng;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::identity::{self, PUBLIC_KEY_LEN};
+use crate::net::packet::{Packet, MessageType};
+use crate::net::transport::PeerAddr;
+
+const EPHEMERAL_LEN: usize = 32;
+const INIT_LEN: usize = EPHEMERAL_LEN + 8;
+const ACCEPT_LEN: usize = 2 * EPHEMERAL_LEN;
+const SEALED_HEADER_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+// What sealing adds to a payload: the header, the message type and the tag
+pub const SEAL_OVERHEAD: usize = SEALED_HEADER_LEN + 1 + TAG_LEN;
+const KDF_LABEL: &[u8] = b"meili session v1";
+
+// Inits whose timestamp is further than this from our clock are rejected
+const INIT_MAX_SKEW_S: u64 = 120;
+// How long we wait for a SessionAccept before dropping the packets waiting on it
+const HANDSHAKE_TIMEOUT_S: u64 = 10;
+// Packets sent before the peer learned of a rekey may still arrive for a while
+const REPLACED_SESSION_GRACE_S: u64 = 30;
+const MAX_QUEUED_PER_HANDSHAKE: usize = 64;
+// Rekeying leaves replaced sessions around for a grace period; past this
+// many for one peer the oldest are closed early
+const MAX_SESSIONS_PER_PEER: usize = 4;
+// Inits from new peers are rejected while this many sessions are open
+const MAX_SESSIONS: usize = 4096;
+const REPLAY_WINDOW: u64 = 64;
+
+/**
+ * Remembers which of the last REPLAY_WINDOW counters were received.
+ */
+#[derive(Default)]
+struct ReplayWindow {
+  highest: u64,
+  // Bit i is set when counter highest - i has been received
+  seen: u64,
+}
+
+impl ReplayWindow {
+  fn is_replay(&self, counter: u64) -> bool {
+    if counter == 0 {
+      return true; // Counters start at 1
+    }
+    if counter > self.highest {
+      return false;
+    }
+    let age = self.highest - counter;
+    age >= REPLAY_WINDOW || self.seen & (1 << age) != 0
+  }
+
+  fn mark(&mut self, counter: u64) {
+    if counter > self.highest {
+      let shift = counter - self.highest;
+      self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+      self.seen |= 1;
+      self.highest = counter;
+    } else {
+      self.seen |= 1 << (self.highest - counter);
+    }
+  }
+}
+
+struct Session {
+  peer: [u8; PUBLIC_KEY_LEN],
+  addr: PeerAddr,
+  initiator: bool,
+  established: Instant,
+  last_activity: Instant,
+  // Set when a newer session with the same peer replaced this one
+  replaced: Option<Instant>,
+  send_cipher: ChaCha20Poly1305,
+  recv_cipher: ChaCha20Poly1305,
+  send_counter: u64,
+  replay: ReplayWindow,
+}
+
+struct PendingHandshake {
+  secret: StaticSecret,
+  public: [u8; EPHEMERAL_LEN],
+  started: Instant,
+  // Packets to seal once the session is up
+  queued: Vec<Packet>,
+}
+
+struct SessionKeys {
+  id: u64,
+  initiator_to_responder: [u8; 32],
+  responder_to_initiator: [u8; 32],
+}
+
+/**
+ * A summary of one session for the status command.
+ */
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+  pub id: u64,
+  pub peer_fingerprint: String,
+  pub addr: PeerAddr,
+  pub initiator: bool,
+  pub current: bool,
+  pub age: Duration,
+  pub idle: Duration,
+}
+
+pub struct SessionTable {
+  own_key: [u8; PUBLIC_KEY_LEN],
+  sessions: HashMap<u64, Session>,
+  // The session each peer's packets are sealed with
+  current: HashMap<[u8; PUBLIC_KEY_LEN], u64>,
+  // The same sessions by the address their peer was last heard at
+  current_by_addr: HashMap<PeerAddr, u64>,
+  // Handshakes we started, by the address the init was sent to
+  pending: HashMap<PeerAddr, PendingHandshake>,
+  // Ephemeral keys of inits we accepted, with their timestamps
+  seen_inits: HashMap<[u8; EPHEMERAL_LEN], u64>,
+}
+
+impl fmt::Debug for SessionTable {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("SessionTable")
+      .field("sessions", &self.list())
+      .field("pending", &self.pending.keys().collect::<Vec<&PeerAddr>>())
+      .finish()
+  }
+}
+
+impl SessionTable {
+  pub fn new(own_key: [u8; PUBLIC_KEY_LEN]) -> SessionTable {
+    SessionTable {
+      own_key,
+      sessions: HashMap::new(),
+      current: HashMap::new(),
+      current_by_addr: HashMap::new(),
+      pending: HashMap::new(),
+      seen_inits: HashMap::new(),
+    }
+  }
+
+  /**
+   * Seals packet for peer when we have a session with it. Otherwise the packet
+   * waits for a handshake, and the SessionInit to send is returned unless
+   * one is already on its way.
+   */
+  pub fn seal_outgoing(&mut self, peer: &PeerAddr, packet: Packet) -> Option<Packet> {
+    if let Some(id) = self.current_for_addr(peer) {
+      return self.seal(id, &packet);
+    }
+    if let Some(pending) = self.pending.get_mut(peer) {
+      if pending.queued.len() < MAX_QUEUED_PER_HANDSHAKE {
+        pending.queued.push(packet);
+      } else {
+        println!("Dropping {} packet for {}, too many are waiting for a session", packet.msg_type.name(), peer);
+      }
+      return None;
+    }
+    Some(self.start_handshake(peer, vec![packet]))
+  }
+
+  fn current_for_addr(&self, peer: &PeerAddr) -> Option<u64> {
+    self.current_by_addr.get(peer).copied()
+  }
+
+  /**
+   * Stops finding session id by addr, unless another session has taken the address since.
+   */
+  fn unindex_addr(&mut self, addr: &PeerAddr, id: u64) {
+    if self.current_by_addr.get(addr) == Some(&id) {
+      self.current_by_addr.remove(addr);
+    }
+  }
+
+  fn start_handshake(&mut self, peer: &PeerAddr, queued: Vec<Packet>) -> Packet {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = X25519PublicKey::from(&secret).to_bytes();
+    let mut payload = Vec::with_capacity(INIT_LEN);
+    payload.extend_from_slice(&public);
+    payload.extend_from_slice(&now_s().to_be_bytes());
+    self.pending.insert(peer.clone(), PendingHandshake {
+      secret,
+      public,
+      started: Instant::now(),
+      queued,
+    });
+    Packet::new(MessageType::SessionInit, payload)
+  }
+
+  /**
+   * Seals packet with session id, eg to answer a packet which arrived in it.
+   */
+  pub fn seal(&mut self, id: u64, packet: &Packet) -> Option<Packet> {
+    let session = self.sessions.get_mut(&id)?;
+    session.send_counter += 1;
+    let counter = session.send_counter;
+
+    let mut header = Vec::with_capacity(SEALED_HEADER_LEN);
+    header.extend_from_slice(&id.to_be_bytes());
+    header.extend_from_slice(&counter.to_be_bytes());
+    let mut plaintext = Vec::with_capacity(1 + packet.payload.len());
+    plaintext.push(packet.msg_type.to_u8());
+    plaintext.extend_from_slice(&packet.payload);
+
+    let ciphertext = match session.send_cipher.encrypt(&nonce(counter), Payload { msg: &plaintext, aad: &header }) {
+      Ok(c) => c,
+      Err(e) => {
+        println!("Cannot seal packet for {} e={:?}", session.addr, e);
+        return None;
+      }
+    };
+    session.last_activity = Instant::now();
+
+    let mut payload = header;
+    payload.extend_from_slice(&ciphertext);
+    Some(Packet::new(MessageType::Sealed, payload))
+  }
+
+  /**
+   * Answers a SessionInit, returning the SessionAccept to reply with or
+   * the reason the init was rejected.
+   */
+  pub fn accept(&mut self, init: &Packet, from: &PeerAddr) -> Result<Packet, &'static str> {
+    if init.payload.len() != INIT_LEN {
+      return Err("bad_session_init");
+    }
+    let mut their_ephemeral = [0; EPHEMERAL_LEN];
+    their_ephemeral.copy_from_slice(&init.payload[..EPHEMERAL_LEN]);
+    let mut timestamp_bytes = [0; 8];
+    timestamp_bytes.copy_from_slice(&init.payload[EPHEMERAL_LEN..]);
+    let timestamp = u64::from_be_bytes(timestamp_bytes);
+
+    let now = now_s();
+    if timestamp.saturating_add(INIT_MAX_SKEW_S) < now || timestamp > now + INIT_MAX_SKEW_S {
+      return Err("stale_session_init");
+    }
+    if self.sessions.len() >= MAX_SESSIONS && !self.current.contains_key(&init.sender_id) {
+      return Err("too_many_sessions");
+    }
+    self.seen_inits.retain(|_, ts| *ts + INIT_MAX_SKEW_S >= now);
+    if self.seen_inits.contains_key(&their_ephemeral) {
+      return Err("replayed_session_init");
+    }
+    self.seen_inits.insert(their_ephemeral, timestamp);
+
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let our_ephemeral = X25519PublicKey::from(&secret).to_bytes();
+    let shared = secret.diffie_hellman(&X25519PublicKey::from(their_ephemeral));
+    if !shared.was_contributory() {
+      return Err("bad_session_init");
+    }
+    let keys = derive_keys(shared.as_bytes(), &their_ephemeral, &our_ephemeral, &init.sender_id, &self.own_key);
+    self.install(keys, init.sender_id, from.clone(), false);
+
+    let mut payload = Vec::with_capacity(ACCEPT_LEN);
+    payload.extend_from_slice(&our_ephemeral);
+    payload.extend_from_slice(&their_ephemeral);
+    Ok(Packet::new(MessageType::SessionAccept, payload))
+  }
+
+  /**
+   * Completes a handshake we started, returning the packets which were
+   * waiting on it (now ready to be sealed) or the reason the accept was rejected.
+   */
+  pub fn complete(&mut self, accept: &Packet, from: &PeerAddr) -> Result<Vec<Packet>, &'static str> {
+    if accept.payload.len() != ACCEPT_LEN {
+      return Err("bad_session_accept");
+    }
+    let mut their_ephemeral = [0; EPHEMERAL_LEN];
+    their_ephemeral.copy_from_slice(&accept.payload[..EPHEMERAL_LEN]);
+    let echo = &accept.payload[EPHEMERAL_LEN..];
+
+    let addr = match self.pending.iter().find(|(_, p)| p.public[..] == *echo) {
+      Some((addr, _)) => addr.clone(),
+      None => return Err("unexpected_session_accept"),
+    };
+    let pending = match self.pending.remove(&addr) {
+      Some(p) => p,
+      None => return Err("unexpected_session_accept"),
+    };
+    let shared = pending.secret.diffie_hellman(&X25519PublicKey::from(their_ephemeral));
+    if !shared.was_contributory() {
+      return Err("bad_session_accept");
+    }
+    let keys = derive_keys(shared.as_bytes(), &pending.public, &their_ephemeral, &self.own_key, &accept.sender_id);
+    self.install(keys, accept.sender_id, from.clone(), true);
+    Ok(pending.queued)
+  }
+
+  fn install(&mut self, keys: SessionKeys, peer: [u8; PUBLIC_KEY_LEN], addr: PeerAddr, initiator: bool) {
+    let now = Instant::now();
+    if let Some(old_id) = self.current.insert(peer, keys.id) {
+      let old_addr = match self.sessions.get_mut(&old_id) {
+        Some(old) => {
+          old.replaced = Some(now);
+          Some(old.addr.clone())
+        }
+        None => None,
+      };
+      if let Some(old_addr) = old_addr {
+        self.unindex_addr(&old_addr, old_id);
+      }
+    }
+    self.current_by_addr.insert(addr.clone(), keys.id);
+
+    // Make room by closing this peer's oldest replaced sessions
+    let mut replaced: Vec<(u64, Instant)> = self.sessions.iter()
+      .filter(|(_, s)| s.peer == peer)
+      .filter_map(|(id, s)| s.replaced.map(|r| (*id, r)))
+      .collect();
+    if replaced.len() >= MAX_SESSIONS_PER_PEER {
+      replaced.sort_by_key(|(_, r)| *r);
+      for (id, _) in &replaced[..replaced.len() + 1 - MAX_SESSIONS_PER_PEER] {
+        self.sessions.remove(id);
+      }
+    }
+    let (send_key, recv_key) = if initiator {
+      (keys.initiator_to_responder, keys.responder_to_initiator)
+    } else {
+      (keys.responder_to_initiator, keys.initiator_to_responder)
+    };
+    println!("Established session {:016x} with {} at {}", keys.id, identity::fingerprint_of(&peer), addr);
+    self.sessions.insert(keys.id, Session {
+      peer,
+      addr,
+      initiator,
+      established: now,
+      last_activity: now,
+      replaced: None,
+      send_cipher: ChaCha20Poly1305::new(Key::from_slice(&send_key)),
+      recv_cipher: ChaCha20Poly1305::new(Key::from_slice(&recv_key)),
+      send_counter: 0,
+      replay: ReplayWindow::default(),
+    });
+  }
+
+  /**
+   * Decrypts a Sealed packet, returning the packet it carried and the
+   * id of its session, or the reason it was rejected.
+   */
+  pub fn open(&mut self, sealed: &Packet, from: &PeerAddr) -> Result<(Packet, u64), &'static str> {
+    if sealed.payload.len() < SEALED_HEADER_LEN + TAG_LEN {
+      return Err("bad_sealed");
+    }
+    let mut id_bytes = [0; 8];
+    id_bytes.copy_from_slice(&sealed.payload[..8]);
+    let id = u64::from_be_bytes(id_bytes);
+    let mut counter_bytes = [0; 8];
+    counter_bytes.copy_from_slice(&sealed.payload[8..SEALED_HEADER_LEN]);
+    let counter = u64::from_be_bytes(counter_bytes);
+
+    let session = match self.sessions.get_mut(&id) {
+      Some(s) => s,
+      None => return Err("unknown_session"),
+    };
+    if session.peer != sealed.sender_id {
+      return Err("wrong_session_peer");
+    }
+    if session.replay.is_replay(counter) {
+      return Err("replayed_sealed");
+    }
+    let payload = Payload {
+      msg: &sealed.payload[SEALED_HEADER_LEN..],
+      aad: &sealed.payload[..SEALED_HEADER_LEN],
+    };
+    let plaintext = match session.recv_cipher.decrypt(&nonce(counter), payload) {
+      Ok(p) => p,
+      Err(_) => return Err("bad_sealed"),
+    };
+    session.replay.mark(counter);
+    session.last_activity = Instant::now();
+    if session.addr != *from {
+      // The peer moved, eg its NAT mapping changed
+      let old_addr = std::mem::replace(&mut session.addr, from.clone());
+      let peer = session.peer;
+      if self.current.get(&peer) == Some(&id) {
+        self.unindex_addr(&old_addr, id);
+        self.current_by_addr.insert(from.clone(), id);
+      }
+    }
+
+    let msg_type = match plaintext.first().and_then(|t| MessageType::from_u8(*t)) {
+      Some(MessageType::SessionInit) | Some(MessageType::SessionAccept) | Some(MessageType::Sealed) | None => {
+        return Err("bad_sealed");
+      }
+      Some(t) => t,
+    };
+    Ok((Packet {
+      msg_type,
+      sender_id: sealed.sender_id,
+      payload: plaintext[1..].to_vec(),
+    }, id))
+  }
+
+  /**
+   * Forgets idle sessions and abandoned handshakes, and returns the
+   * SessionInits which rekey the sessions we started rekey_interval ago.
+   */
+  pub fn maintain(&mut self, rekey_interval: Duration, idle_timeout: Duration) -> Vec<(PeerAddr, Packet)> {
+    let handshake_timeout = Duration::from_secs(HANDSHAKE_TIMEOUT_S);
+    let timed_out: Vec<PeerAddr> = self.pending.iter()
+      .filter(|(_, p)| p.started.elapsed() >= handshake_timeout)
+      .map(|(addr, _)| addr.clone())
+      .collect();
+    for addr in timed_out {
+      if let Some(pending) = self.pending.remove(&addr) {
+        if !pending.queued.is_empty() {
+          println!("No session could be set up with {}, dropping {} packets", addr, pending.queued.len());
+        }
+      }
+    }
+
+    let grace = Duration::from_secs(REPLACED_SESSION_GRACE_S);
+    let expired: Vec<u64> = self.sessions.iter()
+      .filter(|(_, s)| s.last_activity.elapsed() >= idle_timeout || s.replaced.map(|r| r.elapsed() >= grace).unwrap_or(false))
+      .map(|(id, _)| *id)
+      .collect();
+    for id in expired {
+      if let Some(session) = self.sessions.remove(&id) {
+        if self.current.get(&session.peer) == Some(&id) {
+          println!("Session {:016x} with {} has been idle for {}s, closing it", id, session.addr, session.last_activity.elapsed().as_secs());
+          self.current.remove(&session.peer);
+          self.unindex_addr(&session.addr, id);
+        }
+      }
+    }
+
+    let due: Vec<PeerAddr> = self.current.values()
+      .filter_map(|id| self.sessions.get(id))
+      .filter(|s| s.initiator && s.established.elapsed() >= rekey_interval && !self.pending.contains_key(&s.addr))
+      .map(|s| s.addr.clone())
+      .collect();
+    due.into_iter()
+      .map(|addr| {
+        let init = self.start_handshake(&addr, vec![]);
+        (addr, init)
+      })
+      .collect()
+  }
+
+  pub fn list(&self) -> Vec<SessionInfo> {
+    self.sessions.iter()
+      .map(|(id, s)| SessionInfo {
+        id: *id,
+        peer_fingerprint: identity::fingerprint_of(&s.peer),
+        addr: s.addr.clone(),
+        initiator: s.initiator,
+        current: self.current.get(&s.peer) == Some(id),
+        age: s.established.elapsed(),
+        idle: s.last_activity.elapsed(),
+      })
+      .collect()
+  }
+}
+
+fn derive_keys(shared: &[u8; 32], init_ephemeral: &[u8; EPHEMERAL_LEN], accept_ephemeral: &[u8; EPHEMERAL_LEN], initiator: &[u8; PUBLIC_KEY_LEN], responder: &[u8; PUBLIC_KEY_LEN]) -> SessionKeys {
+  let mut transcript = Sha256::new();
+  transcript.update(KDF_LABEL);
+  transcript.update(init_ephemeral);
+  transcript.update(accept_ephemeral);
+  transcript.update(initiator);
+  transcript.update(responder);
+  let salt = transcript.finalize();
+
+  let mut okm = [0u8; 72];
+  Hkdf::<Sha256>::new(Some(&salt), shared)
+    .expand(KDF_LABEL, &mut okm)
+    .expect("72 bytes is a valid HKDF-SHA256 output length");
+
+  let mut keys = SessionKeys {
+    id: 0,
+    initiator_to_responder: [0; 32],
+    responder_to_initiator: [0; 32],
+  };
+  keys.initiator_to_responder.copy_from_slice(&okm[0..32]);
+  keys.responder_to_initiator.copy_from_slice(&okm[32..64]);
+  let mut id_bytes = [0; 8];
+  id_bytes.copy_from_slice(&okm[64..72]);
+  keys.id = u64::from_be_bytes(id_bytes);
+  keys
+}
+
+fn nonce(counter: u64) -> Nonce {
+  let mut nonce = [0; 12];
+  nonce[4..].copy_from_slice(&counter.to_be_bytes());
+  *Nonce::from_slice(&nonce)
+}
+
+fn now_s() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
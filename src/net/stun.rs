@@ -0,0 +1,710 @@
+
+/*!
+ * A STUN (RFC 5389) binding client which learns the address our UDP
+ * listeners are seen at from outside the NAT, and tells the NAT types
+ * apart with the tests of RFC 3489 section 10.1 (RFC 5780 servers
+ * answer them too):
+ *
+ *   1. A plain binding request tells us our mapped address. When it is
+ *      our own address there is no NAT.
+ *   2. Asking the server to answer from another IP and port: an answer
+ *      means anyone may send to our mapping (full cone).
+ *   3. A plain request to the server's other address (or the next
+ *      [[stun_servers]] entry): a different mapped address means each
+ *      destination gets its own mapping (symmetric).
+ *   4. Asking the server to answer from another port only: an answer
+ *      means only addresses we sent to may send to us (restricted),
+ *      otherwise only the exact address and port (port restricted).
+ *
+ * Servers which do not support CHANGE-REQUEST answer tests 2 and 4 with
+ * an error, after which we can still tell symmetric NATs from cone ones.
+ * Probes run on the listener's own socket (see net::run_listeners), so
+ * the mapping they see is the one peers reach us through.
+ */
+
+use rand::RngCore;
+
+use std::io;
+use std::fmt;
+use std::net::{UdpSocket, SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant, SystemTime};
+
+const HEADER_LEN: usize = 20;
+const MAGIC_COOKIE: [u8; 4] = [0x21, 0x12, 0xa4, 0x42];
+const TXID_LEN: usize = 12;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const BINDING_ERROR: u16 = 0x0111;
+
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_CHANGE_REQUEST: u16 = 0x0003;
+// What RFC 3489 servers call OTHER-ADDRESS
+const ATTR_CHANGED_ADDRESS: u16 = 0x0005;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_OTHER_ADDRESS: u16 = 0x802c;
+
+const CHANGE_IP: u32 = 0x04;
+const CHANGE_PORT: u32 = 0x02;
+
+const FAMILY_V4: u8 = 0x01;
+const FAMILY_V6: u8 = 0x02;
+
+// RFC 5389 7.2.1: the first resend is after 500ms, doubling after that
+const FIRST_RESEND_MS: u64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+  // Our address is not translated
+  Open,
+  // Anyone may send to our mapping
+  FullCone,
+  // Only IPs we have sent to may send to our mapping
+  Restricted,
+  // Only the IPs and ports we have sent to may send to our mapping
+  PortRestricted,
+  // Each destination gets its own mapping, so punching rarely works
+  Symmetric,
+  // One mapping for every destination, but the server cannot test filtering
+  Cone,
+  // No STUN server answered
+  Blocked,
+  // The server could not run the tests which tell the types apart
+  Unknown,
+}
+
+impl NatType {
+  pub fn name(&self) -> &'static str {
+    match self {
+      NatType::Open => "open",
+      NatType::FullCone => "full cone",
+      NatType::Restricted => "restricted",
+      NatType::PortRestricted => "port restricted",
+      NatType::Symmetric => "symmetric",
+      NatType::Cone => "cone",
+      NatType::Blocked => "blocked",
+      NatType::Unknown => "unknown",
+    }
+  }
+
+  /**
+   * Whether peers can reach our mapped address without punching first.
+   */
+  pub fn is_reachable(&self) -> bool {
+    matches!(self, NatType::Open | NatType::FullCone)
+  }
+}
+
+impl fmt::Display for NatType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.name())
+  }
+}
+
+/**
+ * What a probe from one listener found out.
+ */
+#[derive(Debug, Clone)]
+pub struct StunResult {
+  pub listener: SocketAddr,
+  // The server which answered the first test, if any did
+  pub server: Option<SocketAddr>,
+  pub mapped: Option<SocketAddr>,
+  pub nat_type: NatType,
+  pub checked: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Test {
+  // Test 1 against the i'th server, trying the next until one answers
+  Mapping(usize),
+  ChangeIpPort,
+  OtherAddress,
+  ChangePort,
+}
+
+#[derive(Debug)]
+struct Response {
+  txid: [u8; TXID_LEN],
+  success: bool,
+  mapped: Option<SocketAddr>,
+  other: Option<SocketAddr>,
+}
+
+/**
+ * Runs the tests one request at a time. The owner of the socket sends
+ * what poll() returns and passes every STUN message it receives to handle().
+ */
+#[derive(Debug)]
+pub struct StunProbe {
+  listener: SocketAddr,
+  // The address our mapping is compared with to tell whether there is a NAT
+  local_ip: Option<IpAddr>,
+  servers: Vec<SocketAddr>,
+  timeout: Duration,
+  test: Test,
+  dest: SocketAddr,
+  request: Vec<u8>,
+  txid: [u8; TXID_LEN],
+  started: Instant,
+  next_send: Instant,
+  resend_wait: Duration,
+  server: Option<SocketAddr>,
+  mapped: Option<SocketAddr>,
+  other: Option<SocketAddr>,
+  change_supported: bool,
+  result: Option<NatType>,
+  // When the result was found
+  checked: SystemTime,
+}
+
+impl StunProbe {
+  /**
+   * Each test gives up after timeout without an answer.
+   */
+  pub fn new(listener: SocketAddr, servers: Vec<SocketAddr>, timeout: Duration) -> StunProbe {
+    let now = Instant::now();
+    let local_ip = servers.first().and_then(|server| local_ip_towards(listener, *server));
+    let mut probe = StunProbe {
+      listener,
+      local_ip,
+      servers,
+      timeout,
+      test: Test::Mapping(0),
+      dest: listener,
+      request: Vec::new(),
+      txid: [0; TXID_LEN],
+      started: now,
+      next_send: now,
+      resend_wait: Duration::from_millis(FIRST_RESEND_MS),
+      server: None,
+      mapped: None,
+      other: None,
+      change_supported: true,
+      result: None,
+      checked: SystemTime::now(),
+    };
+    match probe.servers.first() {
+      Some(server) => {
+        let server = *server;
+        probe.start(Test::Mapping(0), server, 0);
+      }
+      None => probe.finish(NatType::Blocked),
+    }
+    probe
+  }
+
+  fn start(&mut self, test: Test, dest: SocketAddr, change: u32) {
+    let now = Instant::now();
+    rand::rngs::OsRng.fill_bytes(&mut self.txid);
+    self.test = test;
+    self.dest = dest;
+    self.request = encode_binding_request(&self.txid, change);
+    self.started = now;
+    self.next_send = now;
+    self.resend_wait = Duration::from_millis(FIRST_RESEND_MS);
+  }
+
+  fn finish(&mut self, nat_type: NatType) {
+    self.result = Some(nat_type);
+    self.checked = SystemTime::now();
+  }
+
+  /**
+   * Where test 3 is sent: the server's other address, or another server.
+   */
+  fn other_server(&self) -> Option<SocketAddr> {
+    self.other.or_else(|| self.servers.iter().find(|s| Some(**s) != self.server).copied())
+  }
+
+  fn after_change_ip_port(&mut self) {
+    match self.other_server() {
+      Some(other) => self.start(Test::OtherAddress, other, 0),
+      None => self.finish(NatType::Unknown),
+    }
+  }
+
+  fn after_other_address(&mut self) {
+    match self.server {
+      Some(server) if self.change_supported => self.start(Test::ChangePort, server, CHANGE_PORT),
+      _ => self.finish(NatType::Cone),
+    }
+  }
+
+  fn timed_out(&mut self) {
+    match self.test {
+      Test::Mapping(i) => match self.servers.get(i + 1) {
+        Some(next) => {
+          let next = *next;
+          self.start(Test::Mapping(i + 1), next, 0);
+        }
+        None => self.finish(NatType::Blocked),
+      },
+      Test::ChangeIpPort => self.after_change_ip_port(),
+      // We cannot tell whether the mapping depends on the destination
+      Test::OtherAddress => self.finish(NatType::Unknown),
+      Test::ChangePort => self.finish(NatType::PortRestricted),
+    }
+  }
+
+  /**
+   * Returns the request to send now, if any, moving on to the next
+   * test when the current one has gone unanswered for too long.
+   */
+  pub fn poll(&mut self, now: Instant) -> Option<(SocketAddr, Vec<u8>)> {
+    if self.result.is_none() && now.duration_since(self.started) >= self.timeout {
+      self.timed_out();
+    }
+    if self.result.is_some() || now < self.next_send {
+      return None;
+    }
+    self.next_send = now + self.resend_wait;
+    self.resend_wait *= 2;
+    Some((self.dest, self.request.clone()))
+  }
+
+  /**
+   * When poll() next has something to do.
+   */
+  pub fn next_wakeup(&self) -> Option<Instant> {
+    if self.result.is_some() {
+      return None;
+    }
+    Some(std::cmp::min(self.next_send, self.started + self.timeout))
+  }
+
+  /**
+   * Returns false for messages which do not answer our current request.
+   */
+  pub fn handle(&mut self, buf: &[u8]) -> bool {
+    let response = match decode_response(buf) {
+      Some(response) if response.txid == self.txid && self.result.is_none() => response,
+      _ => return false,
+    };
+    match (self.test, response.success) {
+      (Test::Mapping(_), true) => {
+        self.server = Some(self.dest);
+        self.mapped = response.mapped;
+        self.other = response.other;
+        match response.mapped {
+          Some(mapped) if Some(mapped) == self.local_ip.map(|ip| SocketAddr::new(ip, self.listener.port())) => {
+            self.finish(NatType::Open);
+          }
+          Some(_) => {
+            let server = self.dest;
+            self.start(Test::ChangeIpPort, server, CHANGE_IP | CHANGE_PORT);
+          }
+          None => self.finish(NatType::Unknown),
+        }
+      }
+      (Test::Mapping(_), false) => self.timed_out(),
+      (Test::ChangeIpPort, true) => self.finish(NatType::FullCone),
+      (Test::ChangeIpPort, false) => {
+        self.change_supported = false;
+        self.after_change_ip_port();
+      }
+      (Test::OtherAddress, true) => {
+        if response.mapped != self.mapped {
+          self.finish(NatType::Symmetric);
+        } else {
+          self.after_other_address();
+        }
+      }
+      (Test::OtherAddress, false) => self.finish(NatType::Unknown),
+      (Test::ChangePort, true) => self.finish(NatType::Restricted),
+      (Test::ChangePort, false) => self.finish(NatType::Cone),
+    }
+    true
+  }
+
+  pub fn result(&self) -> Option<StunResult> {
+    Some(StunResult {
+      listener: self.listener,
+      server: self.server,
+      mapped: self.mapped,
+      nat_type: self.result?,
+      checked: self.checked,
+    })
+  }
+}
+
+/**
+ * The IP of the interface a listener bound to a wildcard address reaches server through.
+ */
+fn local_ip_towards(listener: SocketAddr, server: SocketAddr) -> Option<IpAddr> {
+  if !listener.ip().is_unspecified() {
+    return Some(listener.ip());
+  }
+  let socket = UdpSocket::bind(SocketAddr::new(listener.ip(), 0)).ok()?;
+  socket.connect(server).ok()?;
+  socket.local_addr().ok().map(|a| a.ip())
+}
+
+/**
+ * STUN messages start with two zero bits and carry the magic cookie,
+ * which meili packets (starting b"MEIL") never do.
+ */
+pub fn is_stun_message(buf: &[u8]) -> bool {
+  buf.len() >= HEADER_LEN && buf[0] & 0xc0 == 0 && buf[4..8] == MAGIC_COOKIE
+}
+
+fn encode_binding_request(txid: &[u8; TXID_LEN], change: u32) -> Vec<u8> {
+  let mut attrs = Vec::new();
+  if change != 0 {
+    attrs.extend_from_slice(&ATTR_CHANGE_REQUEST.to_be_bytes());
+    attrs.extend_from_slice(&4u16.to_be_bytes());
+    attrs.extend_from_slice(&change.to_be_bytes());
+  }
+  let mut buf = Vec::with_capacity(HEADER_LEN + attrs.len());
+  buf.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+  buf.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+  buf.extend_from_slice(&MAGIC_COOKIE);
+  buf.extend_from_slice(txid);
+  buf.extend_from_slice(&attrs);
+  buf
+}
+
+fn decode_response(buf: &[u8]) -> Option<Response> {
+  if !is_stun_message(buf) {
+    return None;
+  }
+  let success = match u16::from_be_bytes([buf[0], buf[1]]) {
+    BINDING_SUCCESS => true,
+    BINDING_ERROR => false,
+    _ => return None,
+  };
+  let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+  if buf.len() != HEADER_LEN + len {
+    return None;
+  }
+  let mut response = Response {
+    txid: [0; TXID_LEN],
+    success,
+    mapped: None,
+    other: None,
+  };
+  response.txid.copy_from_slice(&buf[8..HEADER_LEN]);
+
+  let mut xor_mapped = None;
+  let mut rest = &buf[HEADER_LEN..];
+  while rest.len() >= 4 {
+    let attr_type = u16::from_be_bytes([rest[0], rest[1]]);
+    let attr_len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+    let value = rest.get(4..4 + attr_len)?;
+    match attr_type {
+      ATTR_XOR_MAPPED_ADDRESS => xor_mapped = decode_address(value, Some(&buf[4..HEADER_LEN])),
+      ATTR_MAPPED_ADDRESS => response.mapped = decode_address(value, None),
+      ATTR_OTHER_ADDRESS | ATTR_CHANGED_ADDRESS => response.other = decode_address(value, None),
+      _ => {}
+    }
+    // Attributes are padded to a multiple of 4 bytes
+    let padded = (4 + attr_len + 3) & !3;
+    rest = rest.get(padded..).unwrap_or(&[]);
+  }
+  // RFC 5389 servers send both, XOR-MAPPED-ADDRESS survives NATs rewriting addresses in payloads
+  if xor_mapped.is_some() {
+    response.mapped = xor_mapped;
+  }
+  Some(response)
+}
+
+/**
+ * Address attributes are a reserved byte, the family, the port and the ip.
+ * XOR-MAPPED-ADDRESS xors them with the magic cookie and transaction id (xor_with).
+ */
+fn decode_address(value: &[u8], xor_with: Option<&[u8]>) -> Option<SocketAddr> {
+  let ip_len = match *value.get(1)? {
+    FAMILY_V4 => 4,
+    FAMILY_V6 => 16,
+    _ => return None,
+  };
+  let mut port_ip = value.get(2..4 + ip_len)?.to_vec();
+  if let Some(xor_with) = xor_with {
+    for (i, b) in port_ip.iter_mut().enumerate() {
+      // The port is xored with the first 2 bytes of the cookie, the ip from the cookie on
+      *b ^= xor_with[if i < 2 { i } else { i - 2 }];
+    }
+  }
+  let port = u16::from_be_bytes([port_ip[0], port_ip[1]]);
+  let ip = if ip_len == 4 {
+    let mut octets = [0u8; 4];
+    octets.copy_from_slice(&port_ip[2..]);
+    IpAddr::V4(Ipv4Addr::from(octets))
+  } else {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&port_ip[2..]);
+    IpAddr::V6(Ipv6Addr::from(octets))
+  };
+  Some(SocketAddr::new(ip, port))
+}
+
+/**
+ * Runs a probe to completion on its own socket, for when the listeners
+ * are not running (eg meili about). The listener's address is used when it
+ * is free, otherwise another port on the same IP.
+ */
+pub fn probe_blocking(listener: SocketAddr, servers: Vec<SocketAddr>, timeout: Duration) -> io::Result<StunResult> {
+  let socket = match UdpSocket::bind(listener) {
+    Ok(socket) => socket,
+    Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => UdpSocket::bind(SocketAddr::new(listener.ip(), 0))?,
+    Err(e) => return Err(e),
+  };
+  let mut probe = StunProbe::new(socket.local_addr()?, servers, timeout);
+  let mut buf = [0u8; 1500];
+  loop {
+    let now = Instant::now();
+    if let Some((dest, request)) = probe.poll(now) {
+      socket.send_to(&request, dest)?;
+    }
+    if let Some(result) = probe.result() {
+      return Ok(result);
+    }
+    let wakeup = probe.next_wakeup().unwrap_or(now);
+    let wait = wakeup.saturating_duration_since(Instant::now());
+    // A zero read timeout is an error, and means to send right away anyway
+    if wait.is_zero() {
+      continue;
+    }
+    socket.set_read_timeout(Some(wait))?;
+    match socket.recv_from(&mut buf) {
+      Ok((n, _)) => {
+        probe.handle(&buf[..n]);
+      }
+      Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+      Err(e) => return Err(e),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::thread;
+
+  const TIMEOUT: Duration = Duration::from_millis(300);
+
+  // RFC 5769 2.2, a response carrying XOR-MAPPED-ADDRESS 192.0.2.1:32853
+  const RESPONSE_V4: [u8; 80] = [
+    0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86,
+    0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63,
+    0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+    0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3, 0x8c, 0x74, 0x89, 0xf9,
+    0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d, 0x4c, 0x96,
+  ];
+
+  // RFC 5769 2.3, the same with XOR-MAPPED-ADDRESS [2001:db8:1234:5678:11:2233:4455:6677]:32853
+  const RESPONSE_V6: [u8; 92] = [
+    0x01, 0x01, 0x00, 0x48, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86,
+    0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63,
+    0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa,
+    0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9, 0x00, 0x08, 0x00, 0x14,
+    0xa3, 0x82, 0x95, 0x4e, 0x4b, 0xe6, 0x7b, 0xf1, 0x17, 0x84, 0xc9, 0x7c, 0x82, 0x92, 0xc2, 0x75,
+    0xbf, 0xe3, 0xed, 0x41, 0x80, 0x28, 0x00, 0x04, 0xc8, 0xfb, 0x0b, 0x4c,
+  ];
+
+  const TXID: [u8; TXID_LEN] = [0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae];
+
+  #[test]
+  fn decode_xor_mapped_v4() {
+    let response = decode_response(&RESPONSE_V4).unwrap();
+    assert!(response.success);
+    assert_eq!(response.txid, TXID);
+    assert_eq!(response.mapped, Some("192.0.2.1:32853".parse().unwrap()));
+    assert_eq!(response.other, None);
+  }
+
+  #[test]
+  fn decode_xor_mapped_v6() {
+    let response = decode_response(&RESPONSE_V6).unwrap();
+    assert!(response.success);
+    assert_eq!(response.mapped, Some("[2001:db8:1234:5678:11:2233:4455:6677]:32853".parse().unwrap()));
+  }
+
+  #[test]
+  fn decode_rejects_truncated() {
+    assert!(decode_response(&RESPONSE_V4[..RESPONSE_V4.len() - 4]).is_none());
+    assert!(decode_response(&RESPONSE_V4[..HEADER_LEN - 1]).is_none());
+  }
+
+  /**
+   * The address attribute attr_type carries for addr, xored when it is
+   * XOR-MAPPED-ADDRESS.
+   */
+  fn encode_address(attr_type: u16, addr: SocketAddr, txid: &[u8]) -> Vec<u8> {
+    let (family, ip) = match addr.ip() {
+      IpAddr::V4(ip) => (FAMILY_V4, ip.octets().to_vec()),
+      IpAddr::V6(ip) => (FAMILY_V6, ip.octets().to_vec()),
+    };
+    let mut port_ip = addr.port().to_be_bytes().to_vec();
+    port_ip.extend_from_slice(&ip);
+    if attr_type == ATTR_XOR_MAPPED_ADDRESS {
+      let xor_with: Vec<u8> = MAGIC_COOKIE.iter().chain(txid).copied().collect();
+      for (i, b) in port_ip.iter_mut().enumerate() {
+        *b ^= xor_with[if i < 2 { i } else { i - 2 }];
+      }
+    }
+    let mut attr = attr_type.to_be_bytes().to_vec();
+    attr.extend_from_slice(&(2 + port_ip.len() as u16).to_be_bytes());
+    attr.extend_from_slice(&[0, family]);
+    attr.extend_from_slice(&port_ip);
+    attr
+  }
+
+  fn encode_response(msg_type: u16, txid: &[u8], attrs: &[u8]) -> Vec<u8> {
+    let mut buf = msg_type.to_be_bytes().to_vec();
+    buf.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&MAGIC_COOKIE);
+    buf.extend_from_slice(txid);
+    buf.extend_from_slice(attrs);
+    buf
+  }
+
+  #[test]
+  fn decode_mapped_and_other_address() {
+    // RFC 3489 servers send MAPPED-ADDRESS and CHANGED-ADDRESS
+    let mapped: SocketAddr = "198.51.100.2:4000".parse().unwrap();
+    let other: SocketAddr = "192.0.2.9:3479".parse().unwrap();
+    let mut attrs = encode_address(ATTR_MAPPED_ADDRESS, mapped, &TXID);
+    attrs.extend(encode_address(ATTR_CHANGED_ADDRESS, other, &TXID));
+    let response = decode_response(&encode_response(BINDING_SUCCESS, &TXID, &attrs)).unwrap();
+    assert_eq!(response.mapped, Some(mapped));
+    assert_eq!(response.other, Some(other));
+    // XOR-MAPPED-ADDRESS wins over a MAPPED-ADDRESS rewritten by a NAT
+    attrs.extend(encode_address(ATTR_XOR_MAPPED_ADDRESS, "203.0.113.1:5000".parse().unwrap(), &TXID));
+    let response = decode_response(&encode_response(BINDING_SUCCESS, &TXID, &attrs)).unwrap();
+    assert_eq!(response.mapped, Some("203.0.113.1:5000".parse().unwrap()));
+
+    let response = decode_response(&encode_response(BINDING_ERROR, &TXID, &[])).unwrap();
+    assert!(!response.success);
+  }
+
+  /**
+   * How a fake server answers a binding request.
+   */
+  enum Answer {
+    Ignore,
+    Error,
+    Mapped(SocketAddr),
+  }
+
+  /**
+   * A STUN server on loopback. answer is given the request's CHANGE-REQUEST
+   * flags and where it came from.
+   */
+  fn stun_server(answer: impl Fn(u32, SocketAddr) -> Answer + Send + 'static) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || {
+      let mut buf = [0u8; 1500];
+      while let Ok((n, from)) = socket.recv_from(&mut buf) {
+        let req = &buf[..n];
+        let txid = &req[8..HEADER_LEN];
+        let change = match req.get(HEADER_LEN..HEADER_LEN + 8) {
+          Some(attr) if attr[..2] == ATTR_CHANGE_REQUEST.to_be_bytes() => u32::from_be_bytes([attr[4], attr[5], attr[6], attr[7]]),
+          _ => 0,
+        };
+        let response = match answer(change, from) {
+          Answer::Ignore => continue,
+          Answer::Error => encode_response(BINDING_ERROR, txid, &[]),
+          Answer::Mapped(mapped) => encode_response(BINDING_SUCCESS, txid, &encode_address(ATTR_XOR_MAPPED_ADDRESS, mapped, txid)),
+        };
+        let _ = socket.send_to(&response, from);
+      }
+    });
+    addr
+  }
+
+  fn nat_type(servers: Vec<SocketAddr>) -> NatType {
+    probe_blocking("127.0.0.1:0".parse().unwrap(), servers, TIMEOUT).unwrap().nat_type
+  }
+
+  fn mapped() -> SocketAddr {
+    "203.0.113.5:40000".parse().unwrap()
+  }
+
+  #[test]
+  fn open() {
+    let server = stun_server(|_, from| Answer::Mapped(from));
+    let result = probe_blocking("127.0.0.1:0".parse().unwrap(), vec![server], TIMEOUT).unwrap();
+    assert_eq!(result.nat_type, NatType::Open);
+    assert_eq!(result.server, Some(server));
+    assert_eq!(result.mapped, Some(result.listener));
+  }
+
+  #[test]
+  fn full_cone() {
+    let server = stun_server(|_, _| Answer::Mapped(mapped()));
+    assert_eq!(nat_type(vec![server]), NatType::FullCone);
+  }
+
+  #[test]
+  fn restricted() {
+    let server = stun_server(|change, _| match change {
+      0 | CHANGE_PORT => Answer::Mapped(mapped()),
+      _ => Answer::Ignore,
+    });
+    let other = stun_server(|_, _| Answer::Mapped(mapped()));
+    assert_eq!(nat_type(vec![server, other]), NatType::Restricted);
+  }
+
+  #[test]
+  fn port_restricted() {
+    let server = stun_server(|change, _| if change == 0 { Answer::Mapped(mapped()) } else { Answer::Ignore });
+    let other = stun_server(|_, _| Answer::Mapped(mapped()));
+    assert_eq!(nat_type(vec![server, other]), NatType::PortRestricted);
+  }
+
+  #[test]
+  fn symmetric() {
+    let server = stun_server(|change, _| if change == 0 { Answer::Mapped(mapped()) } else { Answer::Ignore });
+    let other = stun_server(|_, _| Answer::Mapped("203.0.113.5:40001".parse().unwrap()));
+    assert_eq!(nat_type(vec![server, other]), NatType::Symmetric);
+  }
+
+  #[test]
+  fn cone_without_change_request() {
+    let server = stun_server(|change, _| if change == 0 { Answer::Mapped(mapped()) } else { Answer::Error });
+    let other = stun_server(|_, _| Answer::Mapped(mapped()));
+    assert_eq!(nat_type(vec![server, other]), NatType::Cone);
+  }
+
+  #[test]
+  fn unknown_without_another_server() {
+    let server = stun_server(|change, _| if change == 0 { Answer::Mapped(mapped()) } else { Answer::Error });
+    assert_eq!(nat_type(vec![server]), NatType::Unknown);
+  }
+
+  #[test]
+  fn tries_the_next_server() {
+    let silent = stun_server(|_, _| Answer::Ignore);
+    let server = stun_server(|_, _| Answer::Mapped(mapped()));
+    let result = probe_blocking("127.0.0.1:0".parse().unwrap(), vec![silent, server], TIMEOUT).unwrap();
+    assert_eq!(result.server, Some(server));
+    assert_eq!(result.nat_type, NatType::FullCone);
+  }
+
+  #[test]
+  fn blocked() {
+    let silent = stun_server(|_, _| Answer::Ignore);
+    assert_eq!(nat_type(vec![silent]), NatType::Blocked);
+    assert_eq!(nat_type(Vec::new()), NatType::Blocked);
+  }
+
+  #[test]
+  fn ignores_other_transactions() {
+    let mut probe = StunProbe::new("127.0.0.1:1".parse().unwrap(), vec!["127.0.0.1:2".parse().unwrap()], TIMEOUT);
+    assert!(probe.poll(Instant::now()).is_some());
+    assert!(!probe.handle(&RESPONSE_V4));
+    assert!(probe.result().is_none());
+  }
+
+  #[test]
+  fn checked_is_when_the_probe_finished() {
+    let mut probe = StunProbe::new("127.0.0.1:1".parse().unwrap(), Vec::new(), TIMEOUT);
+    let checked = probe.result().unwrap().checked;
+    thread::sleep(Duration::from_millis(10));
+    assert!(probe.poll(Instant::now()).is_none());
+    assert_eq!(probe.result().unwrap().checked, checked);
+  }
+}
@@ -0,0 +1,166 @@
+
+/*!
+ * The HTTP mailbox transport sends packets by PUTting them into the
+ * recipient's mailbox on a relay (see net::http_mailbox) and receives
+ * by periodically reading our own mailbox with a GET, then removing
+ * what it returned with a DELETE.
+ * All HTTP happens on a worker thread so recv_from() never blocks.
+ */
+
+use std::io;
+use std::thread;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError, RecvTimeoutError};
+use std::net::{TcpStream, SocketAddr};
+use std::time::{Duration, Instant};
+
+use crate::punwrap_r;
+use crate::config::ConfSocket;
+use crate::global::Global;
+use crate::identity::PUBLIC_KEY_LEN;
+use crate::net::{http, http_mailbox, packet};
+use super::{Transport, PeerAddr, wrong_transport};
+
+const POLL_INTERVAL_MS: u64 = 2000;
+const REQUEST_TIMEOUT_MS: u64 = 5000;
+
+pub struct HttpMailboxTransport {
+  server: SocketAddr,
+  // Each packet, its recipient and whether to count it as flushed Data once PUT
+  outgoing: Sender<(Vec<u8>, [u8; PUBLIC_KEY_LEN], bool)>,
+  incoming: Receiver<Vec<u8>>,
+}
+
+impl Transport for HttpMailboxTransport {
+  fn bind(conf_socket: &ConfSocket, global: &Arc<Global>) -> io::Result<HttpMailboxTransport> {
+    let (outgoing_tx, outgoing_rx) = channel();
+    let (incoming_tx, incoming_rx) = channel();
+    let server = conf_socket.socket;
+    let global = global.clone();
+    thread::spawn(move || {
+      run_worker(server, global, outgoing_rx, incoming_tx);
+    });
+    Ok(HttpMailboxTransport {
+      server,
+      outgoing: outgoing_tx,
+      incoming: incoming_rx,
+    })
+  }
+
+  fn name(&self) -> String {
+    format!("http://{}{}", self.server, http_mailbox::MAILBOX_PATH)
+  }
+
+  fn send_to(&mut self, buf: &[u8], peer: &PeerAddr) -> io::Result<()> {
+    self.queue_put(buf, peer, false)
+  }
+
+  fn send_counted(&mut self, buf: &[u8], peer: &PeerAddr, _global: &Global) -> io::Result<()> {
+    self.queue_put(buf, peer, true)
+  }
+
+  fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, PeerAddr)> {
+    match self.incoming.try_recv() {
+      Ok(packet) => {
+        if packet.len() > buf.len() {
+          return Err(io::Error::new(io::ErrorKind::InvalidData, "packet too large"));
+        }
+        buf[..packet.len()].copy_from_slice(&packet);
+        let sender = packet::peek_sender_id(&packet).unwrap_or([0; PUBLIC_KEY_LEN]);
+        Ok((packet.len(), PeerAddr::HttpMailbox(self.server, sender)))
+      }
+      Err(TryRecvError::Empty) => Err(io::Error::new(io::ErrorKind::WouldBlock, "no mail")),
+      Err(TryRecvError::Disconnected) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "http mailbox worker exited")),
+    }
+  }
+}
+
+impl HttpMailboxTransport {
+  fn queue_put(&self, buf: &[u8], peer: &PeerAddr, counted: bool) -> io::Result<()> {
+    match peer {
+      PeerAddr::HttpMailbox(server, recipient) if *server == self.server => {
+        self.outgoing.send((buf.to_vec(), *recipient, counted))
+          .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "http mailbox worker exited"))
+      }
+      _ => Err(wrong_transport(peer, &self.name())),
+    }
+  }
+}
+
+fn run_worker(server: SocketAddr, global: Arc<Global>, outgoing: Receiver<(Vec<u8>, [u8; PUBLIC_KEY_LEN], bool)>, incoming: Sender<Vec<u8>>) {
+  let own_path = http_mailbox::mailbox_path(&global.identity.public_key());
+  let poll_interval = Duration::from_millis(POLL_INTERVAL_MS);
+  let mut next_poll = Instant::now();
+  loop {
+    // Sleep until there is something to send or it is time to poll
+    let until_poll = next_poll.saturating_duration_since(Instant::now());
+    match outgoing.recv_timeout(until_poll) {
+      Ok((packet, recipient, counted)) => {
+        let path = http_mailbox::mailbox_path(&recipient);
+        match request(server, "PUT", &path, vec![], &packet) {
+          Ok(response) if response.status() == Some(204) => {
+            if counted {
+              global.count_flushed_data(&PeerAddr::HttpMailbox(server, recipient));
+            }
+          }
+          Ok(response) => println!("http mailbox PUT {} answered '{}'", path, response.start_line),
+          Err(e) => println!("http mailbox PUT {} e={:?}", path, e),
+        }
+      }
+      Err(RecvTimeoutError::Timeout) => {}
+      Err(RecvTimeoutError::Disconnected) => return,
+    }
+
+    if Instant::now() >= next_poll {
+      next_poll = Instant::now() + poll_interval;
+      let auth = http_mailbox::auth_header(&global.identity, &own_path);
+      match request(server, "GET", &own_path, vec![(http_mailbox::AUTH_HEADER, auth)], &[]) {
+        Ok(response) if response.status() == Some(200) => {
+          let mut body = &response.body[..];
+          while let Ok(Some(frame_len)) = packet::frame_len(body) {
+            if frame_len > body.len() {
+              break;
+            }
+            punwrap_r!(incoming.send(body[..frame_len].to_vec()), return);
+            body = &body[frame_len..];
+          }
+          if !response.body.is_empty() {
+            global.wake_listeners();
+            acknowledge(server, &global, &own_path, &response);
+          }
+        }
+        Ok(response) => println!("http mailbox GET {} answered '{}'", own_path, response.start_line),
+        Err(e) => println!("http mailbox GET {} e={:?}", own_path, e),
+      }
+    }
+  }
+}
+
+/**
+ * Removes the packets a GET returned from the mailbox, so they are not returned again.
+ */
+fn acknowledge(server: SocketAddr, global: &Global, own_path: &str, response: &http::HttpMessage) {
+  let cursor = match response.headers.get(&http_mailbox::CURSOR_HEADER.to_lowercase()) {
+    Some(cursor) => cursor,
+    None => {
+      println!("http mailbox GET {} answered without a {}", own_path, http_mailbox::CURSOR_HEADER);
+      return;
+    }
+  };
+  let ack = http_mailbox::ack_header(&global.identity, own_path, cursor);
+  match request(server, "DELETE", own_path, vec![(http_mailbox::AUTH_HEADER, ack)], &[]) {
+    Ok(response) if response.status() == Some(204) => {}
+    Ok(response) => println!("http mailbox DELETE {} answered '{}'", own_path, response.start_line),
+    Err(e) => println!("http mailbox DELETE {} e={:?}", own_path, e),
+  }
+}
+
+fn request(server: SocketAddr, method: &str, path: &str, mut headers: Vec<(&str, String)>, body: &[u8]) -> io::Result<http::HttpMessage> {
+  let timeout = Duration::from_millis(REQUEST_TIMEOUT_MS);
+  let stream = TcpStream::connect_timeout(&server, timeout)?;
+  stream.set_read_timeout(Some(timeout))?;
+  stream.set_write_timeout(Some(timeout))?;
+  headers.push(("Host", server.to_string()));
+  http::write_message(&stream, &format!("{} {} HTTP/1.1", method, path), &headers, body)?;
+  http::read_message(&stream, http_mailbox::MAX_GET_BODY)
+}
@@ -0,0 +1,107 @@
+
+/*!
+ * A Transport carries encoded packets between meili nodes.
+ * The listener loop only ever talks to Box<dyn Transport>, so handlers
+ * and everything above them see a PeerAddr and never need to know
+ * whether a packet arrived over UDP, TCP or anything added later.
+ *
+ * recv_from() follows the std::net::UdpSocket non-blocking convention:
+ * it returns io::ErrorKind::WouldBlock when nothing is waiting.
+ * Transports backed by sockets register them with the listener's
+ * mio::Poll so the listener sleeps until one is readable; the rest
+ * call Global::wake_listeners() when they have something to deliver.
+ */
+
+use mio;
+
+use std::io;
+use std::fmt;
+use std::sync::Arc;
+use std::net::SocketAddr;
+
+use crate::config::ConfSocket;
+use crate::global::Global;
+use crate::identity::{self, PUBLIC_KEY_LEN};
+
+pub mod udp;
+pub mod tcp;
+pub mod http;
+
+pub use udp::UdpTransport;
+pub use tcp::TcpTransport;
+pub use http::HttpMailboxTransport;
+
+/**
+ * Where a packet came from, or where it should be sent.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+  Udp(SocketAddr),
+  Tcp(SocketAddr),
+  // A relay server and the public key whose mailbox on it we talk to
+  HttpMailbox(SocketAddr, [u8; PUBLIC_KEY_LEN]),
+  // A peer we reach through the UDP rendezvous server, see net::rendezvous
+  Relay(SocketAddr, [u8; PUBLIC_KEY_LEN]),
+}
+
+impl fmt::Display for PeerAddr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      PeerAddr::Udp(addr) => write!(f, "udp://{}", addr),
+      PeerAddr::Tcp(addr) => write!(f, "tcp://{}", addr),
+      PeerAddr::HttpMailbox(server, key) => write!(f, "http://{}/mailbox/{}", server, identity::fingerprint_of(key)),
+      PeerAddr::Relay(server, key) => write!(f, "relay://{}/{}", server, identity::fingerprint_of(key)),
+    }
+  }
+}
+
+pub trait Transport: Send {
+  fn bind(conf_socket: &ConfSocket, global: &Arc<Global>) -> io::Result<Self> where Self: Sized;
+
+  /**
+   * A human readable description used in log messages.
+   */
+  fn name(&self) -> String;
+
+  /**
+   * Registers every socket of the transport under token. Returns false
+   * for transports with nothing to register, which are instead polled
+   * each time the listener is woken.
+   */
+  fn register(&mut self, _registry: &mio::Registry, _token: mio::Token) -> io::Result<bool> {
+    Ok(false)
+  }
+
+  /**
+   * Undoes register, before the transport is closed or registered under another token.
+   */
+  fn deregister(&mut self, _registry: &mio::Registry) -> io::Result<()> {
+    Ok(())
+  }
+
+  fn send_to(&mut self, buf: &[u8], peer: &PeerAddr) -> io::Result<()>;
+
+  /**
+   * Sends a Data packet like send_to, and counts it in Global::count_flushed_data
+   * once it is written out. Transports which write from a worker count it there.
+   */
+  fn send_counted(&mut self, buf: &[u8], peer: &PeerAddr, global: &Global) -> io::Result<()> {
+    self.send_to(buf, peer)?;
+    global.count_flushed_data(peer);
+    Ok(())
+  }
+
+  fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, PeerAddr)>;
+
+  /**
+   * Sends a LAN announcement beacon to the multicast group the transport
+   * listens on, if any. Returns false when there is no group to send to.
+   */
+  fn announce(&mut self, _buf: &[u8], _ttl: u32) -> io::Result<bool> {
+    Ok(false)
+  }
+}
+
+pub fn wrong_transport(peer: &PeerAddr, transport: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, format!("cannot send to {} over {}", peer, transport))
+}
@@ -0,0 +1,535 @@
+/*!
+ * TCP carries the same packets as UDP, written back to back on a stream.
+ * Packets are split apart again using the payload length in their header.
+ * Connections are kept open and reused for replies in either direction.
+ * Neither connecting nor a peer which stops reading blocks the listener:
+ * unwritten bytes wait on the connection until mio reports it writable.
+ * Connections past MAX_CONNS are refused, and ones which stay silent are closed.
+ */
+
+use mio;
+use mio::net::{TcpListener, TcpStream};
+
+use std::io::{self, Read, Write};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::net::SocketAddr;
+
+use crate::config::ConfSocket;
+use crate::global::Global;
+use crate::net::packet;
+use super::{Transport, PeerAddr, wrong_transport};
+
+const CONNECT_TIMEOUT_MS: u64 = 3000;
+const READ_CHUNK_SIZE: usize = 4096;
+// Bytes waiting on a connection being established or a peer reading slowly
+const MAX_PENDING_BYTES: usize = 256 * 1024;
+const MAX_CONNS: usize = 256;
+// A connection must carry a whole packet this soon after it opens
+const FIRST_FRAME_TIMEOUT_MS: u64 = 10_000;
+// and is closed when no packet went either way for this long
+const IDLE_TIMEOUT_MS: u64 = 300_000;
+
+struct TcpConn {
+  stream: TcpStream,
+  rx_buf: Vec<u8>,
+  // When we started connecting, until the connection is established
+  connecting: Option<Instant>,
+  // Bytes not written yet, and where in them each counted Data packet ends
+  pending: Vec<u8>,
+  counted_ends: Vec<usize>,
+  // Whether the stream is registered for WRITABLE as well as READABLE
+  wants_writable: bool,
+  // When a whole packet last went either way, and whether this one is past its first
+  last_frame: Instant,
+  framed: bool,
+}
+
+impl TcpConn {
+  fn new(stream: TcpStream, connecting: bool) -> io::Result<TcpConn> {
+    stream.set_nodelay(true)?;
+    Ok(TcpConn {
+      stream,
+      rx_buf: Vec::new(),
+      connecting: if connecting { Some(Instant::now()) } else { None },
+      pending: Vec::new(),
+      counted_ends: Vec::new(),
+      wants_writable: connecting,
+      last_frame: Instant::now(),
+      // We open connections to send on them, only accepted ones must prove themselves
+      framed: connecting,
+    })
+  }
+
+  /**
+   * Returns true once the connection is established.
+   */
+  fn finish_connect(&mut self) -> io::Result<bool> {
+    let started = match self.connecting {
+      Some(started) => started,
+      None => return Ok(true),
+    };
+    if let Some(e) = self.stream.take_error()? {
+      return Err(e);
+    }
+    match self.stream.peer_addr() {
+      Ok(_) => {}
+      Err(ref e) if e.kind() == io::ErrorKind::NotConnected || e.kind() == io::ErrorKind::WouldBlock => {
+        if started.elapsed() > Duration::from_millis(CONNECT_TIMEOUT_MS) {
+          return Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out"));
+        }
+        return Ok(false);
+      }
+      Err(e) => return Err(e),
+    }
+    self.connecting = None;
+    Ok(true)
+  }
+
+  /**
+   * Writes what is pending once the connection is established, then listens
+   * for WRITABLE only while bytes are left. Returns how many counted Data
+   * packets were written out completely.
+   */
+  fn flush_registered(&mut self, registration: &Option<(mio::Registry, mio::Token)>) -> io::Result<usize> {
+    let mut flushed = 0;
+    if self.finish_connect()? {
+      flushed = self.flush()?;
+    }
+    let wants_writable = self.connecting.is_some() || !self.pending.is_empty();
+    if wants_writable != self.wants_writable {
+      if let Some((registry, token)) = registration {
+        let interest = if wants_writable { mio::Interest::READABLE | mio::Interest::WRITABLE } else { mio::Interest::READABLE };
+        registry.reregister(&mut self.stream, *token, interest)?;
+      }
+      self.wants_writable = wants_writable;
+    }
+    Ok(flushed)
+  }
+
+  /**
+   * Queues buf behind what is pending, counted or not, and writes as much as the socket takes.
+   */
+  fn send(&mut self, buf: &[u8], counted: bool, registration: &Option<(mio::Registry, mio::Token)>) -> io::Result<usize> {
+    if self.pending.len() + buf.len() > MAX_PENDING_BYTES {
+      let reason = if self.connecting.is_some() { "still connecting" } else { "peer is not reading" };
+      return Err(io::Error::new(io::ErrorKind::WouldBlock, reason));
+    }
+    self.pending.extend_from_slice(buf);
+    if counted {
+      self.counted_ends.push(self.pending.len());
+    }
+    self.last_frame = Instant::now();
+    self.flush_registered(registration)
+  }
+
+  /**
+   * Writes pending bytes until the socket would block.
+   */
+  fn flush(&mut self) -> io::Result<usize> {
+    let mut written = 0;
+    let mut result = Ok(());
+    while written < self.pending.len() {
+      match self.stream.write(&self.pending[written..]) {
+        Ok(0) => {
+          result = Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed"));
+          break;
+        }
+        Ok(n) => written += n,
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(e) => {
+          result = Err(e);
+          break;
+        }
+      }
+    }
+    self.pending.drain(..written);
+    let flushed = self.counted_ends.iter().take_while(|end| **end <= written).count();
+    self.counted_ends.drain(..flushed);
+    for end in self.counted_ends.iter_mut() {
+      *end -= written;
+    }
+    result.map(|_| flushed)
+  }
+
+  fn is_idle(&self) -> bool {
+    let timeout = if self.framed { IDLE_TIMEOUT_MS } else { FIRST_FRAME_TIMEOUT_MS };
+    self.last_frame.elapsed() > Duration::from_millis(timeout)
+  }
+
+  /**
+   * Reads until a complete packet is buffered or nothing more is available,
+   * rejecting a packet longer than max_frame as soon as its header arrives.
+   * Returns false once the peer hung up.
+   */
+  fn fill(&mut self, max_frame: usize) -> io::Result<bool> {
+    let mut chunk = [0; READ_CHUNK_SIZE];
+    loop {
+      match packet::frame_len(&self.rx_buf) {
+        Ok(Some(frame_len)) if frame_len > max_frame => {
+          return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} byte packet is too large", frame_len)));
+        }
+        Ok(Some(frame_len)) if self.rx_buf.len() >= frame_len => return Ok(true),
+        Ok(_) => {}
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+      }
+      match self.stream.read(&mut chunk) {
+        Ok(0) => return Ok(false),
+        Ok(n) => self.rx_buf.extend_from_slice(&chunk[..n]),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+        Err(e) => return Err(e),
+      }
+    }
+  }
+
+  /**
+   * Moves the first complete packet into buf, if one has arrived.
+   */
+  fn take_frame(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    let frame_len = match packet::frame_len(&self.rx_buf) {
+      Ok(Some(frame_len)) => frame_len,
+      Ok(None) => return Ok(None),
+      Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+    };
+    if frame_len > buf.len() {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} byte packet is too large", frame_len)));
+    }
+    if self.rx_buf.len() < frame_len {
+      return Ok(None);
+    }
+    buf[..frame_len].copy_from_slice(&self.rx_buf[..frame_len]);
+    self.rx_buf.drain(..frame_len);
+    self.last_frame = Instant::now();
+    self.framed = true;
+    Ok(Some(frame_len))
+  }
+}
+
+pub struct TcpTransport {
+  addr: SocketAddr,
+  listener: TcpListener,
+  conns: HashMap<SocketAddr, TcpConn>,
+  // Connections opened after register() must be registered too
+  registration: Option<(mio::Registry, mio::Token)>,
+  global: Arc<Global>,
+}
+
+impl TcpTransport {
+  fn add_conn(&mut self, addr: SocketAddr, stream: TcpStream, connecting: bool) -> io::Result<()> {
+    let mut conn = TcpConn::new(stream, connecting)?;
+    if let Some((registry, token)) = &self.registration {
+      let interest = interest(&conn);
+      registry.register(&mut conn.stream, *token, interest)?;
+    }
+    self.conns.insert(addr, conn);
+    Ok(())
+  }
+
+  fn accept_pending(&mut self) {
+    loop {
+      match self.listener.accept() {
+        Ok((stream, addr)) => {
+          if self.conns.len() >= MAX_CONNS {
+            self.close_idle();
+          }
+          if self.conns.len() >= MAX_CONNS {
+            // Dropping the stream closes it
+            println!("tcp refused {}, {} connections are open", addr, self.conns.len());
+            continue;
+          }
+          if let Err(e) = self.add_conn(addr, stream, false) {
+            println!("tcp accept e={:?}", e);
+          }
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(e) => {
+          println!("tcp accept e={:?}", e);
+          break;
+        }
+      }
+    }
+  }
+
+  fn close_idle(&mut self) {
+    let idle: Vec<SocketAddr> = self.conns.iter().filter(|(_, conn)| conn.is_idle()).map(|(addr, _)| *addr).collect();
+    for addr in idle {
+      println!("tcp {} closed, it was idle", addr);
+      self.remove_conn(&addr);
+    }
+  }
+
+  fn remove_conn(&mut self, addr: &SocketAddr) {
+    if let Some(mut conn) = self.conns.remove(addr) {
+      if let Some((registry, _)) = &self.registration {
+        let _ = registry.deregister(&mut conn.stream);
+      }
+    }
+  }
+
+  fn count_flushed(&self, addr: SocketAddr, flushed: usize) {
+    for _ in 0..flushed {
+      self.global.count_flushed_data(&PeerAddr::Tcp(addr));
+    }
+  }
+
+  fn send(&mut self, buf: &[u8], peer: &PeerAddr, counted: bool) -> io::Result<()> {
+    let addr = match peer {
+      PeerAddr::Tcp(addr) => *addr,
+      _ => return Err(wrong_transport(peer, "tcp")),
+    };
+    if !self.conns.contains_key(&addr) {
+      if self.conns.len() >= MAX_CONNS {
+        self.close_idle();
+      }
+      if self.conns.len() >= MAX_CONNS {
+        return Err(io::Error::new(io::ErrorKind::WouldBlock, "too many connections"));
+      }
+      self.add_conn(addr, TcpStream::connect(addr)?, true)?;
+    }
+    let result = match self.conns.get_mut(&addr) {
+      Some(conn) => conn.send(buf, counted, &self.registration),
+      None => Ok(0),
+    };
+    match result {
+      Ok(flushed) => {
+        self.count_flushed(addr, flushed);
+        Ok(())
+      }
+      // A full buffer is the peer's to drain, the connection is still good
+      Err(e) if e.kind() == io::ErrorKind::WouldBlock => Err(e),
+      Err(e) => {
+        self.remove_conn(&addr);
+        Err(e)
+      }
+    }
+  }
+}
+
+/**
+ * A connection becomes writable once it is established or the peer has read
+ * enough, which wakes the listener to send what waited.
+ */
+fn interest(conn: &TcpConn) -> mio::Interest {
+  if conn.wants_writable {
+    mio::Interest::READABLE | mio::Interest::WRITABLE
+  } else {
+    mio::Interest::READABLE
+  }
+}
+
+impl Transport for TcpTransport {
+  fn bind(conf_socket: &ConfSocket, global: &Arc<Global>) -> io::Result<TcpTransport> {
+    let listener = TcpListener::bind(conf_socket.socket)?;
+    Ok(TcpTransport {
+      addr: conf_socket.socket,
+      listener,
+      conns: HashMap::new(),
+      registration: None,
+      global: global.clone(),
+    })
+  }
+
+  fn register(&mut self, registry: &mio::Registry, token: mio::Token) -> io::Result<bool> {
+    registry.register(&mut self.listener, token, mio::Interest::READABLE)?;
+    for conn in self.conns.values_mut() {
+      let interest = interest(conn);
+      registry.register(&mut conn.stream, token, interest)?;
+    }
+    self.registration = Some((registry.try_clone()?, token));
+    Ok(true)
+  }
+
+  fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+    // One connection failing must not leave the others registered
+    for (addr, conn) in self.conns.iter_mut() {
+      if let Err(e) = registry.deregister(&mut conn.stream) {
+        println!("tcp {} deregister e={:?}", addr, e);
+      }
+    }
+    self.registration = None;
+    registry.deregister(&mut self.listener)
+  }
+
+  fn name(&self) -> String {
+    format!("tcp://{}", self.addr)
+  }
+
+  fn send_to(&mut self, buf: &[u8], peer: &PeerAddr) -> io::Result<()> {
+    self.send(buf, peer, false)
+  }
+
+  /**
+   * The packet may wait on the connection, so it is counted once written out.
+   */
+  fn send_counted(&mut self, buf: &[u8], peer: &PeerAddr, _global: &Global) -> io::Result<()> {
+    self.send(buf, peer, true)
+  }
+
+  fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, PeerAddr)> {
+    self.accept_pending();
+    self.close_idle();
+
+    let mut closed = Vec::new();
+    let mut flushed = Vec::new();
+    let mut received = None;
+    for (addr, conn) in self.conns.iter_mut() {
+      // Wakes for a writable connection land here too
+      match conn.flush_registered(&self.registration) {
+        Ok(n) => flushed.push((*addr, n)),
+        Err(e) => {
+          println!("tcp {} e={:?}", addr, e);
+          closed.push(*addr);
+          continue;
+        }
+      }
+      if conn.connecting.is_some() {
+        continue;
+      }
+      let open = match conn.fill(buf.len()) {
+        Ok(open) => open,
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+          println!("tcp {} e={}", addr, e);
+          closed.push(*addr);
+          continue;
+        }
+        Err(e) => {
+          println!("tcp {} e={:?}", addr, e);
+          false
+        }
+      };
+      match conn.take_frame(buf) {
+        Ok(Some(num_bytes)) => {
+          received = Some((num_bytes, PeerAddr::Tcp(*addr)));
+          break;
+        }
+        Ok(None) => {}
+        Err(e) => {
+          // A stream we cannot frame cannot be recovered, drop it
+          println!("tcp {} e={}", addr, e);
+          closed.push(*addr);
+          continue;
+        }
+      }
+      if !open {
+        closed.push(*addr);
+      }
+    }
+    for (addr, n) in flushed {
+      self.count_flushed(addr, n);
+    }
+    for addr in closed {
+      self.remove_conn(&addr);
+    }
+
+    match received {
+      Some(r) => Ok(r),
+      None => Err(io::Error::new(io::ErrorKind::WouldBlock, "no complete packet")),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::fs;
+  use std::net::TcpStream as StdTcpStream;
+  use std::path::PathBuf;
+  use std::thread;
+
+  use crate::config::Config;
+  use crate::identity::Identity;
+  use crate::peers::PeerRegistry;
+
+  fn transport(name: &str) -> (PathBuf, TcpTransport) {
+    let app_dir = std::env::temp_dir().join(format!("meili-tcp-{}-{}", std::process::id(), name));
+    fs::create_dir_all(&app_dir).unwrap();
+    let global = Arc::new(Global::new(Identity::generate(), PeerRegistry::load(&app_dir), Arc::new(Config::default())));
+    let conf_socket = ConfSocket {
+      name: None,
+      socket: "127.0.0.1:0".parse().unwrap(),
+      forward: false,
+    };
+    (app_dir, TcpTransport::bind(&conf_socket, &global).unwrap())
+  }
+
+  fn poll_until(t: &mut TcpTransport, what: &str, done: impl Fn(&TcpTransport) -> bool) {
+    let mut buf = [0; 2048];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done(t) {
+      assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+      let _ = t.recv_from(&mut buf);
+      thread::sleep(Duration::from_millis(5));
+    }
+  }
+
+  #[test]
+  fn a_peer_which_does_not_read_does_not_block() {
+    let (app_dir, mut t) = transport("slow");
+    let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = PeerAddr::Tcp(server.local_addr().unwrap());
+
+    let started = Instant::now();
+    let chunk = vec![7; 16 * 1024];
+    let mut sent = 0;
+    loop {
+      match t.send_counted(&chunk, &peer, &t.global.clone()) {
+        Ok(()) => sent += chunk.len(),
+        Err(e) => {
+          assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+          break;
+        }
+      }
+      assert!(sent < 64 * 1024 * 1024, "the socket never filled up");
+    }
+    assert!(started.elapsed() < Duration::from_secs(1), "send blocked for {:?}", started.elapsed());
+    assert!(t.global.get_flushed_data(&peer) < sent / chunk.len());
+
+    // Once the peer reads, what waited is written out
+    let (mut stream, _) = server.accept().unwrap();
+    let reader = thread::spawn(move || {
+      let mut received = 0;
+      let mut buf = [0; 64 * 1024];
+      while received < sent {
+        received += stream.read(&mut buf).unwrap();
+      }
+      received
+    });
+    poll_until(&mut t, "the pending bytes", |t| t.global.get_flushed_data(&peer) == sent / chunk.len());
+    assert_eq!(reader.join().unwrap(), sent);
+    let _ = fs::remove_dir_all(&app_dir);
+  }
+
+  #[test]
+  fn connections_past_the_limit_are_refused() {
+    let (app_dir, mut t) = transport("limit");
+    let addr = t.listener.local_addr().unwrap();
+    let mut clients: Vec<StdTcpStream> = (0..MAX_CONNS + 1).map(|_| StdTcpStream::connect(addr).unwrap()).collect();
+    poll_until(&mut t, "every connection to be accepted", |t| t.conns.len() == MAX_CONNS);
+
+    let last = clients.pop().unwrap();
+    last.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!((&last).read(&mut [0; 16]).unwrap(), 0);
+    assert_eq!(t.conns.len(), MAX_CONNS);
+    let _ = fs::remove_dir_all(&app_dir);
+  }
+
+  #[test]
+  fn silent_connections_are_closed() {
+    let (app_dir, mut t) = transport("silent");
+    let addr = t.listener.local_addr().unwrap();
+    let client = StdTcpStream::connect(addr).unwrap();
+    // Half a packet header is not a packet
+    (&client).write_all(&[0; 4]).unwrap();
+    poll_until(&mut t, "the connection to be accepted", |t| t.conns.len() == 1);
+
+    for conn in t.conns.values_mut() {
+      conn.last_frame = Instant::now() - Duration::from_millis(FIRST_FRAME_TIMEOUT_MS + 1);
+    }
+    poll_until(&mut t, "the connection to be closed", |t| t.conns.is_empty());
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!((&client).read(&mut [0; 16]).unwrap(), 0);
+    let _ = fs::remove_dir_all(&app_dir);
+  }
+}
@@ -0,0 +1,99 @@
+
+use mio;
+
+use std::io;
+use std::sync::Arc;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+
+use crate::punwrap_r;
+use crate::config::ConfSocket;
+use crate::global::Global;
+use crate::net::packet::{self, Packet, MessageType};
+use crate::net::rendezvous;
+use super::{Transport, PeerAddr, wrong_transport};
+
+pub struct UdpTransport {
+  addr: SocketAddr,
+  socket: mio::net::UdpSocket,
+  // Signs the Relay packets wrapping what we send through a rendezvous server
+  global: Arc<Global>,
+}
+
+impl Transport for UdpTransport {
+  fn bind(conf_socket: &ConfSocket, global: &Arc<Global>) -> io::Result<UdpTransport> {
+    let s = mio::net::UdpSocket::bind(conf_socket.socket)?;
+
+    if conf_socket.socket.ip().is_multicast() {
+      match conf_socket.socket.ip() {
+        IpAddr::V4(ip_a) => {
+          punwrap_r!(s.join_multicast_v4(&ip_a, &Ipv4Addr::new(0,0,0,0)), nothing);
+        }
+        IpAddr::V6(ip_a) => {
+          punwrap_r!(s.join_multicast_v6(&ip_a, 0), nothing);
+        }
+      }
+    }
+
+    Ok(UdpTransport {
+      addr: conf_socket.socket,
+      socket: s,
+      global: global.clone(),
+    })
+  }
+
+  fn name(&self) -> String {
+    format!("udp://{}", self.addr)
+  }
+
+  fn register(&mut self, registry: &mio::Registry, token: mio::Token) -> io::Result<bool> {
+    registry.register(&mut self.socket, token, mio::Interest::READABLE)?;
+    Ok(true)
+  }
+
+  fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+    registry.deregister(&mut self.socket)
+  }
+
+  fn send_to(&mut self, buf: &[u8], peer: &PeerAddr) -> io::Result<()> {
+    match peer {
+      PeerAddr::Udp(addr) => {
+        self.socket.send_to(buf, *addr)?;
+        Ok(())
+      }
+      PeerAddr::Relay(server, recipient) => {
+        let relay = Packet::new(MessageType::Relay, rendezvous::encode_relay(recipient, buf));
+        self.socket.send_to(&relay.encode(&self.global.identity), *server)?;
+        Ok(())
+      }
+      _ => Err(wrong_transport(peer, "udp")),
+    }
+  }
+
+  fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, PeerAddr)> {
+    let (num_bytes, addr) = self.socket.recv_from(buf)?;
+    // Packets relayed by one of our rendezvous servers are handled as if
+    // their sender sent them to us, anyone else's are left for dispatch to reject
+    if let Some(inner) = packet::peek_payload(&buf[..num_bytes], MessageType::Relayed) {
+      if self.global.get_config().is_rendezvous_server(&addr) {
+        if let Some(sender) = packet::peek_sender_id(&buf[inner.clone()]) {
+          let inner_len = inner.len();
+          buf.copy_within(inner, 0);
+          return Ok((inner_len, PeerAddr::Relay(addr, sender)));
+        }
+      }
+    }
+    Ok((num_bytes, PeerAddr::Udp(addr)))
+  }
+
+  fn announce(&mut self, buf: &[u8], ttl: u32) -> io::Result<bool> {
+    if !self.addr.ip().is_multicast() {
+      return Ok(false);
+    }
+    // mio cannot set the ipv6 hop limit, which the OS defaults to 1 for multicast
+    if self.addr.is_ipv4() {
+      self.socket.set_multicast_ttl_v4(ttl)?;
+    }
+    self.socket.send_to(buf, self.addr)?;
+    Ok(true)
+  }
+}
@@ -0,0 +1,633 @@
+
+/*!
+ * UPnP port mappings only last as long as their lease, so once mappings
+ * are added the manager thread renews them at half their lease, searching
+ * for the gateway again each time in case it changed. remove_upnp_mappings
+ * is called when meili exits so the router forgets them straight away.
+ * Every listener with forward = true gets a mapping, all from the first of
+ * UPnP IGD, NAT-PMP and PCP which the router answers. When a reloaded
+ * config changes what is forwarded or how, the mappings are removed and
+ * set up again.
+ */
+
+use igd;
+
+use std::thread;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::net::{SocketAddr, SocketAddrV4, IpAddr, Ipv4Addr};
+
+use crate::config::Config;
+use crate::global::Global;
+use super::{natpmp, pcp};
+
+const MAPPING_DESCRIPTION: &str = "meili port mapping";
+// Public address sources which a port mapping gives, see Gateway::name
+const MAPPING_SOURCES: [&str; 3] = ["upnp", "nat-pmp", "pcp"];
+// How long to wait before searching again for a gateway we could not find
+const RETRY_INTERVAL_S: u64 = 60;
+// Mappings with no lease (lease 0) never expire, but the gateway may still change
+const PERMANENT_LEASE_CHECK_INTERVAL_S: u64 = 600;
+// NAT-PMP and PCP take a lifetime of 0 to mean delete, so a permanent
+// mapping is asked for as this and renewed like any other (RFC 6886 3.3)
+const NATPMP_PERMANENT_LIFETIME_S: u32 = 7200;
+// Longest the manager sleeps before checking for a reloaded config
+const MAX_IDLE_MS: u64 = 1000;
+
+/**
+ * A router we can ask for port mappings, and the protocol it speaks.
+ */
+#[derive(Debug, Clone)]
+pub enum Gateway {
+  Igd(igd::Gateway),
+  NatPmp(SocketAddrV4),
+  // PCP mappings belong to the nonce they were made with
+  Pcp(SocketAddrV4, [u8; pcp::NONCE_LEN]),
+}
+
+// Finds the router speaking one of the protocols, see attempt_upnp_setup
+type FindGateway = fn(&Config) -> Result<Gateway, String>;
+
+impl Gateway {
+  /**
+   * Also the source we give for the public address a mapping gets us.
+   */
+  pub fn name(&self) -> &'static str {
+    match self {
+      Gateway::Igd(_) => "upnp",
+      Gateway::NatPmp(_) => "nat-pmp",
+      Gateway::Pcp(_, _) => "pcp",
+    }
+  }
+
+  pub fn addr(&self) -> SocketAddrV4 {
+    match self {
+      Gateway::Igd(gw) => gw.addr,
+      Gateway::NatPmp(addr) => *addr,
+      Gateway::Pcp(addr, _) => *addr,
+    }
+  }
+
+  /**
+   * Looks for the same kind of gateway again, eg after the network changed.
+   */
+  fn search_again(&self, config: &Config) -> Result<Gateway, String> {
+    match self {
+      Gateway::Igd(_) => search_gateway(config).map(Gateway::Igd).map_err(|e| format!("{:?}", e)),
+      Gateway::NatPmp(_) => Ok(Gateway::NatPmp(SocketAddrV4::new(default_gateway(config)?, natpmp::PORT))),
+      Gateway::Pcp(_, nonce) => Ok(Gateway::Pcp(SocketAddrV4::new(default_gateway(config)?, natpmp::PORT), *nonce)),
+    }
+  }
+
+  /**
+   * Asks the gateway to forward external_port to local_addr and returns
+   * what it granted, which may be another port or a shorter lease.
+   */
+  fn add_port(&self, protocol: igd::PortMappingProtocol, local_addr: SocketAddrV4, external_port: u16, lease_duration_s: u32, timeout: Duration) -> Result<natpmp::Mapped, String> {
+    let lifetime_s = if lease_duration_s == 0 { NATPMP_PERMANENT_LIFETIME_S } else { lease_duration_s };
+    match self {
+      Gateway::Igd(gw) => {
+        let external_port = match gw.add_port(protocol, external_port, local_addr, lease_duration_s, MAPPING_DESCRIPTION) {
+          Ok(()) => external_port,
+          Err(e) => {
+            println!("upnp e={:?}", e);
+            // Attempt w/ random public port
+            gw.add_any_port(protocol, local_addr, lease_duration_s, MAPPING_DESCRIPTION).map_err(|e| format!("{:?}", e))?
+          }
+        };
+        Ok(natpmp::Mapped {
+          external_port,
+          lifetime_s: lease_duration_s,
+          external_ip: None,
+        })
+      }
+      Gateway::NatPmp(addr) => natpmp::add_port(*addr, protocol, local_addr, external_port, lifetime_s, timeout),
+      Gateway::Pcp(addr, nonce) => pcp::add_port(*addr, nonce, protocol, local_addr, external_port, lifetime_s, timeout),
+    }
+  }
+
+  fn remove_port(&self, protocol: igd::PortMappingProtocol, local_addr: SocketAddrV4, external_port: u16, timeout: Duration) -> Result<(), String> {
+    match self {
+      Gateway::Igd(gw) => gw.remove_port(protocol, external_port).map_err(|e| format!("{:?}", e)),
+      Gateway::NatPmp(addr) => natpmp::remove_port(*addr, protocol, local_addr, timeout),
+      Gateway::Pcp(addr, nonce) => pcp::remove_port(*addr, nonce, protocol, local_addr, timeout),
+    }
+  }
+
+  fn get_external_ip(&self, timeout: Duration) -> Result<Ipv4Addr, String> {
+    match self {
+      Gateway::Igd(gw) => gw.get_external_ip().map_err(|e| format!("{:?}", e)),
+      Gateway::NatPmp(addr) => natpmp::get_external_ip(*addr, timeout),
+      Gateway::Pcp(_, _) => Err("PCP gateways only give the external address with a mapping".to_string()),
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct UpnpMapping {
+  pub gateway: Gateway,
+  pub protocol: igd::PortMappingProtocol,
+  pub local_addr: SocketAddrV4,
+  pub external_port: u16,
+  pub lease_duration_s: u32,
+  pub renewed_at: Instant,
+  pub external_ip: Option<Ipv4Addr>,
+  // From upnp_gw_timeout_ms, kept so the mapping can be removed without the config
+  pub gw_timeout: Duration,
+}
+
+impl UpnpMapping {
+  /**
+   * Builds the mapping a gateway granted, asking for its external
+   * address if the grant did not include it.
+   */
+  fn granted(gateway: Gateway, protocol: igd::PortMappingProtocol, local_addr: SocketAddrV4, mapped: natpmp::Mapped, gw_timeout: Duration) -> UpnpMapping {
+    // Asked on every renewal as the ISP may hand the gateway a new address
+    let external_ip = match mapped.external_ip {
+      Some(ip) => Some(ip),
+      None => match gateway.get_external_ip(gw_timeout) {
+        Ok(ip) => Some(ip),
+        Err(e) => {
+          println!("Cannot get {} gateway's external address e={}", gateway.name(), e);
+          None
+        }
+      },
+    };
+    UpnpMapping {
+      gateway,
+      protocol,
+      local_addr,
+      external_port: mapped.external_port,
+      lease_duration_s: mapped.lifetime_s,
+      renewed_at: Instant::now(),
+      external_ip,
+      gw_timeout,
+    }
+  }
+
+  pub fn renew_due(&self) -> Instant {
+    if self.lease_duration_s == 0 {
+      return self.renewed_at + Duration::from_secs(PERMANENT_LEASE_CHECK_INTERVAL_S);
+    }
+    self.renewed_at + Duration::from_secs(self.lease_duration_s as u64 / 2)
+  }
+}
+
+/**
+ * A listener we ask the router to forward a public port to.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ForwardTarget {
+  protocol: igd::PortMappingProtocol,
+  // The listener's address, unspecified when it listens on every interface
+  local_addr: SocketAddrV4,
+  pref_public_port: u16,
+}
+
+impl ForwardTarget {
+  fn is_mapped_by(&self, mapping: &UpnpMapping) -> bool {
+    mapping.protocol == self.protocol && mapping.local_addr.port() == self.local_addr.port()
+  }
+}
+
+fn protocol_name(protocol: igd::PortMappingProtocol) -> &'static str {
+  match protocol {
+    igd::PortMappingProtocol::UDP => "udp",
+    igd::PortMappingProtocol::TCP => "tcp",
+  }
+}
+
+/**
+ * Every unicast IPv4 listener with forward = true. The listener on
+ * upnp_local_port asks for upnp_pref_public_port, the others for their own
+ * port. Configs with no forward = true listeners forward upnp_local_port over UDP.
+ */
+fn forward_targets(config: &Config) -> Vec<ForwardTarget> {
+  let listeners: Vec<_> = config.udp_sockets_to_listen_on.iter().map(|s| (igd::PortMappingProtocol::UDP, s))
+    .chain(config.tcp_sockets_to_listen_on.iter().map(|s| (igd::PortMappingProtocol::TCP, s)))
+    .filter(|(_, s)| s.forward)
+    .collect();
+  if listeners.is_empty() {
+    return vec![ForwardTarget {
+      protocol: igd::PortMappingProtocol::UDP,
+      local_addr: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.upnp_local_port as u16),
+      pref_public_port: config.upnp_pref_public_port as u16,
+    }];
+  }
+
+  let mut targets: Vec<ForwardTarget> = Vec::new();
+  for (protocol, listener) in listeners {
+    let local_addr = match forwardable_addr(&listener.socket) {
+      Some(addr) => addr,
+      None => continue,
+    };
+    let pref_public_port = if local_addr.port() == config.upnp_local_port as u16 {
+      config.upnp_pref_public_port as u16
+    } else {
+      local_addr.port()
+    };
+    let target = ForwardTarget {
+      protocol,
+      local_addr,
+      pref_public_port,
+    };
+    let is_duplicate = targets.iter().any(|t| t.protocol == protocol && t.local_addr.port() == local_addr.port());
+    if !is_duplicate {
+      targets.push(target);
+    }
+  }
+  targets
+}
+
+// igd only takes SocketAddrV4 so we cannot use ipv6 for this :(
+fn forwardable_addr(socket: &SocketAddr) -> Option<SocketAddrV4> {
+  match socket {
+    SocketAddr::V4(addr) if !addr.ip().is_multicast() => Some(*addr),
+    _ => None,
+  }
+}
+
+fn unmapped_targets(config: &Config, mappings: &[UpnpMapping]) -> Vec<ForwardTarget> {
+  forward_targets(config).into_iter()
+    .filter(|t| !mappings.iter().any(|m| t.is_mapped_by(m)))
+    .collect()
+}
+
+/**
+ * The manager runs for as long as meili does, even with port forwarding
+ * turned off, as a reloaded config may turn it on.
+ */
+pub fn spawn_upnp_manager(global: Arc<Global>) {
+  global.set_upnp_manager_running(true);
+  // We spawn this to a thread b/c gateway searches block
+  thread::spawn(move || {
+    run_upnp_manager(&global);
+    global.set_upnp_manager_running(false);
+  });
+}
+
+fn run_upnp_manager(global: &Global) {
+  let mut config = global.get_config();
+  let mut next_setup = Instant::now();
+  loop {
+    if global.is_upnp_torn_down() {
+      return;
+    }
+    let latest = global.get_config();
+    if !Arc::ptr_eq(&latest, &config) {
+      if upnp_settings_changed(&config, &latest) {
+        // Mappings are redone from scratch rather than worked out one by one
+        let mappings = global.get_upnp_mappings();
+        for mapping in &mappings {
+          remove_mapping(mapping);
+        }
+        if !mappings.is_empty() {
+          forget_mappings(global);
+        }
+        next_setup = Instant::now();
+      }
+      config = latest;
+    }
+    // The setup-upnp shell command asks us to try now
+    let requested = global.take_upnp_setup_request();
+    if requested {
+      next_setup = Instant::now();
+    }
+    if !config.attempt_upnp_port_forward {
+      thread::sleep(Duration::from_millis(MAX_IDLE_MS));
+      continue;
+    }
+
+    let now = Instant::now();
+    let mappings = global.get_upnp_mappings();
+    let is_missing_some = !unmapped_targets(&config, &mappings).is_empty();
+    if is_missing_some && now >= next_setup {
+      if let Err(e) = attempt_upnp_setup(&config, global) {
+        println!("upnp e={}, retrying in {}s", e, RETRY_INTERVAL_S);
+      }
+      next_setup = Instant::now() + Duration::from_secs(RETRY_INTERVAL_S);
+      continue;
+    }
+    let renew_due = mappings.iter().map(|m| m.renew_due()).min();
+    if let Some(renew_due) = renew_due {
+      if now >= renew_due || requested {
+        renew_mappings(&config, global, mappings);
+        // A gateway which forgot everything is searched for again straight away
+        next_setup = Instant::now();
+        continue;
+      }
+    }
+    let wake = match (renew_due, is_missing_some) {
+      (Some(renew_due), true) => std::cmp::min(renew_due, next_setup),
+      (Some(renew_due), false) => renew_due,
+      (None, _) => next_setup,
+    };
+    let idle = Duration::from_millis(MAX_IDLE_MS);
+    thread::sleep(std::cmp::min(wake.saturating_duration_since(now), idle));
+  }
+}
+
+/**
+ * Whether new asks the router for other mappings than old, or asks differently.
+ */
+fn upnp_settings_changed(old: &Config, new: &Config) -> bool {
+  old.attempt_upnp_port_forward != new.attempt_upnp_port_forward ||
+  old.upnp_gw_timeout_ms != new.upnp_gw_timeout_ms ||
+  old.upnp_lease_duration_s != new.upnp_lease_duration_s ||
+  old.port_mapping_gateway != new.port_mapping_gateway ||
+  forward_targets(old) != forward_targets(new)
+}
+
+/**
+ * Asks the gateway to extend the lease of every mapping. If the gateway
+ * moved the mappings are forgotten so the manager sets up new ones, and
+ * any the gateway refuses are dropped to be set up again later.
+ */
+fn renew_mappings(config: &Config, global: &Global, mappings: Vec<UpnpMapping>) {
+  let old_gw = match mappings.first() {
+    Some(mapping) => mapping.gateway.clone(),
+    None => return,
+  };
+  let gw = match old_gw.search_again(config) {
+    Ok(gw) => gw,
+    Err(e) => {
+      println!("Cannot find {} gateway to renew port mappings e={}", old_gw.name(), e);
+      forget_mappings(global);
+      return;
+    }
+  };
+  if gw.addr() != old_gw.addr() {
+    println!("{} gateway changed from {} to {}", gw.name(), old_gw.addr(), gw.addr());
+    // The old gateway is likely unreachable, but if it is not we tidy up
+    for mapping in &mappings {
+      if let Err(e) = old_gw.remove_port(mapping.protocol, mapping.local_addr, mapping.external_port, mapping.gw_timeout) {
+        println!("Cannot remove port mapping from old gateway e={}", e);
+      }
+    }
+    forget_mappings(global);
+    return;
+  }
+  let mut renewed = Vec::new();
+  for mapping in mappings {
+    match gw.add_port(mapping.protocol, mapping.local_addr, mapping.external_port, config.upnp_lease_duration_s, mapping.gw_timeout) {
+      Ok(mapped) => {
+        if mapped.external_port != mapping.external_port {
+          println!("{} gateway moved our mapping from public port :{} to :{}", gw.name(), mapping.external_port, mapped.external_port);
+        }
+        renewed.push(UpnpMapping::granted(gw.clone(), mapping.protocol, mapping.local_addr, mapped, mapping.gw_timeout));
+      }
+      Err(e) => {
+        println!("Cannot renew {} port mapping on public port :{} e={}", gw.name(), mapping.external_port, e);
+      }
+    }
+  }
+  store_mappings(global, renewed);
+}
+
+/**
+ * Records the mappings we hold and the public address they give us.
+ * If meili began exiting while the gateway was answering, the mappings
+ * are removed again instead.
+ */
+fn store_mappings(global: &Global, mappings: Vec<UpnpMapping>) {
+  if !global.set_upnp_mappings(mappings.clone()) {
+    for mapping in &mappings {
+      remove_mapping(mapping);
+    }
+    return;
+  }
+  // Peers are told of one address, which is where our first forwarded UDP listener is reachable
+  let advertised = mappings.iter().find(|m| m.protocol == igd::PortMappingProtocol::UDP);
+  let advertised = match advertised {
+    Some(mapping) => mapping,
+    None => {
+      global.clear_public_address(&MAPPING_SOURCES);
+      return;
+    }
+  };
+  let source = advertised.gateway.name();
+  match advertised.external_ip {
+    Some(ip) if is_public(ip) => {
+      global.set_public_address(Some(SocketAddr::new(IpAddr::V4(ip), advertised.external_port)), source);
+    }
+    Some(ip) => {
+      println!("{} gateway's external address {} is not public, we are likely behind another NAT", source, ip);
+      global.clear_public_address(&MAPPING_SOURCES);
+    }
+    None => {
+      global.clear_public_address(&MAPPING_SOURCES);
+    }
+  }
+}
+
+fn forget_mappings(global: &Global) {
+  global.set_upnp_mappings(Vec::new());
+  global.clear_public_address(&MAPPING_SOURCES);
+}
+
+/**
+ * Addresses a gateway may be given by an upstream NAT rather than the internet.
+ */
+fn is_public(ip: Ipv4Addr) -> bool {
+  let o = ip.octets();
+  // 100.64.0.0/10 is carrier grade NAT
+  let is_shared = o[0] == 100 && (o[1] & 0xc0) == 64;
+  !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || is_shared)
+}
+
+fn remove_mapping(mapping: &UpnpMapping) {
+  let name = mapping.gateway.name();
+  match mapping.gateway.remove_port(mapping.protocol, mapping.local_addr, mapping.external_port, mapping.gw_timeout) {
+    Ok(()) => println!("Removed {} port mapping on public port :{}", name, mapping.external_port),
+    Err(e) => println!("Cannot remove {} port mapping on public port :{} e={}", name, mapping.external_port, e),
+  }
+}
+
+/**
+ * Removes our port mappings from the gateway and stops the manager from
+ * adding more. Called on every way out of meili.
+ */
+pub fn remove_upnp_mappings(global: &Global) {
+  let mappings = global.tear_down_upnp();
+  for mapping in &mappings {
+    remove_mapping(mapping);
+  }
+  if !mappings.is_empty() {
+    global.clear_public_address(&MAPPING_SOURCES);
+  }
+}
+
+fn search_gateway(config: &Config) -> Result<igd::Gateway, igd::SearchError> {
+  let igd_opts = igd::SearchOptions {
+    timeout: Some(Duration::from_millis(config.upnp_gw_timeout_ms as u64)),
+    ..Default::default()
+  };
+  igd::search_gateway(igd_opts)
+}
+
+/**
+ * The router NAT-PMP and PCP requests go to: port_mapping_gateway if set,
+ * otherwise the gateway of the default route. The default route is only
+ * looked up on linux, elsewhere NAT-PMP and PCP are not tried unless
+ * port_mapping_gateway is set.
+ */
+fn default_gateway(config: &Config) -> Result<Ipv4Addr, String> {
+  if let Some(ip) = config.port_mapping_gateway {
+    return Ok(ip);
+  }
+  #[cfg(target_os = "linux")]
+  {
+    let routes = std::fs::read_to_string("/proc/net/route").map_err(|e| format!("Cannot read /proc/net/route: {}", e))?;
+    // Iface Destination Gateway Flags ..., addresses are little-endian hex
+    for line in routes.lines().skip(1) {
+      let fields: Vec<&str> = line.split_whitespace().collect();
+      if fields.len() < 4 || fields[1] != "00000000" {
+        continue;
+      }
+      let flags = u16::from_str_radix(fields[3], 16).unwrap_or(0);
+      // RTF_GATEWAY
+      if flags & 0x2 == 0 {
+        continue;
+      }
+      if let Ok(gw) = u32::from_str_radix(fields[2], 16) {
+        return Ok(Ipv4Addr::from(gw.swap_bytes()));
+      }
+    }
+    Err("No default route with a gateway".to_string())
+  }
+  #[cfg(not(target_os = "linux"))]
+  {
+    Err("The default route is only looked up on linux, set port_mapping_gateway to the router's address".to_string())
+  }
+}
+
+/**
+ * Our address on the interface which routes to the gateway, which is the
+ * address the gateway knows us by. Connecting a UDP socket sends nothing,
+ * it only makes the OS pick the route.
+ */
+fn lan_ip_towards(gateway: SocketAddrV4) -> Result<Ipv4Addr, String> {
+  let socket = natpmp::connect(Ipv4Addr::UNSPECIFIED, gateway)?;
+  natpmp::local_ip(&socket)
+}
+
+/**
+ * Lists the gateway's port mappings, so public ports which an earlier
+ * meili run left mapped to us can be taken back.
+ */
+fn igd_mapping_entries(gw: &igd::Gateway) -> Vec<igd::PortMappingEntry> {
+  let mut entries = Vec::new();
+  // 256 is a sanity check, gateways answer an error past their last entry
+  for i in 0..=256 {
+    match gw.get_generic_port_mapping_entry(i) {
+      Ok(entry) => {
+        println!(
+          "i={} external_port={} internal_client={} port_mapping_description={} lease_duration={}",
+          i, entry.external_port, entry.internal_client, entry.port_mapping_description, entry.lease_duration
+        );
+        entries.push(entry);
+      }
+      Err(igd::GetGenericPortMappingEntryError::RequestError(re))  => {
+        println!("re={:?}", re);
+        continue;
+      }
+      Err(_e)  => {
+        break;
+      }
+    }
+  }
+  entries
+}
+
+/**
+ * Asks the router to forward a public port to every forwarded listener
+ * which does not have one yet, trying UPnP IGD, then NAT-PMP, then PCP.
+ * Once the router has answered one of them, later mappings use the same.
+ */
+pub fn attempt_upnp_setup(config: &Config, global: &Global) -> Result<(), String> {
+  if !config.attempt_upnp_port_forward {
+    return Ok(());
+  }
+  let mut mappings = global.get_upnp_mappings();
+  let targets = unmapped_targets(config, &mappings);
+  if targets.is_empty() {
+    return Ok(());
+  }
+
+  if let Some(mapping) = mappings.first() {
+    let gw = mapping.gateway.clone();
+    let added = map_targets(config, &gw, &targets).map_err(|e| format!("{}: {}", gw.name(), e))?;
+    mappings.extend(added);
+    store_mappings(global, mappings);
+    return Ok(());
+  }
+
+  let gateways: [(&str, FindGateway); 3] = [
+    ("upnp", |config| search_gateway(config).map(Gateway::Igd).map_err(|e| format!("{:?}", e))),
+    ("nat-pmp", |config| Ok(Gateway::NatPmp(SocketAddrV4::new(default_gateway(config)?, natpmp::PORT)))),
+    ("pcp", |config| Ok(Gateway::Pcp(SocketAddrV4::new(default_gateway(config)?, natpmp::PORT), pcp::new_nonce()))),
+  ];
+  let mut errors = Vec::new();
+  for (name, find_gateway) in gateways.iter() {
+    match find_gateway(config).and_then(|gw| map_targets(config, &gw, &targets)) {
+      Ok(added) => {
+        store_mappings(global, added);
+        return Ok(());
+      }
+      Err(e) => errors.push(format!("{}: {}", name, e)),
+    }
+  }
+  Err(errors.join(", "))
+}
+
+/**
+ * Maps each target on gateway. Fails only if none could be mapped,
+ * printing why the others could not.
+ */
+fn map_targets(config: &Config, gateway: &Gateway, targets: &[ForwardTarget]) -> Result<Vec<UpnpMapping>, String> {
+  let gw_timeout = Duration::from_millis(config.upnp_gw_timeout_ms as u64);
+  let lan_ip = lan_ip_towards(gateway.addr())?;
+  println!("lan_ip_a={:?}", &lan_ip);
+  let existing = match gateway {
+    Gateway::Igd(gw) => igd_mapping_entries(gw),
+    // NAT-PMP and PCP hand back the mapping we already hold when asked again
+    _ => Vec::new(),
+  };
+
+  let mut mappings = Vec::new();
+  let mut errors = Vec::new();
+  for target in targets {
+    let local_ip = if target.local_addr.ip().is_unspecified() { lan_ip } else { *target.local_addr.ip() };
+    let local_addr = SocketAddrV4::new(local_ip, target.local_addr.port());
+    let stale = existing.iter().find(|entry| {
+      entry.port_mapping_description.contains("meili") &&
+      entry.protocol == target.protocol &&
+      entry.internal_client == format!("{}", local_ip) &&
+      entry.internal_port == local_addr.port()
+    });
+    let external_port = match stale {
+      Some(entry) => {
+        // Adding it again below resets the lease to ours
+        println!("Already have requested UPNP port mapping on public port :{}", entry.external_port);
+        entry.external_port
+      }
+      None => target.pref_public_port,
+    };
+    match gateway.add_port(target.protocol, local_addr, external_port, config.upnp_lease_duration_s, gw_timeout) {
+      Ok(mapped) => {
+        println!("Added requested {} port mapping on public port :{} for {}://{} for {}s",
+          gateway.name(), mapped.external_port, protocol_name(target.protocol), local_addr, mapped.lifetime_s
+        );
+        mappings.push(UpnpMapping::granted(gateway.clone(), target.protocol, local_addr, mapped, gw_timeout));
+      }
+      Err(e) => errors.push(format!("{}://{} {}", protocol_name(target.protocol), local_addr, e)),
+    }
+  }
+  if mappings.is_empty() {
+    return Err(errors.join(", "));
+  }
+  for e in errors {
+    println!("Cannot forward {}", e);
+  }
+  Ok(mappings)
+}
@@ -0,0 +1,72 @@
+
+/*!
+ * A CidrWalk is a seeded pseudo-random permutation of the offsets
+ * 0..size of an address range. Walking indices 0, 1, 2, ... through nth()
+ * visits every offset exactly once, so a scan may be resumed from
+ * nothing more than the seed and the index it stopped at.
+ *
+ * The permutation is a small Feistel network over the smallest even
+ * number of bits covering size; outputs which land outside the range
+ * are fed back through the network ("cycle walking") until they fit.
+ * Because the domain is less than 4x size this takes few iterations.
+ *
+ * This is a scan order, not cryptography.
+ */
+
+const ROUNDS: u64 = 6;
+
+#[derive(Debug, Clone)]
+pub struct CidrWalk {
+  size: u128,
+  seed: u64,
+  half_bits: u32,
+}
+
+impl CidrWalk {
+  pub fn new(size: u128, seed: u64) -> CidrWalk {
+    let bits = 128 - size.saturating_sub(1).leading_zeros();
+    CidrWalk {
+      size,
+      seed,
+      half_bits: bits.div_ceil(2),
+    }
+  }
+
+  pub fn size(&self) -> u128 {
+    self.size
+  }
+
+  /**
+   * The offset visited at step index, for index < size.
+   */
+  pub fn nth(&self, index: u128) -> u128 {
+    if self.half_bits == 0 {
+      return 0;
+    }
+    let mut val = self.permute(index);
+    while val >= self.size {
+      val = self.permute(val);
+    }
+    val
+  }
+
+  fn permute(&self, val: u128) -> u128 {
+    let mask: u64 = if self.half_bits >= 64 { u64::MAX } else { (1u64 << self.half_bits) - 1 };
+    let mut left = ((val >> self.half_bits) as u64) & mask;
+    let mut right = (val as u64) & mask;
+    for round in 0..ROUNDS {
+      let f = splitmix64(self.seed ^ round.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ right) & mask;
+      let next_right = left ^ f;
+      left = right;
+      right = next_right;
+    }
+    ((left as u128) << self.half_bits) | (right as u128)
+  }
+}
+
+fn splitmix64(x: u64) -> u64 {
+  let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+  z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+  z ^ (z >> 31)
+}
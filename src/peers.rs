@@ -0,0 +1,499 @@
+
+/*!
+ * The PeerRegistry is meili's address book. Peers are keyed by their
+ * Ed25519 public key and remember every address they were seen at,
+ * how far we trust them and a nickname and notes chosen by the user.
+ * It is stored as peers.toml in the app_dir so it survives restarts,
+ * and may be edited by hand while meili is not running.
+ */
+
+use serde::{Serialize, Deserialize, Serializer, Deserializer, de};
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::net::SocketAddr;
+use std::fs;
+use std::fmt;
+use std::io::{self, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::punwrap_r;
+use crate::identity::{self, PUBLIC_KEY_LEN};
+use crate::net::transport::PeerAddr;
+use crate::util::{to_hex, from_hex};
+
+// Anyone can make up keys and addresses to send from, so what we hear
+// is saved at most this often rather than rewriting peers.toml per packet.
+const SAVE_INTERVAL_S: u64 = 60;
+// The least recently seen address makes way for a new one
+const MAX_ADDRESSES_PER_PEER: usize = 16;
+// Likewise the least recently seen Unknown or Seen peer makes way for a new
+// one, so made up keys cannot grow peers.toml without end. Verified and
+// Blocked peers are decisions the user made and are never dropped.
+pub const MAX_UNTRUSTED_PEERS: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TrustLevel {
+  // Added by hand but never heard from
+  Unknown,
+  Seen,
+  // The user has confirmed the peer's identity out of band
+  Verified,
+  // Packets from blocked peers are dropped
+  Blocked,
+}
+
+impl TrustLevel {
+  pub fn name(&self) -> &'static str {
+    match self {
+      TrustLevel::Unknown => "unknown",
+      TrustLevel::Seen => "seen",
+      TrustLevel::Verified => "verified",
+      TrustLevel::Blocked => "blocked",
+    }
+  }
+  /**
+   * Whether a peer at this level is trusted at least as much as min.
+   * Blocked peers are never trusted.
+   */
+  pub fn at_least(&self, min: TrustLevel) -> bool {
+    let rank = |level: TrustLevel| match level {
+      TrustLevel::Blocked => None,
+      TrustLevel::Unknown => Some(0),
+      TrustLevel::Seen => Some(1),
+      TrustLevel::Verified => Some(2),
+    };
+    match (rank(*self), rank(min)) {
+      (Some(rank), Some(min_rank)) => rank >= min_rank,
+      _ => false,
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<TrustLevel> {
+    match name {
+      "unknown" => Some(TrustLevel::Unknown),
+      "seen" => Some(TrustLevel::Seen),
+      "verified" => Some(TrustLevel::Verified),
+      "blocked" => Some(TrustLevel::Blocked),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for TrustLevel {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.name())
+  }
+}
+
+fn default_trust() -> TrustLevel {
+  TrustLevel::Unknown
+}
+
+/**
+ * One address a peer was seen at. Times are unix seconds.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SeenAddress {
+  // "udp", "tcp", "http" (the socket of an http mailbox relay)
+  // or "relay" (the socket of a rendezvous server relaying for the peer)
+  pub transport: String,
+  pub socket: SocketAddr,
+  pub first_seen: u64,
+  pub last_seen: u64,
+}
+
+impl SeenAddress {
+  /**
+   * The address to send to, public_key being the peer seen here.
+   */
+  pub fn to_peer_addr(&self, public_key: &[u8; PUBLIC_KEY_LEN]) -> Option<PeerAddr> {
+    match self.transport.as_str() {
+      "udp" => Some(PeerAddr::Udp(self.socket)),
+      "tcp" => Some(PeerAddr::Tcp(self.socket)),
+      "http" => Some(PeerAddr::HttpMailbox(self.socket, *public_key)),
+      "relay" => Some(PeerAddr::Relay(self.socket, *public_key)),
+      _ => None,
+    }
+  }
+}
+
+fn transport_and_socket(addr: &PeerAddr) -> (&'static str, SocketAddr) {
+  match addr {
+    PeerAddr::Udp(socket) => ("udp", *socket),
+    PeerAddr::Tcp(socket) => ("tcp", *socket),
+    PeerAddr::HttpMailbox(server, _) => ("http", *server),
+    PeerAddr::Relay(server, _) => ("relay", *server),
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KnownPeer {
+  #[serde(serialize_with = "serialize_key", deserialize_with = "deserialize_key")]
+  pub public_key: [u8; PUBLIC_KEY_LEN],
+  pub nickname: Option<String>,
+  #[serde(default = "default_trust")]
+  pub trust: TrustLevel,
+  #[serde(default)]
+  pub notes: String,
+  // The last public address the peer advertised, for reaching it from outside its LAN
+  #[serde(default)]
+  pub public_addr: Option<SocketAddr>,
+  // Each side of a `verify` confirms the short authentication string
+  // matched; once both have the peer becomes Verified.
+  #[serde(default)]
+  pub verify_confirmed_by_us: bool,
+  #[serde(default)]
+  pub verify_confirmed_by_peer: bool,
+  // Written as [[peers.addresses]] tables, so this must stay the last field
+  #[serde(default)]
+  pub addresses: Vec<SeenAddress>,
+}
+
+impl KnownPeer {
+  pub fn fingerprint(&self) -> String {
+    identity::fingerprint_of(&self.public_key)
+  }
+
+  /**
+   * The address we heard from most recently.
+   */
+  pub fn last_address(&self) -> Option<&SeenAddress> {
+    self.addresses.iter().max_by_key(|a| a.last_seen)
+  }
+
+  pub fn last_seen(&self) -> Option<u64> {
+    self.last_address().map(|a| a.last_seen)
+  }
+}
+
+fn serialize_key<S: Serializer>(key: &[u8; PUBLIC_KEY_LEN], serializer: S) -> Result<S::Ok, S::Error> {
+  serializer.serialize_str(&to_hex(key))
+}
+
+fn deserialize_key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; PUBLIC_KEY_LEN], D::Error> {
+  let s = String::deserialize(deserializer)?;
+  match from_hex(&s) {
+    Some(bytes) if bytes.len() == PUBLIC_KEY_LEN => {
+      let mut key = [0; PUBLIC_KEY_LEN];
+      key.copy_from_slice(&bytes);
+      Ok(key)
+    }
+    _ => Err(de::Error::invalid_value(de::Unexpected::Str(&s), &"a hex encoded 32 byte public key")),
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct PeersFile {
+  #[serde(default)]
+  peers: Vec<KnownPeer>,
+}
+
+#[derive(Debug)]
+pub struct PeerRegistry {
+  path: PathBuf,
+  peers: HashMap<[u8; PUBLIC_KEY_LEN], KnownPeer>,
+  dirty: bool,
+  last_save: Instant,
+}
+
+impl PeerRegistry {
+  pub fn load(app_dir: &Path) -> PeerRegistry {
+    let mut path = app_dir.to_path_buf();
+    path.push("peers.toml");
+    let data = match fs::read_to_string(&path) {
+      Ok(contents) => {
+        match toml::from_str(&contents) {
+          Ok(data) => data,
+          Err(e) => {
+            // Saving over it would lose every Verified and Blocked peer for good
+            let aside = path.with_file_name(format!("peers.toml.bad-{}", now_s()));
+            match fs::rename(&path, &aside) {
+              Ok(()) => println!("Error reading {}, it was moved to {} and no peers are known until it is fixed and moved back: {}", path.to_string_lossy(), aside.to_string_lossy(), e),
+              Err(rename_e) => println!("Error reading {}, and it could not be moved aside e={}: {}", path.to_string_lossy(), rename_e, e),
+            }
+            PeersFile::default()
+          }
+        }
+      }
+      Err(_) => PeersFile::default(),
+    };
+    PeerRegistry {
+      path,
+      peers: data.peers.into_iter().map(|p| (p.public_key, p)).collect(),
+      dirty: false,
+      last_save: Instant::now(),
+    }
+  }
+
+  /**
+   * Records that public_key was heard from at addr, adding the peer if it is new.
+   * This is saved by the next save_if_due.
+   */
+  pub fn record_seen(&mut self, public_key: [u8; PUBLIC_KEY_LEN], addr: &PeerAddr) {
+    let now = now_s();
+    let (transport, socket) = transport_and_socket(addr);
+    if !self.peers.contains_key(&public_key) {
+      self.make_room_for_untrusted();
+    }
+    let peer = self.peers.entry(public_key).or_insert_with(|| {
+      KnownPeer {
+        public_key,
+        nickname: None,
+        trust: TrustLevel::Unknown,
+        notes: String::new(),
+        addresses: Vec::new(),
+        public_addr: None,
+        verify_confirmed_by_us: false,
+        verify_confirmed_by_peer: false,
+      }
+    });
+    if peer.trust == TrustLevel::Unknown {
+      peer.trust = TrustLevel::Seen;
+    }
+    match peer.addresses.iter_mut().find(|a| a.transport == transport && a.socket == socket) {
+      Some(seen) => seen.last_seen = now,
+      None => {
+        if peer.addresses.len() >= MAX_ADDRESSES_PER_PEER {
+          if let Some(oldest) = peer.addresses.iter().enumerate().min_by_key(|(_, a)| a.last_seen).map(|(i, _)| i) {
+            peer.addresses.swap_remove(oldest);
+          }
+        }
+        peer.addresses.push(SeenAddress {
+          transport: transport.to_string(),
+          socket,
+          first_seen: now,
+          last_seen: now,
+        });
+      }
+    }
+    self.dirty = true;
+  }
+
+  fn make_room_for_untrusted(&mut self) {
+    let untrusted: Vec<&KnownPeer> = self.peers.values()
+      .filter(|p| p.trust == TrustLevel::Unknown || p.trust == TrustLevel::Seen)
+      .collect();
+    if untrusted.len() < MAX_UNTRUSTED_PEERS {
+      return;
+    }
+    if let Some(oldest) = untrusted.iter().min_by_key(|p| p.last_seen()).map(|p| p.public_key) {
+      self.peers.remove(&oldest);
+    }
+  }
+
+  pub fn get(&self, public_key: &[u8; PUBLIC_KEY_LEN]) -> Option<&KnownPeer> {
+    self.peers.get(public_key)
+  }
+
+  pub fn is_blocked(&self, public_key: &[u8; PUBLIC_KEY_LEN]) -> bool {
+    self.peers.get(public_key).map(|p| p.trust == TrustLevel::Blocked).unwrap_or(false)
+  }
+
+  /**
+   * Every peer, most recently seen first.
+   */
+  pub fn list(&self) -> Vec<KnownPeer> {
+    let mut peers: Vec<KnownPeer> = self.peers.values().cloned().collect();
+    peers.sort_by_key(|p| std::cmp::Reverse(p.last_seen()));
+    peers
+  }
+
+  /**
+   * Looks a peer up by nickname, hex public key or a prefix
+   * of its fingerprint (with or without the colons).
+   */
+  pub fn find(&self, query: &str) -> Result<[u8; PUBLIC_KEY_LEN], String> {
+    let query_lower = query.to_lowercase();
+    let query_hex = query_lower.replace(":", "");
+    let matches: Vec<&KnownPeer> = self.peers.values()
+      .filter(|p| {
+        p.nickname.as_ref().map(|n| n == query).unwrap_or(false) ||
+        to_hex(&p.public_key) == query_hex ||
+        (!query_hex.is_empty() && p.fingerprint().replace(":", "").starts_with(&query_hex))
+      })
+      .collect();
+    match matches.len() {
+      0 => Err(format!("No known peer matches '{}'", query)),
+      1 => Ok(matches[0].public_key),
+      n => Err(format!("{} known peers match '{}', be more specific", n, query)),
+    }
+  }
+
+  pub fn set_nickname(&mut self, public_key: &[u8; PUBLIC_KEY_LEN], nickname: Option<String>) {
+    if let Some(peer) = self.peers.get_mut(public_key) {
+      peer.nickname = nickname;
+      self.dirty = true;
+      self.save();
+    }
+  }
+
+  pub fn set_trust(&mut self, public_key: &[u8; PUBLIC_KEY_LEN], trust: TrustLevel) {
+    if let Some(peer) = self.peers.get_mut(public_key) {
+      peer.trust = trust;
+      self.dirty = true;
+      self.save();
+    }
+  }
+
+  pub fn set_notes(&mut self, public_key: &[u8; PUBLIC_KEY_LEN], notes: String) {
+    if let Some(peer) = self.peers.get_mut(public_key) {
+      peer.notes = notes;
+      self.dirty = true;
+      self.save();
+    }
+  }
+
+  pub fn set_public_addr(&mut self, public_key: &[u8; PUBLIC_KEY_LEN], public_addr: SocketAddr) {
+    if let Some(peer) = self.peers.get_mut(public_key) {
+      if peer.public_addr != Some(public_addr) {
+        peer.public_addr = Some(public_addr);
+        self.dirty = true;
+        self.save();
+      }
+    }
+  }
+
+  /**
+   * Records that we (by_us) or the peer confirmed the short authentication
+   * string matched, returning the peer's trust afterwards. Blocked peers stay blocked.
+   */
+  pub fn confirm_verification(&mut self, public_key: &[u8; PUBLIC_KEY_LEN], by_us: bool) -> Option<TrustLevel> {
+    let peer = self.peers.get_mut(public_key)?;
+    if by_us {
+      peer.verify_confirmed_by_us = true;
+    } else {
+      peer.verify_confirmed_by_peer = true;
+    }
+    if peer.verify_confirmed_by_us && peer.verify_confirmed_by_peer && peer.trust != TrustLevel::Blocked {
+      peer.trust = TrustLevel::Verified;
+    }
+    let trust = peer.trust;
+    self.dirty = true;
+    self.save();
+    Some(trust)
+  }
+
+  pub fn save_if_due(&mut self) {
+    if self.dirty && self.last_save.elapsed() >= Duration::from_secs(SAVE_INTERVAL_S) {
+      self.save();
+    }
+  }
+
+  pub fn save(&mut self) {
+    self.last_save = Instant::now();
+    if !self.dirty {
+      return;
+    }
+    let data = PeersFile {
+      peers: self.list(),
+    };
+    let contents = punwrap_r!(toml::to_string(&data), return);
+    punwrap_r!(write_atomically(&self.path, contents.as_bytes()), return);
+    self.dirty = false;
+  }
+}
+
+/**
+ * Writes a file next to path and renames it over path, so a crash
+ * leaves either the old contents or the new ones and never a mix.
+ */
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+  let file_name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+  let tmp = path.with_file_name(format!("{}.tmp", file_name));
+  let mut file = fs::File::create(&tmp)?;
+  file.write_all(contents)?;
+  file.sync_all()?;
+  fs::rename(&tmp, path)
+}
+
+fn now_s() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn app_dir(name: &str) -> PathBuf {
+    let app_dir = std::env::temp_dir().join(format!("meili-peers-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&app_dir);
+    fs::create_dir_all(&app_dir).unwrap();
+    app_dir
+  }
+
+  fn key(n: usize) -> [u8; PUBLIC_KEY_LEN] {
+    let mut key = [0; PUBLIC_KEY_LEN];
+    key[..8].copy_from_slice(&(n as u64).to_be_bytes());
+    key
+  }
+
+  fn seen_at(registry: &mut PeerRegistry, n: usize, last_seen: u64) {
+    registry.record_seen(key(n), &PeerAddr::Udp("127.0.0.1:1337".parse().unwrap()));
+    for a in registry.peers.get_mut(&key(n)).unwrap().addresses.iter_mut() {
+      a.last_seen = last_seen;
+    }
+  }
+
+  #[test]
+  fn the_oldest_untrusted_peer_makes_way() {
+    let app_dir = app_dir("cap");
+    let mut registry = PeerRegistry::load(&app_dir);
+    // The oldest of all, but decided on by the user
+    seen_at(&mut registry, 0, 1);
+    registry.set_trust(&key(0), TrustLevel::Verified);
+    seen_at(&mut registry, 1, 1);
+    registry.set_trust(&key(1), TrustLevel::Blocked);
+    for n in 2..MAX_UNTRUSTED_PEERS + 2 {
+      seen_at(&mut registry, n, 100 + n as u64);
+    }
+    assert_eq!(registry.peers.len(), MAX_UNTRUSTED_PEERS + 2);
+
+    seen_at(&mut registry, MAX_UNTRUSTED_PEERS + 2, 10_000);
+    assert_eq!(registry.peers.len(), MAX_UNTRUSTED_PEERS + 2);
+    assert!(registry.get(&key(2)).is_none());
+    assert_eq!(registry.get(&key(0)).map(|p| p.trust), Some(TrustLevel::Verified));
+    assert_eq!(registry.get(&key(1)).map(|p| p.trust), Some(TrustLevel::Blocked));
+    assert!(registry.get(&key(MAX_UNTRUSTED_PEERS + 2)).is_some());
+
+    // A peer we already know is not new
+    seen_at(&mut registry, 3, 10_001);
+    assert!(registry.get(&key(4)).is_some());
+    let _ = fs::remove_dir_all(&app_dir);
+  }
+
+  #[test]
+  fn saves_replace_the_file_whole() {
+    let app_dir = app_dir("save");
+    let mut registry = PeerRegistry::load(&app_dir);
+    seen_at(&mut registry, 1, 1);
+    registry.set_trust(&key(1), TrustLevel::Blocked);
+    assert!(!app_dir.join("peers.toml.tmp").exists());
+    let registry = PeerRegistry::load(&app_dir);
+    assert!(registry.is_blocked(&key(1)));
+    let _ = fs::remove_dir_all(&app_dir);
+  }
+
+  #[test]
+  fn a_bad_file_is_kept_aside() {
+    let app_dir = app_dir("bad");
+    let mut registry = PeerRegistry::load(&app_dir);
+    seen_at(&mut registry, 1, 1);
+    registry.set_trust(&key(1), TrustLevel::Blocked);
+    let saved = fs::read_to_string(app_dir.join("peers.toml")).unwrap();
+    let torn = &saved[..saved.len() / 2];
+    fs::write(app_dir.join("peers.toml"), torn).unwrap();
+
+    let registry = PeerRegistry::load(&app_dir);
+    assert!(registry.list().is_empty());
+    assert!(!app_dir.join("peers.toml").exists());
+    let aside: Vec<PathBuf> = fs::read_dir(&app_dir).unwrap()
+      .map(|e| e.unwrap().path())
+      .filter(|p| p.file_name().unwrap().to_string_lossy().starts_with("peers.toml.bad-"))
+      .collect();
+    assert_eq!(aside.len(), 1);
+    assert_eq!(fs::read_to_string(&aside[0]).unwrap(), torn);
+    let _ = fs::remove_dir_all(&app_dir);
+  }
+}
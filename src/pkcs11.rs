@@ -0,0 +1,266 @@
+
+/*!
+ * Identity keys held by a hardware token, smartcard or SoftHSM.
+ * The private key never leaves the token, we only ask the token's
+ * PKCS#11 module to sign with it (CKM_EDDSA over an Ed25519 key).
+ */
+
+use cryptoki::context::{Pkcs11, CInitializeArgs};
+use cryptoki::error::{Error, RvError};
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{Attribute, AttributeType, ObjectClass, ObjectHandle, KeyType};
+use cryptoki::session::{Session, UserType};
+use cryptoki::slot::Slot;
+use cryptoki::types::AuthPin;
+
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::config::Pkcs11Identity;
+use crate::identity::{PUBLIC_KEY_LEN, SIGNATURE_LEN};
+use crate::util::{to_hex, from_hex};
+
+pub struct TokenKey {
+  context: Pkcs11,
+  session: Mutex<Session>,
+  key: ObjectHandle,
+  // eg "slot 0 token 'meili' key 'identity'", for humans
+  pub description: String,
+}
+
+/**
+ * What the pkcs11-slots shell command prints about each slot.
+ */
+pub struct SlotSummary {
+  pub slot_id: u64,
+  pub description: String,
+  // None when the slot is empty
+  pub token_label: Option<String>,
+  pub keys: Vec<KeySummary>,
+  // Why keys could not be listed, eg a missing pin
+  pub error: Option<String>,
+}
+
+pub struct KeySummary {
+  pub handle: String,
+  pub class: &'static str,
+  pub key_type: String,
+  pub label: String,
+  pub id: String,
+}
+
+/**
+ * Loads and initializes a PKCS#11 module.
+ */
+pub fn open_module(module: &Path) -> Result<Pkcs11, String> {
+  let context = Pkcs11::new(module)
+    .map_err(|e| format!("Could not load PKCS#11 module {}: {}", module.to_string_lossy(), e))?;
+  match context.initialize(CInitializeArgs::OsThreads) {
+    // Someone else in this process already initialized the module, which is fine
+    Ok(()) | Err(Error::Pkcs11(RvError::CryptokiAlreadyInitialized)) => Ok(context),
+    Err(e) => Err(format!("Could not initialize PKCS#11 module {}: {}", module.to_string_lossy(), e)),
+  }
+}
+
+impl TokenKey {
+  /**
+   * Finds the identity key described by conf and logs in to its token.
+   * Returns the key together with its public half.
+   */
+  pub fn open(conf: &Pkcs11Identity) -> Result<(TokenKey, [u8; PUBLIC_KEY_LEN]), String> {
+    if conf.key_label.is_none() && conf.key_id.is_none() {
+      return Err("pkcs11_identity needs a key_label or key_id to pick the identity key".to_string());
+    }
+    let key_id = match &conf.key_id {
+      Some(hex) => Some(from_hex(hex).ok_or_else(|| format!("pkcs11_identity key_id '{}' is not hex", hex))?),
+      None => None,
+    };
+    let context = open_module(&conf.module)?;
+    let slots = context.get_slots_with_token().map_err(|e| format!("Could not list PKCS#11 slots: {}", e))?;
+
+    let mut last_error = format!("No token in {} holds the key", conf.module.to_string_lossy());
+    for slot in slots {
+      let token_label = match context.get_token_info(slot) {
+        Ok(info) => info.label().trim().to_string(),
+        Err(e) => {
+          last_error = format!("Could not read token in slot {}: {}", slot.id(), e);
+          continue;
+        }
+      };
+      if let Some(want) = &conf.token_label {
+        if want != &token_label {
+          continue;
+        }
+      }
+      let session = match open_session(&context, slot, conf.user_pin()) {
+        Ok(session) => session,
+        Err(e) => {
+          last_error = e;
+          continue;
+        }
+      };
+
+      let mut template = vec![Attribute::KeyType(KeyType::EC_EDWARDS)];
+      if let Some(label) = &conf.key_label {
+        template.push(Attribute::Label(label.as_bytes().to_vec()));
+      }
+      if let Some(id) = &key_id {
+        template.push(Attribute::Id(id.clone()));
+      }
+      let find = |class: ObjectClass| -> Result<Vec<ObjectHandle>, String> {
+        let mut t = template.clone();
+        t.push(Attribute::Class(class));
+        session.find_objects(&t).map_err(|e| format!("Could not search token '{}': {}", token_label, e))
+      };
+      let private_keys = find(ObjectClass::PRIVATE_KEY)?;
+      let public_keys = find(ObjectClass::PUBLIC_KEY)?;
+      if private_keys.is_empty() {
+        continue;
+      }
+      if private_keys.len() > 1 {
+        return Err(format!("{} keys on token '{}' match pkcs11_identity, set key_id to pick one", private_keys.len(), token_label));
+      }
+      let public_key = match public_keys.first() {
+        Some(handle) => read_ec_point(&session, *handle)?,
+        None => return Err(format!("Token '{}' holds the identity key but not its public key", token_label)),
+      };
+
+      let key = TokenKey {
+        context: context.clone(),
+        key: private_keys[0],
+        description: format!("slot {} token '{}' key {}", slot.id(), token_label, private_keys[0]),
+        session: Mutex::new(session),
+      };
+      // A key whose signatures do not verify against the public key we
+      // found would give us an identity nobody can talk to.
+      let probe = b"meili pkcs11 identity check";
+      let signature = key.sign(probe)?;
+      if !crate::identity::verify(&public_key, probe, &signature) {
+        return Err(format!("The public key on token '{}' does not match its private key", token_label));
+      }
+      return Ok((key, public_key));
+    }
+    Err(last_error)
+  }
+
+  pub fn context(&self) -> &Pkcs11 {
+    &self.context
+  }
+
+  pub fn sign(&self, msg: &[u8]) -> Result<[u8; SIGNATURE_LEN], String> {
+    let session = self.session.lock().map_err(|e| e.to_string())?;
+    let bytes = session.sign(&Mechanism::Eddsa, self.key, msg).map_err(|e| e.to_string())?;
+    if bytes.len() != SIGNATURE_LEN {
+      return Err(format!("token returned a {} byte signature", bytes.len()));
+    }
+    let mut signature = [0u8; SIGNATURE_LEN];
+    signature.copy_from_slice(&bytes);
+    Ok(signature)
+  }
+}
+
+fn open_session(context: &Pkcs11, slot: Slot, pin: Option<String>) -> Result<Session, String> {
+  let session = context.open_ro_session(slot).map_err(|e| format!("Could not open session on slot {}: {}", slot.id(), e))?;
+  let info = context.get_token_info(slot).map_err(|e| format!("Could not read token in slot {}: {}", slot.id(), e))?;
+  if !info.login_required() {
+    return Ok(session);
+  }
+  let login = match pin {
+    Some(pin) => session.login(UserType::User, Some(&AuthPin::new(pin))),
+    // Pin pad readers take the pin themselves
+    None if info.protected_authentication_path() => session.login(UserType::User, None),
+    None => return Err(format!("Token '{}' needs a pin, set pin in [pkcs11_identity] or MEILI_PKCS11_PIN", info.label().trim())),
+  };
+  match login {
+    Ok(()) | Err(Error::Pkcs11(RvError::UserAlreadyLoggedIn)) => Ok(session),
+    Err(e) => Err(format!("Could not log in to token '{}': {}", info.label().trim(), e)),
+  }
+}
+
+/**
+ * Ed25519 public keys are stored in CKA_EC_POINT, which most modules
+ * wrap in a DER OCTET STRING (04 20 ...) and some store raw.
+ */
+fn read_ec_point(session: &Session, handle: ObjectHandle) -> Result<[u8; PUBLIC_KEY_LEN], String> {
+  let attrs = session.get_attributes(handle, &[AttributeType::EcPoint]).map_err(|e| e.to_string())?;
+  let point = attrs.into_iter().find_map(|a| match a {
+    Attribute::EcPoint(point) => Some(point),
+    _ => None,
+  }).ok_or_else(|| "public key has no CKA_EC_POINT".to_string())?;
+  let raw = match point.len() {
+    PUBLIC_KEY_LEN => &point[..],
+    34 if point[0] == 0x04 && point[1] == PUBLIC_KEY_LEN as u8 => &point[2..],
+    n => return Err(format!("CKA_EC_POINT is {} bytes, not an Ed25519 key", n)),
+  };
+  let mut public_key = [0u8; PUBLIC_KEY_LEN];
+  public_key.copy_from_slice(raw);
+  Ok(public_key)
+}
+
+/**
+ * Lists every slot of the module and the keys on its token.
+ * Private keys are only visible once logged in, so pin is used if given.
+ */
+pub fn list_slots(context: &Pkcs11, pin: Option<String>) -> Result<Vec<SlotSummary>, String> {
+  let slots = context.get_all_slots().map_err(|e| format!("Could not list PKCS#11 slots: {}", e))?;
+  let mut summaries = Vec::new();
+  for slot in slots {
+    let description = match context.get_slot_info(slot) {
+      Ok(info) => info.slot_description().trim().to_string(),
+      Err(e) => e.to_string(),
+    };
+    let mut summary = SlotSummary {
+      slot_id: slot.id(),
+      description,
+      token_label: None,
+      keys: Vec::new(),
+      error: None,
+    };
+    match context.get_token_info(slot) {
+      Ok(info) => summary.token_label = Some(info.label().trim().to_string()),
+      Err(_) => {
+        summaries.push(summary);
+        continue;
+      }
+    }
+    match list_keys(context, slot, pin.clone()) {
+      Ok(keys) => summary.keys = keys,
+      Err(e) => summary.error = Some(e),
+    }
+    summaries.push(summary);
+  }
+  Ok(summaries)
+}
+
+fn list_keys(context: &Pkcs11, slot: Slot, pin: Option<String>) -> Result<Vec<KeySummary>, String> {
+  let session = match open_session(context, slot, pin) {
+    Ok(session) => session,
+    // Without a pin we can still show the public objects
+    Err(_) => context.open_ro_session(slot).map_err(|e| e.to_string())?,
+  };
+  let mut keys = Vec::new();
+  for (class, class_name) in &[(ObjectClass::PRIVATE_KEY, "private"), (ObjectClass::PUBLIC_KEY, "public")] {
+    let handles = session.find_objects(&[Attribute::Class(*class)]).map_err(|e| e.to_string())?;
+    for handle in handles {
+      let mut key = KeySummary {
+        handle: handle.to_string(),
+        class: class_name,
+        key_type: String::new(),
+        label: String::new(),
+        id: String::new(),
+      };
+      let attrs = session.get_attributes(handle, &[AttributeType::KeyType, AttributeType::Label, AttributeType::Id])
+        .unwrap_or_default();
+      for attr in attrs {
+        match attr {
+          Attribute::KeyType(t) => key.key_type = t.to_string(),
+          Attribute::Label(l) => key.label = String::from_utf8_lossy(&l).to_string(),
+          Attribute::Id(id) => key.id = to_hex(&id),
+          _ => {}
+        }
+      }
+      keys.push(key);
+    }
+  }
+  Ok(keys)
+}
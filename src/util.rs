@@ -49,3 +49,44 @@ macro_rules! punwrap_r {
       }
     };
 }
+
+pub fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+  if !s.len().is_multiple_of(2) || !s.is_ascii() {
+    return None;
+  }
+  (0..s.len()).step_by(2)
+    .map(|i| u8::from_str_radix(&s[i..i+2], 16).ok())
+    .collect()
+}
+
+/**
+ * The candidate closest to word, if it is close enough to likely be
+ * what a mistyped word meant. Closeness is the edit distance.
+ */
+pub fn closest_match<'a>(word: &str, candidates: &[&'a str]) -> Option<&'a str> {
+  let max_distance = std::cmp::max(1, word.chars().count() / 3);
+  candidates.iter()
+    .map(|c| (edit_distance(word, c), *c))
+    .filter(|(distance, _)| *distance <= max_distance)
+    .min_by_key(|(distance, _)| *distance)
+    .map(|(_, c)| c)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut row: Vec<usize> = (0..=b.len()).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut diagonal = row[0];
+    row[0] = i + 1;
+    for (j, cb) in b.iter().enumerate() {
+      let substitute = diagonal + if ca == *cb { 0 } else { 1 };
+      diagonal = row[j + 1];
+      row[j + 1] = std::cmp::min(substitute, std::cmp::min(row[j], row[j + 1]) + 1);
+    }
+  }
+  row[b.len()]
+}